@@ -0,0 +1,108 @@
+use criterion::{criterion_group, criterion_main, Benchmark, Criterion};
+use std::collections::{BTreeMap, HashMap};
+use vector::sinks::influxdb::{
+    encode_fields, encode_tags, influx_line_protocol, BoolFormat, Field,
+};
+
+struct Point {
+    measurement: String,
+    tags: BTreeMap<String, String>,
+    fields: HashMap<String, Field>,
+}
+
+// A counter with a couple of tags, as most metrics are.
+fn small_point() -> Point {
+    Point {
+        measurement: "vector.requests_total".to_owned(),
+        tags: vec![("host", "web-01"), ("region", "us-east-1")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect(),
+        fields: vec![("value".to_owned(), Field::Float(42.0))]
+            .into_iter()
+            .collect(),
+    }
+}
+
+// A histogram with many high-cardinality tags and bucket fields.
+fn many_tags_point() -> Point {
+    Point {
+        measurement: "vector.request_duration_seconds".to_owned(),
+        tags: (0..32)
+            .map(|i| (format!("tag_{}", i), format!("value-{}-{:x}", i, i * 7919)))
+            .collect(),
+        fields: (0..16)
+            .map(|i| (format!("bucket_{}", i), Field::UnsignedInt(i * 100)))
+            .chain(vec![
+                ("count".to_owned(), Field::UnsignedInt(1600)),
+                ("sum".to_owned(), Field::Float(1234.5678)),
+            ])
+            .collect(),
+    }
+}
+
+// A log line with long strings that need escaping.
+fn log_point() -> Point {
+    Point {
+        measurement: "ns.vector".to_owned(),
+        tags: vec![("host", "web-01"), ("source_type", "file")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect(),
+        fields: vec![
+            (
+                "message",
+                r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#,
+            ),
+            ("file", r"C:\var\log\apache\access.log"),
+            ("user", "frank"),
+            ("request", "GET /apache_pb.gif HTTP/1.0"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), Field::String(value.to_owned())))
+        .collect(),
+    }
+}
+
+fn benchmark(encode: fn(Point, &mut String)) -> Benchmark {
+    Benchmark::new("small", move |b| {
+        b.iter_with_setup(small_point, |point| encode(point, &mut String::new()))
+    })
+    .with_function("many_tags", move |b| {
+        b.iter_with_setup(many_tags_point, |point| encode(point, &mut String::new()))
+    })
+    .with_function("log", move |b| {
+        b.iter_with_setup(log_point, |point| encode(point, &mut String::new()))
+    })
+    .sample_size(50)
+    .noise_threshold(0.05)
+}
+
+fn encoders(c: &mut Criterion) {
+    c.bench(
+        "influxdb_encode_tags",
+        benchmark(|point, output| encode_tags(point.tags, output)),
+    );
+    c.bench(
+        "influxdb_encode_fields",
+        benchmark(|point, output| encode_fields(point.fields, BoolFormat::Long, output)),
+    );
+    c.bench(
+        "influxdb_line_protocol",
+        benchmark(|point, output| {
+            influx_line_protocol(
+                point.measurement,
+                "logs",
+                Some(point.tags),
+                Some(point.fields),
+                1_542_182_950_000_000_011,
+                BoolFormat::Long,
+                output,
+            )
+            .unwrap()
+        }),
+    );
+}
+
+criterion_group!(influxdb, encoders);
+criterion_main!(influxdb);
@@ -5,10 +5,14 @@ use hyper::client::connect::dns::{Name, Resolve};
 use hyper13::client::connect::dns::Name as Name13;
 use snafu::{futures01::FutureExt as _, ResultExt};
 use std::{
+    cmp,
+    collections::HashMap,
     fmt,
     net::{IpAddr, SocketAddr},
     str::FromStr,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 use tower03::Service;
 use trust_dns_resolver::{
@@ -22,14 +26,34 @@ const DNS_PORT: u16 = 53;
 
 pub type ResolverFuture = Box<dyn Future<Item = LookupIp, Error = DnsError> + Send + 'static>;
 
+/// Resolved addresses together with the instant until which they are valid.
+pub type CacheableLookupFuture =
+    Box<dyn Future<Item = (Vec<IpAddr>, Instant), Error = DnsError> + Send + 'static>;
+
 #[derive(Debug, Clone)]
 pub struct Resolver {
     inner: AsyncResolver,
+    cache: Option<DnsCache>,
 }
 
 pub enum LookupIp {
     Single(Option<IpAddr>),
     Query(LookupIpIntoIter),
+    Cached(std::vec::IntoIter<IpAddr>),
+}
+
+/// Keeps the results of successful lookups for at most `ttl`, or until the
+/// DNS records themselves expire, whichever comes first.
+#[derive(Debug, Clone)]
+pub struct DnsCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, CachedLookup>>>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedLookup {
+    addrs: Vec<IpAddr>,
+    expires_at: Instant,
 }
 
 impl Resolver {
@@ -81,7 +105,16 @@ impl Resolver {
 
         exec.spawn(bg_task);
 
-        Ok(Self { inner })
+        Ok(Self { inner, cache: None })
+    }
+
+    /// Returns a resolver sharing the same underlying resolver, but caching
+    /// successful lookups for at most `ttl`.
+    pub fn with_cache(&self, ttl: Duration) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            cache: Some(DnsCache::new(ttl)),
+        }
     }
 
     pub fn lookup_ip(&self, name: impl AsRef<str>) -> ResolverFuture {
@@ -89,12 +122,73 @@ impl Resolver {
             return Box::new(future::ok(LookupIp::Single(Some(ip))));
         }
 
-        Box::new(
-            self.inner
-                .lookup_ip(name.as_ref())
-                .context(UnableLookup)
-                .map(|lu| LookupIp::Query(lu.into_iter())),
-        )
+        match &self.cache {
+            Some(cache) => cache.lookup(name.as_ref(), |name: &str| -> CacheableLookupFuture {
+                Box::new(
+                    self.inner
+                        .lookup_ip(name)
+                        .context(UnableLookup)
+                        .map(|lu| (lu.iter().collect::<Vec<_>>(), lu.valid_until())),
+                )
+            }),
+            None => Box::new(
+                self.inner
+                    .lookup_ip(name.as_ref())
+                    .context(UnableLookup)
+                    .map(|lu| LookupIp::Query(lu.into_iter())),
+            ),
+        }
+    }
+}
+
+impl DnsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Answers from the cache when possible, otherwise performs `lookup` and
+    /// stores its result.
+    pub fn lookup<F>(&self, name: &str, lookup: F) -> ResolverFuture
+    where
+        F: FnOnce(&str) -> CacheableLookupFuture,
+    {
+        if let Some(addrs) = self.get(name) {
+            return Box::new(future::ok(LookupIp::Cached(addrs.into_iter())));
+        }
+
+        let entries = Arc::clone(&self.entries);
+        let ttl = self.ttl;
+        let key = name.to_owned();
+
+        Box::new(lookup(name).map(move |(addrs, valid_until)| {
+            let expires_at = cmp::min(Instant::now() + ttl, valid_until);
+            entries.lock().unwrap().insert(
+                key,
+                CachedLookup {
+                    addrs: addrs.clone(),
+                    expires_at,
+                },
+            );
+            LookupIp::Cached(addrs.into_iter())
+        }))
+    }
+
+    fn get(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        let addrs = entries
+            .get(name)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| entry.addrs.clone());
+        if addrs.is_none() {
+            entries.remove(name);
+        }
+
+        addrs
     }
 }
 
@@ -105,6 +199,7 @@ impl Iterator for LookupIp {
         match self {
             LookupIp::Single(ip) => ip.take(),
             LookupIp::Query(iter) => iter.next(),
+            LookupIp::Cached(iter) => iter.next(),
         }
     }
 }
@@ -202,13 +297,19 @@ impl From<trust_dns_proto::error::ProtoError> for ProtoError {
 
 #[cfg(test)]
 mod tests {
-    use super::Resolver;
+    use super::{CacheableLookupFuture, DnsCache, Resolver};
     use crate::runtime::Runtime;
     use crate::test_util::{next_addr, runtime};
     use crate::topology::config::GlobalOptions;
+    use futures01::{future, Future};
     use std::collections::BTreeMap;
     use std::net::{IpAddr, SocketAddr, UdpSocket};
     use std::str::FromStr;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::{Duration, Instant};
     use tokio01::prelude::{future::poll_fn, Async};
     use trust_dns::rr::{record_data::RData, LowerName, Name, RecordSet, RecordType, RrKey};
     use trust_dns_proto::rr::rdata::soa::SOA;
@@ -421,4 +522,78 @@ mod tests {
             Some(IpAddr::from_str("2001:0db8:85a3:0000:0000:8a2e:0370:7334").unwrap())
         );
     }
+
+    fn counting_lookup(
+        lookups: Arc<AtomicUsize>,
+        valid_for: Duration,
+    ) -> impl FnOnce(&str) -> CacheableLookupFuture {
+        move |_name: &str| -> CacheableLookupFuture {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            let ip = "10.45.12.34".parse::<IpAddr>().unwrap();
+            Box::new(future::ok((vec![ip], Instant::now() + valid_for)))
+        }
+    }
+
+    #[test]
+    fn cache_reuses_lookups_until_ttl() {
+        let cache = DnsCache::new(Duration::from_millis(200));
+        let lookups = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..5 {
+            let mut addrs = cache
+                .lookup(
+                    "influxdb.vector.test",
+                    counting_lookup(Arc::clone(&lookups), Duration::from_secs(60)),
+                )
+                .wait()
+                .unwrap();
+            assert_eq!(addrs.next(), Some("10.45.12.34".parse().unwrap()));
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        std::thread::sleep(Duration::from_millis(300));
+
+        cache
+            .lookup(
+                "influxdb.vector.test",
+                counting_lookup(Arc::clone(&lookups), Duration::from_secs(60)),
+            )
+            .wait()
+            .unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn cache_respects_record_ttl() {
+        let cache = DnsCache::new(Duration::from_secs(60));
+        let lookups = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..3 {
+            cache
+                .lookup(
+                    "influxdb.vector.test",
+                    counting_lookup(Arc::clone(&lookups), Duration::from_secs(0)),
+                )
+                .wait()
+                .unwrap();
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn cache_keys_by_name() {
+        let cache = DnsCache::new(Duration::from_secs(60));
+        let lookups = Arc::new(AtomicUsize::new(0));
+
+        for name in &["a.vector.test", "b.vector.test", "a.vector.test"] {
+            cache
+                .lookup(
+                    name,
+                    counting_lookup(Arc::clone(&lookups), Duration::from_secs(60)),
+                )
+                .wait()
+                .unwrap();
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
 }
@@ -0,0 +1,630 @@
+use super::InternalEvent;
+use crate::sinks::influxdb::{
+    AuthError, CircuitState, LineProtocolError, OnEncodeError, SchemaViolation,
+};
+use http02::Uri;
+use metrics::{counter, gauge, value};
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct InfluxDBDryRunBatch<'a> {
+    pub uri: &'a Uri,
+    pub body: &'a [u8],
+}
+
+impl<'a> InternalEvent for InfluxDBDryRunBatch<'a> {
+    fn emit_logs(&self) {
+        debug!(
+            message = "dry run; not sending batch.",
+            uri = %self.uri,
+            body = %String::from_utf8_lossy(self.body),
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "dry_run_bytes", self.body.len() as u64,
+            "component_kind" => "sink",
+            "component_type" => "influxdb_metrics",
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBStdoutWriteFailed {
+    pub error: io::Error,
+}
+
+impl InternalEvent for InfluxDBStdoutWriteFailed {
+    fn emit_logs(&self) {
+        error!(
+            message = "could not write batch to stdout; dropping it.",
+            error = %self.error,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "stdout_write_errors", 1,
+            "component_kind" => "sink",
+            "component_type" => "influxdb_metrics",
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBEventDropped {
+    pub component_type: &'static str,
+    pub error: LineProtocolError,
+    pub on_encode_error: OnEncodeError,
+}
+
+impl InternalEvent for InfluxDBEventDropped {
+    fn emit_logs(&self) {
+        match self.on_encode_error {
+            OnEncodeError::Drop => debug!(
+                message = "event can't be encoded as line protocol; dropping event.",
+                error = %self.error,
+                rate_limit_secs = 30,
+            ),
+            OnEncodeError::DropAndLog => warn!(
+                message = "event can't be encoded as line protocol; dropping event.",
+                error = %self.error,
+                rate_limit_secs = 30,
+            ),
+            OnEncodeError::Error => error!(
+                message = "event can't be encoded as line protocol; dropping event.",
+                error = %self.error,
+                rate_limit_secs = 30,
+            ),
+        }
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "events_dropped", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBFieldTypeConflict<'a> {
+    pub component_type: &'static str,
+    pub measurement: &'a str,
+    pub field: &'a str,
+    pub expected: &'static str,
+    pub actual: &'static str,
+    pub dropped: bool,
+}
+
+impl<'a> InternalEvent for InfluxDBFieldTypeConflict<'a> {
+    fn emit_logs(&self) {
+        warn!(
+            message = "field type differs from the one first written; InfluxDB will reject the point.",
+            measurement = %self.measurement,
+            field = %self.field,
+            expected = %self.expected,
+            actual = %self.actual,
+            dropped = %self.dropped,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "field_type_conflicts", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+        if self.dropped {
+            counter!(
+                "events_dropped", 1,
+                "component_kind" => "sink",
+                "component_type" => self.component_type,
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBMissingTimestamp {
+    pub component_type: &'static str,
+    pub error: bool,
+}
+
+impl InternalEvent for InfluxDBMissingTimestamp {
+    fn emit_logs(&self) {
+        if self.error {
+            error!(
+                message = "event has no timestamp; dropping event.",
+                rate_limit_secs = 30,
+            );
+        } else {
+            debug!(
+                message = "event has no timestamp; dropping event.",
+                rate_limit_secs = 30,
+            );
+        }
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "events_dropped", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBSchemaViolation {
+    pub component_type: &'static str,
+    pub error: SchemaViolation,
+}
+
+impl InternalEvent for InfluxDBSchemaViolation {
+    fn emit_logs(&self) {
+        warn!(
+            message = "point doesn't match the bucket schema; dropping event.",
+            error = %self.error,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "events_dropped", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBAuthFailed {
+    pub error: AuthError,
+}
+
+impl InternalEvent for InfluxDBAuthFailed {
+    fn emit_logs(&self) {
+        error!(
+            message = "InfluxDB refused the write; dropping the request.",
+            error = %self.error,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "auth_errors", 1,
+            "component_kind" => "sink",
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBCircuitBreakerStateChanged {
+    pub component_type: &'static str,
+    pub state: CircuitState,
+}
+
+impl InternalEvent for InfluxDBCircuitBreakerStateChanged {
+    fn emit_logs(&self) {
+        match self.state {
+            CircuitState::Open => warn!(
+                message =
+                    "writes keep failing; holding them back until the circuit breaker cools down."
+            ),
+            CircuitState::HalfOpen => debug!(message = "circuit breaker probing InfluxDB."),
+            CircuitState::Closed => info!(message = "write succeeded; circuit breaker closed."),
+        }
+    }
+
+    fn emit_metrics(&self) {
+        // 0 when closed, 1 when open and 2 when half-open.
+        let state = match self.state {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        };
+        gauge!(
+            "circuit_breaker_state", state,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBDeadLetterWritten<'a> {
+    pub component_type: &'static str,
+    pub path: &'a Path,
+    pub count: usize,
+}
+
+impl<'a> InternalEvent for InfluxDBDeadLetterWritten<'a> {
+    fn emit_logs(&self) {
+        warn!(
+            message = "points rejected by InfluxDB; written to the dead letter file.",
+            path = ?self.path,
+            count = self.count,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "dead_letter_events", self.count as u64,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBDeadLetterFailed<'a> {
+    pub component_type: &'static str,
+    pub path: &'a Path,
+    pub error: io::Error,
+}
+
+impl<'a> InternalEvent for InfluxDBDeadLetterFailed<'a> {
+    fn emit_logs(&self) {
+        error!(
+            message = "could not write rejected points to the dead letter file.",
+            path = ?self.path,
+            error = %self.error,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "dead_letter_errors", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBRequestBodySize {
+    pub component_type: &'static str,
+    pub bytes: usize,
+    pub warn_bytes: Option<usize>,
+}
+
+impl InfluxDBRequestBodySize {
+    pub fn is_oversized(&self) -> bool {
+        self.warn_bytes
+            .map_or(false, |warn_bytes| self.bytes > warn_bytes)
+    }
+}
+
+impl InternalEvent for InfluxDBRequestBodySize {
+    fn emit_logs(&self) {
+        if self.is_oversized() {
+            warn!(
+                message = "request body is larger than `warn_body_bytes`; check the batch size and the cardinality of tags.",
+                bytes = self.bytes,
+                warn_body_bytes = ?self.warn_bytes,
+                rate_limit_secs = 30,
+            );
+        }
+    }
+
+    fn emit_metrics(&self) {
+        value!(
+            "request_body_bytes", self.bytes as u64,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBRetryBudgetWithdrawn {
+    pub component_type: &'static str,
+    pub remaining: f64,
+    pub granted: bool,
+}
+
+impl InternalEvent for InfluxDBRetryBudgetWithdrawn {
+    fn emit_logs(&self) {
+        if !self.granted {
+            warn!(
+                message = "retry budget exhausted; dropping the request instead of retrying.",
+                rate_limit_secs = 30,
+            );
+        }
+    }
+
+    fn emit_metrics(&self) {
+        gauge!(
+            "retry_budget_remaining", self.remaining as i64,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+        if !self.granted {
+            counter!(
+                "retry_budget_exhausted", 1,
+                "component_kind" => "sink",
+                "component_type" => self.component_type,
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBShutdownFlushTimedOut {
+    pub component_type: &'static str,
+    pub dropped: usize,
+    pub timeout_secs: u64,
+}
+
+impl InternalEvent for InfluxDBShutdownFlushTimedOut {
+    fn emit_logs(&self) {
+        error!(
+            message = "buffered events not written before the shutdown flush timed out; dropping them.",
+            dropped = %self.dropped,
+            timeout_secs = %self.timeout_secs,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "events_dropped", self.dropped as u64,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBCleanupCompleted<'a> {
+    pub component_type: &'static str,
+    pub measurement: &'a str,
+    pub stop: &'a str,
+}
+
+impl<'a> InternalEvent for InfluxDBCleanupCompleted<'a> {
+    fn emit_logs(&self) {
+        debug!(
+            message = "deleted old points.",
+            measurement = %self.measurement,
+            stop = %self.stop,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "cleanups_completed", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBCleanupFailed<'a> {
+    pub component_type: &'static str,
+    pub measurement: &'a str,
+    pub error: crate::Error,
+}
+
+impl<'a> InternalEvent for InfluxDBCleanupFailed<'a> {
+    fn emit_logs(&self) {
+        error!(
+            message = "could not delete old points; retrying on the next interval.",
+            measurement = %self.measurement,
+            error = %self.error,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "cleanup_errors", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBFieldCoercionFailed<'a> {
+    pub component_type: &'static str,
+    pub field: &'a str,
+    pub from: &'static str,
+    pub to: &'static str,
+}
+
+impl<'a> InternalEvent for InfluxDBFieldCoercionFailed<'a> {
+    fn emit_logs(&self) {
+        warn!(
+            message = "field can't be converted to the configured type; dropping field.",
+            field = %self.field,
+            from = %self.from,
+            to = %self.to,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "field_coercion_errors", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBTagValueTruncated<'a> {
+    pub component_type: &'static str,
+    pub key: &'a str,
+    pub bytes: usize,
+    pub max: usize,
+    // Only the first truncation of a key is logged.
+    pub first: bool,
+}
+
+impl<'a> InternalEvent for InfluxDBTagValueTruncated<'a> {
+    fn emit_logs(&self) {
+        if self.first {
+            warn!(
+                message = "tag value is longer than max_tag_value_bytes; truncating it. Further truncations of this tag aren't logged.",
+                key = %self.key,
+                bytes = %self.bytes,
+                max = %self.max,
+            );
+        }
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "tag_values_truncated", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBTagKeyDropped {
+    pub component_type: &'static str,
+    pub key: String,
+    pub bytes: usize,
+    pub max: usize,
+}
+
+impl InternalEvent for InfluxDBTagKeyDropped {
+    fn emit_logs(&self) {
+        warn!(
+            message = "tag key is longer than max_tag_key_bytes; dropping tag.",
+            key = %self.key.chars().take(64).collect::<String>(),
+            bytes = %self.bytes,
+            max = %self.max,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "tags_dropped", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBTokenReloaded {
+    pub component_type: &'static str,
+}
+
+impl InternalEvent for InfluxDBTokenReloaded {
+    fn emit_logs(&self) {
+        info!(message = "token file changed; using the new token.");
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "token_reloads", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBTokenReloadFailed<'a> {
+    pub component_type: &'static str,
+    pub path: &'a Path,
+    pub error: crate::Error,
+}
+
+impl<'a> InternalEvent for InfluxDBTokenReloadFailed<'a> {
+    fn emit_logs(&self) {
+        warn!(
+            message = "could not reload token; keeping the previous one.",
+            path = ?self.path,
+            error = %self.error,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "token_reload_errors", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBBackpressure {
+    pub component_type: &'static str,
+    pub active: bool,
+    pub buffered_events: usize,
+}
+
+impl InternalEvent for InfluxDBBackpressure {
+    fn emit_logs(&self) {
+        if self.active {
+            warn!(
+                message = "max_buffered_events reached; applying back pressure until InfluxDB catches up.",
+                buffered_events = %self.buffered_events,
+                rate_limit_secs = 30,
+            );
+        } else {
+            debug!(
+                message = "buffered events below max_buffered_events; taking events again.",
+                buffered_events = %self.buffered_events,
+            );
+        }
+    }
+
+    fn emit_metrics(&self) {
+        gauge!(
+            "backpressure_active", self.active as i64,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+        if self.active {
+            counter!(
+                "backpressure_applied", 1,
+                "component_kind" => "sink",
+                "component_type" => self.component_type,
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InfluxDBEmptyBatch {
+    pub component_type: &'static str,
+    pub points: usize,
+}
+
+impl InternalEvent for InfluxDBEmptyBatch {
+    fn emit_logs(&self) {
+        warn!(
+            message = "every point of the batch was dropped; skipping the write.",
+            points = %self.points,
+            rate_limit_secs = 30,
+        );
+    }
+
+    fn emit_metrics(&self) {
+        counter!(
+            "influxdb_empty_batches", 1,
+            "component_kind" => "sink",
+            "component_type" => self.component_type,
+        );
+    }
+}
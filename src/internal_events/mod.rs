@@ -3,6 +3,8 @@ mod aws_kinesis_streams;
 mod blackhole;
 mod elasticsearch;
 mod file;
+#[cfg(feature = "sinks-influxdb")]
+mod influxdb;
 mod json;
 #[cfg(feature = "transforms-lua")]
 mod lua;
@@ -23,6 +25,8 @@ pub use self::aws_kinesis_streams::*;
 pub use self::blackhole::*;
 pub use self::elasticsearch::*;
 pub use self::file::*;
+#[cfg(feature = "sinks-influxdb")]
+pub use self::influxdb::*;
 pub use self::json::*;
 #[cfg(feature = "transforms-lua")]
 pub use self::lua::*;
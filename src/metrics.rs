@@ -16,3 +16,12 @@ pub fn init() -> crate::Result<()> {
 
     Ok(())
 }
+
+/// Initializes the metrics system for tests, which share the one recorder of
+/// the process whichever of them gets to initialize it.
+#[cfg(test)]
+pub fn init_test() {
+    if CONTROLLER.get().is_none() {
+        let _ = init();
+    }
+}
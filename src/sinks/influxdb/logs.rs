@@ -1,51 +1,137 @@
-use crate::dns::Resolver;
 use crate::event::Value;
 use crate::sinks::influxdb::{
-    encode_namespace, encode_timestamp, healthcheck, influx_line_protocol, influxdb_settings,
-    Field, InfluxDB1Settings, InfluxDB2Settings,
+    body_hash, cached_resolver, encode_namespace, encode_timestamp, healthcheck, http_client,
+    idempotency_header, influxdb_settings, log_write_uri, non_empty, override_field_types,
+    resolve_default_tags, user_agent, validate_endpoint, validate_path_prefix, with_output_buffer,
+    with_path_prefix, ApiVersion, Authorization, BodyCompression, BodySize, BoolFormat,
+    CleanupConfig, Field, FieldType, FieldTypes, InfluxDB1Settings, InfluxDB2Settings,
+    InfluxDB3Settings, InfluxDBBatchConfig, InfluxDBCompression, InfluxDBRetryLogic, InstanceTag,
+    InstanceTagConfig, LimitedBatch, LineEncoder, MissingTimestamp, OnEncodeError,
+    OnFieldTypeConflict, OnOversize, Precision, RetryBudgetConfig, Schema, SchemaConfig,
+    ServerVersion, ShutdownFlush, TagLimits, UnderscoreKeys, DEFAULT_HOST_TAG,
+    DEFAULT_MAX_RESPONSE_BYTES,
 };
 use crate::sinks::util::encoding::EncodingConfigWithDefault;
-use crate::sinks::util::http2::{BatchedHttpSink, HttpSink};
-use crate::sinks::util::{service2::TowerRequestConfig, BatchBytesConfig, Buffer, Compression};
+use crate::sinks::util::http2::{BatchedHttpSink, HttpClient, HttpSink};
+use crate::sinks::util::{service2::TowerRequestConfig, Buffer, Compression};
 use crate::sinks::Healthcheck;
 use crate::{
     event::{log_schema, Event},
-    topology::config::{DataType, SinkConfig, SinkContext, SinkDescription},
+    topology::config::{
+        DataType, SinkConfig, SinkContext, SinkDescription, SinkHealthcheckOptions,
+    },
 };
 use futures01::Sink;
-use http02::{Method, Request, Uri};
+use http02::{
+    header::{HeaderName, HeaderValue, USER_AGENT},
+    Method, Request, Uri,
+};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use string_cache::DefaultAtom as Atom;
+
+/// The measurement of events without a `measurement_key` field, after the
+/// namespace.
+const DEFAULT_MEASUREMENT: &str = "vector";
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 #[serde(deny_unknown_fields)]
 pub struct InfluxDBLogsConfig {
     pub namespace: String,
     pub endpoint: String,
+    pub path_prefix: Option<String>,
     #[serde(default)]
     pub tags: Vec<String>,
+    #[serde(default)]
+    pub default_tags: BTreeMap<String, String>,
+    pub api_version: Option<ApiVersion>,
     #[serde(flatten)]
     pub influxdb1_settings: Option<InfluxDB1Settings>,
     #[serde(flatten)]
     pub influxdb2_settings: Option<InfluxDB2Settings>,
+    // Assembled from the v1 `database` and v2 `token` by `influxdb_settings`
+    // with `api_version = "v3"`.
+    #[serde(skip)]
+    pub influxdb3_settings: Option<InfluxDB3Settings>,
     #[serde(
         skip_serializing_if = "crate::serde::skip_serializing_if_default",
         default
     )]
     pub encoding: EncodingConfigWithDefault<Encoding>,
     #[serde(default)]
-    pub batch: BatchBytesConfig,
+    pub batch: InfluxDBBatchConfig,
     #[serde(default)]
     pub request: TowerRequestConfig,
+    pub retry_budget: Option<RetryBudgetConfig>,
+    pub shutdown_flush_timeout_secs: Option<u64>,
+    pub max_tag_key_bytes: Option<usize>,
+    pub max_tag_value_bytes: Option<usize>,
+    pub cleanup: Option<CleanupConfig>,
+    pub dns_cache_ttl_secs: Option<u64>,
+    pub keepalive_idle_timeout_secs: Option<u64>,
+    pub max_idle_connections_per_host: Option<usize>,
+    #[serde(default)]
+    pub compression: InfluxDBCompression,
+    pub compression_level: Option<i32>,
+    pub compression_min_bytes: Option<usize>,
+    pub warn_body_bytes: Option<usize>,
+    pub healthcheck_max_body_bytes: Option<usize>,
+    pub max_line_bytes: Option<usize>,
+    #[serde(default)]
+    pub on_oversize: OnOversize,
+    #[serde(default)]
+    pub on_encode_error: OnEncodeError,
+    #[serde(default)]
+    pub underscore_keys: UnderscoreKeys,
+    #[serde(default)]
+    pub bool_format: BoolFormat,
+    #[serde(default)]
+    pub precision: Precision,
+    pub on_field_type_conflict: Option<OnFieldTypeConflict>,
+    pub max_tracked_fields: Option<usize>,
+    pub schema: Option<SchemaConfig>,
+    pub user_agent: Option<String>,
+    pub idempotency_header: Option<String>,
+    #[serde(default)]
+    pub missing_timestamp: MissingTimestamp,
+    pub message_field: Option<String>,
+    pub measurement_key: Option<Atom>,
+    pub default_measurement: Option<String>,
+    pub host_key: Option<Atom>,
+    pub host_tag: Option<String>,
+    pub instance_tag: Option<InstanceTagConfig>,
+    #[serde(default)]
+    pub field_types: HashMap<String, FieldType>,
 }
 
 #[derive(Debug)]
 struct InfluxDBLogsSink {
     uri: Uri,
-    token: String,
+    authorization: Authorization,
     namespace: String,
     tags: HashSet<String>,
+    default_tags: BTreeMap<String, String>,
+    tag_limits: Option<TagLimits>,
+    encoder: LineEncoder,
+    compression: BodyCompression,
+    body_size: BodySize,
+    field_types: Option<Arc<Mutex<FieldTypes>>>,
+    schema: Option<Schema>,
+    user_agent: Option<HeaderValue>,
+    idempotency_header: Option<HeaderName>,
+    missing_timestamp: MissingTimestamp,
+    message_field: Option<String>,
+    // Taken from the event, when set, and written without a field.
+    measurement_key: Option<Atom>,
+    default_measurement: String,
+    field_type_overrides: HashMap<String, FieldType>,
+    // Moved to the `host_tag` tag, when set.
+    host_key: Option<Atom>,
+    host_tag: String,
+    instance_tag: Option<InstanceTag>,
+    server_version: ServerVersion,
 }
 
 lazy_static! {
@@ -70,44 +156,143 @@ inventory::submit! {
 #[typetag::serde(name = "influxdb_logs")]
 impl SinkConfig for InfluxDBLogsConfig {
     fn build(&self, cx: SinkContext) -> crate::Result<(super::RouterSink, super::Healthcheck)> {
-        // let mut config = self.clone();
+        // Otherwise a bad endpoint is only reported once the first write fails.
+        validate_endpoint(&self.endpoint)?;
+        validate_path_prefix(self.path_prefix.as_deref())?;
+        let cx = cx.with_resolver(cached_resolver(cx.resolver(), self.dns_cache_ttl_secs));
         let mut tags: HashSet<String> = self.tags.clone().into_iter().collect();
-        tags.insert(log_schema().host_key().to_string());
         tags.insert(log_schema().source_type_key().to_string());
 
-        let healthcheck = self.healthcheck(cx.resolver())?;
+        let client = http_client(
+            cx.resolver(),
+            self.keepalive_idle_timeout_secs,
+            self.max_idle_connections_per_host,
+        )?;
+        let server_version = ServerVersion::default();
+        let healthcheck =
+            self.healthcheck(client.clone(), server_version.clone(), cx.healthcheck())?;
 
-        let batch = self.batch.unwrap_or(bytesize::mib(1u64), 1);
+        let (limits, batch) = self
+            .batch
+            .unwrap_or(None, Some(bytesize::mib(1u64) as usize), 1);
         let request = self.request.unwrap_with(&REQUEST_DEFAULTS);
 
         let settings = influxdb_settings(
+            self.api_version,
             self.influxdb1_settings.clone(),
             self.influxdb2_settings.clone(),
+            self.influxdb3_settings.clone(),
         )
         .unwrap();
 
-        let endpoint = self.endpoint.clone();
-        let uri = settings.write_uri2(endpoint).unwrap();
+        if let Some(InfluxDB2Settings {
+            bucket_tag: Some(_),
+            ..
+        }) = &self.influxdb2_settings
+        {
+            warn!("`bucket_tag` is only supported by the influxdb_metrics sink; ignoring it.");
+        }
+        if let Some(InfluxDB1Settings {
+            rp_tag: Some(_), ..
+        }) = &self.influxdb1_settings
+        {
+            warn!("`rp_tag` is only supported by the influxdb_metrics sink; ignoring it.");
+        }
+
+        let endpoint = self.prefixed_endpoint();
+        let uri = settings.write_uri2(endpoint, self.precision).unwrap();
+        log_write_uri("influxdb_logs", &uri);
 
-        let token = settings.token();
+        let authorization = Authorization::new(&*settings, "influxdb_logs");
         let namespace = self.namespace.clone();
+        let default_measurement = self
+            .default_measurement
+            .clone()
+            .unwrap_or_else(|| DEFAULT_MEASUREMENT.to_owned());
+        non_empty("default_measurement", &default_measurement)?;
+        let user_agent = user_agent(self.user_agent.as_deref())?;
+        let cleanup = self
+            .cleanup
+            .as_ref()
+            .map(|cleanup| {
+                cleanup.build(
+                    &*settings,
+                    self.prefixed_endpoint(),
+                    user_agent.clone(),
+                    "influxdb_logs",
+                )
+            })
+            .transpose()?
+            .map(|cleanup| cleanup.spawn(client.clone(), cx.executor()));
 
         let sink = InfluxDBLogsSink {
             uri,
-            token,
+            authorization,
             namespace,
             tags,
+            default_tags: resolve_default_tags(&self.default_tags),
+            tag_limits: TagLimits::new(
+                "influxdb_logs",
+                self.max_tag_key_bytes,
+                self.max_tag_value_bytes,
+            ),
+            encoder: LineEncoder {
+                max_bytes: self.max_line_bytes,
+                on_oversize: self.on_oversize,
+                on_encode_error: self.on_encode_error,
+                bool_format: self.bool_format,
+                precision: self.precision,
+                underscore_keys: self.underscore_keys,
+            },
+            compression: BodyCompression::new(
+                self.compression,
+                self.compression_level,
+                self.compression_min_bytes,
+            )?,
+            body_size: BodySize {
+                component_type: "influxdb_logs",
+                warn_bytes: self.warn_body_bytes,
+            },
+            field_types: FieldTypes::shared(self.on_field_type_conflict, self.max_tracked_fields),
+            schema: self.schema.as_ref().map(SchemaConfig::build).transpose()?,
+            user_agent,
+            idempotency_header: idempotency_header(self.idempotency_header.as_deref())?,
+            missing_timestamp: self.missing_timestamp,
+            message_field: self.message_field.clone(),
+            measurement_key: self.measurement_key.clone(),
+            default_measurement,
+            field_type_overrides: self.field_types.clone(),
+            host_key: Some(
+                self.host_key
+                    .clone()
+                    .unwrap_or_else(|| log_schema().host_key().clone()),
+            ),
+            host_tag: self
+                .host_tag
+                .clone()
+                .unwrap_or_else(|| DEFAULT_HOST_TAG.to_owned()),
+            instance_tag: self
+                .instance_tag
+                .as_ref()
+                .map(InstanceTagConfig::build)
+                .transpose()?,
+            server_version,
         };
 
-        let sink = BatchedHttpSink::new(
+        let sink = BatchedHttpSink::with_client(
             sink,
-            Buffer::new(Compression::None),
+            LimitedBatch::new(Buffer::new(Compression::None), limits, |line: &Vec<u8>| {
+                line.len()
+            }),
+            InfluxDBRetryLogic::new(self.retry_budget.as_ref(), "influxdb_logs"),
+            client,
             request,
             batch,
-            None,
             &cx,
         )
         .sink_map_err(|e| error!("Fatal influxdb_logs sink error: {}", e));
+        let sink = ShutdownFlush::new(sink, "influxdb_logs", self.shutdown_flush_timeout_secs)
+            .with_cleanup(cleanup);
 
         Ok((Box::new(sink), Box::new(healthcheck)))
     }
@@ -126,20 +311,33 @@ impl HttpSink for InfluxDBLogsSink {
     type Output = Vec<u8>;
 
     fn encode_event(&self, event: Event) -> Option<Self::Input> {
-        let mut output = String::new();
         let mut event = event.into_log();
 
-        // Measurement
-        let measurement = encode_namespace(&self.namespace, &"vector");
+        // Measurement, from `measurement_key` when the event has it.
+        let name = self
+            .measurement_key
+            .as_ref()
+            .and_then(|key| event.remove(key))
+            .map(|value| sanitize_measurement(&value.to_string_lossy()))
+            .filter(|name| !name.is_empty());
+        let measurement = encode_namespace(
+            &self.namespace,
+            name.as_deref().unwrap_or(&self.default_measurement),
+        );
 
         // Timestamp
-        let timestamp = encode_timestamp(match event.remove(log_schema().timestamp_key()) {
+        let timestamp = match event.remove(log_schema().timestamp_key()) {
             Some(Value::Timestamp(ts)) => Some(ts),
             _ => None,
-        });
+        };
+        let timestamp = self.missing_timestamp.apply("influxdb_logs", timestamp)?;
+        let timestamp = encode_timestamp(Some(timestamp));
 
         // Tags + Fields
-        let mut tags: BTreeMap<String, String> = BTreeMap::new();
+        let mut tags: BTreeMap<String, String> = self.default_tags.clone();
+        if let Some(host) = self.host_key.as_ref().and_then(|key| event.remove(key)) {
+            tags.insert(self.host_tag.clone(), host.to_string_lossy());
+        }
         let mut fields: HashMap<String, Field> = HashMap::new();
         event.all_fields().for_each(|(key, value)| {
             if self.tags.contains(&key) {
@@ -148,46 +346,116 @@ impl HttpSink for InfluxDBLogsSink {
                 fields.insert(key, value.to_field());
             }
         });
+        if let Some(message_field) = &self.message_field {
+            // Events without a message don't get an empty one.
+            if let Some(message) = fields.remove(&log_schema().message_key()[..]) {
+                fields.insert(message_field.clone(), message);
+            }
+        }
+        override_field_types("influxdb_logs", &self.field_type_overrides, &mut fields);
+        if let Some(instance_tag) = &self.instance_tag {
+            instance_tag.apply(&mut tags);
+        }
+        if let Some(tag_limits) = &self.tag_limits {
+            tag_limits.apply(&mut tags);
+        }
 
-        influx_line_protocol(
-            measurement,
-            "logs",
-            Some(tags),
-            Some(fields),
-            timestamp,
-            &mut output,
-        );
+        if let Some(schema) = &self.schema {
+            if !schema.check("influxdb_logs", &measurement, &fields) {
+                return None;
+            }
+        }
+        if let Some(field_types) = &self.field_types {
+            let mut field_types = field_types.lock().unwrap();
+            if !field_types.check("influxdb_logs", &measurement, &fields) {
+                return None;
+            }
+        }
 
-        Some(output.into_bytes())
+        with_output_buffer(|output| {
+            match self.encoder.encode(
+                measurement,
+                "logs",
+                Some(tags),
+                Some(fields),
+                timestamp,
+                output,
+            ) {
+                Ok(()) => Some(output.as_bytes().to_vec()),
+                Err(error) => {
+                    self.encoder.dropped("influxdb_logs", error);
+                    None
+                }
+            }
+        })
     }
 
     fn build_request(&self, events: Self::Output) -> Request<Vec<u8>> {
-        Request::builder()
+        let mut builder = Request::builder()
             .method(Method::POST)
             .uri(&self.uri)
-            .header("Content-Type", "text/plain")
-            .header("Authorization", format!("Token {}", &self.token))
-            .body(events)
-            .unwrap()
+            .header("Content-Type", "text/plain");
+        if let Some(authorization) = self.authorization.get() {
+            builder = builder.header("Authorization", authorization);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.header(USER_AGENT, user_agent);
+        }
+        if let Some(header) = &self.idempotency_header {
+            builder = builder.header(header, body_hash(&events));
+        }
+        self.body_size.record(events.len());
+        let mut request = builder.body(Vec::new()).unwrap();
+        *request.body_mut() = self.compression.compress(events, request.headers_mut());
+        request
     }
 }
 
 impl InfluxDBLogsConfig {
-    fn healthcheck(&self, resolver: Resolver) -> crate::Result<Healthcheck> {
+    fn prefixed_endpoint(&self) -> String {
+        with_path_prefix(&self.endpoint, self.path_prefix.as_deref())
+    }
+
+    fn healthcheck(
+        &self,
+        client: HttpClient,
+        server_version: ServerVersion,
+        healthcheck_options: SinkHealthcheckOptions,
+    ) -> crate::Result<Healthcheck> {
         let config = self.clone();
 
         let healthcheck = healthcheck(
-            config.endpoint,
+            config.prefixed_endpoint(),
+            config.api_version,
             config.influxdb1_settings,
             config.influxdb2_settings,
-            resolver,
+            config.influxdb3_settings,
+            healthcheck_options.verify_write,
+            healthcheck_options.flux_probe,
+            user_agent(config.user_agent.as_deref())?,
+            config
+                .healthcheck_max_body_bytes
+                .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+            server_version,
+            client,
         )?;
 
         Ok(Box::new(healthcheck))
     }
 }
 
+/// Drops the line breaks and tabs of a measurement taken from a log field,
+/// which can't be escaped in line protocol. Commas and spaces are escaped
+/// when the line is encoded.
+fn sanitize_measurement(name: &str) -> String {
+    name.chars().filter(|c| !"\n\r\t".contains(*c)).collect()
+}
+
 impl Value {
+    /// The field a log value is written as. Log integers are `i64`, so they
+    /// always fit `Field::Int`: JSON numbers above `i64::MAX` have already
+    /// become floats by the time they get here. Larger unsigned values kept as
+    /// strings can be written as `uint` with `field_types`.
     pub fn to_field(&self) -> Field {
         match self {
             Value::Integer(num) => Field::Int(*num),
@@ -204,11 +472,12 @@ mod tests {
     use crate::event::Event;
     use crate::sinks::influxdb::test_util::{assert_fields, split_line_protocol, ts};
     use crate::sinks::util::http2::HttpSink;
-    use crate::sinks::util::test::build_test_server;
+    use crate::sinks::util::test::{build_test_server, build_test_server_failing};
     use crate::test_util;
     use chrono::offset::TimeZone;
     use chrono::Utc;
     use futures01::{Sink, Stream};
+    use http02::header::CONTENT_ENCODING;
 
     #[test]
     fn test_config_without_tags() {
@@ -223,6 +492,28 @@ mod tests {
         toml::from_str::<InfluxDBLogsConfig>(&config).unwrap();
     }
 
+    #[test]
+    fn test_config_schemeless_endpoint() {
+        let config = r#"
+            namespace = "vector-logs"
+            endpoint = "localhost:9999"
+            bucket = "my-bucket"
+            org = "my-org"
+            token = "my-token"
+        "#;
+        let config = toml::from_str::<InfluxDBLogsConfig>(&config).unwrap();
+
+        let rt = crate::test_util::runtime();
+        let error = config
+            .build(SinkContext::new_test(rt.executor()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "InfluxDB endpoint \"localhost:9999\" has no scheme, try \"http://localhost:9999\"."
+        );
+    }
+
     #[test]
     fn test_encode_event() {
         let mut event = Event::from("hello");
@@ -289,6 +580,321 @@ mod tests {
         assert_eq!("1542182950000000011\n", line_protocol.3);
     }
 
+    #[test]
+    fn test_encode_event_oversized() {
+        let mut event = Event::from("x".repeat(1000));
+        event.as_mut_log().insert("timestamp", ts());
+
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", [].to_vec());
+        sink.encoder = LineEncoder {
+            max_bytes: Some(100),
+            on_oversize: OnOversize::Drop,
+            ..Default::default()
+        };
+        assert!(sink.encode_event(event.clone()).is_none());
+
+        sink.encoder.on_oversize = OnOversize::Truncate;
+        let bytes = sink.encode_event(event).unwrap();
+        let string = std::str::from_utf8(&bytes).unwrap();
+        assert_eq!(string.trim_end().len(), 100);
+
+        let line_protocol = split_line_protocol(&string);
+        assert_eq!("ns.vector", line_protocol.0);
+        assert!(line_protocol.2.starts_with("message=\"xxx"));
+        assert_eq!("1542182950000000011\n", line_protocol.3);
+    }
+
+    #[test]
+    fn test_build_request_compression() {
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", [].to_vec());
+        sink.compression = BodyCompression::new(InfluxDBCompression::Gzip, None, Some(64)).unwrap();
+
+        let small = b"ns.vector,metric_type=logs message=\"hi\" 1\n".to_vec();
+        let request = sink.build_request(small.clone());
+        assert_eq!(request.headers().get(CONTENT_ENCODING), None);
+        assert_eq!(request.body(), &small);
+
+        let large = small.repeat(4);
+        let request = sink.build_request(large.clone());
+        assert_eq!(request.headers()[CONTENT_ENCODING], "gzip");
+        assert_ne!(request.body(), &large);
+    }
+
+    #[test]
+    fn test_encode_event_default_tags() {
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("host", "event-host");
+        event.as_mut_log().insert("timestamp", ts());
+
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", vec!["host"]);
+        sink.default_tags = vec![("env", "production"), ("host", "default-host")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+
+        let bytes = sink.encode_event(event).unwrap();
+        let line_protocol = split_line_protocol(std::str::from_utf8(&bytes).unwrap());
+        assert_eq!("ns.vector", line_protocol.0);
+        assert_eq!(
+            "env=production,host=event-host,metric_type=logs",
+            line_protocol.1
+        );
+    }
+
+    #[test]
+    fn test_encode_event_field_type_conflict() {
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", vec![]);
+        sink.field_types = FieldTypes::shared(Some(OnFieldTypeConflict::Drop), None);
+
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("duration", 10);
+        assert!(sink.encode_event(event).is_some());
+
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("duration", 10.5);
+        assert!(sink.encode_event(event).is_none());
+
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("duration", 11);
+        assert!(sink.encode_event(event).is_some());
+    }
+
+    #[test]
+    fn test_encode_event_missing_timestamp() {
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", vec![]);
+        let event = || {
+            let mut event = Event::from("hello");
+            event.as_mut_log().remove(log_schema().timestamp_key());
+            event
+        };
+
+        let bytes = sink.encode_event(event()).unwrap();
+        let line_protocol = split_line_protocol(std::str::from_utf8(&bytes).unwrap());
+        assert!(line_protocol.3.trim_end().parse::<i64>().unwrap() > 0);
+
+        sink.missing_timestamp = MissingTimestamp::Drop;
+        assert!(sink.encode_event(event()).is_none());
+
+        sink.missing_timestamp = MissingTimestamp::Error;
+        assert!(sink.encode_event(event()).is_none());
+
+        let mut event = event();
+        event.as_mut_log().insert("timestamp", ts());
+        assert!(sink.encode_event(event).is_some());
+    }
+
+    #[test]
+    fn test_encode_event_message_field() {
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", vec![]);
+        let encode = |sink: &InfluxDBLogsSink, event: Event| {
+            let bytes = sink.encode_event(event).unwrap();
+            let line = String::from_utf8(bytes).unwrap();
+            split_line_protocol(&line).2
+        };
+
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("timestamp", ts());
+        assert_eq!(encode(&sink, event.clone()), r#"message="hello""#);
+
+        sink.message_field = Some("body".to_owned());
+        assert_eq!(encode(&sink, event), r#"body="hello""#);
+
+        let mut event = Event::new_empty_log();
+        event.as_mut_log().insert("timestamp", ts());
+        event.as_mut_log().insert("level", "info");
+        assert_eq!(encode(&sink, event), r#"level="info""#);
+    }
+
+    #[test]
+    fn test_encode_event_measurement_key() {
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", vec![]);
+        sink.measurement_key = Some("log_type".into());
+        sink.default_measurement = "logs".to_owned();
+        let encode = |sink: &InfluxDBLogsSink, log_type: Option<&str>| {
+            let mut event = Event::from("hello");
+            event.as_mut_log().insert("timestamp", ts());
+            if let Some(log_type) = log_type {
+                event.as_mut_log().insert("log_type", log_type);
+            }
+            String::from_utf8(sink.encode_event(event).unwrap()).unwrap()
+        };
+
+        // The field is the measurement rather than a field.
+        let line = encode(&sink, Some("access"));
+        let line_protocol = split_line_protocol(&line);
+        assert_eq!("ns.access", line_protocol.0);
+        assert_eq!(r#"message="hello""#, line_protocol.2);
+
+        assert_eq!("ns.logs", split_line_protocol(&encode(&sink, None)).0);
+        assert_eq!("ns.logs", split_line_protocol(&encode(&sink, Some("\n"))).0);
+
+        let line = encode(&sink, Some("web access,eu\n"));
+        assert!(
+            line.starts_with(r"ns.web\ access\,eu,metric_type=logs "),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_encode_event_field_types() {
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", vec![]);
+        sink.field_type_overrides = vec![
+            ("id", FieldType::String),
+            ("latency", FieldType::Float),
+            ("count", FieldType::Uint),
+            ("delta", FieldType::Int),
+            ("ok", FieldType::Bool),
+            ("missing", FieldType::Int),
+        ]
+        .into_iter()
+        .map(|(key, field_type)| (key.to_owned(), field_type))
+        .collect();
+        let encode = |event: Event| {
+            let bytes = sink.encode_event(event).unwrap();
+            let line = String::from_utf8(bytes).unwrap();
+            split_line_protocol(&line).2
+        };
+
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("timestamp", ts());
+        event.as_mut_log().insert("id", 12345);
+        event.as_mut_log().insert("latency", "0.25");
+        event.as_mut_log().insert("count", 3);
+        event.as_mut_log().insert("delta", "-7");
+        event.as_mut_log().insert("ok", "true");
+        assert_fields(
+            encode(event),
+            vec![
+                "count=3u",
+                "delta=-7i",
+                r#"id="12345""#,
+                "latency=0.25",
+                r#"message="hello""#,
+                "ok=true",
+            ],
+        );
+
+        // Fields that can't be converted are dropped, the rest is written.
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("timestamp", ts());
+        event.as_mut_log().insert("ok", "yes");
+        event.as_mut_log().insert("count", -3);
+        event.as_mut_log().insert("latency", "fast");
+        event.as_mut_log().insert("delta", 1.5);
+        assert_eq!(encode(event), r#"message="hello""#);
+    }
+
+    #[test]
+    fn test_to_field_integer_range() {
+        assert_eq!(Value::Integer(i64::MAX).to_field(), Field::Int(i64::MAX));
+        assert_eq!(Value::Integer(i64::MIN).to_field(), Field::Int(i64::MIN));
+        assert_eq!(Value::Integer(0).to_field(), Field::Int(0));
+
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", vec![]);
+        sink.field_type_overrides = vec![("count".to_owned(), FieldType::Uint)]
+            .into_iter()
+            .collect();
+        let encode = |count: Value| {
+            let mut event = Event::from("hello");
+            event.as_mut_log().insert("timestamp", ts());
+            event.as_mut_log().insert("count", count);
+            let bytes = sink.encode_event(event).unwrap();
+            let line = String::from_utf8(bytes).unwrap();
+            split_line_protocol(&line).2
+        };
+
+        assert_fields(
+            encode(Value::Integer(i64::MAX)),
+            vec!["count=9223372036854775807u", r#"message="hello""#],
+        );
+        assert_fields(
+            encode(Value::from("9223372036854775808")),
+            vec!["count=9223372036854775808u", r#"message="hello""#],
+        );
+        assert_fields(
+            encode(Value::from("18446744073709551615")),
+            vec!["count=18446744073709551615u", r#"message="hello""#],
+        );
+        // Above `u64::MAX` the field can't be written as `uint` and is dropped.
+        assert_eq!(
+            encode(Value::from("18446744073709551616")),
+            r#"message="hello""#
+        );
+    }
+
+    #[test]
+    fn test_encode_event_host_tag() {
+        let mut sink = create_sink("http://localhost:9999", "my-token", "ns", vec![]);
+        sink.host_key = Some("hostname".into());
+        sink.host_tag = "server".to_owned();
+        let encode = |sink: &InfluxDBLogsSink, event: Event| {
+            let bytes = sink.encode_event(event).unwrap();
+            let line = String::from_utf8(bytes).unwrap();
+            let (_, tags, fields, _) = split_line_protocol(&line);
+            (tags.to_owned(), fields)
+        };
+
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("timestamp", ts());
+        event.as_mut_log().insert("hostname", "web-01");
+        assert_eq!(
+            encode(&sink, event),
+            (
+                "metric_type=logs,server=web-01".to_owned(),
+                r#"message="hello""#.to_owned()
+            )
+        );
+
+        // Without a host there's no tag
+        let mut event = Event::from("hello");
+        event.as_mut_log().insert("timestamp", ts());
+        assert_eq!(
+            encode(&sink, event),
+            (
+                "metric_type=logs".to_owned(),
+                r#"message="hello""#.to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn test_encode_event_instance_tag() {
+        let mut sink = create_sink(
+            "http://localhost:9999",
+            "my-token",
+            "ns",
+            vec!["source", "vector_instance"],
+        );
+        sink.instance_tag = Some(InstanceTag {
+            key: "vector_instance".to_owned(),
+            value: "agent-7".to_owned(),
+        });
+
+        let events = vec![
+            vec![],
+            vec![("source", "file")],
+            // The configured id wins over a tag of the event.
+            vec![("vector_instance", "spoofed")],
+        ];
+        for fields in events {
+            let mut event = Event::from("hello");
+            event.as_mut_log().insert("timestamp", ts());
+            for (key, value) in fields {
+                event.as_mut_log().insert(key, value);
+            }
+            let bytes = sink.encode_event(event).unwrap();
+            let line = String::from_utf8(bytes).unwrap();
+            let (_, tags, _, _) = split_line_protocol(&line);
+            assert!(
+                tags.split(',').any(|tag| tag == "vector_instance=agent-7"),
+                "{}",
+                line
+            );
+            assert!(!tags.contains("spoofed"), "{}", line);
+        }
+    }
+
     #[test]
     fn test_encode_nested_fields() {
         let mut event = Event::new_empty_log();
@@ -475,6 +1081,8 @@ mod tests {
         assert!(query.contains("org=my-org"));
         assert!(query.contains("bucket=my-bucket"));
         assert!(query.contains("precision=ns"));
+        let user_agent = request.headers.get("User-Agent").unwrap();
+        assert!(user_agent.to_str().unwrap().starts_with("Vector/"));
 
         let body = std::str::from_utf8(&output[0].1[..]).unwrap();
         let mut lines = body.lines();
@@ -483,6 +1091,130 @@ mod tests {
         assert_line_protocol(0, lines.next());
     }
 
+    #[test]
+    fn smoke_user_agent() {
+        let (mut config, cx, mut rt) = crate::sinks::util::test::load_sink::<InfluxDBLogsConfig>(
+            r#"
+            namespace = "ns"
+            endpoint = "http://localhost:9999"
+            bucket = "my-bucket"
+            org = "my-org"
+            token = "my-token"
+            user_agent = "my-collector/1.0"
+        "#,
+        )
+        .unwrap();
+
+        let addr = test_util::next_addr();
+        config.endpoint = format!("http://{}", addr);
+
+        let (sink, _) = config.build(cx).unwrap();
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let pump = sink.send_all(futures01::stream::iter_ok(vec![Event::from("hello")]));
+        let _ = rt.block_on(pump).unwrap();
+
+        let output = rx.take(1).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let user_agents = output[0].0.headers.get_all("User-Agent");
+        assert_eq!(
+            user_agents.iter().collect::<Vec<_>>(),
+            vec!["my-collector/1.0"]
+        );
+
+        let config = InfluxDBLogsConfig {
+            user_agent: Some("bad\nagent".to_owned()),
+            ..config
+        };
+        assert!(config.build(SinkContext::new_test(rt.executor())).is_err());
+    }
+
+    #[test]
+    fn smoke_idempotency_header() {
+        let (mut config, cx, mut rt) = crate::sinks::util::test::load_sink::<InfluxDBLogsConfig>(
+            r#"
+            namespace = "ns"
+            endpoint = "http://localhost:9999"
+            bucket = "my-bucket"
+            org = "my-org"
+            token = "my-token"
+            idempotency_header = "X-Batch-Hash"
+            request.retry_initial_backoff_secs = 1
+        "#,
+        )
+        .unwrap();
+
+        let addr = test_util::next_addr();
+        config.endpoint = format!("http://{}", addr);
+
+        let (sink, _) = config.build(cx).unwrap();
+
+        let (rx, _trigger, server) = build_test_server_failing(addr, &mut rt, 1);
+        rt.spawn(server);
+
+        // Without a timestamp the line gets the time it was encoded at
+        let mut event = Event::from("hello");
+        event.as_mut_log().remove(log_schema().timestamp_key());
+        let pump = sink.send_all(futures01::stream::iter_ok(vec![event]));
+        let _ = rt.block_on(pump).unwrap();
+
+        let output = rx.take(2).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let (first, retried) = (&output[0], &output[1]);
+        assert_eq!(first.1, retried.1);
+        let hash = first.0.headers.get("X-Batch-Hash").unwrap();
+        assert_eq!(hash, retried.0.headers.get("X-Batch-Hash").unwrap());
+        assert_eq!(hash, &body_hash(&first.1));
+
+        let config = InfluxDBLogsConfig {
+            idempotency_header: Some("bad header".to_owned()),
+            ..config
+        };
+        assert!(config.build(SinkContext::new_test(rt.executor())).is_err());
+    }
+
+    #[cfg(feature = "sources-generator")]
+    #[test]
+    fn healthcheck_disabled_sends_no_request() {
+        let mut rt = test_util::runtime();
+        let addr = test_util::next_addr();
+
+        let config = crate::topology::config::Config::load(
+            format!(
+                r#"
+                [sources.in]
+                type = "generator"
+                lines = []
+                count = 0
+
+                [sinks.out]
+                type = "influxdb_logs"
+                inputs = ["in"]
+                namespace = "ns"
+                endpoint = "http://{}"
+                bucket = "my-bucket"
+                org = "my-org"
+                token = "my-token"
+                healthcheck.enabled = false
+            "#,
+                addr
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let (rx, trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        // A required healthcheck that isn't run still lets the topology start
+        let (topology, _crash) = crate::topology::start(config, &mut rt, true).unwrap();
+        rt.block_on(topology.stop()).unwrap();
+        drop(trigger);
+
+        let output = rx.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(output.is_empty());
+    }
+
     fn assert_line_protocol(i: i64, value: Option<&str>) {
         //ns.vector,metric_type=logs key0="value0",message="message_value" 1000000000
         let line_protocol = split_line_protocol(value.unwrap());
@@ -502,14 +1234,35 @@ mod tests {
 
     fn create_sink(uri: &str, token: &str, namespace: &str, tags: Vec<&str>) -> InfluxDBLogsSink {
         let uri = uri.parse::<Uri>().unwrap();
-        let token = token.to_string();
+        let authorization = Authorization::Fixed(Some(format!("Token {}", token)));
         let namespace = namespace.to_string();
         let tags: HashSet<String> = tags.into_iter().map(|tag| tag.to_string()).collect();
         let sink = InfluxDBLogsSink {
             uri,
-            token,
+            authorization,
             namespace,
             tags,
+            default_tags: BTreeMap::new(),
+            tag_limits: None,
+            encoder: LineEncoder::default(),
+            compression: BodyCompression::default(),
+            body_size: BodySize {
+                component_type: "influxdb_logs",
+                warn_bytes: None,
+            },
+            field_types: None,
+            schema: None,
+            user_agent: None,
+            idempotency_header: None,
+            missing_timestamp: MissingTimestamp::default(),
+            message_field: None,
+            measurement_key: None,
+            default_measurement: DEFAULT_MEASUREMENT.to_owned(),
+            field_type_overrides: HashMap::new(),
+            host_key: None,
+            host_tag: DEFAULT_HOST_TAG.to_owned(),
+            instance_tag: None,
+            server_version: ServerVersion::default(),
         };
         sink
     }
@@ -520,7 +1273,7 @@ mod tests {
 mod integration_tests {
     use super::*;
     use crate::sinks::influxdb::logs::InfluxDBLogsConfig;
-    use crate::sinks::influxdb::test_util::{onboarding_v2, BUCKET, ORG, TOKEN};
+    use crate::sinks::influxdb::test_util::{create_bucket_v2, onboarding_v2, BUCKET, ORG, TOKEN};
     use crate::sinks::influxdb::InfluxDB2Settings;
     use crate::test_util::runtime;
     use crate::topology::SinkContext;
@@ -541,14 +1294,14 @@ mod integration_tests {
             endpoint: "http://localhost:9999".to_string(),
             tags: Default::default(),
             influxdb1_settings: None,
-            influxdb2_settings: Some(InfluxDB2Settings {
-                org: ORG.to_string(),
-                bucket: BUCKET.to_string(),
-                token: TOKEN.to_string(),
-            }),
-            encoding: Default::default(),
-            batch: Default::default(),
-            request: Default::default(),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org(ORG)
+                    .bucket(BUCKET)
+                    .token(TOKEN)
+                    .build(),
+            ),
+            ..Default::default()
         };
 
         let (sink, _) = config.build(cx).unwrap();
@@ -671,4 +1424,212 @@ mod integration_tests {
             "message_2"
         );
     }
+
+    #[test]
+    fn influxdb2_logs_put_data_escaped_bucket() {
+        onboarding_v2();
+        let bucket = "my bucket/ünïcode";
+        create_bucket_v2(bucket);
+
+        let ns = format!("ns-{}", Utc::now().timestamp_nanos());
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+
+        let config = InfluxDBLogsConfig {
+            namespace: ns.clone(),
+            endpoint: "http://localhost:9999".to_string(),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org(ORG)
+                    .bucket(bucket.to_string())
+                    .token(TOKEN)
+                    .build(),
+            ),
+            ..Default::default()
+        };
+
+        let (sink, _) = config.build(cx).unwrap();
+
+        let pump = sink.send_all(futures01::stream::iter_ok(vec![Event::from("message_1")]));
+        let _ = rt.block_on(pump).unwrap();
+
+        let mut body = std::collections::HashMap::new();
+        body.insert("query", format!("from(bucket:\"{}\") |> range(start: 0) |> filter(fn: (r) => r._measurement == \"{}.vector\")", bucket, ns));
+        body.insert("type", "flux".to_owned());
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let mut res = client
+            .post("http://localhost:9999/api/v2/query?org=my-org")
+            .json(&body)
+            .header("accept", "application/json")
+            .header("Authorization", "Token my-token")
+            .send()
+            .unwrap();
+        let string = res.text().unwrap();
+
+        let lines = string.split("\n").collect::<Vec<&str>>();
+        let header = lines[0].split(",").collect::<Vec<&str>>();
+        let record = lines[1].split(",").collect::<Vec<&str>>();
+        assert_eq!(
+            record[header.iter().position(|&r| r.trim() == "_value").unwrap()].trim(),
+            "message_1"
+        );
+    }
+
+    #[test]
+    fn influxdb2_logs_put_data_gzip() {
+        onboarding_v2();
+
+        let ns = format!("ns-{}", Utc::now().timestamp_nanos());
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+
+        let config = InfluxDBLogsConfig {
+            namespace: ns.clone(),
+            endpoint: "http://localhost:9999".to_string(),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org(ORG)
+                    .bucket(BUCKET)
+                    .token(TOKEN)
+                    .build(),
+            ),
+            compression: InfluxDBCompression::Gzip,
+            ..Default::default()
+        };
+
+        let (sink, _) = config.build(cx).unwrap();
+
+        let events = (0..10)
+            .map(|i| Event::from(format!("gzipped message {}", i)))
+            .collect::<Vec<_>>();
+        let pump = sink.send_all(futures01::stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+
+        let mut body = std::collections::HashMap::new();
+        body.insert("query", format!("from(bucket:\"my-bucket\") |> range(start: 0) |> filter(fn: (r) => r._measurement == \"{}.vector\")", ns));
+        body.insert("type", "flux".to_owned());
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let mut res = client
+            .post("http://localhost:9999/api/v2/query?org=my-org")
+            .json(&body)
+            .header("accept", "application/json")
+            .header("Authorization", "Token my-token")
+            .send()
+            .unwrap();
+        let string = res.text().unwrap();
+
+        let lines = string.split("\n").collect::<Vec<&str>>();
+        let header = lines[0].split(",").collect::<Vec<&str>>();
+        let value = header.iter().position(|&r| r.trim() == "_value").unwrap();
+        let mut messages = lines[1..]
+            .iter()
+            .map(|line| line.split(",").collect::<Vec<&str>>())
+            .filter(|record| record.len() > value)
+            .map(|record| record[value].trim().to_owned())
+            .collect::<Vec<_>>();
+        messages.sort();
+        let mut expected = (0..10)
+            .map(|i| format!("gzipped message {}", i))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(messages, expected);
+    }
+
+    #[test]
+    fn influxdb2_logs_cleanup() {
+        onboarding_v2();
+
+        let ns = format!("ns-{}", Utc::now().timestamp_nanos());
+        let measurement = format!("{}.vector", ns);
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+
+        let settings = InfluxDB2Settings::builder()
+            .org(ORG)
+            .bucket(BUCKET)
+            .token(TOKEN)
+            .build();
+        let config = InfluxDBLogsConfig {
+            namespace: ns.clone(),
+            endpoint: "http://localhost:9999".to_string(),
+            influxdb2_settings: Some(settings.clone()),
+            ..Default::default()
+        };
+
+        let (sink, _) = config.build(cx.clone()).unwrap();
+
+        let now = Utc::now();
+        let events = vec![("old", now - chrono::Duration::days(2)), ("new", now)]
+            .into_iter()
+            .map(|(message, timestamp)| {
+                let mut event = Event::from(message);
+                event
+                    .as_mut_log()
+                    .insert(log_schema().timestamp_key().clone(), timestamp);
+                event
+            })
+            .collect::<Vec<_>>();
+        let pump = sink.send_all(futures01::stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+        assert_eq!(query_messages(&measurement), vec!["new", "old"]);
+
+        let cleanup = CleanupConfig {
+            measurement: measurement.clone(),
+            older_than_secs: 24 * 60 * 60,
+            interval_secs: 60,
+        }
+        .build(&settings, config.endpoint.clone(), None, "influxdb_logs")
+        .unwrap();
+        let mut client = HttpClient::new(cx.resolver(), None).unwrap();
+        rt.block_on_std(async move { cleanup.delete(&mut client, Utc::now()).await })
+            .unwrap();
+
+        assert_eq!(query_messages(&measurement), vec!["new"]);
+    }
+
+    /// The sorted messages written to `measurement`.
+    fn query_messages(measurement: &str) -> Vec<String> {
+        let mut body = std::collections::HashMap::new();
+        body.insert("query", format!("from(bucket:\"my-bucket\") |> range(start: 0) |> filter(fn: (r) => r._measurement == \"{}\" and r._field == \"message\")", measurement));
+        body.insert("type", "flux".to_owned());
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let mut res = client
+            .post("http://localhost:9999/api/v2/query?org=my-org")
+            .json(&body)
+            .header("accept", "application/json")
+            .header("Authorization", "Token my-token")
+            .send()
+            .unwrap();
+        let string = res.text().unwrap();
+
+        let lines = string.split("\n").collect::<Vec<&str>>();
+        let header = lines[0].split(",").collect::<Vec<&str>>();
+        let value = header.iter().position(|&r| r.trim() == "_value").unwrap();
+        let mut messages = lines[1..]
+            .iter()
+            .map(|line| line.split(",").collect::<Vec<&str>>())
+            .filter(|record| record.len() > value)
+            .map(|record| record[value].trim().to_owned())
+            .collect::<Vec<_>>();
+        messages.sort();
+        messages
+    }
 }
@@ -1,47 +1,415 @@
 use crate::{
-    event::metric::{Metric, MetricValue},
+    buffers::Acker,
+    event::{
+        log_schema,
+        metric::{Metric, MetricValue},
+    },
+    internal_events::{
+        InfluxDBBackpressure, InfluxDBDryRunBatch, InfluxDBEmptyBatch, InfluxDBStdoutWriteFailed,
+    },
     sinks::influxdb::{
-        encode_namespace, encode_timestamp, healthcheck, influx_line_protocol, influxdb_settings,
-        Field, InfluxDB1Settings, InfluxDB2Settings,
+        body_hash, cached_resolver, encode_namespace, encode_timestamp, healthcheck, http_client,
+        idempotency_header, influxdb_settings, insert_metric_type, log_write_uri,
+        merge_default_tags, opentsdb::encode_put_lines, resolve_default_tags, user_agent,
+        validate_endpoint, validate_path_prefix, with_output_buffer, with_path_prefix, ApiVersion,
+        Authorization, BodyCompression, BodySize, BoolFormat, BreakerGate, CircuitBreakerConfig,
+        CleanupConfig, ConfigError, DeadLetter, DeadLetterConfig, Field, FieldTypes,
+        InfluxDB1Settings, InfluxDB2Settings, InfluxDB3Settings, InfluxDBBatchConfig,
+        InfluxDBCompression, InfluxDBRetryLogic, InfluxDBSettings, InstanceTag, InstanceTagConfig,
+        LimitedBatch, LineEncoder, MissingTimestamp, OnEncodeError, OnFieldTypeConflict,
+        OnOversize, Precision, RetryBudgetConfig, Schema, SchemaConfig, ServerVersion,
+        ShutdownFlush, TagFilter, TagFilterConfig, TagLimits, UnderscoreKeys, DEFAULT_HOST_TAG,
+        DEFAULT_MAX_RESPONSE_BYTES,
     },
     sinks::util::{
-        http2::{Error as HttpError, HttpBatchService, HttpRetryLogic, Response as HttpResponse},
+        http2::{Error as HttpError, HttpClient, Response as HttpResponse},
         service2::TowerRequestConfig,
-        BatchEventsConfig, MetricBuffer,
+        Batch, MetricBuffer,
     },
     topology::config::{DataType, SinkConfig, SinkContext, SinkDescription},
+    Event,
 };
-use futures::future::BoxFuture;
-use futures01::Sink;
-use hyper13;
+use bytes05::{Buf, Bytes};
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture};
+use futures01::{task::AtomicTask, Sink};
+use http02::{
+    header::{HeaderName, HeaderValue, USER_AGENT},
+    Uri,
+};
+use hyper13::{self, Body};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::sync::{
+    atomic::{self, AtomicUsize},
+    Arc, Mutex,
+};
 use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio01::timer::Delay;
 use tower03::Service;
 
 #[derive(Clone)]
 struct InfluxDBSvc {
     config: InfluxDBConfig,
-    inner: HttpBatchService,
+    settings: Arc<dyn InfluxDBSettings>,
+    uri: Uri,
+    authorization: Authorization,
+    default_tags: BTreeMap<String, String>,
+    tag_filter: Option<TagFilter>,
+    tag_limits: Option<TagLimits>,
+    host_key: String,
+    host_tag: String,
+    instance_tag: Option<InstanceTag>,
+    encoder: LineEncoder,
+    compression: BodyCompression,
+    body_size: BodySize,
+    field_types: Option<Arc<Mutex<FieldTypes>>>,
+    schema: Option<Schema>,
+    user_agent: Option<HeaderValue>,
+    idempotency_header: Option<HeaderName>,
+    breaker: Option<BreakerGate>,
+    dead_letter: Option<DeadLetter>,
+    server_version: ServerVersion,
+    client: HttpClient,
+    stdout: Option<OutputWriter>,
 }
 
+/// Where batches are written with `output = "stdout"`, standard output but
+/// for tests.
+type OutputWriter = Arc<Mutex<dyn Write + Send>>;
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 #[serde(deny_unknown_fields)]
 pub struct InfluxDBConfig {
+    #[serde(default)]
     pub namespace: String,
     pub endpoint: String,
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub default_tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub metric_type_tags: MetricTypeTags,
+    #[serde(default)]
+    pub tags: TagFilterConfig,
+    pub api_version: Option<ApiVersion>,
     #[serde(flatten)]
     pub influxdb1_settings: Option<InfluxDB1Settings>,
     #[serde(flatten)]
     pub influxdb2_settings: Option<InfluxDB2Settings>,
+    // Assembled from the v1 `database` and v2 `token` by `influxdb_settings`
+    // with `api_version = "v3"`.
+    #[serde(skip)]
+    pub influxdb3_settings: Option<InfluxDB3Settings>,
     #[serde(default)]
-    pub batch: BatchEventsConfig,
+    pub batch: InfluxDBBatchConfig,
     #[serde(default)]
     pub request: TowerRequestConfig,
+    pub retry_budget: Option<RetryBudgetConfig>,
+    pub shutdown_flush_timeout_secs: Option<u64>,
+    pub max_tag_key_bytes: Option<usize>,
+    pub max_tag_value_bytes: Option<usize>,
+    pub cleanup: Option<CleanupConfig>,
+    pub dns_cache_ttl_secs: Option<u64>,
+    pub keepalive_idle_timeout_secs: Option<u64>,
+    pub max_idle_connections_per_host: Option<usize>,
+    #[serde(default)]
+    pub compression: InfluxDBCompression,
+    pub compression_level: Option<i32>,
+    pub compression_min_bytes: Option<usize>,
+    pub warn_body_bytes: Option<usize>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub dead_letter: Option<DeadLetterConfig>,
+    pub host_key: Option<String>,
+    pub host_tag: Option<String>,
+    pub instance_tag: Option<InstanceTagConfig>,
+    pub single_measurement: Option<String>,
+    pub name_tag: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub coalesce_series: bool,
+    #[serde(default)]
+    pub group_by_measurement: bool,
+    pub series_staleness_secs: Option<u64>,
+    pub max_buffered_events: Option<usize>,
+    pub healthcheck_max_body_bytes: Option<usize>,
+    pub max_line_bytes: Option<usize>,
+    #[serde(default)]
+    pub on_oversize: OnOversize,
+    #[serde(default)]
+    pub on_encode_error: OnEncodeError,
+    #[serde(default)]
+    pub underscore_keys: UnderscoreKeys,
+    #[serde(default)]
+    pub bool_format: BoolFormat,
+    #[serde(default)]
+    pub precision: Precision,
+    pub precision_tag: Option<String>,
+    pub on_field_type_conflict: Option<OnFieldTypeConflict>,
+    pub max_tracked_fields: Option<usize>,
+    pub schema: Option<SchemaConfig>,
+    pub user_agent: Option<String>,
+    pub idempotency_header: Option<String>,
+    #[serde(default)]
+    pub missing_timestamp: MissingTimestamp,
+    #[serde(default)]
+    pub set_format: SetFormat,
+    pub max_set_values: Option<usize>,
+    pub distribution: Option<DistributionConfig>,
+    #[serde(default)]
+    pub field_name: FieldName,
+    #[serde(default)]
+    pub namespace_as_tag: bool,
+    #[serde(default)]
+    pub output: Output,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    pub chunk_bytes: Option<usize>,
+}
+
+/// Where batches are written.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    /// The InfluxDB write API.
+    Http,
+    /// Standard output, one batch after the other, for piping the encoded
+    /// points into other tools.
+    Stdout,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::Http
+    }
+}
+
+/// The protocol points are written in.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// InfluxDB line protocol.
+    InfluxLineProtocol,
+    /// OpenTSDB telnet `put` lines.
+    OpentsdbTelnet,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::InfluxLineProtocol
+    }
+}
+
+/// How the members of a set are written.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SetFormat {
+    /// The number of members as the `value` field.
+    Count,
+    /// The members joined with commas as the `values` string field.
+    Values,
+    /// Both the `value` and the `values` fields.
+    Both,
+}
+
+impl Default for SetFormat {
+    fn default() -> Self {
+        SetFormat::Count
+    }
+}
+
+/// The key of the field holding the value of a counter or gauge.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldName {
+    /// The `value` field.
+    Value,
+    /// A field named after the metric, without its namespace.
+    MetricName,
+}
+
+impl Default for FieldName {
+    fn default() -> Self {
+        FieldName::Value
+    }
+}
+
+/// The statistics of a distribution written as fields, besides its quantiles.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DistributionStatistic {
+    Avg,
+    Count,
+    Max,
+    Median,
+    Min,
+    Sum,
+}
+
+impl DistributionStatistic {
+    fn as_str(self) -> &'static str {
+        match self {
+            DistributionStatistic::Avg => "avg",
+            DistributionStatistic::Count => "count",
+            DistributionStatistic::Max => "max",
+            DistributionStatistic::Median => "median",
+            DistributionStatistic::Min => "min",
+            DistributionStatistic::Sum => "sum",
+        }
+    }
+}
+
+/// The fields a distribution is written as. The defaults are every statistic
+/// and the 0.95 quantile.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DistributionConfig {
+    #[serde(default = "default_distribution_fields")]
+    pub fields: Vec<DistributionStatistic>,
+    /// Written as `quantile_<quantile>` fields, computed from the samples.
+    #[serde(default = "default_distribution_quantiles")]
+    pub quantiles: Vec<f64>,
+}
+
+fn default_distribution_fields() -> Vec<DistributionStatistic> {
+    vec![
+        DistributionStatistic::Avg,
+        DistributionStatistic::Count,
+        DistributionStatistic::Max,
+        DistributionStatistic::Median,
+        DistributionStatistic::Min,
+        DistributionStatistic::Sum,
+    ]
+}
+
+fn default_distribution_quantiles() -> Vec<f64> {
+    vec![0.95]
+}
+
+impl Default for DistributionConfig {
+    fn default() -> Self {
+        DistributionConfig {
+            fields: default_distribution_fields(),
+            quantiles: default_distribution_quantiles(),
+        }
+    }
+}
+
+impl DistributionConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        match self
+            .quantiles
+            .iter()
+            .find(|quantile| !(0.0..=1.0).contains(*quantile))
+        {
+            Some(&quantile) => Err(ConfigError::InvalidQuantile { quantile }),
+            // A distribution would be written without any field.
+            None if self.fields.is_empty() && self.quantiles.is_empty() => {
+                Err(ConfigError::EmptySetting {
+                    field: "distribution.fields",
+                })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Static tags added to the points of one metric type only, such as a tag
+/// that only makes sense on histograms. A tag of the metric itself takes
+/// precedence, and `metric_type` can't be set this way.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricTypeTags {
+    #[serde(default)]
+    pub counter: BTreeMap<String, String>,
+    #[serde(default)]
+    pub gauge: BTreeMap<String, String>,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub histogram: BTreeMap<String, String>,
+    #[serde(default)]
+    pub summary: BTreeMap<String, String>,
+    #[serde(default)]
+    pub distribution: BTreeMap<String, String>,
+}
+
+impl MetricTypeTags {
+    /// Adds the tags of `metric_type`, as written in the `metric_type` tag.
+    fn apply(&self, metric_type: &str, tags: &mut Option<BTreeMap<String, String>>) {
+        let type_tags = match metric_type {
+            "counter" => &self.counter,
+            "gauge" => &self.gauge,
+            "set" => &self.set,
+            "histogram" => &self.histogram,
+            "summary" => &self.summary,
+            "distribution" => &self.distribution,
+            _ => return,
+        };
+        if type_tags.is_empty() {
+            return;
+        }
+        let tags = tags.get_or_insert_with(BTreeMap::new);
+        for (key, value) in type_tags.iter().filter(|(key, _)| *key != "metric_type") {
+            tags.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+const DEFAULT_MAX_SET_VALUES: usize = 100;
+
+/// The tag holding the metric name when all points are written to a single
+/// measurement.
+const DEFAULT_NAME_TAG: &str = "name";
+
+#[derive(Clone, Copy, Debug)]
+struct SetEncoding {
+    format: SetFormat,
+    max_values: usize,
+}
+
+impl Default for SetEncoding {
+    fn default() -> Self {
+        SetEncoding {
+            format: SetFormat::default(),
+            max_values: DEFAULT_MAX_SET_VALUES,
+        }
+    }
+}
+
+/// The tag holding the namespace with `namespace_as_tag`.
+const NAMESPACE_TAG: &str = "namespace";
+
+/// How metrics are mapped onto the measurement and fields of points.
+#[derive(Clone, Debug, Default)]
+struct MetricEncoding {
+    sets: SetEncoding,
+    distribution: DistributionConfig,
+    type_tags: MetricTypeTags,
+    field_name: FieldName,
+    namespace_as_tag: bool,
+}
+
+impl MetricEncoding {
+    /// The fields of a counter or gauge named `name`.
+    fn value_fields(&self, name: &str, value: f64) -> HashMap<String, Field> {
+        match self.field_name {
+            FieldName::Value => to_fields(value),
+            FieldName::MetricName => vec![(name.to_owned(), Field::Float(value))]
+                .into_iter()
+                .collect(),
+        }
+    }
 }
 
+const QUANTILE_DECIMALS: usize = 4;
+
 lazy_static! {
     static ref REQUEST_DEFAULTS: TowerRequestConfig = TowerRequestConfig {
         retry_attempts: Some(5),
@@ -62,13 +430,31 @@ inventory::submit! {
 #[typetag::serde(name = "influxdb_metrics")]
 impl SinkConfig for InfluxDBConfig {
     fn build(&self, cx: SinkContext) -> crate::Result<(super::RouterSink, super::Healthcheck)> {
-        let healthcheck = healthcheck(
-            self.clone().endpoint,
-            self.clone().influxdb1_settings,
-            self.clone().influxdb2_settings,
-            cx.resolver(),
-        )?;
-        let sink = InfluxDBSvc::new(self.clone(), cx)?;
+        // Otherwise a bad endpoint is only reported once the first write fails.
+        validate_endpoint(&self.endpoint)?;
+        validate_path_prefix(self.path_prefix.as_deref())?;
+        let cx = cx.with_resolver(cached_resolver(cx.resolver(), self.dns_cache_ttl_secs));
+        let client = self.http_client(&cx)?;
+        let server_version = ServerVersion::default();
+        let healthcheck: super::Healthcheck = if self.dry_run || self.output == Output::Stdout {
+            Box::new(futures01::future::ok(()))
+        } else {
+            healthcheck(
+                self.prefixed_endpoint(),
+                self.api_version,
+                self.clone().influxdb1_settings,
+                self.clone().influxdb2_settings,
+                self.clone().influxdb3_settings,
+                cx.healthcheck().verify_write,
+                cx.healthcheck().flux_probe,
+                user_agent(self.user_agent.as_deref())?,
+                self.healthcheck_max_body_bytes
+                    .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+                server_version.clone(),
+                client.clone(),
+            )?
+        };
+        let sink = InfluxDBSvc::with_client(self.clone(), cx, client, server_version)?;
         Ok((sink, healthcheck))
     }
 
@@ -81,499 +467,3887 @@ impl SinkConfig for InfluxDBConfig {
     }
 }
 
+impl InfluxDBConfig {
+    fn prefixed_endpoint(&self) -> String {
+        with_path_prefix(&self.endpoint, self.path_prefix.as_deref())
+    }
+
+    fn http_client(&self, cx: &SinkContext) -> crate::Result<HttpClient> {
+        http_client(
+            cx.resolver(),
+            self.keepalive_idle_timeout_secs,
+            self.max_idle_connections_per_host,
+        )
+    }
+
+    /// Moves the measurement of `point` to the name tag when all points are
+    /// written to `single_measurement`.
+    fn apply_single_measurement(&self, point: &mut Point) {
+        if let Some(measurement) = &self.single_measurement {
+            let name = std::mem::replace(&mut point.measurement, measurement.clone());
+            point.tags.get_or_insert_with(BTreeMap::new).insert(
+                self.name_tag
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_NAME_TAG.to_owned()),
+                name,
+            );
+        }
+    }
+
+    fn metric_encoding(&self) -> MetricEncoding {
+        MetricEncoding {
+            sets: SetEncoding {
+                format: self.set_format,
+                max_values: self.max_set_values.unwrap_or(DEFAULT_MAX_SET_VALUES),
+            },
+            distribution: self.distribution.clone().unwrap_or_default(),
+            type_tags: self.metric_type_tags.clone(),
+            field_name: self.field_name,
+            namespace_as_tag: self.namespace_as_tag,
+        }
+    }
+
+    fn line_encoder(&self) -> LineEncoder {
+        LineEncoder {
+            max_bytes: self.max_line_bytes,
+            on_oversize: self.on_oversize,
+            on_encode_error: self.on_encode_error,
+            bool_format: self.bool_format,
+            precision: self.precision,
+            underscore_keys: self.underscore_keys,
+        }
+    }
+}
+
 impl InfluxDBSvc {
     pub fn new(config: InfluxDBConfig, cx: SinkContext) -> crate::Result<super::RouterSink> {
+        let client = config.http_client(&cx)?;
+        Self::with_client(config, cx, client, ServerVersion::default())
+    }
+
+    fn with_client(
+        config: InfluxDBConfig,
+        cx: SinkContext,
+        client: HttpClient,
+        server_version: ServerVersion,
+    ) -> crate::Result<super::RouterSink> {
+        let stdout: OutputWriter = Arc::new(Mutex::new(std::io::stdout()));
+        Self::with_output(config, cx, client, server_version, stdout)
+    }
+
+    fn with_output(
+        config: InfluxDBConfig,
+        cx: SinkContext,
+        client: HttpClient,
+        server_version: ServerVersion,
+        stdout: OutputWriter,
+    ) -> crate::Result<super::RouterSink> {
         let settings = influxdb_settings(
+            config.api_version,
             config.influxdb1_settings.clone(),
             config.influxdb2_settings.clone(),
+            config.influxdb3_settings.clone(),
         )?;
 
-        let endpoint = config.endpoint.clone();
-        let token = settings.token();
+        let endpoint = config.prefixed_endpoint();
+        let authorization = Authorization::new(&*settings, "influxdb_metrics");
 
-        let batch = config.batch.unwrap_or(20, 1);
+        let (limits, batch) = config.batch.unwrap_or(Some(20), None, 1);
         let request = config.request.unwrap_with(&REQUEST_DEFAULTS);
 
-        let uri = settings.write_uri(endpoint)?;
+        let uri = settings.write_uri(endpoint, config.precision)?;
+        log_write_uri("influxdb_metrics", &uri);
 
-        let build_request = move |body: Vec<u8>| {
-            hyper13::Request::post(uri.clone())
-                .header("Content-Type", "text/plain")
-                .header("Authorization", format!("Token {}", token))
-                .body(body)
-                .unwrap()
+        let user_agent = user_agent(config.user_agent.as_deref())?;
+        let idempotency_header = idempotency_header(config.idempotency_header.as_deref())?;
+        let field_types =
+            FieldTypes::shared(config.on_field_type_conflict, config.max_tracked_fields);
+        let schema = config
+            .schema
+            .as_ref()
+            .map(SchemaConfig::build)
+            .transpose()?;
+        if let Some(distribution) = &config.distribution {
+            distribution.validate()?;
+        }
+        if config.max_buffered_events == Some(0) {
+            return Err(ConfigError::ZeroMaxBufferedEvents.into());
+        }
+
+        let default_tags = resolve_default_tags(&config.default_tags);
+        let instance_tag = config
+            .instance_tag
+            .as_ref()
+            .map(InstanceTagConfig::build)
+            .transpose()?;
+        let tag_filter = config.tags.build();
+        let tag_limits = TagLimits::new(
+            "influxdb_metrics",
+            config.max_tag_key_bytes,
+            config.max_tag_value_bytes,
+        );
+        let encoder = config.line_encoder();
+
+        let buffer = {
+            let namespace = config.namespace.clone();
+            // The instance tag is counted with the default tags it's written
+            // alongside.
+            let mut default_tags = default_tags.clone();
+            if let Some(instance_tag) = &instance_tag {
+                instance_tag.apply(&mut default_tags);
+            }
+            let encoding = config.metric_encoding();
+            LimitedBatch::new(MetricBuffer::new(), limits, move |event: &Event| {
+                encoded_len(
+                    event.as_metric(),
+                    &namespace,
+                    &encoding,
+                    &default_tags,
+                    &encoder,
+                )
+            })
         };
 
-        let http_service = HttpBatchService::new(cx.resolver(), None, build_request);
+        let host_key = config
+            .host_key
+            .clone()
+            .unwrap_or_else(|| log_schema().host_key().to_string());
+        let host_tag = config
+            .host_tag
+            .clone()
+            .unwrap_or_else(|| DEFAULT_HOST_TAG.to_owned());
+        let compression = BodyCompression::new(
+            config.compression,
+            config.compression_level,
+            config.compression_min_bytes,
+        )?;
+        let body_size = BodySize {
+            component_type: "influxdb_metrics",
+            warn_bytes: config.warn_body_bytes,
+        };
+        let breaker = config
+            .circuit_breaker
+            .map(|breaker| BreakerGate::new(breaker.build("influxdb_metrics")));
+        let dead_letter = config
+            .dead_letter
+            .as_ref()
+            .map(|dead_letter| dead_letter.build("influxdb_metrics"));
+        let retry_logic = InfluxDBRetryLogic::new(config.retry_budget.as_ref(), "influxdb_metrics");
+        let shutdown_flush_timeout_secs = config.shutdown_flush_timeout_secs;
+        let series_staleness_secs = config.series_staleness_secs;
+        let max_buffered_events = config.max_buffered_events;
+        // A dry run doesn't delete either, nor does writing to stdout.
+        let cleanup = config
+            .cleanup
+            .as_ref()
+            .filter(|_| !config.dry_run && config.output == Output::Http)
+            .map(|cleanup| {
+                cleanup.build(
+                    &*settings,
+                    config.prefixed_endpoint(),
+                    user_agent.clone(),
+                    "influxdb_metrics",
+                )
+            })
+            .transpose()?
+            .map(|cleanup| cleanup.spawn(client.clone(), cx.executor()));
 
+        let stdout = Some(stdout).filter(|_| config.output == Output::Stdout);
         let influxdb_http_service = InfluxDBSvc {
             config,
-            inner: http_service,
+            settings: settings.into(),
+            uri,
+            authorization,
+            default_tags,
+            tag_filter,
+            tag_limits,
+            host_key,
+            host_tag,
+            instance_tag,
+            encoder,
+            compression,
+            body_size,
+            field_types,
+            schema,
+            user_agent,
+            idempotency_header,
+            breaker,
+            dead_letter,
+            server_version,
+            client,
+            stdout,
         };
 
+        let staleness =
+            series_staleness_secs.map(|secs| SeriesStaleness::new(Duration::from_secs(secs)));
+        let buffered_events = BufferedEvents::new();
+        let buffer = BufferedEventsBatch {
+            inner: StaleSeriesBatch {
+                inner: buffer,
+                max_events: batch.size,
+                staleness: staleness.clone(),
+            },
+            events: buffered_events.clone(),
+        };
         let sink = request
             .batch_sink(
-                HttpRetryLogic,
+                retry_logic,
                 influxdb_http_service,
-                MetricBuffer::new(),
+                buffer,
                 batch,
-                cx.acker(),
+                buffered_events.acker(),
             )
             .sink_map_err(|e| error!("Fatal influxdb sink error: {}", e));
 
-        Ok(Box::new(sink))
+        let sink = BufferedEventsLimit {
+            inner: sink,
+            max_events: max_buffered_events,
+            events: buffered_events,
+            acker: cx.acker(),
+            forwarded: 0,
+            active: false,
+        };
+        let sink = StaleSeriesFlush {
+            inner: sink,
+            staleness,
+            wakeup: None,
+        };
+
+        Ok(Box::new(
+            ShutdownFlush::new(sink, "influxdb_metrics", shutdown_flush_timeout_secs)
+                .with_cleanup(cleanup),
+        ))
     }
 }
 
-impl Service<Vec<Metric>> for InfluxDBSvc {
-    type Response = HttpResponse;
-    type Error = HttpError;
-    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+/// When each series of the batch being built last got a point, for
+/// `series_staleness_secs`. Shared between `StaleSeriesBatch`, which records
+/// the points and reports itself full once a series goes stale, and
+/// `StaleSeriesFlush`, which wakes the sink up at that moment as no new event
+/// may arrive to do it.
+#[derive(Clone)]
+struct SeriesStaleness {
+    window: Duration,
+    last_seen: Arc<Mutex<HashMap<u64, Instant>>>,
+}
 
-    fn poll_ready(&mut self, cx: &mut std::task::Context) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+impl SeriesStaleness {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_seen: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn seen(&self, event: &Event, now: Instant) {
+        let metric = event.as_metric();
+        let mut hasher = DefaultHasher::new();
+        metric.name.hash(&mut hasher);
+        metric.tags.hash(&mut hasher);
+        self.last_seen.lock().unwrap().insert(hasher.finish(), now);
     }
 
-    fn call(&mut self, items: Vec<Metric>) -> Self::Future {
-        let input = encode_events(items, &self.config.namespace);
-        let body: Vec<u8> = input.into_bytes();
+    /// When the series that went longest without a point goes stale.
+    fn deadline(&self) -> Option<Instant> {
+        let last_seen = self.last_seen.lock().unwrap();
+        last_seen.values().min().map(|seen| *seen + self.window)
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        self.deadline().map_or(false, |deadline| deadline <= now)
+    }
 
-        self.inner.call(body)
+    fn clear(&self) {
+        self.last_seen.lock().unwrap().clear();
     }
 }
 
-fn encode_events(events: Vec<Metric>, namespace: &str) -> String {
-    let mut output = String::new();
-    for event in events.into_iter() {
-        let fullname = encode_namespace(namespace, &event.name);
-        let ts = encode_timestamp(event.timestamp);
-        let tags = event.tags.clone();
-        match event.value {
-            MetricValue::Counter { value } => {
-                let fields = to_fields(value);
-
-                influx_line_protocol(fullname, "counter", tags, Some(fields), ts, &mut output)
-            }
-            MetricValue::Gauge { value } => {
-                let fields = to_fields(value);
+/// A batch that reports itself full once one of its series has had no new
+/// point for the staleness window, so that `BatchSink` sends it without
+/// waiting for the batch timeout.
+struct StaleSeriesBatch<B: Batch> {
+    inner: B,
+    max_events: usize,
+    staleness: Option<SeriesStaleness>,
+}
 
-                influx_line_protocol(fullname, "gauge", tags, Some(fields), ts, &mut output);
-            }
-            MetricValue::Set { values } => {
-                let fields = to_fields(values.len() as f64);
+impl<B: Batch<Input = Event>> Batch for StaleSeriesBatch<B> {
+    type Input = B::Input;
+    type Output = B::Output;
 
-                influx_line_protocol(fullname, "set", tags, Some(fields), ts, &mut output);
-            }
-            MetricValue::AggregatedHistogram {
-                buckets,
-                counts,
-                count,
-                sum,
-            } => {
-                let mut fields: HashMap<String, Field> = buckets
-                    .iter()
-                    .zip(counts.iter())
-                    .map(|pair| (format!("bucket_{}", pair.0), Field::UnsignedInt(*pair.1)))
-                    .collect();
-                fields.insert("count".to_owned(), Field::UnsignedInt(count));
-                fields.insert("sum".to_owned(), Field::Float(sum));
-
-                influx_line_protocol(fullname, "histogram", tags, Some(fields), ts, &mut output);
-            }
-            MetricValue::AggregatedSummary {
-                quantiles,
-                values,
-                count,
-                sum,
-            } => {
-                let mut fields: HashMap<String, Field> = quantiles
-                    .iter()
-                    .zip(values.iter())
-                    .map(|pair| (format!("quantile_{}", pair.0), Field::Float(*pair.1)))
-                    .collect();
-                fields.insert("count".to_owned(), Field::UnsignedInt(count));
-                fields.insert("sum".to_owned(), Field::Float(sum));
-
-                influx_line_protocol(fullname, "summary", tags, Some(fields), ts, &mut output);
-            }
-            MetricValue::Distribution {
-                values,
-                sample_rates,
-            } => {
-                let fields = encode_distribution(&values, &sample_rates);
+    fn len(&self) -> usize {
+        let len = self.inner.len();
+        match &self.staleness {
+            Some(staleness) if staleness.is_stale(Instant::now()) => len.max(self.max_events),
+            _ => len,
+        }
+    }
 
-                influx_line_protocol(fullname, "distribution", tags, fields, ts, &mut output);
-            }
+    fn push(&mut self, item: Self::Input) {
+        if let Some(staleness) = &self.staleness {
+            staleness.seen(&item, Instant::now());
         }
+        self.inner.push(item)
     }
 
-    // remove last '\n'
-    output.pop();
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
 
-    return output;
+    // Only called when the batch is sent, so none of its series are left.
+    fn fresh(&self) -> Self {
+        if let Some(staleness) = &self.staleness {
+            staleness.clear();
+        }
+        Self {
+            inner: self.inner.fresh(),
+            max_events: self.max_events,
+            staleness: self.staleness.clone(),
+        }
+    }
+
+    fn finish(self) -> Self::Output {
+        self.inner.finish()
+    }
+
+    fn num_items(&self) -> usize {
+        self.inner.num_items()
+    }
 }
 
-fn encode_distribution(values: &[f64], counts: &[u32]) -> Option<HashMap<String, Field>> {
-    if values.len() != counts.len() {
-        return None;
+/// Polls the sink again when a series of its batch goes stale.
+struct StaleSeriesFlush<S> {
+    inner: S,
+    staleness: Option<SeriesStaleness>,
+    wakeup: Option<(Instant, Delay)>,
+}
+
+impl<S: Sink> Sink for StaleSeriesFlush<S> {
+    type SinkItem = S::SinkItem;
+    type SinkError = S::SinkError;
+
+    fn start_send(
+        &mut self,
+        item: Self::SinkItem,
+    ) -> futures01::StartSend<Self::SinkItem, Self::SinkError> {
+        self.inner.start_send(item)
     }
 
-    let mut samples = Vec::new();
-    for (v, c) in values.iter().zip(counts.iter()) {
-        for _ in 0..*c {
-            samples.push(*v);
+    fn poll_complete(&mut self) -> futures01::Poll<(), Self::SinkError> {
+        let deadline = self.staleness.as_ref().and_then(SeriesStaleness::deadline);
+        match deadline {
+            Some(deadline) => {
+                if self.wakeup.as_ref().map(|(at, _)| *at) != Some(deadline) {
+                    self.wakeup = Some((deadline, Delay::new(deadline)));
+                }
+                if let Some((_, delay)) = &mut self.wakeup {
+                    // Only registers the wakeup, a broken timer leaves the
+                    // batch timeout to send the batch.
+                    let _ = futures01::Future::poll(delay);
+                }
+            }
+            None => self.wakeup = None,
         }
+        self.inner.poll_complete()
     }
 
-    if samples.is_empty() {
-        return None;
+    fn close(&mut self) -> futures01::Poll<(), Self::SinkError> {
+        self.inner.close()
     }
+}
 
-    if samples.len() == 1 {
-        let val = samples[0];
-        return Some(
-            vec![
-                ("min".to_owned(), Field::Float(val)),
-                ("max".to_owned(), Field::Float(val)),
-                ("median".to_owned(), Field::Float(val)),
-                ("avg".to_owned(), Field::Float(val)),
-                ("sum".to_owned(), Field::Float(val)),
-                ("count".to_owned(), Field::Float(1.0)),
-                ("quantile_0.95".to_owned(), Field::Float(val)),
-            ]
-            .into_iter()
-            .collect(),
-        );
-    }
+/// The events `max_buffered_events` limits: those in the batch being built
+/// and in requests sent but not yet acknowledged. They're counted in the
+/// batch's items, as acknowledged, so that metrics `MetricBuffer` aggregates
+/// into one are counted once.
+#[derive(Clone)]
+struct BufferedEvents {
+    pushed: Arc<AtomicUsize>,
+    acked: Arc<AtomicUsize>,
+    notifier: Arc<AtomicTask>,
+}
 
-    samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+impl BufferedEvents {
+    fn new() -> Self {
+        Self {
+            pushed: Arc::new(AtomicUsize::new(0)),
+            acked: Arc::new(AtomicUsize::new(0)),
+            notifier: Arc::new(AtomicTask::new()),
+        }
+    }
 
-    let length = samples.len() as f64;
-    let min = samples.first().unwrap();
-    let max = samples.last().unwrap();
-
-    let p50 = samples[(0.50 * length - 1.0).round() as usize];
-    let p95 = samples[(0.95 * length - 1.0).round() as usize];
-
-    let sum = samples.iter().sum();
-    let avg = sum / length;
-
-    let fields: HashMap<String, Field> = vec![
-        ("min".to_owned(), Field::Float(*min)),
-        ("max".to_owned(), Field::Float(*max)),
-        ("median".to_owned(), Field::Float(p50)),
-        ("avg".to_owned(), Field::Float(avg)),
-        ("sum".to_owned(), Field::Float(sum)),
-        ("count".to_owned(), Field::Float(length)),
-        ("quantile_0.95".to_owned(), Field::Float(p95)),
-    ]
-    .into_iter()
-    .collect();
+    /// The acker given to `BatchSink`, whose acks `BufferedEventsLimit` passes
+    /// on to the topology's acker.
+    fn acker(&self) -> Acker {
+        Acker::Disk(Arc::clone(&self.acked), Arc::clone(&self.notifier))
+    }
 
-    Some(fields)
+    fn count(&self) -> usize {
+        self.pushed
+            .load(atomic::Ordering::Acquire)
+            .saturating_sub(self.acked.load(atomic::Ordering::Acquire))
+    }
 }
 
-fn to_fields(value: f64) -> HashMap<String, Field> {
-    let fields: HashMap<String, Field> = vec![("value".to_owned(), Field::Float(value))]
-        .into_iter()
-        .collect();
-    fields
+/// Counts the items pushed into the batch for `BufferedEvents`, and finishes
+/// it into a request.
+struct BufferedEventsBatch<B: Batch> {
+    inner: B,
+    events: BufferedEvents,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::event::metric::{Metric, MetricKind, MetricValue};
-    use crate::sinks::influxdb::test_util::{assert_fields, split_line_protocol, tags, ts};
-    use pretty_assertions::assert_eq;
+impl<B: Batch<Output = Vec<Metric>>> Batch for BufferedEventsBatch<B> {
+    type Input = B::Input;
+    type Output = MetricsRequest;
 
-    #[test]
-    fn test_encode_counter() {
-        let events = vec![
-            Metric {
-                name: "total".into(),
-                timestamp: Some(ts()),
-                tags: None,
-                kind: MetricKind::Incremental,
-                value: MetricValue::Counter { value: 1.5 },
-            },
-            Metric {
-                name: "check".into(),
-                timestamp: Some(ts()),
-                tags: Some(tags()),
-                kind: MetricKind::Incremental,
-                value: MetricValue::Counter { value: 1.0 },
-            },
-        ];
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
 
-        let line_protocols = encode_events(events, "ns");
-        assert_eq!(
-            line_protocols,
-            "ns.total,metric_type=counter value=1.5 1542182950000000011\n\
-            ns.check,metric_type=counter,normal_tag=value,true_tag=true value=1 1542182950000000011"
-        );
+    fn push(&mut self, item: Self::Input) {
+        let before = self.inner.num_items();
+        self.inner.push(item);
+        let added = self.inner.num_items().saturating_sub(before);
+        self.events
+            .pushed
+            .fetch_add(added, atomic::Ordering::AcqRel);
     }
 
-    #[test]
-    fn test_encode_gauge() {
-        let events = vec![Metric {
-            name: "meter".to_owned(),
-            timestamp: Some(ts()),
-            tags: Some(tags()),
-            kind: MetricKind::Incremental,
-            value: MetricValue::Gauge { value: -1.5 },
-        }];
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
 
-        let line_protocols = encode_events(events, "ns");
-        assert_eq!(
-            line_protocols,
-            "ns.meter,metric_type=gauge,normal_tag=value,true_tag=true value=-1.5 1542182950000000011"
-        );
+    fn fresh(&self) -> Self {
+        Self {
+            inner: self.inner.fresh(),
+            events: self.events.clone(),
+        }
     }
 
-    #[test]
-    fn test_encode_set() {
-        let events = vec![Metric {
-            name: "users".into(),
-            timestamp: Some(ts()),
-            tags: Some(tags()),
-            kind: MetricKind::Incremental,
-            value: MetricValue::Set {
+    fn finish(self) -> Self::Output {
+        MetricsRequest {
+            metrics: self.inner.finish(),
+            sent_at: Utc::now(),
+            written: Arc::default(),
+        }
+    }
+
+    fn num_items(&self) -> usize {
+        self.inner.num_items()
+    }
+}
+
+type Route = (Option<String>, Precision);
+
+/// A batch, split into one write per route by `InfluxDBSvc`. The clones made
+/// to retry it share the routes already written, so that only the writes that
+/// failed are sent again.
+#[derive(Clone, Debug)]
+struct MetricsRequest {
+    metrics: Vec<Metric>,
+    /// Written for the metrics without a timestamp, so that retries write the
+    /// same one.
+    sent_at: DateTime<Utc>,
+    written: Arc<Mutex<BTreeSet<Route>>>,
+}
+
+/// Stops taking events while `max_buffered_events` are buffered or in
+/// flight, until requests are acknowledged, and passes the acks on to the
+/// topology's acker.
+struct BufferedEventsLimit<S> {
+    inner: S,
+    max_events: Option<usize>,
+    events: BufferedEvents,
+    acker: Acker,
+    forwarded: usize,
+    active: bool,
+}
+
+impl<S> BufferedEventsLimit<S> {
+    fn forward_acks(&mut self) {
+        let acked = self.events.acked.load(atomic::Ordering::Acquire);
+        self.acker.ack(acked.wrapping_sub(self.forwarded));
+        self.forwarded = acked;
+    }
+
+    fn set_active(&mut self, active: bool) {
+        if self.active != active {
+            self.active = active;
+            emit!(InfluxDBBackpressure {
+                component_type: "influxdb_metrics",
+                active,
+                buffered_events: self.events.count(),
+            });
+        }
+    }
+}
+
+impl<S: Sink> Sink for BufferedEventsLimit<S> {
+    type SinkItem = S::SinkItem;
+    type SinkError = S::SinkError;
+
+    fn start_send(
+        &mut self,
+        item: Self::SinkItem,
+    ) -> futures01::StartSend<Self::SinkItem, Self::SinkError> {
+        if let Some(max_events) = self.max_events {
+            if self.events.count() >= max_events {
+                // Sends the batch and collects the responses, the acks of
+                // which wake the task up to try again.
+                self.events.notifier.register();
+                let polled = self.inner.poll_complete();
+                self.forward_acks();
+                polled?;
+                if self.events.count() >= max_events {
+                    self.set_active(true);
+                    return Ok(futures01::AsyncSink::NotReady(item));
+                }
+            }
+            self.set_active(false);
+        }
+
+        let sent = self.inner.start_send(item);
+        self.forward_acks();
+        sent
+    }
+
+    fn poll_complete(&mut self) -> futures01::Poll<(), Self::SinkError> {
+        let polled = self.inner.poll_complete();
+        self.forward_acks();
+        polled
+    }
+
+    fn close(&mut self) -> futures01::Poll<(), Self::SinkError> {
+        let closed = self.inner.close();
+        self.forward_acks();
+        closed
+    }
+}
+
+impl Service<MetricsRequest> for InfluxDBSvc {
+    type Response = HttpResponse;
+    type Error = HttpError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context) -> Poll<Result<(), Self::Error>> {
+        if let Some(breaker) = &mut self.breaker {
+            futures::ready!(breaker.poll_ready(cx));
+        }
+        self.client.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: MetricsRequest) -> Self::Future {
+        let permit = self.breaker.as_mut().and_then(BreakerGate::take);
+
+        // Each route and precision is a separate write, as both are
+        // parameters of the write URI.
+        let MetricsRequest {
+            metrics,
+            sent_at,
+            written,
+        } = request;
+        let mut routes: BTreeMap<Route, Vec<Metric>> = BTreeMap::new();
+        for item in metrics.into_iter() {
+            routes
+                .entry((
+                    self.settings.route(item.tags.as_ref()),
+                    self.precision(&item),
+                ))
+                .or_insert_with(Vec::new)
+                .push(item);
+        }
+
+        let mut requests = Vec::with_capacity(routes.len());
+        for ((route, precision), metrics) in routes.into_iter() {
+            let key = (route.clone(), precision);
+            // Written by an earlier try of this batch.
+            if written.lock().unwrap().contains(&key) {
+                continue;
+            }
+            let uri = self.write_uri(route.as_deref(), precision);
+            let encoder = LineEncoder {
+                precision,
+                ..self.encoder
+            };
+            let metrics = with_timestamps(metrics, self.config.missing_timestamp, sent_at);
+            let mut points = to_points(
+                metrics,
+                &self.config.namespace,
+                &self.config.metric_encoding(),
+            );
+            for point in points.iter_mut() {
+                point.tags = merge_default_tags(&self.default_tags, point.tags.take());
+                if let Some(tags) = &mut point.tags {
+                    rename_host_tag(tags, &self.host_key, &self.host_tag);
+                }
+                if let (Some(filter), Some(tags)) = (&self.tag_filter, &mut point.tags) {
+                    filter.apply(tags, point.fields.get_or_insert_with(HashMap::new));
+                }
+                self.config.apply_single_measurement(point);
+                if let Some(instance_tag) = &self.instance_tag {
+                    instance_tag.apply(point.tags.get_or_insert_with(BTreeMap::new));
+                }
+                if let (Some(tag_limits), Some(tags)) = (&self.tag_limits, &mut point.tags) {
+                    tag_limits.apply(tags);
+                }
+            }
+            if self.config.coalesce_series {
+                points = coalesce_series(points);
+            }
+            if self.config.group_by_measurement {
+                group_by_measurement(&mut points);
+            }
+            if let Some(schema) = &self.schema {
+                points.retain(|point| {
+                    point.fields.as_ref().map_or(true, |fields| {
+                        schema.check("influxdb_metrics", &point.measurement, fields)
+                    })
+                });
+            }
+            if let Some(field_types) = &self.field_types {
+                let mut field_types = field_types.lock().unwrap();
+                points.retain(|point| {
+                    point.fields.as_ref().map_or(true, |fields| {
+                        field_types.check("influxdb_metrics", &point.measurement, fields)
+                    })
+                });
+            }
+
+            if let Some(stdout) = &self.stdout {
+                let mut body = self.encode_body(points, &encoder);
+                if !body.is_empty() && !body.ends_with('\n') {
+                    body.push('\n');
+                }
+                let mut stdout = stdout.lock().unwrap();
+                if let Err(error) = stdout
+                    .write_all(body.as_bytes())
+                    .and_then(|()| stdout.flush())
+                {
+                    emit!(InfluxDBStdoutWriteFailed { error });
+                }
+                continue;
+            }
+
+            // Encoding consumes the points, which an empty body is reported with.
+            let point_count = points.len();
+            if self.config.dry_run {
+                let body = self.encode_body(points, &encoder);
+                emit!(InfluxDBDryRunBatch {
+                    uri: &uri,
+                    body: body.as_bytes(),
+                });
+                continue;
+            }
+
+            let mut rejected = None;
+            let request = if self.idempotency_header.is_some()
+                || self.compression.is_enabled()
+                || self.dead_letter.is_some()
+                || self.config.output_format != OutputFormat::InfluxLineProtocol
+            {
+                // The hash, the compression threshold and the dead letter file
+                // need the whole body, so it can't be streamed. Only line
+                // protocol is streamed at all.
+                let body = self.encode_body(points, &encoder).into_bytes();
+                if body.is_empty() {
+                    emit!(InfluxDBEmptyBatch {
+                        component_type: "influxdb_metrics",
+                        points: point_count,
+                    });
+                    continue;
+                }
+                self.body_size.record(body.len());
+                if let Some(dead_letter) = &self.dead_letter {
+                    rejected = Some((dead_letter.clone(), body.clone()));
+                }
+                let hash = self
+                    .idempotency_header
+                    .as_ref()
+                    .map(|header| (header.clone(), body_hash(&body)));
+                let mut request = self.build_request(uri, Body::empty());
+                if let Some((header, hash)) = hash {
+                    request.headers_mut().insert(header, hash);
+                }
+                let body = self.compression.compress(body, request.headers_mut());
+                *request.body_mut() = Body::from(body);
+                request
+            } else {
+                let mut chunks = LineChunks::new(
+                    points,
+                    encoder,
+                    self.config.chunk_bytes.unwrap_or(BODY_CHUNK_BYTES),
+                );
+                // Encoding the first chunk up front tells whether any point
+                // could be encoded, without holding the rest of the body.
+                let first = match chunks.next() {
+                    Some(first) => first,
+                    None => {
+                        emit!(InfluxDBEmptyBatch {
+                            component_type: "influxdb_metrics",
+                            points: point_count,
+                        });
+                        continue;
+                    }
+                };
+                let body = Body::wrap_stream(futures::stream::iter(
+                    std::iter::once(first).chain(chunks.with_body_size(self.body_size)),
+                ));
+                self.build_request(uri, body)
+            };
+            let response = self.client.call(request);
+            let written = Arc::clone(&written);
+            requests.push(async move {
+                let (parts, body) = response.await?.into_parts();
+                let mut body = hyper13::body::aggregate(body).await?;
+                let response = HttpResponse::from_parts(parts, body.to_bytes());
+                if response.status().is_success() {
+                    written.lock().unwrap().insert(key);
+                }
+                if let Some((dead_letter, lines)) = rejected {
+                    if DeadLetter::is_rejection(response.status()) {
+                        dead_letter.write(&lines, response.status(), response.body());
+                    }
+                }
+                Ok::<_, HttpError>(response)
+            });
+        }
+
+        Box::pin(async move {
+            // Dropped with this future, when the request times out, the
+            // permit records a failure.
+            let result = future::try_join_all(requests).await;
+            if let Some(permit) = permit {
+                match &result {
+                    // Nothing was sent, as on a dry run or when every point
+                    // was dropped.
+                    Ok(responses) if responses.is_empty() => permit.release(),
+                    // Rejected points don't mean the server is down.
+                    Ok(responses) => permit.record(
+                        !responses
+                            .iter()
+                            .any(|response| response.status().is_server_error()),
+                        Instant::now(),
+                    ),
+                    Err(_) => permit.record(false, Instant::now()),
+                }
+            }
+
+            let mut responses = result?;
+            if responses.is_empty() {
+                return Ok(HttpResponse::new(Bytes::new()));
+            }
+
+            // The first failed response decides whether the batch is retried,
+            // which only sends the writes that weren't successful again.
+            let index = responses
+                .iter()
+                .position(|response| !response.status().is_success())
+                .unwrap_or(0);
+            Ok(responses.swap_remove(index))
+        })
+    }
+}
+
+impl InfluxDBSvc {
+    fn encode_body(&self, points: Vec<Point>, encoder: &LineEncoder) -> String {
+        match self.config.output_format {
+            OutputFormat::InfluxLineProtocol => encode_points(points, encoder),
+            OutputFormat::OpentsdbTelnet => encode_put_points(points, encoder.precision),
+        }
+    }
+
+    fn build_request(&self, uri: Uri, body: Body) -> hyper13::Request<Body> {
+        let mut builder = hyper13::Request::post(uri).header("Content-Type", "text/plain");
+        if let Some(authorization) = self.authorization.get() {
+            builder = builder.header("Authorization", authorization);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.header(USER_AGENT, user_agent);
+        }
+        builder.body(body).unwrap()
+    }
+
+    fn write_uri(&self, route: Option<&str>, precision: Precision) -> Uri {
+        let endpoint = self.config.prefixed_endpoint();
+        let uri = match route {
+            None if precision == self.config.precision => return self.uri.clone(),
+            None => self.settings.write_uri(endpoint, precision),
+            Some(route) => self.settings.routed_write_uri(endpoint, precision, route),
+        };
+        uri.unwrap_or_else(|error| {
+            error!(
+                message = "invalid write uri for route; using the configured one.",
+                ?route,
+                %error,
+                rate_limit_secs = 30
+            );
+            self.uri.clone()
+        })
+    }
+
+    /// The precision named by the `precision_tag` of `metric`, falling back to
+    /// the configured one.
+    fn precision(&self, metric: &Metric) -> Precision {
+        let value = match (&self.config.precision_tag, &metric.tags) {
+            (Some(tag), Some(tags)) => tags.get(tag),
+            _ => None,
+        };
+        match value {
+            None => self.config.precision,
+            Some(value) => Precision::parse(value).unwrap_or_else(|| {
+                warn!(
+                    message = "unknown precision in precision tag; using the configured one.",
+                    precision = %value,
+                    rate_limit_secs = 30
+                );
+                self.config.precision
+            }),
+        }
+    }
+}
+
+/// Writes the host from the `host_key` tag as the `host_tag` tag.
+fn rename_host_tag(tags: &mut BTreeMap<String, String>, host_key: &str, host_tag: &str) {
+    if host_key != host_tag {
+        if let Some(host) = tags.remove(host_key) {
+            tags.insert(host_tag.to_owned(), host);
+        }
+    }
+}
+
+/// Gives the metrics without a timestamp `now`, or drops them, as
+/// `missing_timestamp` says.
+fn with_timestamps(
+    metrics: Vec<Metric>,
+    missing_timestamp: MissingTimestamp,
+    now: DateTime<Utc>,
+) -> Vec<Metric> {
+    metrics
+        .into_iter()
+        .filter_map(|mut metric| {
+            metric.timestamp =
+                Some(missing_timestamp.apply_with("influxdb_metrics", metric.timestamp, || now)?);
+            Some(metric)
+        })
+        .collect()
+}
+
+/// A metric broken down into the parts of a line protocol point.
+#[derive(Debug, PartialEq)]
+struct Point {
+    measurement: String,
+    metric_type: &'static str,
+    tags: Option<BTreeMap<String, String>>,
+    fields: Option<HashMap<String, Field>>,
+    timestamp: i64,
+}
+
+fn encode_events(events: Vec<Metric>, namespace: &str) -> String {
+    encode_points(
+        to_points(events, namespace, &MetricEncoding::default()),
+        &LineEncoder::default(),
+    )
+}
+
+/// Writes a metric as a line of line protocol, without the trailing newline,
+/// the way the sink configured by `config` does. Default tags, routing,
+/// coalescing and the schema checks are left to the sink.
+///
+/// Returns `None` for metrics that are dropped: those with an empty name, no
+/// fields or that can't be encoded.
+pub fn metric_to_line(metric: &Metric, config: &InfluxDBConfig) -> Option<String> {
+    if metric.name.is_empty() {
+        return None;
+    }
+
+    let mut point = to_point(metric.clone(), &config.namespace, &config.metric_encoding());
+    config.apply_single_measurement(&mut point);
+    let mut line = String::new();
+    config
+        .line_encoder()
+        .encode(
+            point.measurement,
+            point.metric_type,
+            point.tags,
+            point.fields,
+            point.timestamp,
+            &mut line,
+        )
+        .ok()?;
+    line.pop();
+    Some(line)
+}
+
+fn to_points(events: Vec<Metric>, namespace: &str, encoding: &MetricEncoding) -> Vec<Point> {
+    events
+        .into_iter()
+        .map(|event| to_point(event, namespace, encoding))
+        .collect()
+}
+
+/// Maps a metric onto the measurement, tags and fields of a point.
+fn to_point(event: Metric, namespace: &str, encoding: &MetricEncoding) -> Point {
+    let timestamp = encode_timestamp(event.timestamp);
+    let mut tags = event.tags.clone();
+    let measurement = if encoding.namespace_as_tag {
+        if !namespace.is_empty() {
+            tags.get_or_insert_with(BTreeMap::new)
+                .insert(NAMESPACE_TAG.to_owned(), namespace.to_owned());
+        }
+        event.name.clone()
+    } else {
+        encode_namespace(namespace, &event.name)
+    };
+    let (metric_type, fields) = match event.value {
+        MetricValue::Counter { value } => {
+            ("counter", Some(encoding.value_fields(&event.name, value)))
+        }
+        MetricValue::Gauge { value } => ("gauge", Some(encoding.value_fields(&event.name, value))),
+        MetricValue::Set { values } => {
+            ("set", Some(set_fields(&measurement, values, encoding.sets)))
+        }
+        MetricValue::AggregatedHistogram {
+            buckets,
+            counts,
+            count,
+            sum,
+        } => {
+            let mut fields: HashMap<String, Field> = buckets
+                .iter()
+                .zip(counts.iter())
+                .map(|pair| (format!("bucket_{}", pair.0), Field::from(*pair.1)))
+                .collect();
+            fields.insert("count".to_owned(), Field::from(count));
+            fields.insert("sum".to_owned(), Field::Float(sum));
+
+            ("histogram", Some(fields))
+        }
+        MetricValue::AggregatedSummary {
+            quantiles,
+            values,
+            count,
+            sum,
+        } => {
+            let mut fields: HashMap<String, Field> = quantiles
+                .iter()
+                .zip(values.iter())
+                .map(|pair| {
+                    (
+                        quantile_field(*pair.0, QUANTILE_DECIMALS),
+                        Field::Float(*pair.1),
+                    )
+                })
+                .collect();
+            fields.insert("count".to_owned(), Field::from(count));
+            fields.insert("sum".to_owned(), Field::Float(sum));
+
+            ("summary", Some(fields))
+        }
+        MetricValue::Distribution {
+            values,
+            sample_rates,
+        } => (
+            "distribution",
+            encode_distribution(&values, &sample_rates, &encoding.distribution),
+        ),
+    };
+    encoding.type_tags.apply(metric_type, &mut tags);
+
+    Point {
+        measurement,
+        metric_type,
+        tags,
+        fields,
+        timestamp,
+    }
+}
+
+/// Orders points by measurement, so that InfluxDB gets the lines of each
+/// measurement together. The sort is stable, so points keep their order
+/// within a measurement.
+fn group_by_measurement(points: &mut [Point]) {
+    points.sort_by(|a, b| a.measurement.cmp(&b.measurement));
+}
+
+/// Merges the fields of points that share a measurement, tag set and
+/// timestamp, so they're written as a single line.
+fn coalesce_series(points: Vec<Point>) -> Vec<Point> {
+    let mut coalesced: Vec<Point> = Vec::with_capacity(points.len());
+    let mut series: HashMap<(String, &'static str, Option<BTreeMap<String, String>>, i64), usize> =
+        HashMap::new();
+
+    for point in points.into_iter() {
+        let key = (
+            point.measurement.clone(),
+            point.metric_type,
+            point.tags.clone(),
+            point.timestamp,
+        );
+        let index = match series.get(&key) {
+            Some(index) => *index,
+            None => {
+                series.insert(key, coalesced.len());
+                coalesced.push(point);
+                continue;
+            }
+        };
+
+        let target = &mut coalesced[index];
+        let fields = target.fields.get_or_insert_with(HashMap::new);
+        for (key, value) in point.fields.unwrap_or_default().into_iter() {
+            if fields.insert(key.clone(), value).is_some() {
+                warn!(
+                    message = "field written more than once for the same series; keeping the last value.",
+                    field = %key,
+                    measurement = %target.measurement,
+                    rate_limit_secs = 30
+                );
+            }
+        }
+    }
+
+    coalesced
+}
+
+fn encode_point(point: Point, encoder: &LineEncoder, output: &mut String) {
+    if let Err(error) = encoder.encode(
+        point.measurement,
+        point.metric_type,
+        point.tags,
+        point.fields,
+        point.timestamp,
+        output,
+    ) {
+        encoder.dropped("influxdb_metrics", error);
+    }
+}
+
+/// Size of the line protocol a metric encodes to, for the batch byte limit.
+/// Observations the buffer aggregates are counted separately, so this is an
+/// upper bound of what the batch writes.
+fn encoded_len(
+    metric: &Metric,
+    namespace: &str,
+    encoding: &MetricEncoding,
+    default_tags: &BTreeMap<String, String>,
+    encoder: &LineEncoder,
+) -> usize {
+    let points = to_points(vec![metric.clone()], namespace, encoding);
+    with_output_buffer(|output| {
+        for point in points.into_iter() {
+            // Points that fail to encode are reported when the batch is sent.
+            let _ = encoder.encode(
+                point.measurement,
+                point.metric_type,
+                merge_default_tags(default_tags, point.tags),
+                point.fields,
+                point.timestamp,
+                output,
+            );
+        }
+        output.len()
+    })
+}
+
+fn encode_points(points: Vec<Point>, encoder: &LineEncoder) -> String {
+    let mut output = String::new();
+    for point in points.into_iter() {
+        encode_point(point, encoder, &mut output);
+    }
+
+    // remove last '\n'
+    output.pop();
+
+    return output;
+}
+
+/// Like `encode_points`, writing OpenTSDB `put` lines instead.
+fn encode_put_points(points: Vec<Point>, precision: Precision) -> String {
+    let mut output = String::new();
+    for point in points.into_iter() {
+        if point.measurement.is_empty() {
+            continue;
+        }
+        let mut tags = point.tags.unwrap_or_default();
+        insert_metric_type(&mut tags, point.metric_type);
+        encode_put_lines(
+            &point.measurement,
+            &tags,
+            &point.fields.unwrap_or_default(),
+            point.timestamp,
+            precision,
+            &mut output,
+        );
+    }
+
+    // remove last '\n'
+    output.pop();
+
+    output
+}
+
+/// The default `chunk_bytes`.
+const BODY_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Encodes points into chunks of about `chunk_bytes` as the request body is
+/// sent, so the whole batch is never held in one buffer. A chunk is cut once
+/// it reaches `chunk_bytes`, after the line that got it there, so a line is
+/// never split between chunks. The chunks joined are identical to the output
+/// of `encode_points`.
+struct LineChunks {
+    points: std::vec::IntoIter<Point>,
+    encoder: LineEncoder,
+    chunk_bytes: usize,
+    started: bool,
+    bytes: usize,
+    // Recorded once the last chunk is sent.
+    body_size: Option<BodySize>,
+}
+
+impl LineChunks {
+    fn new(points: Vec<Point>, encoder: LineEncoder, chunk_bytes: usize) -> Self {
+        Self {
+            points: points.into_iter(),
+            encoder,
+            chunk_bytes,
+            started: false,
+            bytes: 0,
+            body_size: None,
+        }
+    }
+
+    fn with_body_size(self, body_size: BodySize) -> Self {
+        Self {
+            body_size: Some(body_size),
+            ..self
+        }
+    }
+}
+
+impl Iterator for LineChunks {
+    type Item = Result<Bytes, Infallible>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        with_output_buffer(|chunk| {
+            // The newline ending the previous chunk is held back until another
+            // line follows, as the body has no trailing newline.
+            if self.started {
+                chunk.push('\n');
+            }
+            let prefix = chunk.len();
+
+            for point in &mut self.points {
+                encode_point(point, &self.encoder, chunk);
+                if chunk.len() > prefix && chunk.len() >= self.chunk_bytes {
+                    break;
+                }
+            }
+
+            if chunk.len() == prefix {
+                if let Some(body_size) = self.body_size.take() {
+                    body_size.record(self.bytes);
+                }
+                return None;
+            }
+            chunk.pop();
+            self.started = true;
+            self.bytes += chunk.len();
+            Some(Ok(Bytes::copy_from_slice(chunk.as_bytes())))
+        })
+    }
+}
+
+fn encode_distribution(
+    values: &[f64],
+    counts: &[u32],
+    config: &DistributionConfig,
+) -> Option<HashMap<String, Field>> {
+    if values.len() != counts.len() {
+        return None;
+    }
+
+    let mut samples = Vec::new();
+    for (v, c) in values.iter().zip(counts.iter()) {
+        for _ in 0..*c {
+            samples.push(*v);
+        }
+    }
+
+    if samples.is_empty() {
+        return None;
+    }
+
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+    let length = samples.len() as f64;
+    // The nearest rank, clamped so that a single sample is every quantile.
+    let quantile = |quantile: f64| {
+        let rank = (quantile * length - 1.0).round().max(0.0) as usize;
+        samples[rank.min(samples.len() - 1)]
+    };
+    let sum: f64 = samples.iter().sum();
+
+    let mut fields: HashMap<String, Field> = config
+        .fields
+        .iter()
+        .map(|statistic| {
+            let value = match statistic {
+                DistributionStatistic::Avg => sum / length,
+                DistributionStatistic::Count => length,
+                DistributionStatistic::Max => *samples.last().unwrap(),
+                DistributionStatistic::Median => quantile(0.5),
+                DistributionStatistic::Min => *samples.first().unwrap(),
+                DistributionStatistic::Sum => sum,
+            };
+            (statistic.as_str().to_owned(), Field::Float(value))
+        })
+        .collect();
+    for &q in &config.quantiles {
+        fields.insert(
+            quantile_field(q, QUANTILE_DECIMALS),
+            Field::Float(quantile(q)),
+        );
+    }
+
+    Some(fields)
+}
+
+/// Formats a quantile as a field name such as `quantile_0.95`.
+///
+/// The quantile is rounded to `decimals` places and trailing zeros are
+/// trimmed, so `0.9` and `0.90` both map to `quantile_0.9`.
+fn quantile_field(quantile: f64, decimals: usize) -> String {
+    let mut formatted = format!("{:.*}", decimals, quantile);
+    if formatted.contains('.') {
+        let trimmed = formatted.trim_end_matches('0').trim_end_matches('.').len();
+        formatted.truncate(trimmed);
+    }
+    format!("quantile_{}", formatted)
+}
+
+// Only the first `max_values` members are joined, as a set can grow without
+// bound. `values_truncated` marks the sets that were cut short.
+fn set_fields(
+    measurement: &str,
+    values: BTreeSet<String>,
+    sets: SetEncoding,
+) -> HashMap<String, Field> {
+    let mut fields = match sets.format {
+        SetFormat::Count | SetFormat::Both => to_fields(values.len() as f64),
+        SetFormat::Values => HashMap::new(),
+    };
+    if sets.format == SetFormat::Count {
+        return fields;
+    }
+
+    if values.len() > sets.max_values {
+        warn!(
+            message = "set has more members than max_set_values; writing the first ones only.",
+            %measurement,
+            members = values.len(),
+            max_set_values = sets.max_values,
+            rate_limit_secs = 30
+        );
+        fields.insert("values_truncated".to_owned(), Field::Bool(true));
+    }
+    let joined = values
+        .into_iter()
+        .take(sets.max_values)
+        .collect::<Vec<_>>()
+        .join(",");
+    fields.insert("values".to_owned(), Field::String(joined));
+    fields
+}
+
+fn to_fields(value: f64) -> HashMap<String, Field> {
+    let fields: HashMap<String, Field> = vec![("value".to_owned(), Field::Float(value))]
+        .into_iter()
+        .collect();
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::metric::{Metric, MetricKind, MetricValue};
+    use crate::sinks::influxdb::test_util::{assert_fields, split_line_protocol, tags, ts};
+    use crate::sinks::util::test::{
+        build_test_responder, build_test_server, build_test_server_failing,
+    };
+    use crate::test_util::{next_addr, runtime};
+    use crate::Event;
+    use futures01::{stream, Future, Sink, Stream};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_config_schemeless_endpoint() {
+        let config = r#"
+            namespace = "vector"
+            endpoint = "localhost:9999"
+            database = "my-database"
+        "#;
+        let config = toml::from_str::<InfluxDBConfig>(&config).unwrap();
+
+        let rt = runtime();
+        let error = config
+            .build(SinkContext::new_test(rt.executor()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "InfluxDB endpoint \"localhost:9999\" has no scheme, try \"http://localhost:9999\"."
+        );
+    }
+
+    #[test]
+    fn test_encode_counter() {
+        let events = vec![
+            Metric {
+                name: "total".into(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.5 },
+            },
+            Metric {
+                name: "check".into(),
+                timestamp: Some(ts()),
+                tags: Some(tags()),
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.0 },
+            },
+        ];
+
+        let line_protocols = encode_events(events, "ns");
+        assert_eq!(
+            line_protocols,
+            "ns.total,metric_type=counter value=1.5 1542182950000000011\n\
+            ns.check,metric_type=counter,normal_tag=value,true_tag=true value=1.0 1542182950000000011"
+        );
+    }
+
+    #[test]
+    fn test_encode_gauge() {
+        let events = vec![Metric {
+            name: "meter".to_owned(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Incremental,
+            value: MetricValue::Gauge { value: -1.5 },
+        }];
+
+        let line_protocols = encode_events(events, "ns");
+        assert_eq!(
+            line_protocols,
+            "ns.meter,metric_type=gauge,normal_tag=value,true_tag=true value=-1.5 1542182950000000011"
+        );
+    }
+
+    #[test]
+    fn test_encode_field_name() {
+        let events = vec![
+            Metric {
+                name: "cpu".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Absolute,
+                value: MetricValue::Gauge { value: 0.5 },
+            },
+            Metric {
+                name: "requests".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 3.0 },
+            },
+            Metric {
+                name: "latency".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Absolute,
+                value: MetricValue::AggregatedSummary {
+                    quantiles: vec![0.5],
+                    values: vec![2.0],
+                    count: 6,
+                    sum: 12.0,
+                },
+            },
+        ];
+        let encode = |field_name| {
+            let encoding = MetricEncoding {
+                field_name,
+                ..Default::default()
+            };
+            encode_points(
+                to_points(events.clone(), "ns", &encoding),
+                &LineEncoder::default(),
+            )
+        };
+
+        assert_eq!(
+            encode(FieldName::Value),
+            "ns.cpu,metric_type=gauge value=0.5 1542182950000000011
+\
+             ns.requests,metric_type=counter value=3.0 1542182950000000011
+\
+             ns.latency,metric_type=summary count=6u,quantile_0.5=2.0,sum=12.0 1542182950000000011"
+        );
+        // Only counters and gauges are named after the metric
+        assert_eq!(
+            encode(FieldName::MetricName),
+            "ns.cpu,metric_type=gauge cpu=0.5 1542182950000000011
+\
+             ns.requests,metric_type=counter requests=3.0 1542182950000000011
+\
+             ns.latency,metric_type=summary count=6u,quantile_0.5=2.0,sum=12.0 1542182950000000011"
+        );
+
+        let config: InfluxDBConfig = toml::from_str(
+            r#"
+            endpoint = "http://localhost:8086"
+            field_name = "metric_name"
+            single_measurement = "vector"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            metric_to_line(&events[0], &config).unwrap(),
+            "vector,metric_type=gauge,name=cpu cpu=0.5 1542182950000000011"
+        );
+    }
+
+    #[test]
+    fn test_encode_namespace_as_tag() {
+        let metric = || Metric {
+            name: "status".to_owned(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Absolute,
+            value: MetricValue::Gauge { value: 1.0 },
+        };
+        let encode = |namespace, namespace_as_tag| {
+            let encoding = MetricEncoding {
+                namespace_as_tag,
+                ..Default::default()
+            };
+            encode_points(
+                to_points(vec![metric()], namespace, &encoding),
+                &LineEncoder::default(),
+            )
+        };
+
+        assert_eq!(
+            encode("services", false),
+            "services.status,metric_type=gauge,normal_tag=value,true_tag=true value=1.0 1542182950000000011"
+        );
+        assert_eq!(
+            encode("services", true),
+            "status,metric_type=gauge,namespace=services,normal_tag=value,true_tag=true value=1.0 1542182950000000011"
+        );
+        // Without a namespace there is no tag to add
+        assert_eq!(
+            encode("", true),
+            "status,metric_type=gauge,normal_tag=value,true_tag=true value=1.0 1542182950000000011"
+        );
+    }
+
+    #[test]
+    fn test_encode_set() {
+        let events = vec![Metric {
+            name: "users".into(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Incremental,
+            value: MetricValue::Set {
                 values: vec!["alice".into(), "bob".into()].into_iter().collect(),
             },
-        }];
+        }];
+
+        let line_protocols = encode_events(events, "ns");
+        assert_eq!(
+            line_protocols,
+            "ns.users,metric_type=set,normal_tag=value,true_tag=true value=2.0 1542182950000000011"
+        );
+    }
+
+    #[test]
+    fn test_encode_set_values() {
+        let set = |values: Vec<&str>| Metric {
+            name: "methods".into(),
+            timestamp: Some(ts()),
+            tags: None,
+            kind: MetricKind::Incremental,
+            value: MetricValue::Set {
+                values: values.into_iter().map(Into::into).collect(),
+            },
+        };
+        let encode = |metric, format, max_values| {
+            let encoding = MetricEncoding {
+                sets: SetEncoding { format, max_values },
+                ..Default::default()
+            };
+            encode_points(
+                to_points(vec![metric], "ns", &encoding),
+                &LineEncoder::default(),
+            )
+        };
+        // The joined members contain commas, so the fields can't be split on them.
+        let assert_fields = |line: String, expected: Vec<&str>| {
+            let fields = split_line_protocol(&line).2;
+            assert_eq!(fields.len(), expected.join(",").len());
+            for field in expected {
+                assert!(fields.contains(field), "{} has to have: {}", fields, field);
+            }
+        };
+
+        assert_fields(
+            encode(set(vec!["PUT", "GET", "\"quoted\""]), SetFormat::Both, 10),
+            vec!["value=3.0", r#"values="\"quoted\",GET,PUT""#],
+        );
+        assert_fields(
+            encode(set(vec!["GET", "POST", "PUT"]), SetFormat::Values, 2),
+            vec![r#"values="GET,POST""#, "values_truncated=true"],
+        );
+    }
+
+    #[test]
+    fn test_encode_histogram() {
+        let events = vec![Metric {
+            name: "requests".to_owned(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Absolute,
+            value: MetricValue::AggregatedHistogram {
+                buckets: vec![1.0, 2.1, 3.0],
+                counts: vec![1, 2, 3],
+                count: 6,
+                sum: 12.5,
+            },
+        }];
+
+        let line_protocols = encode_events(events, "ns");
+        let line_protocols: Vec<&str> = line_protocols.split('\n').collect();
+        assert_eq!(line_protocols.len(), 1);
+
+        let line_protocol1 = split_line_protocol(line_protocols[0]);
+        assert_eq!("ns.requests", line_protocol1.0);
+        assert_eq!(
+            "metric_type=histogram,normal_tag=value,true_tag=true",
+            line_protocol1.1
+        );
+        assert_fields(
+            line_protocol1.2.to_string(),
+            [
+                "bucket_1=1u",
+                "bucket_2.1=2u",
+                "bucket_3=3u",
+                "count=6u",
+                "sum=12.5",
+            ]
+            .to_vec(),
+        );
+        assert_eq!("1542182950000000011", line_protocol1.3);
+    }
+
+    #[test]
+    fn test_encode_summary() {
+        let events = vec![Metric {
+            name: "requests_sum".to_owned(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Absolute,
+            value: MetricValue::AggregatedSummary {
+                quantiles: vec![0.01, 0.5, 0.99],
+                values: vec![1.5, 2.0, 3.0],
+                count: 6,
+                sum: 12.0,
+            },
+        }];
+
+        let line_protocols = encode_events(events, "ns");
+        let line_protocols: Vec<&str> = line_protocols.split('\n').collect();
+        assert_eq!(line_protocols.len(), 1);
+
+        let line_protocol1 = split_line_protocol(line_protocols[0]);
+        assert_eq!("ns.requests_sum", line_protocol1.0);
+        assert_eq!(
+            "metric_type=summary,normal_tag=value,true_tag=true",
+            line_protocol1.1
+        );
+        assert_fields(
+            line_protocol1.2.to_string(),
+            [
+                "count=6u",
+                "quantile_0.01=1.5",
+                "quantile_0.5=2.0",
+                "quantile_0.99=3.0",
+                "sum=12.0",
+            ]
+            .to_vec(),
+        );
+        assert_eq!("1542182950000000011", line_protocol1.3);
+    }
+
+    #[test]
+    fn test_encode_distribution() {
+        let events = vec![
+            Metric {
+                name: "requests".into(),
+                timestamp: Some(ts()),
+                tags: Some(tags()),
+                kind: MetricKind::Incremental,
+                value: MetricValue::Distribution {
+                    values: vec![1.0, 2.0, 3.0],
+                    sample_rates: vec![3, 3, 2],
+                },
+            },
+            Metric {
+                name: "dense_stats".into(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Distribution {
+                    values: (0..20).into_iter().map(f64::from).collect::<Vec<_>>(),
+                    sample_rates: vec![1; 20],
+                },
+            },
+            Metric {
+                name: "sparse_stats".into(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Distribution {
+                    values: (1..5).into_iter().map(f64::from).collect::<Vec<_>>(),
+                    sample_rates: (1..5).into_iter().collect::<Vec<_>>(),
+                },
+            },
+        ];
+
+        let line_protocols = encode_events(events, "ns");
+        let line_protocols: Vec<&str> = line_protocols.split('\n').collect();
+        assert_eq!(line_protocols.len(), 3);
+
+        let line_protocol1 = split_line_protocol(line_protocols[0]);
+        assert_eq!("ns.requests", line_protocol1.0);
+        assert_eq!(
+            "metric_type=distribution,normal_tag=value,true_tag=true",
+            line_protocol1.1
+        );
+        assert_fields(
+            line_protocol1.2.to_string(),
+            [
+                "avg=1.875",
+                "count=8.0",
+                "max=3.0",
+                "median=2.0",
+                "min=1.0",
+                "quantile_0.95=3.0",
+                "sum=15.0",
+            ]
+            .to_vec(),
+        );
+        assert_eq!("1542182950000000011", line_protocol1.3);
+
+        let line_protocol2 = split_line_protocol(line_protocols[1]);
+        assert_eq!("ns.dense_stats", line_protocol2.0);
+        assert_eq!("metric_type=distribution", line_protocol2.1);
+        assert_fields(
+            line_protocol2.2.to_string(),
+            [
+                "avg=9.5",
+                "count=20.0",
+                "max=19.0",
+                "median=9.0",
+                "min=0.0",
+                "quantile_0.95=18.0",
+                "sum=190.0",
+            ]
+            .to_vec(),
+        );
+        assert_eq!("1542182950000000011", line_protocol2.3);
+
+        let line_protocol3 = split_line_protocol(line_protocols[2]);
+        assert_eq!("ns.sparse_stats", line_protocol3.0);
+        assert_eq!("metric_type=distribution", line_protocol3.1);
+        assert_fields(
+            line_protocol3.2.to_string(),
+            [
+                "avg=3.0",
+                "count=10.0",
+                "max=4.0",
+                "median=3.0",
+                "min=1.0",
+                "quantile_0.95=4.0",
+                "sum=30.0",
+            ]
+            .to_vec(),
+        );
+        assert_eq!("1542182950000000011", line_protocol3.3);
+    }
+
+    #[test]
+    fn test_encode_distribution_empty_stats() {
+        let events = vec![Metric {
+            name: "requests".into(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Incremental,
+            value: MetricValue::Distribution {
+                values: vec![],
+                sample_rates: vec![],
+            },
+        }];
+
+        let line_protocols = encode_events(events, "ns");
+        assert_eq!(line_protocols.len(), 0);
+    }
+
+    #[test]
+    fn test_encode_distribution_zero_counts_stats() {
+        let events = vec![Metric {
+            name: "requests".into(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Incremental,
+            value: MetricValue::Distribution {
+                values: vec![1.0, 2.0],
+                sample_rates: vec![0, 0],
+            },
+        }];
+
+        let line_protocols = encode_events(events, "ns");
+        assert_eq!(line_protocols.len(), 0);
+    }
+
+    #[test]
+    fn test_encode_distribution_unequal_stats() {
+        let events = vec![Metric {
+            name: "requests".into(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Incremental,
+            value: MetricValue::Distribution {
+                values: vec![1.0],
+                sample_rates: vec![1, 2, 3],
+            },
+        }];
+
+        let line_protocols = encode_events(events, "ns");
+        assert_eq!(line_protocols.len(), 0);
+    }
+
+    #[test]
+    fn test_encode_distribution_fields() {
+        let metric = Metric {
+            name: "requests".into(),
+            timestamp: Some(ts()),
+            tags: None,
+            kind: MetricKind::Incremental,
+            value: MetricValue::Distribution {
+                values: (1..=100).map(f64::from).collect(),
+                sample_rates: vec![1; 100],
+            },
+        };
+        let encode = |distribution| {
+            let encoding = MetricEncoding {
+                distribution,
+                ..Default::default()
+            };
+            let output = encode_points(
+                to_points(vec![metric.clone()], "ns", &encoding),
+                &LineEncoder::default(),
+            );
+            split_line_protocol(output.trim_end()).2.to_owned()
+        };
+
+        // A reduced set, without the default quantile.
+        assert_fields(
+            encode(DistributionConfig {
+                fields: vec![DistributionStatistic::Count, DistributionStatistic::Sum],
+                quantiles: vec![],
+            }),
+            vec!["count=100.0", "sum=5050.0"],
+        );
+
+        // Custom quantiles, next to the default statistics.
+        assert_fields(
+            encode(DistributionConfig {
+                quantiles: vec![0.5, 0.9, 0.99, 0.999],
+                ..Default::default()
+            }),
+            vec![
+                "avg=50.5",
+                "count=100.0",
+                "max=100.0",
+                "median=50.0",
+                "min=1.0",
+                "quantile_0.5=50.0",
+                "quantile_0.9=90.0",
+                "quantile_0.99=99.0",
+                "quantile_0.999=100.0",
+                "sum=5050.0",
+            ],
+        );
+
+        // Quantiles only, including the bounds.
+        assert_fields(
+            encode(DistributionConfig {
+                fields: vec![],
+                quantiles: vec![0.0, 1.0],
+            }),
+            vec!["quantile_0=1.0", "quantile_1=100.0"],
+        );
+
+        let config: InfluxDBConfig = toml::from_str(
+            r#"
+            namespace = "ns"
+            endpoint = "http://localhost:9999"
+            distribution.fields = ["min", "max"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.distribution,
+            Some(DistributionConfig {
+                fields: vec![DistributionStatistic::Min, DistributionStatistic::Max],
+                quantiles: vec![0.95],
+            })
+        );
+    }
+
+    #[test]
+    fn test_distribution_config_validate() {
+        assert!(DistributionConfig::default().validate().is_ok());
+        for quantiles in &[vec![1.5], vec![-0.1], vec![f64::NAN]] {
+            let config = DistributionConfig {
+                quantiles: quantiles.clone(),
+                ..Default::default()
+            };
+            assert!(matches!(
+                config.validate(),
+                Err(ConfigError::InvalidQuantile { .. })
+            ));
+        }
+        let config = DistributionConfig {
+            fields: vec![],
+            quantiles: vec![],
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::EmptySetting { .. })
+        ));
+    }
+
+    #[test]
+    fn test_metric_type_tags() {
+        let config: InfluxDBConfig = toml::from_str(
+            r#"
+            namespace = "ns"
+            endpoint = "http://localhost:9999"
+            metric_type_tags.counter.monotonic = "true"
+            metric_type_tags.histogram.le_unit = "seconds"
+            metric_type_tags.histogram.host = "default"
+            metric_type_tags.gauge.metric_type = "other"
+            "#,
+        )
+        .unwrap();
+
+        let events = vec![
+            Metric {
+                name: "requests".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.0 },
+            },
+            Metric {
+                name: "latency".to_owned(),
+                timestamp: Some(ts()),
+                tags: Some(
+                    vec![("host".to_owned(), "web-01".to_owned())]
+                        .into_iter()
+                        .collect(),
+                ),
+                kind: MetricKind::Absolute,
+                value: MetricValue::AggregatedHistogram {
+                    buckets: vec![1.0],
+                    counts: vec![2],
+                    count: 2,
+                    sum: 1.5,
+                },
+            },
+            Metric {
+                name: "memory".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Absolute,
+                value: MetricValue::Gauge { value: 0.5 },
+            },
+        ];
+
+        let output = encode_points(
+            to_points(events, "ns", &config.metric_encoding()),
+            &LineEncoder::default(),
+        );
+        let tags = output
+            .lines()
+            .map(|line| {
+                let (measurement, tags, _, _) = split_line_protocol(line);
+                (measurement.to_owned(), tags.to_owned())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            vec![
+                (
+                    "ns.requests".to_owned(),
+                    "metric_type=counter,monotonic=true".to_owned()
+                ),
+                // The tag of the metric wins over the configured one.
+                (
+                    "ns.latency".to_owned(),
+                    "host=web-01,le_unit=seconds,metric_type=histogram".to_owned()
+                ),
+                // `metric_type` stays the automatic one.
+                ("ns.memory".to_owned(), "metric_type=gauge".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_from_env_var() {
+        std::env::set_var("VECTOR_TEST_INFLUXDB_TOKEN", "env-token");
+
+        let config = crate::topology::Config::load(
+            r#"
+            [sinks.out]
+            type = "influxdb_metrics"
+            inputs = ["in"]
+            namespace = "ns"
+            endpoint = "http://localhost:9999"
+            org = "my-org"
+            bucket = "my-bucket"
+            token = "${VECTOR_TEST_INFLUXDB_TOKEN}"
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let sink = format!("{:?}", config.sinks["out"].inner);
+        assert!(sink.contains(r#"token: Some("env-token")"#), "{}", sink);
+    }
+
+    #[test]
+    fn test_line_chunks_match_encode_points() {
+        let points = || {
+            (0..10)
+                .map(|i| Point {
+                    measurement: format!("ns.metric_{}", i),
+                    metric_type: "gauge",
+                    tags: Some(tags()),
+                    // A point without fields writes no line at all.
+                    fields: if i == 4 || i == 9 {
+                        None
+                    } else {
+                        Some(
+                            vec![("value".to_owned(), Field::Float(i as f64))]
+                                .into_iter()
+                                .collect(),
+                        )
+                    },
+                    timestamp: 1_542_182_950_000_000_011,
+                })
+                .collect::<Vec<_>>()
+        };
+        let encoded = encode_points(points(), &LineEncoder::default());
+
+        // Each round reuses the output buffer left by the one before.
+        for chunk_bytes in vec![1, 100, BODY_CHUNK_BYTES, 1] {
+            let streamed = LineChunks::new(points(), LineEncoder::default(), chunk_bytes)
+                .map(Result::unwrap)
+                .flat_map(|chunk| chunk.to_vec())
+                .collect::<Vec<u8>>();
+            assert_eq!(
+                streamed,
+                encoded.as_bytes(),
+                "chunk_bytes = {}",
+                chunk_bytes
+            );
+        }
+
+        assert_eq!(
+            LineChunks::new(Vec::new(), LineEncoder::default(), 1).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_line_chunks_break_on_lines() {
+        let points = || {
+            (0..20)
+                .map(|i| Point {
+                    measurement: format!("ns.metric_{}", "x".repeat(i)),
+                    metric_type: "gauge",
+                    tags: Some(tags()),
+                    fields: Some(
+                        vec![("value".to_owned(), Field::Float(i as f64))]
+                            .into_iter()
+                            .collect(),
+                    ),
+                    timestamp: 1_542_182_950_000_000_011,
+                })
+                .collect::<Vec<_>>()
+        };
+        let encoded = encode_points(points(), &LineEncoder::default());
+        let lines = encoded.split('\n').collect::<HashSet<_>>();
+
+        for chunk_bytes in vec![1, 50, 100, 333, 1000] {
+            let chunks = LineChunks::new(points(), LineEncoder::default(), chunk_bytes)
+                .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+                .collect::<Vec<_>>();
+            assert!(!chunks.is_empty());
+
+            for (i, chunk) in chunks.iter().enumerate() {
+                // Only the newline between two chunks is carried over, at the
+                // start of the later one.
+                let chunk = if i == 0 {
+                    chunk.as_str()
+                } else {
+                    assert!(chunk.starts_with('\n'), "chunk_bytes = {}", chunk_bytes);
+                    &chunk[1..]
+                };
+                assert!(!chunk.ends_with('\n'), "chunk_bytes = {}", chunk_bytes);
+                for line in chunk.split('\n') {
+                    assert!(
+                        lines.contains(line),
+                        "chunk_bytes = {}, split line {:?}",
+                        chunk_bytes,
+                        line
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_coalesce_series() {
+        let point = |tags: Option<BTreeMap<String, String>>, field: &str, value: f64| Point {
+            measurement: "ns.cpu".to_owned(),
+            metric_type: "gauge",
+            tags,
+            fields: Some(
+                vec![(field.to_owned(), Field::Float(value))]
+                    .into_iter()
+                    .collect(),
+            ),
+            timestamp: 1542182950000000011,
+        };
+
+        let points = coalesce_series(vec![
+            point(Some(tags()), "user", 1.0),
+            point(Some(tags()), "system", 2.0),
+            point(Some(tags()), "idle", 3.0),
+        ]);
+        assert_eq!(points.len(), 1);
+
+        let line_protocols = encode_points(points, &LineEncoder::default());
+        let (metric, tags, fields, timestamp) = split_line_protocol(&line_protocols);
+        assert_eq!("ns.cpu", metric);
+        assert_eq!("metric_type=gauge,normal_tag=value,true_tag=true", tags);
+        assert_fields(
+            fields.to_string(),
+            ["idle=3.0", "system=2.0", "user=1.0"].to_vec(),
+        );
+        assert_eq!("1542182950000000011", timestamp);
+    }
+
+    #[test]
+    fn test_coalesce_series_keeps_distinct_series() {
+        let point = |measurement: &str, timestamp: i64, value: f64| Point {
+            measurement: measurement.to_owned(),
+            metric_type: "gauge",
+            tags: None,
+            fields: Some(to_fields(value)),
+            timestamp,
+        };
+
+        let points = coalesce_series(vec![
+            point("ns.cpu", 1, 1.0),
+            point("ns.cpu", 2, 2.0),
+            point("ns.mem", 1, 3.0),
+            point("ns.cpu", 1, 4.0),
+        ]);
+        assert_eq!(
+            encode_points(points, &LineEncoder::default()),
+            "ns.cpu,metric_type=gauge value=4.0 1\n\
+            ns.cpu,metric_type=gauge value=2.0 2\n\
+            ns.mem,metric_type=gauge value=3.0 1"
+        );
+    }
+
+    #[test]
+    fn test_group_by_measurement() {
+        let point = |measurement: &str, timestamp: i64| Point {
+            measurement: measurement.to_owned(),
+            metric_type: "gauge",
+            tags: None,
+            fields: Some(to_fields(timestamp as f64)),
+            timestamp,
+        };
+
+        let mut points = vec![
+            point("ns.mem", 1),
+            point("ns.cpu", 2),
+            point("ns.mem", 3),
+            point("ns.disk", 4),
+            point("ns.cpu", 5),
+            point("ns.mem", 6),
+        ];
+        group_by_measurement(&mut points);
+        assert_eq!(
+            encode_points(points, &LineEncoder::default()),
+            "ns.cpu,metric_type=gauge value=2.0 2\n\
+            ns.cpu,metric_type=gauge value=5.0 5\n\
+            ns.disk,metric_type=gauge value=4.0 4\n\
+            ns.mem,metric_type=gauge value=1.0 1\n\
+            ns.mem,metric_type=gauge value=3.0 3\n\
+            ns.mem,metric_type=gauge value=6.0 6"
+        );
+    }
+
+    #[test]
+    fn test_instance_tag() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            instance_tag: Some(InstanceTagConfig {
+                id: Some("agent-7".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let events = vec![
+            Event::Metric(Metric {
+                name: "requests".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.0 },
+            }),
+            Event::Metric(Metric {
+                name: "latency".to_owned(),
+                timestamp: Some(ts()),
+                tags: Some(
+                    vec![("region".to_owned(), "us-west-1".to_owned())]
+                        .into_iter()
+                        .collect(),
+                ),
+                kind: MetricKind::Absolute,
+                value: MetricValue::Gauge { value: 0.25 },
+            }),
+        ];
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+
+        let output = rx.take(1).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let body = std::str::from_utf8(&output[0].1).unwrap();
+        assert_eq!(body.lines().count(), 2);
+        for line in body.lines() {
+            let (_, tags, _, _) = split_line_protocol(line);
+            assert!(
+                tags.split(',').any(|tag| tag == "vector_instance=agent-7"),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_dry_run_sends_nothing() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let (sink, healthcheck) = config.build(cx).unwrap();
+        // The healthcheck is skipped, so it passes before the server is up
+        rt.block_on(healthcheck).unwrap();
+
+        let (rx, trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let events = (0..10).map(|i| {
+            Event::Metric(Metric {
+                name: format!("counter-{}", i),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: i as f64 },
+            })
+        });
+
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+        drop(trigger);
+
+        let output = rx.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_output_stdout() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config: InfluxDBConfig = toml::from_str(&format!(
+            r#"
+            namespace = "ns"
+            endpoint = "http://{}"
+            org = "my-org"
+            bucket = "my-bucket"
+            token = "my-token"
+            output = "stdout"
+            "#,
+            addr
+        ))
+        .unwrap();
+        assert_eq!(config.output, Output::Stdout);
+
+        let (rx, trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let stdout = Arc::new(Mutex::new(Vec::new()));
+        let client = config.http_client(&cx).unwrap();
+        let sink =
+            InfluxDBSvc::with_output(config, cx, client, ServerVersion::default(), stdout.clone())
+                .unwrap();
+
+        let events = vec![
+            Event::Metric(Metric {
+                name: "requests".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Absolute,
+                value: MetricValue::Gauge { value: 1.5 },
+            }),
+            Event::Metric(Metric {
+                name: "errors".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Absolute,
+                value: MetricValue::Gauge { value: 2.0 },
+            }),
+        ];
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+        drop(trigger);
+
+        let output = String::from_utf8(stdout.lock().unwrap().clone()).unwrap();
+        let mut lines = output.lines().collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "ns.errors,metric_type=gauge value=2.0 1542182950000000011",
+                "ns.requests,metric_type=gauge value=1.5 1542182950000000011",
+            ]
+        );
+        assert!(output.ends_with('\n'));
+
+        // Nothing is sent to the server.
+        let requests = rx.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn test_empty_batch_skips_write() {
+        let empty_batches = || {
+            crate::metrics::CONTROLLER
+                .get()
+                .unwrap()
+                .snapshot()
+                .into_measurements()
+                .into_iter()
+                .filter(|(key, _)| key.name() == "influxdb_empty_batches")
+                .map(|(_, measurement)| match measurement {
+                    metrics_runtime::Measurement::Counter(value) => value,
+                    _ => 0,
+                })
+                .sum::<u64>()
+        };
+        crate::metrics::init_test();
+
+        // Streamed bodies and whole ones are both checked.
+        for idempotency_header in vec![None, Some("Idempotency-Key".to_owned())] {
+            let mut rt = runtime();
+            let cx = SinkContext::new_test(rt.executor());
+            let addr = next_addr();
+
+            let config = InfluxDBConfig {
+                namespace: "ns".to_owned(),
+                endpoint: format!("http://{}", addr),
+                influxdb2_settings: Some(
+                    InfluxDB2Settings::builder()
+                        .org("my-org")
+                        .bucket("my-bucket")
+                        .token("my-token")
+                        .build(),
+                ),
+                idempotency_header,
+                ..Default::default()
+            };
+
+            let (rx, trigger, server) = build_test_server(addr, &mut rt);
+            rt.spawn(server);
+
+            let before = empty_batches();
+            let sink = InfluxDBSvc::new(config, cx).unwrap();
+            // Neither can be written, as InfluxDB can't store values that
+            // aren't finite.
+            let events = vec![("nan", f64::NAN), ("infinite", f64::INFINITY)]
+                .into_iter()
+                .map(|(name, value)| {
+                    Event::Metric(Metric {
+                        name: name.to_owned(),
+                        timestamp: Some(ts()),
+                        tags: None,
+                        kind: MetricKind::Absolute,
+                        value: MetricValue::Gauge { value },
+                    })
+                })
+                .collect::<Vec<_>>();
+            let _ = rt.block_on(sink.send_all(stream::iter_ok(events))).unwrap();
+            drop(trigger);
+
+            let requests = rx.wait().collect::<Result<Vec<_>, _>>().unwrap();
+            assert!(requests.is_empty());
+            assert!(empty_batches() > before);
+        }
+    }
+
+    #[test]
+    fn test_empty_batch_as_breaker_probe() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config: InfluxDBConfig = toml::from_str(&format!(
+            r#"
+            namespace = "ns"
+            endpoint = "http://{}"
+            org = "my-org"
+            bucket = "my-bucket"
+            token = "my-token"
+            batch.max_events = 1
+            request.retry_attempts = 0
+            circuit_breaker.failure_threshold = 1
+            circuit_breaker.cooldown_secs = 0
+            "#,
+            addr
+        ))
+        .unwrap();
+
+        let (rx, trigger, server) = build_test_server_failing(addr, &mut rt, 1);
+        rt.spawn(server);
+
+        let gauge = |name: &str, value| {
+            Event::Metric(Metric {
+                name: name.to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Absolute,
+                value: MetricValue::Gauge { value },
+            })
+        };
+        // The failed write opens the breaker and the empty batch is the
+        // probe, which must leave the next write to probe again.
+        let mut sink = InfluxDBSvc::new(config, cx).unwrap();
+        for event in vec![
+            gauge("requests", 1.0),
+            gauge("nan", f64::NAN),
+            gauge("errors", 2.0),
+        ] {
+            sink = rt.block_on(sink.send(event)).unwrap();
+        }
+        drop(trigger);
+
+        let requests = rx.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(requests.len(), 2);
+        let body = std::str::from_utf8(&requests[1].1).unwrap();
+        assert!(body.starts_with("ns.errors,"), "{}", body);
+    }
+
+    #[test]
+    fn test_batch_held_back_by_open_breaker() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config: InfluxDBConfig = toml::from_str(&format!(
+            r#"
+            namespace = "ns"
+            endpoint = "http://{}"
+            org = "my-org"
+            bucket = "my-bucket"
+            token = "my-token"
+            batch.max_events = 1
+            request.retry_attempts = 0
+            circuit_breaker.failure_threshold = 1
+            circuit_breaker.cooldown_secs = 1
+            "#,
+            addr
+        ))
+        .unwrap();
+
+        let (rx, trigger, server) = build_test_server_failing(addr, &mut rt, 1);
+        rt.spawn(server);
+
+        let gauge = |name: &str| {
+            Event::Metric(Metric {
+                name: name.to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Absolute,
+                value: MetricValue::Gauge { value: 1.0 },
+            })
+        };
+        // The failed write opens the breaker, and isn't retried.
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let sink = rt.block_on(sink.send(gauge("requests"))).unwrap();
+        let sink = rt.block_on(sink.flush()).unwrap();
+
+        // Sent during the cooldown, the batch waits for the breaker to
+        // half-open instead of failing.
+        let start = Instant::now();
+        let sink = rt.block_on(sink.send(gauge("errors"))).unwrap();
+        rt.block_on(sink.flush()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        drop(trigger);
+
+        let requests = rx.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(requests.len(), 2);
+        let body = std::str::from_utf8(&requests[1].1).unwrap();
+        assert!(body.starts_with("ns.errors,"), "{}", body);
+    }
+
+    #[test]
+    fn test_split_batch_by_rp_tag() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb1_settings: Some(
+                InfluxDB1Settings::builder()
+                    .database("my-database")
+                    .retention_policy_name("autogen")
+                    .rp_tag("tier")
+                    .build(),
+            ),
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let events = vec![
+            ("a", Some("short")),
+            ("b", Some("downsampled")),
+            ("c", Some("short")),
+            ("d", None),
+        ]
+        .into_iter()
+        .map(|(name, tier)| {
+            Event::Metric(Metric {
+                name: name.to_owned(),
+                timestamp: Some(ts()),
+                tags: tier.map(|tier| {
+                    vec![("tier".to_owned(), tier.to_owned())]
+                        .into_iter()
+                        .collect()
+                }),
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.0 },
+            })
+        })
+        .collect::<Vec<_>>();
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+
+        let output = rx.take(3).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let mut requests = output
+            .iter()
+            .map(|(parts, body)| {
+                let rp = parts
+                    .uri
+                    .query()
+                    .unwrap()
+                    .split('&')
+                    .find(|pair| pair.starts_with("rp="))
+                    .unwrap()
+                    .to_owned();
+                let mut measurements = std::str::from_utf8(body)
+                    .unwrap()
+                    .lines()
+                    .map(|line| line.split(',').next().unwrap().to_owned())
+                    .collect::<Vec<_>>();
+                measurements.sort();
+                (rp, measurements)
+            })
+            .collect::<Vec<_>>();
+        requests.sort();
+
+        assert_eq!(
+            requests,
+            vec![
+                ("rp=autogen".to_owned(), vec!["ns.d".to_owned()]),
+                ("rp=downsampled".to_owned(), vec!["ns.b".to_owned()]),
+                (
+                    "rp=short".to_owned(),
+                    vec!["ns.a".to_owned(), "ns.c".to_owned()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_only_failed_routes() {
+        use hyper13::{Body, Request, Response, StatusCode};
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        // The first write to the `short` retention policy fails.
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let (_, server) = build_test_responder(addr, &mut rt, {
+            let written = Arc::clone(&written);
+            move |request: Request<Body>| {
+                let rp = request
+                    .uri()
+                    .query()
+                    .unwrap()
+                    .split('&')
+                    .find(|pair| pair.starts_with("rp="))
+                    .unwrap()
+                    .to_owned();
+                let mut written = written.lock().unwrap();
+                let fail = rp == "rp=short" && !written.contains(&rp);
+                written.push(rp);
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = if fail {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::NO_CONTENT
+                };
+                async move { Ok::<_, crate::Error>(response) }
+            }
+        });
+        rt.spawn(server);
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb1_settings: Some(
+                InfluxDB1Settings::builder()
+                    .database("my-database")
+                    .retention_policy_name("autogen")
+                    .rp_tag("tier")
+                    .build(),
+            ),
+            request: TowerRequestConfig {
+                retry_initial_backoff_secs: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let events = vec!["short", "downsampled"]
+            .into_iter()
+            .map(|tier| {
+                Event::Metric(Metric {
+                    name: "requests".to_owned(),
+                    timestamp: Some(ts()),
+                    tags: Some(
+                        vec![("tier".to_owned(), tier.to_owned())]
+                            .into_iter()
+                            .collect(),
+                    ),
+                    kind: MetricKind::Incremental,
+                    value: MetricValue::Counter { value: 1.0 },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Closing the sink waits for the retry.
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let _ = rt.block_on(sink.send_all(stream::iter_ok(events))).unwrap();
+
+        let mut written = written.lock().unwrap().clone();
+        written.sort();
+        assert_eq!(
+            written,
+            vec![
+                "rp=downsampled".to_owned(),
+                "rp=short".to_owned(),
+                "rp=short".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_batch_by_precision_tag() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            precision_tag: Some("resolution".to_owned()),
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let events = vec![
+            ("trace", Some("ns")),
+            ("orders", Some("s")),
+            ("revenue", Some("s")),
+            ("latency", Some("ms")),
+            ("default", None),
+        ]
+        .into_iter()
+        .map(|(name, resolution)| {
+            Event::Metric(Metric {
+                name: name.to_owned(),
+                timestamp: Some(ts()),
+                tags: resolution.map(|resolution| {
+                    vec![("resolution".to_owned(), resolution.to_owned())]
+                        .into_iter()
+                        .collect()
+                }),
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.0 },
+            })
+        })
+        .collect::<Vec<_>>();
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+
+        let output = rx.take(3).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let mut requests = output
+            .iter()
+            .map(|(parts, body)| {
+                let precision = parts
+                    .uri
+                    .query()
+                    .unwrap()
+                    .split('&')
+                    .find(|pair| pair.starts_with("precision="))
+                    .unwrap()
+                    .to_owned();
+                let mut points = std::str::from_utf8(body)
+                    .unwrap()
+                    .lines()
+                    .map(|line| {
+                        let (measurement, _, _, timestamp) = split_line_protocol(line);
+                        format!("{} {}", measurement, timestamp)
+                    })
+                    .collect::<Vec<_>>();
+                points.sort();
+                (precision, points)
+            })
+            .collect::<Vec<_>>();
+        requests.sort();
+
+        assert_eq!(
+            requests,
+            vec![
+                (
+                    "precision=ms".to_owned(),
+                    vec!["ns.latency 1542182950000".to_owned()]
+                ),
+                (
+                    "precision=ns".to_owned(),
+                    vec![
+                        "ns.default 1542182950000000011".to_owned(),
+                        "ns.trace 1542182950000000011".to_owned()
+                    ]
+                ),
+                (
+                    "precision=s".to_owned(),
+                    vec![
+                        "ns.orders 1542182950".to_owned(),
+                        "ns.revenue 1542182950".to_owned()
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_resends_identical_body() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            request: TowerRequestConfig {
+                retry_initial_backoff_secs: Some(1),
+                ..Default::default()
+            },
+            idempotency_header: Some("X-Batch-Hash".to_owned()),
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server_failing(addr, &mut rt, 1);
+        rt.spawn(server);
+
+        // No timestamp, and enough fields that their order could change
+        let events = vec![Event::Metric(Metric {
+            name: "requests".to_owned(),
+            timestamp: None,
+            tags: Some(tags()),
+            kind: MetricKind::Absolute,
+            value: MetricValue::AggregatedSummary {
+                quantiles: vec![0.01, 0.5, 0.99],
+                values: vec![1.5, 2.0, 3.0],
+                count: 6,
+                sum: 12.0,
+            },
+        })];
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+
+        let output = rx.take(2).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let (first, retried) = (&output[0], &output[1]);
+        assert_eq!(first.1, retried.1);
+        assert_eq!(
+            first.0.headers.get("X-Batch-Hash").unwrap(),
+            retried.0.headers.get("X-Batch-Hash").unwrap()
+        );
+        assert_eq!(
+            first.0.headers.get("X-Batch-Hash").unwrap(),
+            &body_hash(&first.1)
+        );
+    }
+
+    #[test]
+    fn test_compression_min_bytes() {
+        use std::io::Read;
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            batch: InfluxDBBatchConfig {
+                max_events: Some(1),
+                ..Default::default()
+            },
+            compression: InfluxDBCompression::Gzip,
+            compression_min_bytes: Some(200),
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let events = vec![
+            Event::Metric(Metric {
+                name: "small".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.0 },
+            }),
+            Event::Metric(Metric {
+                name: "large".to_owned(),
+                timestamp: Some(ts()),
+                tags: Some(
+                    (0..10)
+                        .map(|i| (format!("tag_{}", i), format!("value_{}", i)))
+                        .collect(),
+                ),
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 2.0 },
+            }),
+        ];
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+
+        let mut output = rx.take(2).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        output.sort_by_key(|(parts, _)| parts.headers.contains_key("Content-Encoding"));
+        let (small, large) = (&output[0], &output[1]);
+
+        assert_eq!(small.0.headers.get("Content-Encoding"), None);
+        assert!(std::str::from_utf8(&small.1)
+            .unwrap()
+            .starts_with("ns.small,"));
+
+        assert_eq!(large.0.headers["Content-Encoding"], "gzip");
+        let mut body = String::new();
+        flate2::read::GzDecoder::new(&large.1[..])
+            .read_to_string(&mut body)
+            .unwrap();
+        assert!(body.starts_with("ns.large,"));
+    }
+
+    #[cfg(feature = "sinks-influxdb-zstd")]
+    #[test]
+    fn test_compression_zstd() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            compression: InfluxDBCompression::Zstd,
+            compression_level: Some(9),
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let events = (0..10).map(|i| {
+            Event::Metric(Metric {
+                name: format!("counter-{}", i),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: i as f64 },
+            })
+        });
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+
+        let output = rx.take(1).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let (parts, body) = &output[0];
+        assert_eq!(parts.headers["Content-Encoding"], "zstd");
+        let body = zstd::stream::decode_all(&body[..]).unwrap();
+        let mut lines = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| line.split(',').next().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(
+            lines,
+            (0..10)
+                .map(|i| format!("ns.counter-{}", i))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_healthcheck_and_writes_share_client() {
+        use hyper13::{Body, Method, Request, Response, StatusCode};
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let (counts, server) =
+            build_test_responder(addr, &mut rt, |request: Request<Body>| async move {
+                let response = if request.method() == Method::GET {
+                    Response::new(Body::from(r#"{"status":"pass"}"#))
+                } else {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NO_CONTENT;
+                    response
+                };
+                Ok::<_, crate::Error>(response)
+            });
+        rt.spawn(server);
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            batch: InfluxDBBatchConfig {
+                max_events: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
 
-        let line_protocols = encode_events(events, "ns");
+        let (mut sink, healthcheck) = config.build(cx).unwrap();
+        rt.block_on(healthcheck).unwrap();
+        for i in 0..3 {
+            let event = Event::Metric(Metric {
+                name: "requests".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: i as f64 },
+            });
+            sink = rt.block_on(sink.send(event)).unwrap();
+        }
+
+        // One client, so the writes reuse the healthcheck's connection.
+        assert_eq!(counts.requests(), 4);
+        assert_eq!(counts.connections(), 1);
+    }
+
+    #[test]
+    fn test_influxdb1_token_writes() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config: InfluxDBConfig = toml::from_str(&format!(
+            r#"
+            namespace = "ns"
+            endpoint = "http://{}"
+            api_version = "v1"
+            database = "my-database"
+            token = "my-jwt"
+            batch.max_events = 1
+            "#,
+            addr
+        ))
+        .unwrap();
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let event = Event::Metric(Metric {
+            name: "requests".to_owned(),
+            timestamp: Some(ts()),
+            tags: None,
+            kind: MetricKind::Incremental,
+            value: MetricValue::Counter { value: 1.0 },
+        });
+        let _ = rt.block_on(sink.send(event)).unwrap();
+
+        let output = rx.take(1).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let (parts, _) = &output[0];
+        assert_eq!(parts.uri.path(), "/write");
+        assert_eq!(parts.uri.query(), Some("db=my-database&precision=ns"));
+        assert_eq!(parts.headers["Authorization"], "Bearer my-jwt");
+    }
+
+    #[test]
+    fn test_path_prefix() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}/", addr),
+            path_prefix: Some("/gateway/influx/".to_owned()),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            batch: InfluxDBBatchConfig {
+                max_events: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let (sink, healthcheck) = config.build(cx).unwrap();
+        rt.block_on(healthcheck).unwrap();
+        let event = Event::Metric(Metric {
+            name: "requests".to_owned(),
+            timestamp: Some(ts()),
+            tags: None,
+            kind: MetricKind::Incremental,
+            value: MetricValue::Counter { value: 1.0 },
+        });
+        let _ = rt.block_on(sink.send(event)).unwrap();
+
+        let output = rx.take(2).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let paths = output
+            .iter()
+            .map(|(parts, _)| parts.uri.path().to_owned())
+            .collect::<Vec<_>>();
         assert_eq!(
-            line_protocols,
-            "ns.users,metric_type=set,normal_tag=value,true_tag=true value=2 1542182950000000011"
+            paths,
+            vec!["/gateway/influx/health", "/gateway/influx/api/v2/write"]
         );
     }
 
     #[test]
-    fn test_encode_histogram() {
-        let events = vec![Metric {
+    fn test_series_staleness() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            series_staleness_secs: Some(1),
+            batch: InfluxDBBatchConfig {
+                timeout_secs: Some(3600),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let event = Event::Metric(Metric {
             name: "requests".to_owned(),
             timestamp: Some(ts()),
-            tags: Some(tags()),
-            kind: MetricKind::Absolute,
-            value: MetricValue::AggregatedHistogram {
-                buckets: vec![1.0, 2.1, 3.0],
-                counts: vec![1, 2, 3],
-                count: 6,
-                sum: 12.5,
+            tags: None,
+            kind: MetricKind::Incremental,
+            value: MetricValue::Counter { value: 1.0 },
+        });
+
+        // Nothing else arrives to fill the batch or poll the sink, so only
+        // the series going stale sends it before the batch timeout.
+        let start = Instant::now();
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        rt.spawn(sink.send(event).map(|_| ()));
+
+        let output = rx.take(1).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(60), "{:?}", elapsed);
+        let body = std::str::from_utf8(&output[0].1).unwrap();
+        assert!(body.starts_with("ns.requests,"), "{}", body);
+    }
+
+    #[test]
+    fn test_write_accepts_ok_with_body() {
+        use hyper13::{Body, Response};
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        // Some InfluxDB compatible backends and proxies answer writes with a
+        // 200 and a body rather than a 204.
+        let (counts, server) = build_test_responder(addr, &mut rt, |_| async {
+            Ok::<_, crate::Error>(Response::new(Body::from(r#"{"status":"written"}"#)))
+        });
+        rt.spawn(server);
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            batch: InfluxDBBatchConfig {
+                max_events: Some(1),
+                ..Default::default()
             },
-        }];
+            ..Default::default()
+        };
 
-        let line_protocols = encode_events(events, "ns");
-        let line_protocols: Vec<&str> = line_protocols.split('\n').collect();
-        assert_eq!(line_protocols.len(), 1);
+        let (mut sink, _) = config.build(cx).unwrap();
+        for i in 0..3 {
+            let event = Event::Metric(Metric {
+                name: "requests".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: i as f64 },
+            });
+            sink = rt.block_on(sink.send(event)).unwrap();
+        }
 
-        let line_protocol1 = split_line_protocol(line_protocols[0]);
-        assert_eq!("ns.requests", line_protocol1.0);
+        // Each batch is written once, none is retried.
+        assert_eq!(counts.requests(), 3);
+    }
+
+    #[test]
+    fn test_max_buffered_events() {
+        use hyper13::{Body, Response};
+
+        let mut rt = runtime();
+        let addr = next_addr();
+
+        // Never answers, so no request is ever acknowledged.
+        let (_, server) = build_test_responder(addr, &mut rt, |_| {
+            futures::future::pending::<Result<Response<Body>, crate::Error>>()
+        });
+        rt.spawn(server);
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            batch: InfluxDBBatchConfig {
+                max_events: Some(1),
+                ..Default::default()
+            },
+            max_buffered_events: Some(3),
+            ..Default::default()
+        };
+        let (mut sink, _) = config.build(SinkContext::new_test(rt.executor())).unwrap();
+
+        let events = (0..5)
+            .map(|i| {
+                Event::Metric(Metric {
+                    name: format!("gauge-{}", i),
+                    timestamp: Some(ts()),
+                    tags: None,
+                    kind: MetricKind::Absolute,
+                    value: MetricValue::Gauge { value: i as f64 },
+                })
+            })
+            .collect::<Vec<_>>();
+        let accepted = rt
+            .block_on(futures01::future::lazy(move || {
+                let mut accepted = 0;
+                for event in events {
+                    match sink.start_send(event).unwrap() {
+                        futures01::AsyncSink::Ready => accepted += 1,
+                        futures01::AsyncSink::NotReady(_) => break,
+                    }
+                }
+                Ok::<_, ()>(accepted)
+            }))
+            .unwrap();
+        assert_eq!(accepted, 3);
+    }
+
+    #[test]
+    fn test_max_buffered_events_zero() {
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: "http://localhost:9999".to_owned(),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            max_buffered_events: Some(0),
+            ..Default::default()
+        };
+        let rt = runtime();
+        let error = config
+            .build(SinkContext::new_test(rt.executor()))
+            .err()
+            .unwrap();
         assert_eq!(
-            "metric_type=histogram,normal_tag=value,true_tag=true",
-            line_protocol1.1
+            error.to_string(),
+            "`max_buffered_events` must be at least 1."
         );
-        assert_fields(
-            line_protocol1.2.to_string(),
-            [
-                "bucket_1=1u",
-                "bucket_2.1=2u",
-                "bucket_3=3u",
-                "count=6u",
-                "sum=12.5",
-            ]
-            .to_vec(),
+    }
+
+    /// Builds a sink holding three buffered metrics, which the batch timeout
+    /// is too long to flush before the sink is closed.
+    fn buffered_sink(
+        rt: &mut crate::runtime::Runtime,
+        addr: std::net::SocketAddr,
+        shutdown_flush_timeout_secs: u64,
+    ) -> crate::sinks::RouterSink {
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            batch: InfluxDBBatchConfig {
+                max_events: Some(100),
+                timeout_secs: Some(3600),
+                ..Default::default()
+            },
+            shutdown_flush_timeout_secs: Some(shutdown_flush_timeout_secs),
+            ..Default::default()
+        };
+        let (mut sink, _) = config.build(SinkContext::new_test(rt.executor())).unwrap();
+
+        let events = (0..3).map(|i| {
+            Event::Metric(Metric {
+                name: format!("counter-{}", i),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: i as f64 },
+            })
+        });
+        rt.block_on(futures01::future::lazy(move || {
+            for event in events {
+                assert!(sink.start_send(event).unwrap().is_ready());
+            }
+            Ok::<_, ()>(sink)
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_shutdown_flushes_buffered_events() {
+        let mut rt = runtime();
+        let addr = next_addr();
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let mut sink = buffered_sink(&mut rt, addr, 30);
+        rt.block_on(futures01::future::poll_fn(move || sink.close()))
+            .unwrap();
+
+        let output = rx.take(1).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let body = String::from_utf8(output[0].1.clone()).unwrap();
+        assert_eq!(body.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_shutdown_flush_times_out() {
+        let mut rt = runtime();
+        let addr = next_addr();
+
+        // Accepts connections and never answers.
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        std::thread::spawn(move || {
+            let _connections = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let mut sink = buffered_sink(&mut rt, addr, 1);
+        let start = Instant::now();
+        rt.block_on(futures01::future::poll_fn(move || sink.close()))
+            .unwrap();
+
+        // The buffered events are dropped rather than holding up shutdown
+        // until the request times out.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= std::time::Duration::from_secs(1));
+        assert!(
+            elapsed < std::time::Duration::from_secs(30),
+            "{:?}",
+            elapsed
         );
-        assert_eq!("1542182950000000011", line_protocol1.3);
     }
 
     #[test]
-    fn test_encode_summary() {
-        let events = vec![Metric {
-            name: "requests_sum".to_owned(),
+    fn test_writes_reuse_connection() {
+        use hyper13::{Body, Response, StatusCode};
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let (counts, server) = build_test_responder(addr, &mut rt, |_| async {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NO_CONTENT;
+            Ok::<_, crate::Error>(response)
+        });
+        rt.spawn(server);
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            batch: InfluxDBBatchConfig {
+                max_events: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut sink = InfluxDBSvc::new(config, cx).unwrap();
+        for i in 0..3 {
+            let event = Event::Metric(Metric {
+                name: "requests".to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: i as f64 },
+            });
+            // Each write is flushed before the next one is sent.
+            sink = rt.block_on(sink.send(event)).unwrap();
+        }
+
+        assert_eq!(counts.requests(), 3);
+        assert_eq!(counts.connections(), 1);
+    }
+
+    #[test]
+    fn test_dead_letter_rejected_batch() {
+        use hyper13::{Body, Response, StatusCode};
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        // Points named `rejected` are refused, as with a field type conflict.
+        let (_, server) = build_test_responder(
+            addr,
+            &mut rt,
+            |request: hyper13::Request<Body>| async move {
+                let body = hyper13::body::to_bytes(request.into_body()).await?;
+                let response = if body.starts_with(b"ns.rejected") {
+                    let mut response = Response::new(Body::from(
+                        r#"{"code":"invalid","message":"field type conflict"}"#,
+                    ));
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+                    response
+                } else {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NO_CONTENT;
+                    response
+                };
+                Ok::<_, crate::Error>(response)
+            },
+        );
+        rt.spawn(server);
+
+        let path = crate::test_util::temp_file();
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .build(),
+            ),
+            batch: InfluxDBBatchConfig {
+                max_events: Some(1),
+                ..Default::default()
+            },
+            dead_letter: Some(DeadLetterConfig { path: path.clone() }),
+            ..Default::default()
+        };
+
+        let mut sink = InfluxDBSvc::new(config, cx).unwrap();
+        for name in &["rejected", "accepted"] {
+            let event = Event::Metric(Metric {
+                name: (*name).to_owned(),
+                timestamp: Some(ts()),
+                tags: None,
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.0 },
+            });
+            sink = rt.block_on(sink.send(event)).unwrap();
+        }
+
+        let written = std::fs::read_to_string(path).unwrap();
+        let records = written
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0]["line"],
+            "ns.rejected,metric_type=counter value=1.0 1542182950000000011"
+        );
+        assert_eq!(records[0]["status"], 400);
+        assert_eq!(
+            records[0]["error"],
+            r#"{"code":"invalid","message":"field type conflict"}"#
+        );
+    }
+
+    #[test]
+    fn test_rename_host_tag() {
+        let mut tags = tags();
+        tags.insert("hostname".to_owned(), "web-01".to_owned());
+        rename_host_tag(&mut tags, "hostname", "host");
+        assert_eq!(tags.get("host"), Some(&"web-01".to_owned()));
+        assert_eq!(tags.get("hostname"), None);
+
+        // No host, no tag
+        let mut tags = self::tags();
+        rename_host_tag(&mut tags, "hostname", "host");
+        assert_eq!(tags, self::tags());
+    }
+
+    #[test]
+    fn test_with_timestamps_missing() {
+        let metrics = || {
+            vec![None, Some(ts())]
+                .into_iter()
+                .map(|timestamp| Metric {
+                    name: "total".into(),
+                    timestamp,
+                    tags: None,
+                    kind: MetricKind::Incremental,
+                    value: MetricValue::Counter { value: 1.0 },
+                })
+                .collect::<Vec<_>>()
+        };
+        let now = Utc::now();
+
+        let stamped = with_timestamps(metrics(), MissingTimestamp::Now, now);
+        let timestamps = stamped
+            .iter()
+            .map(|metric| metric.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![Some(now), Some(ts())]);
+
+        for &policy in &[MissingTimestamp::Drop, MissingTimestamp::Error] {
+            let kept = with_timestamps(metrics(), policy, now);
+            assert_eq!(kept.len(), 1);
+            assert_eq!(kept[0].timestamp, Some(ts()));
+        }
+    }
+
+    fn line_of(value: MetricValue) -> Option<String> {
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            ..Default::default()
+        };
+        let metric = Metric {
+            name: "requests".into(),
+            timestamp: Some(ts()),
+            tags: Some(tags()),
+            kind: MetricKind::Incremental,
+            value,
+        };
+        metric_to_line(&metric, &config)
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let points = || {
+            to_points(
+                vec![
+                    Metric {
+                        name: "cpu_usage".into(),
+                        timestamp: Some(ts()),
+                        tags: Some(tags()),
+                        kind: MetricKind::Absolute,
+                        value: MetricValue::Gauge { value: 0.5 },
+                    },
+                    Metric {
+                        name: "requests".into(),
+                        timestamp: Some(ts()),
+                        tags: None,
+                        kind: MetricKind::Absolute,
+                        value: MetricValue::AggregatedSummary {
+                            quantiles: vec![0.5],
+                            values: vec![2.0],
+                            count: 6,
+                            sum: 12.0,
+                        },
+                    },
+                ],
+                "ns",
+                &MetricEncoding::default(),
+            )
+        };
+
+        assert_eq!(
+            encode_points(points(), &LineEncoder::default()),
+            "ns.cpu_usage,metric_type=gauge,normal_tag=value,true_tag=true value=0.5 1542182950000000011\n\
+             ns.requests,metric_type=summary count=6u,quantile_0.5=2.0,sum=12.0 1542182950000000011"
+        );
+        assert_eq!(
+            encode_put_points(points(), Precision::Ns),
+            "put ns.cpu_usage 1542182950000 0.5 metric_type=gauge normal_tag=value true_tag=true\n\
+             put ns.requests.count 1542182950000 6 metric_type=summary\n\
+             put ns.requests.quantile_0.5 1542182950000 2 metric_type=summary\n\
+             put ns.requests.sum 1542182950000 12 metric_type=summary"
+        );
+        assert_eq!(
+            encode_put_points(points(), Precision::S).lines().next(),
+            Some(
+                "put ns.cpu_usage 1542182950 0.5 metric_type=gauge normal_tag=value true_tag=true"
+            )
+        );
+    }
+
+    #[test]
+    fn test_single_measurement() {
+        let metric = Metric {
+            name: "cpu_usage".into(),
             timestamp: Some(ts()),
             tags: Some(tags()),
             kind: MetricKind::Absolute,
-            value: MetricValue::AggregatedSummary {
-                quantiles: vec![0.01, 0.5, 0.99],
-                values: vec![1.5, 2.0, 3.0],
-                count: 6,
-                sum: 12.0,
-            },
-        }];
+            value: MetricValue::Gauge { value: 0.5 },
+        };
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            single_measurement: Some("vector".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            metric_to_line(&metric, &config).unwrap(),
+            "vector,metric_type=gauge,name=ns.cpu_usage,normal_tag=value,true_tag=true value=0.5 1542182950000000011"
+        );
+
+        let config = InfluxDBConfig {
+            single_measurement: Some("all metrics".to_owned()),
+            name_tag: Some("metric".to_owned()),
+            ..config
+        };
+        assert_eq!(
+            metric_to_line(&metric, &config).unwrap(),
+            "all\\ metrics,metric=ns.cpu_usage,metric_type=gauge,normal_tag=value,true_tag=true value=0.5 1542182950000000011"
+        );
+    }
+
+    #[test]
+    fn test_metric_to_line_counter() {
+        assert_eq!(
+            line_of(MetricValue::Counter { value: 1.5 }).unwrap(),
+            "ns.requests,metric_type=counter,normal_tag=value,true_tag=true value=1.5 1542182950000000011"
+        );
+    }
 
-        let line_protocols = encode_events(events, "ns");
-        let line_protocols: Vec<&str> = line_protocols.split('\n').collect();
-        assert_eq!(line_protocols.len(), 1);
+    #[test]
+    fn test_metric_to_line_gauge() {
+        assert_eq!(
+            line_of(MetricValue::Gauge { value: -1.5 }).unwrap(),
+            "ns.requests,metric_type=gauge,normal_tag=value,true_tag=true value=-1.5 1542182950000000011"
+        );
+    }
 
-        let line_protocol1 = split_line_protocol(line_protocols[0]);
-        assert_eq!("ns.requests_sum", line_protocol1.0);
+    #[test]
+    fn test_metric_to_line_distribution() {
+        let value = MetricValue::Distribution {
+            values: vec![1.0, 2.0, 3.0],
+            sample_rates: vec![3, 3, 2],
+        };
         assert_eq!(
-            "metric_type=summary,normal_tag=value,true_tag=true",
-            line_protocol1.1
+            line_of(value).unwrap(),
+            "ns.requests,metric_type=distribution,normal_tag=value,true_tag=true \
+             avg=1.875,count=8.0,max=3.0,median=2.0,min=1.0,quantile_0.95=3.0,sum=15.0 \
+             1542182950000000011"
         );
-        assert_fields(
-            line_protocol1.2.to_string(),
-            [
-                "count=6u",
-                "quantile_0.01=1.5",
-                "quantile_0.5=2",
-                "quantile_0.99=3",
-                "sum=12",
-            ]
-            .to_vec(),
+    }
+
+    #[test]
+    fn test_metric_to_line_set() {
+        let value = MetricValue::Set {
+            values: vec!["alice".into(), "bob".into()].into_iter().collect(),
+        };
+        assert_eq!(
+            line_of(value).unwrap(),
+            "ns.requests,metric_type=set,normal_tag=value,true_tag=true value=2.0 1542182950000000011"
         );
-        assert_eq!("1542182950000000011", line_protocol1.3);
     }
 
     #[test]
-    fn test_encode_distribution() {
+    fn test_metric_to_line_dropped() {
+        // No fields
+        let value = MetricValue::Distribution {
+            values: vec![],
+            sample_rates: vec![],
+        };
+        assert_eq!(line_of(value), None);
+
+        // No name, even with a namespace
+        let metric = Metric {
+            name: "".into(),
+            timestamp: Some(ts()),
+            tags: None,
+            kind: MetricKind::Incremental,
+            value: MetricValue::Counter { value: 1.5 },
+        };
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(metric_to_line(&metric, &config), None);
+    }
+
+    #[test]
+    fn test_encode_empty_name_dropped() {
         let events = vec![
             Metric {
-                name: "requests".into(),
-                timestamp: Some(ts()),
-                tags: Some(tags()),
-                kind: MetricKind::Incremental,
-                value: MetricValue::Distribution {
-                    values: vec![1.0, 2.0, 3.0],
-                    sample_rates: vec![3, 3, 2],
-                },
-            },
-            Metric {
-                name: "dense_stats".into(),
+                name: "".into(),
                 timestamp: Some(ts()),
                 tags: None,
                 kind: MetricKind::Incremental,
-                value: MetricValue::Distribution {
-                    values: (0..20).into_iter().map(f64::from).collect::<Vec<_>>(),
-                    sample_rates: vec![1; 20],
-                },
+                value: MetricValue::Counter { value: 1.5 },
             },
             Metric {
-                name: "sparse_stats".into(),
+                name: "total".into(),
                 timestamp: Some(ts()),
                 tags: None,
                 kind: MetricKind::Incremental,
-                value: MetricValue::Distribution {
-                    values: (1..5).into_iter().map(f64::from).collect::<Vec<_>>(),
-                    sample_rates: (1..5).into_iter().collect::<Vec<_>>(),
-                },
+                value: MetricValue::Counter { value: 2.0 },
             },
         ];
 
-        let line_protocols = encode_events(events, "ns");
-        let line_protocols: Vec<&str> = line_protocols.split('\n').collect();
-        assert_eq!(line_protocols.len(), 3);
-
-        let line_protocol1 = split_line_protocol(line_protocols[0]);
-        assert_eq!("ns.requests", line_protocol1.0);
         assert_eq!(
-            "metric_type=distribution,normal_tag=value,true_tag=true",
-            line_protocol1.1
-        );
-        assert_fields(
-            line_protocol1.2.to_string(),
-            [
-                "avg=1.875",
-                "count=8",
-                "max=3",
-                "median=2",
-                "min=1",
-                "quantile_0.95=3",
-                "sum=15",
-            ]
-            .to_vec(),
-        );
-        assert_eq!("1542182950000000011", line_protocol1.3);
-
-        let line_protocol2 = split_line_protocol(line_protocols[1]);
-        assert_eq!("ns.dense_stats", line_protocol2.0);
-        assert_eq!("metric_type=distribution", line_protocol2.1);
-        assert_fields(
-            line_protocol2.2.to_string(),
-            [
-                "avg=9.5",
-                "count=20",
-                "max=19",
-                "median=9",
-                "min=0",
-                "quantile_0.95=18",
-                "sum=190",
-            ]
-            .to_vec(),
+            encode_events(events.clone(), ""),
+            "total,metric_type=counter value=2.0 1542182950000000011"
         );
-        assert_eq!("1542182950000000011", line_protocol2.3);
-
-        let line_protocol3 = split_line_protocol(line_protocols[2]);
-        assert_eq!("ns.sparse_stats", line_protocol3.0);
-        assert_eq!("metric_type=distribution", line_protocol3.1);
-        assert_fields(
-            line_protocol3.2.to_string(),
-            [
-                "avg=3",
-                "count=10",
-                "max=4",
-                "median=3",
-                "min=1",
-                "quantile_0.95=4",
-                "sum=30",
-            ]
-            .to_vec(),
+        assert_eq!(
+            encode_events(events, "ns"),
+            "ns.,metric_type=counter value=1.5 1542182950000000011\n\
+            ns.total,metric_type=counter value=2.0 1542182950000000011"
         );
-        assert_eq!("1542182950000000011", line_protocol3.3);
     }
 
     #[test]
-    fn test_encode_distribution_empty_stats() {
-        let events = vec![Metric {
-            name: "requests".into(),
+    fn test_encode_line_break_dropped() {
+        let metric = |name: &str| Metric {
+            name: name.into(),
             timestamp: Some(ts()),
-            tags: Some(tags()),
+            tags: None,
             kind: MetricKind::Incremental,
-            value: MetricValue::Distribution {
-                values: vec![],
-                sample_rates: vec![],
-            },
-        }];
+            value: MetricValue::Counter { value: 1.0 },
+        };
 
-        let line_protocols = encode_events(events, "ns");
-        assert_eq!(line_protocols.len(), 0);
+        // A line break would end the line in the middle of the measurement.
+        assert_eq!(
+            encode_events(vec![metric("total"), metric("requests\r\ntotal")], "ns"),
+            "ns.total,metric_type=counter value=1.0 1542182950000000011"
+        );
+        assert_eq!(encode_events(vec![metric("total")], "ns\ninjected"), "");
     }
 
     #[test]
-    fn test_encode_distribution_zero_counts_stats() {
-        let events = vec![Metric {
-            name: "requests".into(),
+    fn test_on_encode_error_drops() {
+        let metric = |name: &str, value| Metric {
+            name: name.into(),
             timestamp: Some(ts()),
-            tags: Some(tags()),
-            kind: MetricKind::Incremental,
-            value: MetricValue::Distribution {
-                values: vec![1.0, 2.0],
-                sample_rates: vec![0, 0],
-            },
-        }];
+            tags: None,
+            kind: MetricKind::Absolute,
+            value: MetricValue::Gauge { value },
+        };
+        let events = vec![
+            metric("", 1.0),
+            metric("nan", f64::NAN),
+            metric("infinite", f64::NEG_INFINITY),
+            metric("meter", 2.5),
+        ];
 
-        let line_protocols = encode_events(events, "ns");
-        assert_eq!(line_protocols.len(), 0);
+        for &on_encode_error in &[
+            OnEncodeError::Drop,
+            OnEncodeError::DropAndLog,
+            OnEncodeError::Error,
+        ] {
+            let encoder = LineEncoder {
+                on_encode_error,
+                ..Default::default()
+            };
+            assert_eq!(
+                encode_points(
+                    to_points(events.clone(), "", &MetricEncoding::default()),
+                    &encoder
+                ),
+                "meter,metric_type=gauge value=2.5 1542182950000000011",
+                "{:?}",
+                on_encode_error
+            );
+        }
     }
 
     #[test]
-    fn test_encode_distribution_unequal_stats() {
-        let events = vec![Metric {
-            name: "requests".into(),
-            timestamp: Some(ts()),
-            tags: Some(tags()),
-            kind: MetricKind::Incremental,
-            value: MetricValue::Distribution {
-                values: vec![1.0],
-                sample_rates: vec![1, 2, 3],
-            },
-        }];
+    fn test_quantile_field() {
+        assert_eq!(quantile_field(0.5, 4), "quantile_0.5");
+        assert_eq!(quantile_field(0.9, 4), "quantile_0.9");
+        assert_eq!(quantile_field(0.90, 4), "quantile_0.9");
+        assert_eq!(quantile_field(0.95, 4), "quantile_0.95");
+        assert_eq!(quantile_field(0.999, 4), "quantile_0.999");
+        assert_eq!(quantile_field(0.99999, 4), "quantile_1");
+        assert_eq!(quantile_field(0.999, 2), "quantile_1");
+        assert_eq!(quantile_field(0.26, 1), "quantile_0.3");
+        assert_eq!(quantile_field(0.0, 4), "quantile_0");
+        assert_eq!(quantile_field(1.0, 4), "quantile_1");
+        assert_eq!(quantile_field(0.1 + 0.2, 4), "quantile_0.3");
+    }
 
-        let line_protocols = encode_events(events, "ns");
-        assert_eq!(line_protocols.len(), 0);
+    #[test]
+    fn test_split_batch_by_bucket_tag() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let addr = next_addr();
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_owned(),
+            endpoint: format!("http://{}", addr),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org("my-org")
+                    .bucket("my-bucket")
+                    .token("my-token")
+                    .bucket_tag("tenant")
+                    .build(),
+            ),
+            ..Default::default()
+        };
+
+        let (rx, _trigger, server) = build_test_server(addr, &mut rt);
+        rt.spawn(server);
+
+        let events = vec![
+            ("a", Some("tenant-a")),
+            ("b", Some("tenant-b")),
+            ("c", Some("tenant-a")),
+            ("d", None),
+        ]
+        .into_iter()
+        .map(|(name, tenant)| {
+            Event::Metric(Metric {
+                name: name.to_owned(),
+                timestamp: Some(ts()),
+                tags: tenant.map(|tenant| {
+                    vec![("tenant".to_owned(), tenant.to_owned())]
+                        .into_iter()
+                        .collect()
+                }),
+                kind: MetricKind::Incremental,
+                value: MetricValue::Counter { value: 1.0 },
+            })
+        })
+        .collect::<Vec<_>>();
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(events));
+        let _ = rt.block_on(pump).unwrap();
+
+        let output = rx.take(3).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let mut requests = output
+            .iter()
+            .map(|(parts, body)| {
+                let bucket = parts
+                    .uri
+                    .query()
+                    .unwrap()
+                    .split('&')
+                    .find(|pair| pair.starts_with("bucket="))
+                    .unwrap()
+                    .to_owned();
+                let mut measurements = std::str::from_utf8(body)
+                    .unwrap()
+                    .lines()
+                    .map(|line| line.split(',').next().unwrap().to_owned())
+                    .collect::<Vec<_>>();
+                measurements.sort();
+                (bucket, measurements)
+            })
+            .collect::<Vec<_>>();
+        requests.sort();
+
+        assert_eq!(
+            requests,
+            vec![
+                ("bucket=my-bucket".to_owned(), vec!["ns.d".to_owned()]),
+                (
+                    "bucket=tenant-a".to_owned(),
+                    vec!["ns.a".to_owned(), "ns.c".to_owned()]
+                ),
+                ("bucket=tenant-b".to_owned(), vec!["ns.b".to_owned()]),
+            ]
+        );
     }
 }
 
@@ -584,11 +4358,11 @@ mod integration_tests {
     use crate::event::Metric;
     use crate::sinks::influxdb::metrics::{InfluxDBConfig, InfluxDBSvc};
     use crate::sinks::influxdb::test_util::{onboarding_v2, BUCKET, ORG, TOKEN};
-    use crate::sinks::influxdb::InfluxDB2Settings;
+    use crate::sinks::influxdb::{InfluxDB2Settings, Precision};
     use crate::test_util::runtime;
     use crate::topology::SinkContext;
     use crate::Event;
-    use chrono::Utc;
+    use chrono::{offset::TimeZone, Utc};
     use futures01::{stream, Sink};
 
     //    fn onboarding_v1() {
@@ -622,13 +4396,14 @@ mod integration_tests {
             namespace: "ns".to_string(),
             endpoint: "http://localhost:9999".to_string(),
             influxdb1_settings: None,
-            influxdb2_settings: Some(InfluxDB2Settings {
-                org: ORG.to_string(),
-                bucket: BUCKET.to_string(),
-                token: TOKEN.to_string(),
-            }),
-            batch: Default::default(),
-            request: Default::default(),
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org(ORG)
+                    .bucket(BUCKET)
+                    .token(TOKEN)
+                    .build(),
+            ),
+            ..Default::default()
         };
 
         let metric = format!("counter-{}", Utc::now().timestamp_nanos());
@@ -718,4 +4493,138 @@ mod integration_tests {
             "45"
         );
     }
+
+    #[test]
+    fn influxdb2_metrics_put_data_before_epoch() {
+        onboarding_v2();
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_string(),
+            endpoint: "http://localhost:9999".to_string(),
+            influxdb1_settings: None,
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org(ORG)
+                    .bucket(BUCKET)
+                    .token(TOKEN)
+                    .build(),
+            ),
+            ..Default::default()
+        };
+
+        let metric = format!("historical-{}", Utc::now().timestamp_nanos());
+        let event = Event::Metric(Metric {
+            name: metric.clone(),
+            timestamp: Some(Utc.ymd(1969, 7, 20).and_hms(20, 17, 40)),
+            tags: None,
+            kind: MetricKind::Absolute,
+            value: MetricValue::Gauge { value: 42.0 },
+        });
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(vec![event]));
+        let _ = rt.block_on(pump).unwrap();
+
+        let mut body = std::collections::HashMap::new();
+        body.insert("query", format!("from(bucket:\"my-bucket\") |> range(start: 1969-01-01T00:00:00Z, stop: 1970-01-01T00:00:00Z) |> filter(fn: (r) => r._measurement == \"ns.{}\")", metric));
+        body.insert("type", "flux".to_owned());
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let mut res = client
+            .post("http://localhost:9999/api/v2/query?org=my-org")
+            .json(&body)
+            .header("accept", "application/json")
+            .header("Authorization", "Token my-token")
+            .send()
+            .unwrap();
+        let string = res.text().unwrap();
+
+        let lines = string.split("\n").collect::<Vec<&str>>();
+        let header = lines[0].split(",").collect::<Vec<&str>>();
+        let record = lines[1].split(",").collect::<Vec<&str>>();
+
+        assert_eq!(
+            record[header.iter().position(|&r| r.trim() == "_time").unwrap()].trim(),
+            "1969-07-20T20:17:40Z"
+        );
+        assert_eq!(
+            record[header.iter().position(|&r| r.trim() == "_value").unwrap()].trim(),
+            "42"
+        );
+    }
+
+    #[test]
+    fn influxdb2_metrics_put_data_ms_precision() {
+        onboarding_v2();
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+
+        let config = InfluxDBConfig {
+            namespace: "ns".to_string(),
+            endpoint: "http://localhost:9999".to_string(),
+            influxdb1_settings: None,
+            influxdb2_settings: Some(
+                InfluxDB2Settings::builder()
+                    .org(ORG)
+                    .bucket(BUCKET)
+                    .token(TOKEN)
+                    .build(),
+            ),
+            precision: Precision::Ms,
+            ..Default::default()
+        };
+
+        let metric = format!("precision-{}", Utc::now().timestamp_nanos());
+        let event = Event::Metric(Metric {
+            name: metric.clone(),
+            timestamp: Some(Utc.ymd(2020, 1, 2).and_hms_nano(3, 4, 5, 678_901_234)),
+            tags: None,
+            kind: MetricKind::Absolute,
+            value: MetricValue::Gauge { value: 42.0 },
+        });
+
+        let sink = InfluxDBSvc::new(config, cx).unwrap();
+        let pump = sink.send_all(stream::iter_ok(vec![event]));
+        let _ = rt.block_on(pump).unwrap();
+
+        let mut body = std::collections::HashMap::new();
+        body.insert("query", format!("from(bucket:\"my-bucket\") |> range(start: 2020-01-01T00:00:00Z, stop: 2020-01-03T00:00:00Z) |> filter(fn: (r) => r._measurement == \"ns.{}\")", metric));
+        body.insert("type", "flux".to_owned());
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let mut res = client
+            .post("http://localhost:9999/api/v2/query?org=my-org")
+            .json(&body)
+            .header("accept", "application/json")
+            .header("Authorization", "Token my-token")
+            .send()
+            .unwrap();
+        let string = res.text().unwrap();
+
+        let lines = string.split("\n").collect::<Vec<&str>>();
+        let header = lines[0].split(",").collect::<Vec<&str>>();
+        let record = lines[1].split(",").collect::<Vec<&str>>();
+
+        // Written as milliseconds, so the sub-millisecond part is gone
+        assert_eq!(
+            record[header.iter().position(|&r| r.trim() == "_time").unwrap()].trim(),
+            "2020-01-02T03:04:05.678Z"
+        );
+        assert_eq!(
+            record[header.iter().position(|&r| r.trim() == "_value").unwrap()].trim(),
+            "42"
+        );
+    }
 }
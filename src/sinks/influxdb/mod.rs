@@ -1,615 +1,6377 @@
 pub mod logs;
 pub mod metrics;
+pub mod opentsdb;
+pub mod remote_write;
+pub mod validate;
 
 pub(self) use super::{Healthcheck, RouterSink};
 
-use crate::{dns::Resolver, sinks::util::http2::HttpClient};
-use chrono::{DateTime, Utc};
-use futures::TryFutureExt;
-use futures01::Future;
-use http02::{StatusCode, Uri};
+use crate::{
+    dns::Resolver,
+    internal_events::{
+        InfluxDBAuthFailed, InfluxDBCircuitBreakerStateChanged, InfluxDBCleanupCompleted,
+        InfluxDBCleanupFailed, InfluxDBDeadLetterFailed, InfluxDBDeadLetterWritten,
+        InfluxDBEventDropped, InfluxDBFieldCoercionFailed, InfluxDBFieldTypeConflict,
+        InfluxDBMissingTimestamp, InfluxDBRequestBodySize, InfluxDBRetryBudgetWithdrawn,
+        InfluxDBSchemaViolation, InfluxDBShutdownFlushTimedOut, InfluxDBTagKeyDropped,
+        InfluxDBTagValueTruncated, InfluxDBTokenReloadFailed, InfluxDBTokenReloaded,
+    },
+    runtime::TaskExecutor,
+    sinks::util::{
+        http2::{HttpClient, HttpRetryLogic},
+        retries2::{RetryAction, RetryLogic},
+        Batch, BatchSettings,
+    },
+};
+use bytes05::Bytes;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use futures::{compat::Future01CompatExt, FutureExt, StreamExt, TryFutureExt};
+use futures01::{Async, Poll, Sink, StartSend};
+use http02::{
+    header::{
+        HeaderName, HeaderValue, InvalidHeaderName, InvalidHeaderValue, CONTENT_ENCODING,
+        USER_AGENT,
+    },
+    HeaderMap, StatusCode, Uri,
+};
 use hyper13;
+use lru::LruCache;
+use regex::RegexSet;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use snafu::Snafu;
-use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use stream_cancel::{Trigger, Tripwire};
+use tokio01::timer::Delay;
 use tower03::Service;
 
+const DEFAULT_DNS_CACHE_TTL_SECS: u64 = 30;
+const DEFAULT_HOST_TAG: &str = "host";
+const DEFAULT_INSTANCE_TAG: &str = "vector_instance";
+// hyper's own default, made explicit as it is documented.
+const DEFAULT_KEEPALIVE_IDLE_TIMEOUT_SECS: u64 = 90;
+const DEFAULT_SHUTDOWN_FLUSH_TIMEOUT_SECS: u64 = 60;
+// zstd's own default, which compresses about as well as gzip's default in a
+// fraction of the time.
+#[cfg(feature = "sinks-influxdb-zstd")]
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// A line protocol field value.
+///
+/// Values convert from the matching Rust primitives, so fields can be built
+/// with `.into()`:
+///
+/// ```
+/// use vector::sinks::influxdb::Field;
+///
+/// let fields: Vec<(&str, Field)> = vec![
+///     ("value", 1.5.into()),
+///     ("count", 10u32.into()),
+///     ("delta", (-3i64).into()),
+///     ("ok", true.into()),
+///     ("host", "localhost".into()),
+/// ];
+/// assert_eq!(fields[0].1, Field::Float(1.5));
+/// assert_eq!(fields[1].1, Field::UnsignedInt(10));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
 pub enum Field {
     /// string
     String(String),
     /// float
     Float(f64),
     /// unsigned integer
-    UnsignedInt(u32),
+    UnsignedInt(u64),
     /// integer
     Int(i64),
     /// boolean
     Bool(bool),
 }
 
-#[derive(Debug, Snafu)]
-enum ConfigError {
-    #[snafu(display("InfluxDB v1 or v2 should be configured as endpoint."))]
-    MissingConfiguration,
-    #[snafu(display(
-        "Unclear settings. Both version configured v1: {:?}, v2: {:?}.",
-        v1_settings,
-        v2_settings
-    ))]
-    BothConfiguration {
-        v1_settings: InfluxDB1Settings,
-        v2_settings: InfluxDB2Settings,
-    },
+/// Formats the value as it's written in line protocol, with the `u`/`i`
+/// suffix of integers and strings quoted and escaped. The alternate form
+/// (`{:#}`) writes booleans as `t`/`f`.
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::String(s) => write_field_string(s, f),
+            // Whole numbers keep a `.0`, so the field is unambiguously a float.
+            // Floats are always written in plain decimal notation, as some
+            // older parsers reject an exponent, however long that makes them.
+            Field::Float(value) if value.fract() == 0.0 => write!(f, "{:.1}", value),
+            Field::Float(value) => write!(f, "{}", value),
+            Field::UnsignedInt(value) => write!(f, "{}u", value),
+            Field::Int(value) => write!(f, "{}i", value),
+            Field::Bool(value) if f.alternate() => f.write_str(if *value { "t" } else { "f" }),
+            Field::Bool(value) => write!(f, "{}", value),
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct InfluxDB1Settings {
-    database: String,
-    consistency: Option<String>,
-    retention_policy_name: Option<String>,
-    username: Option<String>,
-    password: Option<String>,
+impl Field {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Field::String(_) => "string",
+            Field::Float(_) => "float",
+            Field::UnsignedInt(_) => "unsigned integer",
+            Field::Int(_) => "integer",
+            Field::Bool(_) => "boolean",
+        }
+    }
+
+    /// Converts the value to `field_type`, handing it back when it can't be
+    /// represented as that type, such as a string that isn't a number as a
+    /// `float` or a float with a fraction as an `int`.
+    fn coerce(self, field_type: FieldType) -> Result<Field, Field> {
+        let coerced = match (field_type, &self) {
+            (FieldType::String, Field::String(_))
+            | (FieldType::Float, Field::Float(_))
+            | (FieldType::Int, Field::Int(_))
+            | (FieldType::Uint, Field::UnsignedInt(_))
+            | (FieldType::Bool, Field::Bool(_)) => return Ok(self),
+
+            (FieldType::String, Field::Float(value)) => Some(Field::String(value.to_string())),
+            (FieldType::String, Field::Int(value)) => Some(Field::String(value.to_string())),
+            (FieldType::String, Field::UnsignedInt(value)) => {
+                Some(Field::String(value.to_string()))
+            }
+            (FieldType::String, Field::Bool(value)) => Some(Field::String(value.to_string())),
+
+            (FieldType::Float, Field::Int(value)) => Some(Field::Float(*value as f64)),
+            (FieldType::Float, Field::UnsignedInt(value)) => Some(Field::Float(*value as f64)),
+            (FieldType::Float, Field::String(value)) => value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .map(Field::Float),
+
+            (FieldType::Int, Field::UnsignedInt(value)) => {
+                i64::try_from(*value).ok().map(Field::Int)
+            }
+            // `i64::MAX as f64` rounds up to 2^63, which is out of range.
+            (FieldType::Int, Field::Float(value))
+                if value.fract() == 0.0
+                    && *value >= i64::MIN as f64
+                    && *value < i64::MAX as f64 =>
+            {
+                Some(Field::Int(*value as i64))
+            }
+            (FieldType::Int, Field::String(value)) => value.trim().parse().ok().map(Field::Int),
+
+            (FieldType::Uint, Field::Int(value)) => {
+                u64::try_from(*value).ok().map(Field::UnsignedInt)
+            }
+            (FieldType::Uint, Field::Float(value))
+                if value.fract() == 0.0 && *value >= 0.0 && *value < u64::MAX as f64 =>
+            {
+                Some(Field::UnsignedInt(*value as u64))
+            }
+            (FieldType::Uint, Field::String(value)) => {
+                value.trim().parse().ok().map(Field::UnsignedInt)
+            }
+
+            // The spellings line protocol accepts.
+            (FieldType::Bool, Field::String(value)) => match value.as_str() {
+                "t" | "T" | "true" | "True" | "TRUE" => Some(Field::Bool(true)),
+                "f" | "F" | "false" | "False" | "FALSE" => Some(Field::Bool(false)),
+                _ => None,
+            },
+
+            _ => None,
+        };
+        coerced.ok_or(self)
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct InfluxDB2Settings {
-    org: String,
-    bucket: String,
-    token: String,
+/// The type a field is written as, overriding the one inferred from its value.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    String,
+    Float,
+    Int,
+    Uint,
+    Bool,
 }
 
-trait InfluxDBSettings {
-    fn write_uri(self: &Self, endpoint: String) -> crate::Result<Uri>;
-    fn write_uri2(self: &Self, endpoint: String) -> crate::Result<http02::Uri> {
-        let uri = self.write_uri(endpoint)?;
-        Ok(format!("{}", uri)
-            .parse::<http02::Uri>()
-            .context(super::UriParseError2)?)
+impl FieldType {
+    fn as_str(self) -> &'static str {
+        match self {
+            FieldType::String => "string",
+            FieldType::Float => "float",
+            FieldType::Int => "int",
+            FieldType::Uint => "uint",
+            FieldType::Bool => "bool",
+        }
     }
-    fn healthcheck_uri(self: &Self, endpoint: String) -> crate::Result<Uri>;
-    fn token(self: &Self) -> String;
 }
 
-impl InfluxDBSettings for InfluxDB1Settings {
-    fn write_uri(self: &Self, endpoint: String) -> crate::Result<Uri> {
-        encode_uri(
-            &endpoint,
-            "write",
-            &[
-                ("consistency", self.consistency.clone()),
-                ("db", Some(self.database.clone())),
-                ("rp", self.retention_policy_name.clone()),
-                ("p", self.password.clone()),
-                ("u", self.username.clone()),
-                ("precision", Some("ns".to_owned())),
-            ],
-        )
+/// Converts the fields named in `overrides` to the type configured for them.
+/// Fields that can't be converted are dropped, as writing them with their
+/// inferred type would conflict with the type they are written with otherwise.
+fn override_field_types(
+    component_type: &'static str,
+    overrides: &HashMap<String, FieldType>,
+    fields: &mut HashMap<String, Field>,
+) {
+    for (key, field_type) in overrides {
+        if let Some(field) = fields.remove(key) {
+            match field.coerce(*field_type) {
+                Ok(field) => {
+                    fields.insert(key.clone(), field);
+                }
+                Err(field) => emit!(InfluxDBFieldCoercionFailed {
+                    component_type,
+                    field: key,
+                    from: field.type_name(),
+                    to: field_type.as_str(),
+                }),
+            }
+        }
     }
+}
 
-    fn healthcheck_uri(self: &Self, endpoint: String) -> crate::Result<Uri> {
-        encode_uri(&endpoint, "ping", &[])
-    }
+macro_rules! impl_field_from {
+    ($variant:ident($inner:ty): $($from:ty),+) => {
+        $(
+            impl From<$from> for Field {
+                #[allow(clippy::useless_conversion)]
+                fn from(value: $from) -> Self {
+                    Field::$variant(<$inner>::from(value))
+                }
+            }
+        )+
+    };
+}
 
-    fn token(self: &Self) -> String {
-        "".to_string()
-    }
+impl_field_from!(Float(f64): f64, f32);
+impl_field_from!(Int(i64): i64, i32, i16, i8);
+impl_field_from!(UnsignedInt(u64): u64, u32, u16, u8);
+impl_field_from!(Bool(bool): bool);
+impl_field_from!(String(String): String, &str);
+
+/// Reasons a point can't be written as line protocol.
+#[derive(Debug, PartialEq, Snafu)]
+pub enum LineProtocolError {
+    #[snafu(display("measurement name is empty"))]
+    EmptyMeasurement,
+    #[snafu(display("measurement name {:?} contains a line break", measurement))]
+    MeasurementLineBreak { measurement: String },
+    #[snafu(display("point has no fields that can be written"))]
+    NoFields,
+    #[snafu(display("line of {} bytes is longer than max_line_bytes ({})", length, max))]
+    LineTooLong { length: usize, max: usize },
 }
 
-impl InfluxDBSettings for InfluxDB2Settings {
-    fn write_uri(self: &Self, endpoint: String) -> crate::Result<Uri> {
-        encode_uri(
-            &endpoint,
-            "api/v2/write",
-            &[
-                ("org", Some(self.org.clone())),
-                ("bucket", Some(self.bucket.clone())),
-                ("precision", Some("ns".to_owned())),
-            ],
-        )
-    }
+/// What to do with lines longer than `max_line_bytes`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnOversize {
+    /// Drop the point.
+    Drop,
+    /// Shorten string fields until the line fits, dropping it if that's not enough.
+    Truncate,
+}
 
-    fn healthcheck_uri(self: &Self, endpoint: String) -> crate::Result<Uri> {
-        encode_uri(&endpoint, "health", &[])
+impl Default for OnOversize {
+    fn default() -> Self {
+        OnOversize::Drop
     }
+}
 
-    fn token(self: &Self) -> String {
-        self.token.clone()
+/// What to do with tag and field keys starting with `_`, which InfluxDB 2
+/// reserves for its own columns such as `_measurement`, `_field` and `_time`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnderscoreKeys {
+    /// Write the key as it is.
+    Keep,
+    /// Strip the leading underscores, dropping the key if that leaves it
+    /// empty or it collides with another key.
+    Rename,
+    /// Drop the key.
+    Drop,
+}
+
+impl Default for UnderscoreKeys {
+    fn default() -> Self {
+        UnderscoreKeys::Keep
     }
 }
 
-fn influxdb_settings(
-    influxdb1_settings: Option<InfluxDB1Settings>,
-    influxdb2_settings: Option<InfluxDB2Settings>,
-) -> Result<Box<dyn InfluxDBSettings>, crate::Error> {
-    if influxdb1_settings.is_some() & influxdb2_settings.is_some() {
-        return Err(ConfigError::BothConfiguration {
-            v1_settings: influxdb1_settings.unwrap(),
-            v2_settings: influxdb2_settings.unwrap(),
+impl UnderscoreKeys {
+    /// Applies the policy to the keys of a tag set or field set.
+    fn sanitize<M, V>(self, map: M) -> M
+    where
+        M: IntoIterator<Item = (String, V)> + std::iter::FromIterator<(String, V)>,
+    {
+        if self == UnderscoreKeys::Keep {
+            return map;
         }
-        .into());
-    }
 
-    if influxdb1_settings.is_none() & influxdb2_settings.is_none() {
-        return Err(ConfigError::MissingConfiguration.into());
+        let (reserved, mut kept): (Vec<_>, Vec<_>) =
+            map.into_iter().partition(|(key, _)| key.starts_with('_'));
+        if self == UnderscoreKeys::Rename {
+            for (key, value) in reserved {
+                let key = key.trim_start_matches('_');
+                if !key.is_empty() && kept.iter().all(|(kept, _)| kept != key) {
+                    kept.push((key.to_owned(), value));
+                }
+            }
+        }
+        kept.into_iter().collect()
     }
+}
 
-    if let Some(settings) = influxdb1_settings {
-        Ok(Box::new(settings))
-    } else {
-        Ok(Box::new(influxdb2_settings.unwrap()))
+/// How booleans are spelled in line protocol.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BoolFormat {
+    /// `true` and `false`.
+    Long,
+    /// `t` and `f`.
+    Short,
+}
+
+impl Default for BoolFormat {
+    fn default() -> Self {
+        BoolFormat::Long
     }
 }
 
-// V1: https://docs.influxdata.com/influxdb/v1.7/tools/api/#ping-http-endpoint
-// V2: https://v2.docs.influxdata.com/v2.0/api/#operation/GetHealth
-fn healthcheck(
-    endpoint: String,
-    influxdb1_settings: Option<InfluxDB1Settings>,
-    influxdb2_settings: Option<InfluxDB2Settings>,
-    resolver: Resolver,
-) -> crate::Result<super::Healthcheck> {
-    let settings = influxdb_settings(influxdb1_settings.clone(), influxdb2_settings.clone())?;
+/// How events that can't be written as line protocol are reported. They are
+/// dropped and counted in every case.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnEncodeError {
+    /// Drop the event, logging it only at debug level.
+    Drop,
+    /// Drop the event and log a warning.
+    DropAndLog,
+    /// Drop the event and log an error.
+    Error,
+}
 
-    let endpoint = endpoint.clone();
+impl Default for OnEncodeError {
+    fn default() -> Self {
+        OnEncodeError::DropAndLog
+    }
+}
 
-    let uri = settings.healthcheck_uri(endpoint)?;
+/// What to do with points that have no timestamp.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingTimestamp {
+    /// Use the time the point is first encoded.
+    Now,
+    /// Drop the point.
+    Drop,
+    /// Drop the point and log an error.
+    Error,
+}
 
-    let request = hyper13::Request::get(uri)
-        .body(hyper13::Body::empty())
-        .unwrap();
+impl Default for MissingTimestamp {
+    fn default() -> Self {
+        MissingTimestamp::Now
+    }
+}
 
-    let mut client = HttpClient::new(resolver, None)?;
+impl MissingTimestamp {
+    /// The timestamp to write, or `None` if the point is dropped.
+    fn apply(
+        self,
+        component_type: &'static str,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> Option<DateTime<Utc>> {
+        self.apply_with(component_type, timestamp, Utc::now)
+    }
 
-    let healthcheck = client
-        .call(request)
-        .compat()
-        .map_err(|err| err.into())
-        .and_then(|response| match response.status() {
-            StatusCode::OK => Ok(()),
-            StatusCode::NO_CONTENT => Ok(()),
-            other => Err(super::HealthcheckError::UnexpectedStatus2 { status: other }.into()),
-        });
+    /// Like `apply`, reading the time from `now` when there's none.
+    fn apply_with(
+        self,
+        component_type: &'static str,
+        timestamp: Option<DateTime<Utc>>,
+        now: impl FnOnce() -> DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        match (timestamp, self) {
+            (Some(timestamp), _) => Some(timestamp),
+            (None, MissingTimestamp::Now) => Some(now()),
+            (None, policy) => {
+                emit!(InfluxDBMissingTimestamp {
+                    component_type,
+                    error: policy == MissingTimestamp::Error,
+                });
+                None
+            }
+        }
+    }
+}
 
-    Ok(Box::new(healthcheck))
+/// Unit of the timestamps written, sent as the `precision` of a write.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Precision {
+    /// Seconds.
+    S,
+    /// Milliseconds.
+    Ms,
+    /// Microseconds.
+    Us,
+    /// Nanoseconds.
+    Ns,
 }
 
-// https://v2.docs.influxdata.com/v2.0/reference/syntax/line-protocol/
-fn influx_line_protocol(
-    measurement: String,
-    metric_type: &str,
-    tags: Option<BTreeMap<String, String>>,
-    fields: Option<HashMap<String, Field>>,
-    timestamp: i64,
-    line_protocol: &mut String,
-) {
-    // Fields
-    let unwrapped_fields = fields.unwrap_or_else(|| HashMap::new());
-    // LineProtocol should have a field
-    if unwrapped_fields.is_empty() {
-        return;
+impl Default for Precision {
+    fn default() -> Self {
+        Precision::Ns
     }
+}
 
-    encode_string(measurement, line_protocol);
-    line_protocol.push(',');
+impl Precision {
+    /// Reads a precision by the name used by the v1 and v2 write APIs.
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "s" => Some(Precision::S),
+            "ms" => Some(Precision::Ms),
+            "us" => Some(Precision::Us),
+            "ns" => Some(Precision::Ns),
+            _ => None,
+        }
+    }
 
-    // Tags
-    let mut unwrapped_tags = tags.unwrap_or_else(|| BTreeMap::new());
-    unwrapped_tags.insert("metric_type".to_owned(), metric_type.to_owned());
-    encode_tags(unwrapped_tags, line_protocol);
-    line_protocol.push(' ');
+    /// Name used by the v1 and v2 write APIs.
+    fn as_str(self) -> &'static str {
+        match self {
+            Precision::S => "s",
+            Precision::Ms => "ms",
+            Precision::Us => "us",
+            Precision::Ns => "ns",
+        }
+    }
 
-    // Fields
-    encode_fields(unwrapped_fields, line_protocol);
-    line_protocol.push(' ');
+    /// Name used by the v3 write API.
+    fn as_v3_str(self) -> &'static str {
+        match self {
+            Precision::S => "second",
+            Precision::Ms => "millisecond",
+            Precision::Us => "microsecond",
+            Precision::Ns => "nanosecond",
+        }
+    }
 
-    // Timestamp
-    line_protocol.push_str(&timestamp.to_string());
-    line_protocol.push('\n');
+    /// Converts a nanosecond timestamp to this precision.
+    fn scale(self, nanos: i64) -> i64 {
+        scale_timestamp(nanos, self)
+    }
 }
 
-fn encode_tags(tags: BTreeMap<String, String>, output: &mut String) {
-    let sorted = tags
-        // sort by key
-        .iter()
-        .collect::<BTreeMap<_, _>>();
-
-    for (key, value) in sorted {
-        if key.is_empty() || value.is_empty() {
-            continue;
-        }
-        encode_string(key.to_string(), output);
-        output.push('=');
-        encode_string(value.to_string(), output);
-        output.push(',');
-    }
+/// Per-sink options for writing points as line protocol.
+#[derive(Clone, Copy, Debug, Default)]
+struct LineEncoder {
+    max_bytes: Option<usize>,
+    on_oversize: OnOversize,
+    on_encode_error: OnEncodeError,
+    bool_format: BoolFormat,
+    precision: Precision,
+    underscore_keys: UnderscoreKeys,
+}
 
-    // remove last ','
-    output.pop();
+/// Batch options of the InfluxDB sinks. A batch is flushed as soon as any of
+/// the limits is reached.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InfluxDBBatchConfig {
+    pub max_events: Option<usize>,
+    /// Encoded line protocol bytes, before compression.
+    // `max_size` is what the logs sink called it before `max_events` was added.
+    #[serde(alias = "max_size")]
+    pub max_bytes: Option<usize>,
+    pub timeout_secs: Option<u64>,
 }
 
-fn encode_fields(fields: HashMap<String, Field>, output: &mut String) {
-    for (key, value) in fields.into_iter() {
-        encode_string(key.to_string(), output);
-        output.push('=');
-        match value {
-            Field::String(s) => {
-                output.push('"');
-                for c in s.chars() {
-                    if "\\\"".contains(c) {
-                        output.push('\\');
-                    }
-                    output.push(c);
-                }
-                output.push('"');
-            }
-            Field::Float(f) => output.push_str(&f.to_string()),
-            Field::UnsignedInt(i) => {
-                output.push_str(&i.to_string());
-                output.push('u');
-            }
-            Field::Int(i) => {
-                output.push_str(&i.to_string());
-                output.push('i');
-            }
-            Field::Bool(b) => {
-                output.push_str(&b.to_string());
-            }
+impl InfluxDBBatchConfig {
+    fn unwrap_or(
+        &self,
+        max_events: Option<usize>,
+        max_bytes: Option<usize>,
+        timeout_secs: u64,
+    ) -> (BatchLimits, BatchSettings) {
+        let limits = BatchLimits {
+            max_events: self.max_events.or(max_events).unwrap_or(usize::MAX),
+            max_bytes: self.max_bytes.or(max_bytes),
         };
-        output.push(',');
+        let settings = BatchSettings {
+            size: limits.max_events,
+            timeout: Duration::from_secs(self.timeout_secs.unwrap_or(timeout_secs)),
+        };
+        (limits, settings)
     }
+}
 
-    // remove last ','
-    output.pop();
+#[derive(Clone, Copy, Debug)]
+struct BatchLimits {
+    max_events: usize,
+    max_bytes: Option<usize>,
 }
 
-fn encode_string(key: String, output: &mut String) {
-    for c in key.chars() {
-        if "\\, =".contains(c) {
-            output.push('\\');
+/// Wraps a batch to count the encoded bytes of the items pushed to it.
+///
+/// `BatchSink` only compares `len` against a single size, the number of
+/// events, so a batch over the byte limit reports itself as full.
+struct LimitedBatch<B: Batch> {
+    inner: B,
+    limits: BatchLimits,
+    bytes: usize,
+    encoded_len: Arc<dyn Fn(&B::Input) -> usize + Send + Sync>,
+}
+
+impl<B: Batch> LimitedBatch<B> {
+    fn new(
+        inner: B,
+        limits: BatchLimits,
+        encoded_len: impl Fn(&B::Input) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            limits,
+            bytes: 0,
+            encoded_len: Arc::new(encoded_len),
         }
-        output.push(c);
     }
 }
 
-fn encode_timestamp(timestamp: Option<DateTime<Utc>>) -> i64 {
-    if let Some(ts) = timestamp {
-        ts.timestamp_nanos()
-    } else {
-        encode_timestamp(Some(Utc::now()))
+impl<B: Batch> Batch for LimitedBatch<B> {
+    type Input = B::Input;
+    type Output = B::Output;
+
+    fn len(&self) -> usize {
+        let events = self.inner.num_items();
+        match self.limits.max_bytes {
+            Some(max_bytes) if self.bytes >= max_bytes => events.max(self.limits.max_events),
+            _ => events,
+        }
     }
-}
 
-fn encode_namespace(namespace: &str, name: &str) -> String {
-    if !namespace.is_empty() {
-        format!("{}.{}", namespace, name)
-    } else {
-        name.to_string()
+    fn push(&mut self, item: Self::Input) {
+        if self.limits.max_bytes.is_some() {
+            self.bytes += (self.encoded_len)(&item);
+        }
+        self.inner.push(item)
     }
-}
 
-fn encode_uri(endpoint: &str, path: &str, pairs: &[(&str, Option<String>)]) -> crate::Result<Uri> {
-    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
 
-    for pair in pairs {
-        if let Some(v) = &pair.1 {
-            serializer.append_pair(pair.0, v);
+    fn fresh(&self) -> Self {
+        Self {
+            inner: self.inner.fresh(),
+            limits: self.limits,
+            bytes: 0,
+            encoded_len: Arc::clone(&self.encoded_len),
         }
     }
 
-    let mut url = if endpoint.ends_with('/') {
-        format!("{}{}?{}", endpoint, path, serializer.finish())
-    } else {
-        format!("{}/{}?{}", endpoint, path, serializer.finish())
-    };
+    fn finish(self) -> Self::Output {
+        self.inner.finish()
+    }
 
-    if url.ends_with("?") {
-        url.pop();
+    fn num_items(&self) -> usize {
+        self.inner.num_items()
     }
+}
 
-    Ok(url.parse::<Uri>().context(super::UriParseError2)?)
+/// Bounds how long closing a sink waits for its buffered events to be
+/// written, so that an unresponsive server can't hold up shutdown.
+///
+/// Once the timeout is over the sink reports itself closed, and the events
+/// taken since it last had nothing left to write are counted as dropped.
+struct ShutdownFlush<S> {
+    inner: S,
+    component_type: &'static str,
+    timeout: Duration,
+    deadline: Option<Delay>,
+    unflushed: usize,
+    // Stops the `cleanup` task once the sink is dropped.
+    cleanup: Option<Trigger>,
 }
 
-#[cfg(test)]
-#[allow(dead_code)]
-pub mod test_util {
-    use super::*;
-    use chrono::offset::TimeZone;
+impl<S> ShutdownFlush<S> {
+    fn new(inner: S, component_type: &'static str, timeout_secs: Option<u64>) -> Self {
+        Self {
+            inner,
+            component_type,
+            timeout: Duration::from_secs(
+                timeout_secs.unwrap_or(DEFAULT_SHUTDOWN_FLUSH_TIMEOUT_SECS),
+            ),
+            deadline: None,
+            unflushed: 0,
+            cleanup: None,
+        }
+    }
 
-    pub(crate) const ORG: &str = "my-org";
-    pub(crate) const BUCKET: &str = "my-bucket";
-    pub(crate) const TOKEN: &str = "my-token";
-    pub(crate) const DATABASE: &str = "my-database";
+    fn with_cleanup(self, cleanup: Option<Trigger>) -> Self {
+        Self { cleanup, ..self }
+    }
+}
 
-    pub(crate) fn ts() -> DateTime<Utc> {
-        Utc.ymd(2018, 11, 14).and_hms_nano(8, 9, 10, 11)
+impl<S: Sink<SinkError = ()>> Sink for ShutdownFlush<S> {
+    type SinkItem = S::SinkItem;
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, ()> {
+        let sent = self.inner.start_send(item)?;
+        if sent.is_ready() {
+            self.unflushed += 1;
+        }
+        Ok(sent)
     }
 
-    pub(crate) fn tags() -> BTreeMap<String, String> {
-        vec![
-            ("normal_tag".to_owned(), "value".to_owned()),
-            ("true_tag".to_owned(), "true".to_owned()),
-            ("empty_tag".to_owned(), "".to_owned()),
-        ]
-        .into_iter()
-        .collect()
+    fn poll_complete(&mut self) -> Poll<(), ()> {
+        let flushed = self.inner.poll_complete()?;
+        if flushed.is_ready() {
+            self.unflushed = 0;
+        }
+        Ok(flushed)
     }
 
-    pub(crate) fn assert_fields(value: String, fields: Vec<&str>) {
-        let encoded_fields: Vec<&str> = value.split(',').collect();
+    fn close(&mut self) -> Poll<(), ()> {
+        let timeout = self.timeout;
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+        if self.inner.close()?.is_ready() {
+            self.unflushed = 0;
+            return Ok(Async::Ready(()));
+        }
 
-        assert_eq!(fields.len(), encoded_fields.len());
+        match futures01::Future::poll(deadline) {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // A broken timer can't be waited on either.
+            Ok(Async::Ready(())) | Err(_) => {
+                emit!(InfluxDBShutdownFlushTimedOut {
+                    component_type: self.component_type,
+                    dropped: self.unflushed,
+                    timeout_secs: timeout.as_secs(),
+                });
+                self.unflushed = 0;
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
+
+/// What to do with a point that writes a field with another type than before.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFieldTypeConflict {
+    /// Log a warning and send the point anyway.
+    Warn,
+    /// Log a warning and drop the point.
+    Drop,
+}
+
+const DEFAULT_MAX_TRACKED_FIELDS: usize = 10_000;
+
+#[derive(Debug, PartialEq)]
+struct FieldTypeConflict {
+    field: String,
+    expected: &'static str,
+    actual: &'static str,
+}
+
+/// Remembers the type each field of a measurement was first written with.
+///
+/// InfluxDB rejects every point that writes a field with another type for as
+/// long as the field exists, so conflicts are worth catching before sending.
+/// Only the most recently used fields are remembered.
+struct FieldTypes {
+    types: LruCache<(String, String), &'static str>,
+    on_conflict: OnFieldTypeConflict,
+}
+
+impl fmt::Debug for FieldTypes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FieldTypes")
+            .field("tracked", &self.types.len())
+            .field("on_conflict", &self.on_conflict)
+            .finish()
+    }
+}
+
+impl FieldTypes {
+    /// A tracker shared by the clones of a sink, if conflicts are to be checked.
+    fn shared(
+        on_conflict: Option<OnFieldTypeConflict>,
+        max_tracked_fields: Option<usize>,
+    ) -> Option<Arc<Mutex<Self>>> {
+        let capacity = max_tracked_fields.unwrap_or(DEFAULT_MAX_TRACKED_FIELDS);
+        on_conflict.map(|on_conflict| {
+            Arc::new(Mutex::new(FieldTypes {
+                types: LruCache::new(capacity),
+                on_conflict,
+            }))
+        })
+    }
+
+    /// Returns the fields whose type differs from the remembered one. The
+    /// types of new fields are only remembered if there's no conflict, as
+    /// InfluxDB won't store any of the point otherwise.
+    fn conflicts(
+        &mut self,
+        measurement: &str,
+        fields: &HashMap<String, Field>,
+    ) -> Vec<FieldTypeConflict> {
+        let mut conflicts = Vec::new();
+        let mut new_fields = Vec::new();
+        for (field, value) in fields {
+            let key = (measurement.to_owned(), field.clone());
+            let actual = value.type_name();
+            match self.types.get(&key) {
+                Some(&expected) if expected != actual => conflicts.push(FieldTypeConflict {
+                    field: field.clone(),
+                    expected,
+                    actual,
+                }),
+                Some(_) => {}
+                None => new_fields.push((key, actual)),
+            }
+        }
+
+        if conflicts.is_empty() {
+            for (key, actual) in new_fields {
+                self.types.put(key, actual);
+            }
+        }
+        conflicts
+    }
+
+    /// Reports conflicting fields, returning whether the point should be sent.
+    fn check(
+        &mut self,
+        component_type: &'static str,
+        measurement: &str,
+        fields: &HashMap<String, Field>,
+    ) -> bool {
+        let conflicts = self.conflicts(measurement, fields);
+        let drop = self.on_conflict == OnFieldTypeConflict::Drop;
+        for conflict in conflicts.iter() {
+            emit!(InfluxDBFieldTypeConflict {
+                component_type,
+                measurement,
+                field: &conflict.field,
+                expected: conflict.expected,
+                actual: conflict.actual,
+                dropped: drop,
+            });
+        }
+        conflicts.is_empty() || !drop
+    }
+}
+
+/// The measurements and fields accepted by a bucket with an explicit schema.
+/// Points that don't match it are dropped instead of being sent, as the server
+/// would reject them.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SchemaConfig {
+    /// Measurement names written, including the namespace. Any if empty.
+    #[serde(default)]
+    pub measurements: Vec<String>,
+    /// Regular expressions one of which must match the whole name of every
+    /// field. Any if empty.
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+impl SchemaConfig {
+    fn build(&self) -> crate::Result<Schema> {
+        let fields = self
+            .fields
+            .iter()
+            .map(|pattern| format!("^(?:{})$", pattern));
+        Ok(Schema {
+            measurements: self.measurements.iter().cloned().collect(),
+            fields: RegexSet::new(fields).context(InvalidFieldPattern)?,
+        })
+    }
+}
+
+/// Why a point doesn't match the schema.
+#[derive(Debug, PartialEq, Snafu)]
+pub enum SchemaViolation {
+    #[snafu(display("measurement {:?} isn't in the schema", measurement))]
+    UnknownMeasurement { measurement: String },
+    #[snafu(display("field {:?} doesn't match any field pattern of the schema", field))]
+    UnknownField { field: String },
+}
+
+#[derive(Clone, Debug)]
+struct Schema {
+    measurements: HashSet<String>,
+    fields: RegexSet,
+}
+
+impl Schema {
+    fn violation(
+        &self,
+        measurement: &str,
+        fields: &HashMap<String, Field>,
+    ) -> Option<SchemaViolation> {
+        if !self.measurements.is_empty() && !self.measurements.contains(measurement) {
+            return Some(SchemaViolation::UnknownMeasurement {
+                measurement: measurement.to_owned(),
+            });
+        }
+        if self.fields.is_empty() {
+            return None;
+        }
+        fields
+            .keys()
+            .find(|field| !self.fields.is_match(field))
+            .map(|field| SchemaViolation::UnknownField {
+                field: field.clone(),
+            })
+    }
+
+    /// Reports a point that doesn't match, returning whether it should be sent.
+    fn check(
+        &self,
+        component_type: &'static str,
+        measurement: &str,
+        fields: &HashMap<String, Field>,
+    ) -> bool {
+        match self.violation(measurement, fields) {
+            Some(error) => {
+                emit!(InfluxDBSchemaViolation {
+                    component_type,
+                    error,
+                });
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+/// Which tag keys are written, to keep the number of series in check.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TagFilterConfig {
+    /// When set, only these tag keys are kept.
+    pub allow: Option<Vec<String>>,
+    /// Tag keys that are dropped.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Writes tags that aren't allowed as string fields instead of dropping them.
+    #[serde(default)]
+    pub demote_to_fields: bool,
+}
+
+impl TagFilterConfig {
+    /// `None` when every tag is kept.
+    fn build(&self) -> Option<TagFilter> {
+        if self.allow.is_none() && self.deny.is_empty() {
+            return None;
+        }
+        Some(TagFilter {
+            allow: self
+                .allow
+                .as_ref()
+                .map(|allow| allow.iter().cloned().collect()),
+            deny: self.deny.iter().cloned().collect(),
+            demote_to_fields: self.demote_to_fields,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TagFilter {
+    allow: Option<HashSet<String>>,
+    deny: HashSet<String>,
+    demote_to_fields: bool,
+}
+
+impl TagFilter {
+    /// Removes the denied tags and those not allowed from `tags`. Tags that
+    /// aren't allowed are moved to `fields` when demoting, unless a field has
+    /// the same key.
+    fn apply(&self, tags: &mut BTreeMap<String, String>, fields: &mut HashMap<String, Field>) {
+        let removed = tags
+            .keys()
+            .filter(|key| {
+                self.deny.contains(*key)
+                    || self
+                        .allow
+                        .as_ref()
+                        .map_or(false, |allow| !allow.contains(*key))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in removed {
+            let value = tags.remove(&key).expect("key was just listed");
+            if self.demote_to_fields && !self.deny.contains(&key) {
+                fields.entry(key).or_insert(Field::String(value));
+            }
+        }
+    }
+}
+
+/// How many tag keys are remembered to have had a value truncated. Past
+/// that, truncations are only counted.
+const MAX_TRACKED_TRUNCATED_TAGS: usize = 1_000;
+
+/// Length limits on the tags of a point, so that a runaway value such as a
+/// stack trace can't blow up its series key. Lengths are counted as written,
+/// escapes included.
+#[derive(Clone, Debug)]
+struct TagLimits {
+    component_type: &'static str,
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    // Keys a value was truncated for, each is only logged the first time.
+    truncated: Arc<Mutex<HashSet<String>>>,
+}
+
+impl TagLimits {
+    fn new(
+        component_type: &'static str,
+        max_key_bytes: Option<usize>,
+        max_value_bytes: Option<usize>,
+    ) -> Option<Self> {
+        if max_key_bytes.is_none() && max_value_bytes.is_none() {
+            return None;
+        }
+        Some(Self {
+            component_type,
+            max_key_bytes,
+            max_value_bytes,
+            truncated: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    /// Drops the tags with keys over the limit, and truncates values over it.
+    /// A value truncated to nothing drops its tag, as empty tags aren't
+    /// written.
+    fn apply(&self, tags: &mut BTreeMap<String, String>) {
+        if let Some(max) = self.max_key_bytes {
+            let oversized = tags
+                .keys()
+                .filter(|key| tag_len(key) > max)
+                .cloned()
+                .collect::<Vec<_>>();
+            for key in oversized {
+                tags.remove(&key);
+                emit!(InfluxDBTagKeyDropped {
+                    component_type: self.component_type,
+                    bytes: tag_len(&key),
+                    key,
+                    max,
+                });
+            }
+        }
+
+        if let Some(max) = self.max_value_bytes {
+            for (key, value) in tags.iter_mut() {
+                let bytes = tag_len(value);
+                if bytes <= max {
+                    continue;
+                }
+                truncate_tag_value(value, max);
+                let first = {
+                    let mut truncated = self.truncated.lock().unwrap();
+                    !truncated.contains(key)
+                        && truncated.len() < MAX_TRACKED_TRUNCATED_TAGS
+                        && truncated.insert(key.clone())
+                };
+                emit!(InfluxDBTagValueTruncated {
+                    component_type: self.component_type,
+                    key,
+                    bytes,
+                    max,
+                    first,
+                });
+            }
+        }
+    }
+}
+
+/// Length of a tag key or value once escaped by `encode_string`.
+fn tag_len(value: &str) -> usize {
+    value.chars().map(tag_escaped_len).sum()
+}
+
+fn tag_escaped_len(c: char) -> usize {
+    match c {
+        '\n' | '\r' | '\t' => 0,
+        '\\' | ',' | ' ' | '=' => 2,
+        _ => c.len_utf8(),
+    }
+}
+
+/// Shortens `value` to the longest prefix whose characters add up to at most
+/// `max` bytes, measured by `len`. Returns whether anything was cut.
+///
+/// The value is only cut between characters, so the result is always valid
+/// UTF-8 however long the characters are. Every truncation goes through this,
+/// `len` accounts for the escaping of the context the value is written in.
+fn truncate_on_char_boundary(value: &mut String, max: usize, len: impl Fn(char) -> usize) -> bool {
+    let mut length = 0;
+    let end = value.char_indices().find_map(|(index, c)| {
+        length += len(c);
+        if length > max {
+            Some(index)
+        } else {
+            None
+        }
+    });
+    match end {
+        Some(end) => {
+            value.truncate(end);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Shortens `value` to at most `max` bytes once escaped, so it never ends in
+/// half an escape.
+fn truncate_tag_value(value: &mut String, max: usize) {
+    truncate_on_char_boundary(value, max, tag_escaped_len);
+}
+
+/// Records the size of each write request body before compression, warning
+/// about bodies above `warn_bytes`.
+#[derive(Clone, Copy, Debug)]
+struct BodySize {
+    component_type: &'static str,
+    warn_bytes: Option<usize>,
+}
+
+impl BodySize {
+    /// Returns whether the body was over the warning threshold.
+    fn record(self, bytes: usize) -> bool {
+        let event = InfluxDBRequestBodySize {
+            component_type: self.component_type,
+            bytes,
+            warn_bytes: self.warn_bytes,
+        };
+        let oversized = event.is_oversized();
+        emit!(event);
+        oversized
+    }
+}
+
+/// Where points that InfluxDB rejects for good are kept, so that they can be
+/// inspected and reprocessed.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    /// The file the rejected points are appended to, one JSON object each.
+    pub path: PathBuf,
+}
+
+impl DeadLetterConfig {
+    fn build(&self, component_type: &'static str) -> DeadLetter {
+        DeadLetter {
+            component_type,
+            path: Arc::new(self.path.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct DeadLetter {
+    component_type: &'static str,
+    path: Arc<PathBuf>,
+}
+
+impl DeadLetter {
+    /// Whether the points of a write answered with `status` were rejected
+    /// for good. Transient failures are retried instead.
+    fn is_rejection(status: StatusCode) -> bool {
+        status == StatusCode::BAD_REQUEST
+    }
+
+    /// Appends each line of the rejected `body` with the server's error.
+    fn write(&self, body: &[u8], status: StatusCode, error: &[u8]) {
+        match self.append(body, status, error) {
+            Ok(count) => emit!(InfluxDBDeadLetterWritten {
+                component_type: self.component_type,
+                path: &self.path,
+                count,
+            }),
+            Err(error) => emit!(InfluxDBDeadLetterFailed {
+                component_type: self.component_type,
+                path: &self.path,
+                error,
+            }),
+        }
+    }
+
+    fn append(&self, body: &[u8], status: StatusCode, error: &[u8]) -> std::io::Result<usize> {
+        let error = String::from_utf8_lossy(error);
+        let mut records = Vec::new();
+        let mut count = 0;
+        for line in String::from_utf8_lossy(body).lines() {
+            let record = serde_json::json!({
+                "line": line,
+                "status": status.as_u16(),
+                "error": error.trim(),
+            });
+            serde_json::to_writer(&mut records, &record)?;
+            records.push(b'\n');
+            count += 1;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.as_ref())?;
+        std::io::Write::write_all(&mut file, &records)?;
+        Ok(count)
+    }
+}
+
+/// Stops sending writes for a while after repeated failures.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed writes that open the breaker.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: usize,
+    /// How long writes are held back before one is let through to probe the
+    /// server.
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+fn default_failure_threshold() -> usize {
+    5
+}
+
+fn default_cooldown_secs() -> u64 {
+    30
+}
+
+impl CircuitBreakerConfig {
+    fn build(&self, component_type: &'static str) -> CircuitBreaker {
+        CircuitBreaker {
+            component_type,
+            failure_threshold: self.failure_threshold.max(1),
+            cooldown: Duration::from_secs(self.cooldown_secs),
+            state: Arc::new(Mutex::new(BreakerState {
+                failures: 0,
+                opened_at: None,
+                probing: false,
+            })),
+        }
+    }
+}
+
+/// The state of a circuit breaker, as reported by its internal metric.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    /// Writes are sent.
+    Closed,
+    /// Writes are held back until the cooldown is over.
+    Open,
+    /// A single write is sent to find out whether the server recovered.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    failures: usize,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+impl BreakerState {
+    fn circuit_state(&self) -> CircuitState {
+        match (self.opened_at, self.probing) {
+            (None, _) => CircuitState::Closed,
+            (Some(_), false) => CircuitState::Open,
+            (Some(_), true) => CircuitState::HalfOpen,
+        }
+    }
+}
+
+/// Counts consecutive failed writes, shared by the clones of a sink's service.
+#[derive(Clone, Debug)]
+struct CircuitBreaker {
+    component_type: &'static str,
+    failure_threshold: usize,
+    cooldown: Duration,
+    state: Arc<Mutex<BreakerState>>,
+}
+
+impl CircuitBreaker {
+    /// Lets a write through unless the breaker is open. Once the cooldown is
+    /// over, a single write is let through as a probe until its outcome is
+    /// recorded or it's released.
+    fn allow(&self, now: Instant) -> Option<BreakerPermit> {
+        let mut state = self.state.lock().unwrap();
+        let probe = match state.opened_at {
+            None => false,
+            Some(opened_at) if now.duration_since(opened_at) < self.cooldown => return None,
+            Some(_) if state.probing => return None,
+            Some(_) => {
+                state.probing = true;
+                self.changed(CircuitState::HalfOpen);
+                true
+            }
+        };
+        Some(BreakerPermit {
+            breaker: self.clone(),
+            probe,
+        })
+    }
+
+    fn record(&self, success: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let before = state.circuit_state();
+        if success {
+            state.failures = 0;
+            state.opened_at = None;
+        } else {
+            state.failures += 1;
+            if state.probing || state.failures >= self.failure_threshold {
+                state.opened_at = Some(now);
+            }
+        }
+        state.probing = false;
+
+        let after = state.circuit_state();
+        if after != before {
+            self.changed(after);
+        }
+    }
+
+    /// How long until `allow` may let a write through: the rest of the
+    /// cooldown, or while probing, until the probe's outcome is checked again.
+    fn retry_in(&self, now: Instant) -> Duration {
+        let state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => Duration::from_secs(0),
+            Some(_) if state.probing => BREAKER_PROBE_POLL_INTERVAL,
+            Some(opened_at) => self
+                .cooldown
+                .checked_sub(now.duration_since(opened_at))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Ends a probe without an outcome, so that the next write probes again.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.probing {
+            state.probing = false;
+            self.changed(state.circuit_state());
+        }
+    }
+
+    fn changed(&self, state: CircuitState) {
+        emit!(InfluxDBCircuitBreakerStateChanged {
+            component_type: self.component_type,
+            state,
+        });
+    }
+
+    #[cfg(test)]
+    fn circuit_state(&self) -> CircuitState {
+        self.state.lock().unwrap().circuit_state()
+    }
+}
+
+/// A write let through by `CircuitBreaker::allow`. A probe keeps the breaker
+/// half-open until it's recorded or released, so one dropped without either,
+/// as when the request times out, is recorded as a failure.
+struct BreakerPermit {
+    breaker: CircuitBreaker,
+    probe: bool,
+}
+
+impl BreakerPermit {
+    fn record(mut self, success: bool, now: Instant) {
+        self.probe = false;
+        self.breaker.record(success, now);
+    }
+
+    /// For a write that sent no request, which says nothing of the server.
+    fn release(mut self) {
+        if self.probe {
+            self.probe = false;
+            self.breaker.release();
+        }
+    }
+}
+
+impl Drop for BreakerPermit {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.record(false, Instant::now());
+        }
+    }
+}
+
+const BREAKER_PROBE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Holds writes back in a service's `poll_ready` while the breaker is open,
+/// so that they're sent once it half-opens rather than failed and retried.
+struct BreakerGate {
+    breaker: CircuitBreaker,
+    cooldown: Option<tokio::time::Delay>,
+    permit: Option<BreakerPermit>,
+}
+
+impl BreakerGate {
+    fn new(breaker: CircuitBreaker) -> Self {
+        Self {
+            breaker,
+            cooldown: None,
+            permit: None,
+        }
+    }
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context) -> std::task::Poll<()> {
+        while self.permit.is_none() {
+            if let Some(cooldown) = &mut self.cooldown {
+                futures::ready!(cooldown.poll_unpin(cx));
+                self.cooldown = None;
+            }
+            let now = Instant::now();
+            match self.breaker.allow(now) {
+                Some(permit) => self.permit = Some(permit),
+                None => self.cooldown = Some(tokio::time::delay_for(self.breaker.retry_in(now))),
+            }
+        }
+        std::task::Poll::Ready(())
+    }
+
+    /// The permit taken by `poll_ready` for the next write.
+    fn take(&mut self) -> Option<BreakerPermit> {
+        self.permit.take()
+    }
+}
+
+// A clone readies itself, so it mustn't share the permit.
+impl Clone for BreakerGate {
+    fn clone(&self) -> Self {
+        Self::new(self.breaker.clone())
+    }
+}
+
+impl Drop for BreakerGate {
+    fn drop(&mut self) {
+        // Taken without a write being sent, which says nothing of the server.
+        if let Some(permit) = self.permit.take() {
+            permit.release();
+        }
+    }
+}
+
+/// Caps the rate of retries across all the requests of a sink, so that many
+/// failing batches can't keep a recovering server down with a retry storm.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RetryBudgetConfig {
+    /// Retries allowed per second, on average.
+    pub retries_per_sec: f64,
+    /// Retries allowed at once after a quiet period, defaults to
+    /// `retries_per_sec`.
+    pub burst: Option<usize>,
+}
+
+impl RetryBudgetConfig {
+    fn build(&self, component_type: &'static str) -> RetryBudget {
+        let capacity = self
+            .burst
+            .map(|burst| burst as f64)
+            .unwrap_or_else(|| self.retries_per_sec.ceil())
+            .max(1.0);
+        RetryBudget {
+            component_type,
+            capacity,
+            refill_per_sec: self.retries_per_sec.max(0.0),
+            state: Arc::new(Mutex::new((capacity, Instant::now()))),
+        }
+    }
+}
+
+/// A token bucket shared by the clones of a sink's retry logic. Each retry
+/// takes a token, and tokens come back at `refill_per_sec`.
+#[derive(Clone, Debug)]
+struct RetryBudget {
+    component_type: &'static str,
+    capacity: f64,
+    refill_per_sec: f64,
+    // The tokens left, and when they were last refilled.
+    state: Arc<Mutex<(f64, Instant)>>,
+}
+
+impl RetryBudget {
+    /// Takes a token for one retry, returning whether there was one.
+    fn withdraw(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, refilled_at) = &mut *state;
+        if now > *refilled_at {
+            let elapsed = now.duration_since(*refilled_at).as_secs_f64();
+            *tokens = (*tokens + elapsed * self.refill_per_sec).min(self.capacity);
+            *refilled_at = now;
+        }
+
+        let granted = *tokens >= 1.0;
+        if granted {
+            *tokens -= 1.0;
+        }
+        emit!(InfluxDBRetryBudgetWithdrawn {
+            component_type: self.component_type,
+            remaining: *tokens,
+            granted,
+        });
+        granted
+    }
+}
+
+/// The `Authorization` header of a sink's requests. With `token_reload_secs`
+/// the token file is read again once that long has passed, so a rotated
+/// secret is picked up without a restart. A failed read keeps the previous
+/// token.
+#[derive(Clone, Debug)]
+enum Authorization {
+    Fixed(Option<String>),
+    Reloaded(Arc<Mutex<TokenReload>>),
+}
+
+#[derive(Debug)]
+struct TokenReload {
+    component_type: &'static str,
+    path: PathBuf,
+    interval: Duration,
+    header: String,
+    read_at: Instant,
+}
+
+impl Authorization {
+    fn new(settings: &dyn InfluxDBSettings, component_type: &'static str) -> Self {
+        match (settings.token_reload(), settings.authorization()) {
+            (Some((path, interval)), Some(header)) => {
+                Authorization::Reloaded(Arc::new(Mutex::new(TokenReload {
+                    component_type,
+                    path,
+                    interval,
+                    header,
+                    read_at: Instant::now(),
+                })))
+            }
+            (_, header) => Authorization::Fixed(header),
+        }
+    }
+
+    fn get(&self) -> Option<String> {
+        self.get_at(Instant::now())
+    }
+
+    fn get_at(&self, now: Instant) -> Option<String> {
+        let reload = match self {
+            Authorization::Fixed(header) => return header.clone(),
+            Authorization::Reloaded(reload) => reload,
+        };
+        let mut reload = reload.lock().unwrap();
+        if now.saturating_duration_since(reload.read_at) >= reload.interval {
+            reload.read_at = now;
+            let read = read_token(&reload.path).and_then(|token| {
+                non_empty("token_file", &token)?;
+                Ok(token)
+            });
+            match read {
+                Ok(token) => {
+                    let header = format!("Token {}", token);
+                    if header != reload.header {
+                        emit!(InfluxDBTokenReloaded {
+                            component_type: reload.component_type,
+                        });
+                        reload.header = header;
+                    }
+                }
+                Err(error) => emit!(InfluxDBTokenReloadFailed {
+                    component_type: reload.component_type,
+                    path: &reload.path,
+                    error: error.into(),
+                }),
+            }
+        }
+        Some(reload.header.clone())
+    }
+}
+
+/// Points younger than this are never deleted by `cleanup`, so that a typo
+/// can't delete what is still being written.
+const MIN_CLEANUP_AGE_SECS: u64 = 60 * 60;
+const MIN_CLEANUP_INTERVAL_SECS: u64 = 60;
+const DEFAULT_CLEANUP_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Periodically deletes the points of one measurement that are older than
+/// `older_than_secs`, for retention the bucket's own can't express. Only
+/// InfluxDB v2 has the delete API.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CleanupConfig {
+    /// The only measurement points are deleted from.
+    pub measurement: String,
+    /// How old a point must be to be deleted, at least an hour.
+    pub older_than_secs: u64,
+    /// How often old points are deleted, at most once a minute.
+    #[serde(default = "default_cleanup_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_cleanup_interval_secs() -> u64 {
+    DEFAULT_CLEANUP_INTERVAL_SECS
+}
+
+impl CleanupConfig {
+    fn build(
+        &self,
+        settings: &dyn InfluxDBSettings,
+        endpoint: String,
+        user_agent: Option<HeaderValue>,
+        component_type: &'static str,
+    ) -> crate::Result<Cleanup> {
+        non_empty("cleanup.measurement", &self.measurement)?;
+        if self.older_than_secs < MIN_CLEANUP_AGE_SECS {
+            return Err(ConfigError::CleanupTooRecent {
+                min_secs: MIN_CLEANUP_AGE_SECS,
+            }
+            .into());
+        }
+        if self.interval_secs < MIN_CLEANUP_INTERVAL_SECS {
+            return Err(ConfigError::CleanupTooFrequent {
+                min_secs: MIN_CLEANUP_INTERVAL_SECS,
+            }
+            .into());
+        }
+        let uri = settings
+            .delete_uri(endpoint)
+            .ok_or(ConfigError::CleanupUnsupported)??;
+
+        Ok(Cleanup {
+            component_type,
+            uri,
+            authorization: Authorization::new(settings, component_type),
+            user_agent,
+            measurement: self.measurement.clone(),
+            older_than: chrono::Duration::seconds(
+                self.older_than_secs.min(i64::MAX as u64 / 1000) as i64
+            ),
+            interval: Duration::from_secs(self.interval_secs),
+        })
+    }
+}
+
+/// The `cleanup` of a sink, deleting with the same credentials as its writes.
+#[derive(Debug)]
+struct Cleanup {
+    component_type: &'static str,
+    uri: Uri,
+    authorization: Authorization,
+    user_agent: Option<HeaderValue>,
+    measurement: String,
+    older_than: chrono::Duration,
+    interval: Duration,
+}
+
+impl Cleanup {
+    /// The request deleting the points of the measurement written before
+    /// `stop`.
+    // https://v2.docs.influxdata.com/v2.0/api/#operation/PostDelete
+    fn request(&self, stop: DateTime<Utc>) -> hyper13::Request<hyper13::Body> {
+        let predicate = format!(
+            "_measurement=\"{}\"",
+            self.measurement.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        let body = serde_json::json!({
+            "start": Utc.timestamp(0, 0).to_rfc3339_opts(SecondsFormat::Secs, true),
+            "stop": stop.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "predicate": predicate,
+        });
+
+        let mut builder =
+            hyper13::Request::post(self.uri.clone()).header("Content-Type", "application/json");
+        if let Some(authorization) = self.authorization.get() {
+            builder = builder.header("Authorization", authorization);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.header(USER_AGENT, user_agent.clone());
+        }
+        builder.body(hyper13::Body::from(body.to_string())).unwrap()
+    }
+
+    /// Deletes the points that are old enough at `now`.
+    async fn delete(&self, client: &mut HttpClient, now: DateTime<Utc>) -> crate::Result<()> {
+        // Nothing can be older than the start of time.
+        let stop = match now.checked_sub_signed(self.older_than) {
+            Some(stop) => stop,
+            None => return Ok(()),
+        };
+
+        let (parts, body) = client.send(self.request(stop)).await?.into_parts();
+        if !parts.status.is_success() {
+            let body = ResponseBody::read(body, DEFAULT_MAX_RESPONSE_BYTES).await?;
+            return Err(CleanupError::DeleteFailed {
+                status: parts.status,
+                body: body.to_string(),
+            }
+            .into());
+        }
+        emit!(InfluxDBCleanupCompleted {
+            component_type: self.component_type,
+            measurement: &self.measurement,
+            stop: &stop.to_rfc3339_opts(SecondsFormat::Secs, true),
+        });
+        Ok(())
+    }
+
+    /// Deletes every `interval`, the first time one interval after the sink
+    /// is built, until the returned trigger is dropped.
+    fn spawn(self, mut client: HttpClient, exec: &TaskExecutor) -> Trigger {
+        let (trigger, tripwire) = Tripwire::new();
+        let task = async move {
+            let start = tokio::time::Instant::now() + self.interval;
+            let mut interval = tokio::time::interval_at(start, self.interval);
+            loop {
+                interval.tick().await;
+                if let Err(error) = self.delete(&mut client, Utc::now()).await {
+                    emit!(InfluxDBCleanupFailed {
+                        component_type: self.component_type,
+                        measurement: &self.measurement,
+                        error,
+                    });
+                }
+            }
+        };
+        exec.spawn_std(async move {
+            futures::future::select(Box::pin(task), tripwire.compat()).await;
+        });
+        trigger
+    }
+}
+
+#[derive(Debug, Snafu)]
+enum ConfigError {
+    #[snafu(display("InfluxDB v1, v2 or v3 should be configured as endpoint."))]
+    MissingConfiguration,
+    #[snafu(display(
+        "Unclear settings. Both version configured v1: {:?}, v2: {:?}.",
+        v1_settings,
+        v2_settings
+    ))]
+    BothConfiguration {
+        v1_settings: InfluxDB1Settings,
+        v2_settings: InfluxDB2Settings,
+    },
+    #[snafu(display(
+        "`database` and `token` are used by both InfluxDB v1 and v3, set `api_version` to \"v1\" or \"v3\"."
+    ))]
+    AmbiguousVersion,
+    #[snafu(display(
+        "`api_version = \"{}\"` can't be combined with InfluxDB {} settings.",
+        api_version,
+        other
+    ))]
+    VersionMismatch {
+        api_version: &'static str,
+        other: &'static str,
+    },
+    #[snafu(display("InfluxDB v1 `token` can't be combined with `username` or `password`."))]
+    BothCredentialsAndToken,
+    #[snafu(display("InfluxDB setting `{}` must not be empty.", field))]
+    EmptySetting { field: &'static str },
+    #[snafu(display("InfluxDB {} settings are missing `{}`.", version, field))]
+    MissingSetting {
+        version: &'static str,
+        field: &'static str,
+    },
+    #[snafu(display("Invalid `user_agent`: {}.", source))]
+    InvalidUserAgent { source: InvalidHeaderValue },
+    #[snafu(display("Invalid `idempotency_header`: {}.", source))]
+    InvalidIdempotencyHeader { source: InvalidHeaderName },
+    #[snafu(display(
+        "`compression_level` {} is out of range for {}, which takes {} to {}.",
+        level,
+        compression,
+        min,
+        max
+    ))]
+    InvalidCompressionLevel {
+        compression: &'static str,
+        level: i32,
+        min: i32,
+        max: i32,
+    },
+    #[snafu(display(
+        "`compression = \"zstd\"` needs Vector to be built with the `sinks-influxdb-zstd` feature."
+    ))]
+    ZstdUnsupported,
+    #[snafu(display("`distribution.quantiles` must be between 0 and 1, got {}.", quantile))]
+    InvalidQuantile { quantile: f64 },
+    #[snafu(display("`max_buffered_events` must be at least 1."))]
+    ZeroMaxBufferedEvents,
+    #[snafu(display("Invalid schema field pattern: {}.", source))]
+    InvalidFieldPattern { source: regex::Error },
+    #[snafu(display("Only one of `token` and `token_file` can be set."))]
+    BothTokenAndTokenFile,
+    #[snafu(display("`token_dir` can't be combined with `token` or `token_file`."))]
+    BothTokenAndTokenDir,
+    #[snafu(display("`token_dir` and `token_key` must be set together."))]
+    TokenDirWithoutKey,
+    #[snafu(display("`token_reload_secs` needs `token_file` or `token_dir` to read from."))]
+    TokenReloadWithoutFile,
+    #[snafu(display("Only one of `bucket` and `bucket_id` can be set."))]
+    BothBucketAndBucketId,
+    #[snafu(display("`cleanup` is only supported by InfluxDB 2 with an `org`."))]
+    CleanupUnsupported,
+    #[snafu(display("`cleanup.older_than_secs` must be at least {}.", min_secs))]
+    CleanupTooRecent { min_secs: u64 },
+    #[snafu(display("`cleanup.interval_secs` must be at least {}.", min_secs))]
+    CleanupTooFrequent { min_secs: u64 },
+    #[snafu(display(
+        "InfluxDB v1 `database` {:?} should be `database` or `database/retention_policy`.",
+        database
+    ))]
+    InvalidDatabase { database: String },
+    #[snafu(display(
+        "InfluxDB v1 `database` {:?} names a retention policy, which can't be combined with `retention_policy_name`.",
+        database
+    ))]
+    BothRetentionPolicies { database: String },
+    #[snafu(display("Could not read InfluxDB token from {:?}: {}.", path, source))]
+    TokenFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display(
+        "Could not read InfluxDB token from {:?}: the file doesn't exist, check that the secret is mounted there.",
+        path
+    ))]
+    TokenFileMissing { path: PathBuf },
+    #[snafu(display(
+        "Could not read InfluxDB token from {:?}: permission denied, check that the user Vector runs as can read it.",
+        path
+    ))]
+    TokenFileNotReadable { path: PathBuf },
+}
+
+#[derive(Debug, Snafu)]
+enum EndpointError {
+    #[snafu(display(
+        "InfluxDB endpoint {:?} has no scheme, try \"http://{}\".",
+        endpoint,
+        endpoint
+    ))]
+    MissingScheme { endpoint: String },
+    #[snafu(display("InfluxDB endpoint {:?} has an invalid host: {}.", endpoint, reason))]
+    InvalidHost { endpoint: String, reason: String },
+    #[snafu(display("InfluxDB endpoint {:?} can't be parsed: {}.", endpoint, source))]
+    InvalidEndpoint {
+        endpoint: String,
+        source: http02::uri::InvalidUri,
+    },
+    #[snafu(display(
+        "InfluxDB `path_prefix` {:?} must be a path, without a query or fragment.",
+        path_prefix
+    ))]
+    InvalidPathPrefix { path_prefix: String },
+}
+
+fn non_empty(field: &'static str, value: &str) -> Result<(), ConfigError> {
+    if value.is_empty() {
+        Err(ConfigError::EmptySetting { field })
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct InfluxDB1Settings {
+    database: Option<String>,
+    consistency: Option<String>,
+    retention_policy_name: Option<String>,
+    rp_tag: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    // Taken from the `token` option by `influxdb_settings` with
+    // `api_version = "v1"`, the key belongs to the v2 settings.
+    #[serde(skip)]
+    token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct InfluxDB2Settings {
+    org: Option<String>,
+    bucket: Option<String>,
+    // Bucket IDs are globally unique, so `org` may be left out with one.
+    bucket_id: Option<String>,
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    // A mounted secret directory holds one file per key, e.g. a Kubernetes
+    // secret volume.
+    token_dir: Option<PathBuf>,
+    token_key: Option<String>,
+    token_reload_secs: Option<u64>,
+    bucket_tag: Option<String>,
+}
+
+/// The write API used, set with `api_version`. Without it the version is told
+/// from the options set.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiVersion {
+    V1,
+    V2,
+    V3,
+}
+
+impl ApiVersion {
+    fn as_str(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "v1",
+            ApiVersion::V2 => "v2",
+            ApiVersion::V3 => "v3",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InfluxDB3Settings {
+    database: String,
+    token: String,
+}
+
+/// Builds [`InfluxDB1Settings`] for sinks configured in code, leaving the
+/// options that aren't set unset:
+///
+/// ```
+/// use vector::sinks::influxdb::{metrics::InfluxDBConfig, InfluxDB1Settings};
+///
+/// let settings = InfluxDB1Settings::builder()
+///     .database("vector")
+///     .retention_policy_name("autogen")
+///     .username("writer")
+///     .password("secret")
+///     .build();
+/// let config = InfluxDBConfig {
+///     endpoint: "http://localhost:8086".to_owned(),
+///     influxdb1_settings: Some(settings),
+///     ..Default::default()
+/// };
+/// ```
+///
+/// The settings are validated when the sink is built.
+#[derive(Debug, Clone, Default)]
+pub struct InfluxDB1SettingsBuilder {
+    settings: InfluxDB1Settings,
+}
+
+impl InfluxDB1Settings {
+    pub fn builder() -> InfluxDB1SettingsBuilder {
+        InfluxDB1SettingsBuilder::default()
+    }
+}
+
+impl InfluxDB1SettingsBuilder {
+    pub fn database(mut self, database: impl Into<String>) -> Self {
+        self.settings.database = Some(database.into());
+        self
+    }
+
+    pub fn consistency(mut self, consistency: impl Into<String>) -> Self {
+        self.settings.consistency = Some(consistency.into());
+        self
+    }
+
+    pub fn retention_policy_name(mut self, retention_policy_name: impl Into<String>) -> Self {
+        self.settings.retention_policy_name = Some(retention_policy_name.into());
+        self
+    }
+
+    pub fn rp_tag(mut self, rp_tag: impl Into<String>) -> Self {
+        self.settings.rp_tag = Some(rp_tag.into());
+        self
+    }
+
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.settings.username = Some(username.into());
+        self
+    }
+
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.settings.password = Some(password.into());
+        self
+    }
+
+    /// A JWT sent as a bearer token, instead of `username` and `password`.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.settings.token = Some(token.into());
+        self
+    }
+
+    pub fn build(self) -> InfluxDB1Settings {
+        self.settings
+    }
+}
+
+/// Builds [`InfluxDB2Settings`] for sinks configured in code, leaving the
+/// options that aren't set unset:
+///
+/// ```
+/// use vector::sinks::influxdb::{logs::InfluxDBLogsConfig, InfluxDB2Settings};
+///
+/// let settings = InfluxDB2Settings::builder()
+///     .org("my-org")
+///     .bucket("my-bucket")
+///     .token("my-token")
+///     .build();
+/// let config = InfluxDBLogsConfig {
+///     namespace: "vector".to_owned(),
+///     endpoint: "http://localhost:9999".to_owned(),
+///     influxdb2_settings: Some(settings),
+///     ..Default::default()
+/// };
+/// ```
+///
+/// The settings are validated when the sink is built.
+#[derive(Debug, Clone, Default)]
+pub struct InfluxDB2SettingsBuilder {
+    settings: InfluxDB2Settings,
+}
+
+impl InfluxDB2Settings {
+    pub fn builder() -> InfluxDB2SettingsBuilder {
+        InfluxDB2SettingsBuilder::default()
+    }
+}
+
+impl InfluxDB2SettingsBuilder {
+    pub fn org(mut self, org: impl Into<String>) -> Self {
+        self.settings.org = Some(org.into());
+        self
+    }
+
+    pub fn bucket(mut self, bucket: impl Into<String>) -> Self {
+        self.settings.bucket = Some(bucket.into());
+        self
+    }
+
+    pub fn bucket_id(mut self, bucket_id: impl Into<String>) -> Self {
+        self.settings.bucket_id = Some(bucket_id.into());
+        self
+    }
+
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.settings.token = Some(token.into());
+        self
+    }
+
+    pub fn token_file(mut self, token_file: impl Into<PathBuf>) -> Self {
+        self.settings.token_file = Some(token_file.into());
+        self
+    }
+
+    pub fn token_dir(mut self, token_dir: impl Into<PathBuf>) -> Self {
+        self.settings.token_dir = Some(token_dir.into());
+        self
+    }
+
+    pub fn token_key(mut self, token_key: impl Into<String>) -> Self {
+        self.settings.token_key = Some(token_key.into());
+        self
+    }
+
+    pub fn token_reload_secs(mut self, token_reload_secs: u64) -> Self {
+        self.settings.token_reload_secs = Some(token_reload_secs);
+        self
+    }
+
+    pub fn bucket_tag(mut self, bucket_tag: impl Into<String>) -> Self {
+        self.settings.bucket_tag = Some(bucket_tag.into());
+        self
+    }
+
+    pub fn build(self) -> InfluxDB2Settings {
+        self.settings
+    }
+}
+
+trait InfluxDBSettings: Send + Sync {
+    fn write_uri(self: &Self, endpoint: String, precision: Precision) -> crate::Result<Uri>;
+    fn write_uri2(
+        self: &Self,
+        endpoint: String,
+        precision: Precision,
+    ) -> crate::Result<http02::Uri> {
+        let uri = self.write_uri(endpoint, precision)?;
+        Ok(format!("{}", uri)
+            .parse::<http02::Uri>()
+            .context(super::UriParseError2)?)
+    }
+    fn healthcheck_uri(self: &Self, endpoint: String) -> crate::Result<Uri>;
+    /// Where the healthcheck's Flux probe is sent, `None` without Flux support.
+    fn query_uri(self: &Self, _endpoint: String) -> Option<crate::Result<Uri>> {
+        None
+    }
+    /// Where `cleanup` deletes, `None` without a delete API.
+    fn delete_uri(self: &Self, _endpoint: String) -> Option<crate::Result<Uri>> {
+        None
+    }
+    /// Query for the points of `measurement` tagged `run`, used by `validate`
+    /// to read back its test point. `None` without a query API.
+    fn read_back_request(
+        self: &Self,
+        _endpoint: String,
+        _measurement: &str,
+        _run: &str,
+    ) -> Option<crate::Result<hyper13::Request<hyper13::Body>>> {
+        None
+    }
+    fn token(self: &Self) -> String;
+    /// Value of the `Authorization` header sent with writes, if any.
+    fn authorization(self: &Self) -> Option<String> {
+        Some(format!("Token {}", self.token()))
+    }
+    /// The file the token is read from again and how often, if it's reloaded.
+    fn token_reload(self: &Self) -> Option<(PathBuf, Duration)> {
+        None
+    }
+    /// Destination selected by the tags of a metric, `None` for the configured one.
+    fn route(self: &Self, _tags: Option<&BTreeMap<String, String>>) -> Option<String> {
+        None
+    }
+    fn routed_write_uri(
+        self: &Self,
+        endpoint: String,
+        precision: Precision,
+        _route: &str,
+    ) -> crate::Result<Uri> {
+        self.write_uri(endpoint, precision)
+    }
+}
+
+fn required(
+    version: &'static str,
+    field: &'static str,
+    value: &Option<String>,
+) -> Result<(), ConfigError> {
+    match value {
+        Some(value) => non_empty(field, value),
+        None => Err(ConfigError::MissingSetting { version, field }),
+    }
+}
+
+impl InfluxDB1Settings {
+    fn validate(&self) -> Result<(), ConfigError> {
+        required("v1", "database", &self.database)?;
+        match &self.token {
+            Some(_) if self.username.is_some() || self.password.is_some() => {
+                Err(ConfigError::BothCredentialsAndToken)
+            }
+            Some(token) => non_empty("token", token),
+            None => Ok(()),
+        }
+    }
+
+    /// Splits a `database` written as `db/rp`, as some InfluxDB tooling
+    /// accepts, into the database and the retention policy.
+    fn split_database(self) -> Result<Self, ConfigError> {
+        let database = match &self.database {
+            Some(database) if database.contains('/') => database,
+            _ => return Ok(self),
+        };
+
+        let mut parts = database.splitn(2, '/');
+        let (db, rp) = match (parts.next(), parts.next()) {
+            (Some(db), Some(rp)) if !db.is_empty() && !rp.is_empty() && !rp.contains('/') => {
+                (db.to_owned(), rp.to_owned())
+            }
+            _ => {
+                return Err(ConfigError::InvalidDatabase {
+                    database: database.clone(),
+                })
+            }
+        };
+        if self.retention_policy_name.is_some() {
+            return Err(ConfigError::BothRetentionPolicies {
+                database: database.clone(),
+            });
+        }
+
+        Ok(InfluxDB1Settings {
+            database: Some(db),
+            retention_policy_name: Some(rp),
+            ..self
+        })
+    }
+
+    /// Whether any of the options only v1 has are set.
+    fn has_options(&self) -> bool {
+        self.consistency.is_some()
+            || self.retention_policy_name.is_some()
+            || self.rp_tag.is_some()
+            || self.username.is_some()
+            || self.password.is_some()
+            || self.token.is_some()
+    }
+}
+
+impl InfluxDB2Settings {
+    fn validate(&self) -> Result<(), ConfigError> {
+        match (&self.bucket, &self.bucket_id) {
+            (Some(_), Some(_)) => return Err(ConfigError::BothBucketAndBucketId),
+            // Routed writes name their bucket, which needs the org.
+            (None, Some(bucket_id)) if self.bucket_tag.is_none() => {
+                non_empty("bucket_id", bucket_id)?;
+                if let Some(org) = &self.org {
+                    non_empty("org", org)?;
+                }
+            }
+            (_, bucket_id) => {
+                required("v2", "org", &self.org)?;
+                match bucket_id {
+                    Some(bucket_id) => non_empty("bucket_id", bucket_id)?,
+                    None => required("v2", "bucket", &self.bucket)?,
+                }
+            }
+        }
+        if self.token_dir.is_some() != self.token_key.is_some() {
+            return Err(ConfigError::TokenDirWithoutKey);
+        }
+        if let Some(token_key) = &self.token_key {
+            non_empty("token_key", token_key)?;
+            let has_token = self.token.as_ref().map_or(false, |token| !token.is_empty());
+            if has_token || self.token_file.is_some() {
+                return Err(ConfigError::BothTokenAndTokenDir);
+            }
+        }
+        if self.token_reload_secs.is_some() && self.token_path().is_none() {
+            return Err(ConfigError::TokenReloadWithoutFile);
+        }
+        match (&self.token, self.token_path()) {
+            (Some(token), Some(_)) if !token.is_empty() => Err(ConfigError::BothTokenAndTokenFile),
+            (_, Some(_)) => Ok(()),
+            (token, None) => required("v2", "token", token),
+        }
+    }
+
+    /// The file holding the token, `token_file` or `token_key` in `token_dir`.
+    fn token_path(&self) -> Option<PathBuf> {
+        match (&self.token_file, &self.token_dir, &self.token_key) {
+            (Some(token_file), _, _) => Some(token_file.clone()),
+            (None, Some(token_dir), Some(token_key)) => Some(token_dir.join(token_key)),
+            _ => None,
+        }
+    }
+
+    /// Whether any of the options only v2 has are set.
+    fn has_options(&self) -> bool {
+        self.org.is_some()
+            || self.bucket.is_some()
+            || self.bucket_id.is_some()
+            || self.token_file.is_some()
+            || self.token_dir.is_some()
+            || self.token_key.is_some()
+            || self.token_reload_secs.is_some()
+            || self.bucket_tag.is_some()
+    }
+
+    /// Replaces the token with the contents of its file, if set.
+    fn resolve_token(self) -> Result<Self, ConfigError> {
+        let path = match self.token_path() {
+            Some(path) => path,
+            None => return Ok(self),
+        };
+        let token = read_token(&path)?;
+        non_empty("token_file", &token)?;
+        Ok(InfluxDB2Settings {
+            token: Some(token),
+            ..self
+        })
+    }
+}
+
+/// Reads a token from `path` without its trailing newline, naming the likely
+/// cause when the file is missing or can't be read.
+fn read_token(path: &Path) -> Result<String, ConfigError> {
+    let token = std::fs::read_to_string(path).map_err(|source| {
+        let path = path.to_owned();
+        match source.kind() {
+            std::io::ErrorKind::NotFound => ConfigError::TokenFileMissing { path },
+            std::io::ErrorKind::PermissionDenied => ConfigError::TokenFileNotReadable { path },
+            _ => ConfigError::TokenFile { path, source },
+        }
+    })?;
+    Ok(token.trim_end().to_owned())
+}
+
+impl InfluxDB3Settings {
+    fn validate(&self) -> Result<(), ConfigError> {
+        non_empty("database", &self.database)?;
+        non_empty("token", &self.token)
+    }
+}
+
+impl InfluxDBSettings for InfluxDB1Settings {
+    fn write_uri(self: &Self, endpoint: String, precision: Precision) -> crate::Result<Uri> {
+        encode_uri(
+            &endpoint,
+            "write",
+            &[
+                ("consistency", self.consistency.clone()),
+                ("db", self.database.clone()),
+                ("rp", self.retention_policy_name.clone()),
+                ("p", self.password.clone()),
+                ("u", self.username.clone()),
+                ("precision", Some(precision.as_str().to_owned())),
+            ],
+        )
+    }
+
+    fn healthcheck_uri(self: &Self, endpoint: String) -> crate::Result<Uri> {
+        encode_uri(&endpoint, "ping", &[])
+    }
+
+    // https://docs.influxdata.com/influxdb/v1.8/tools/api/#query-http-endpoint
+    fn read_back_request(
+        self: &Self,
+        endpoint: String,
+        measurement: &str,
+        run: &str,
+    ) -> Option<crate::Result<hyper13::Request<hyper13::Body>>> {
+        let query = format!(
+            "SELECT * FROM {} WHERE \"run\" = '{}'",
+            double_quoted(measurement),
+            run.replace('\\', "\\\\").replace('\'', "\\'")
+        );
+        let uri = encode_uri(
+            &endpoint,
+            "query",
+            &[
+                ("db", self.database.clone()),
+                ("rp", self.retention_policy_name.clone()),
+                ("p", self.password.clone()),
+                ("u", self.username.clone()),
+                ("q", Some(query)),
+            ],
+        );
+        Some(uri.map(|uri| {
+            hyper13::Request::get(uri)
+                .body(hyper13::Body::empty())
+                .unwrap()
+        }))
+    }
+
+    fn token(self: &Self) -> String {
+        self.token.clone().unwrap_or_default()
+    }
+
+    // https://docs.influxdata.com/influxdb/v1.8/administration/authentication_and_authorization/#authenticate-using-jwt-tokens
+    fn authorization(self: &Self) -> Option<String> {
+        self.token.as_ref().map(|token| format!("Bearer {}", token))
+    }
+
+    fn route(self: &Self, tags: Option<&BTreeMap<String, String>>) -> Option<String> {
+        let rp_tag = self.rp_tag.as_ref()?;
+        tags?.get(rp_tag).cloned()
+    }
+
+    fn routed_write_uri(
+        self: &Self,
+        endpoint: String,
+        precision: Precision,
+        retention_policy_name: &str,
+    ) -> crate::Result<Uri> {
+        InfluxDB1Settings {
+            retention_policy_name: Some(retention_policy_name.to_owned()),
+            ..self.clone()
+        }
+        .write_uri(endpoint, precision)
+    }
+}
+
+impl InfluxDBSettings for InfluxDB2Settings {
+    fn write_uri(self: &Self, endpoint: String, precision: Precision) -> crate::Result<Uri> {
+        encode_uri(
+            &endpoint,
+            "api/v2/write",
+            &[
+                ("org", self.org.clone()),
+                // The write API takes either the name or the ID of a bucket.
+                (
+                    "bucket",
+                    self.bucket.clone().or_else(|| self.bucket_id.clone()),
+                ),
+                ("precision", Some(precision.as_str().to_owned())),
+            ],
+        )
+    }
+
+    fn healthcheck_uri(self: &Self, endpoint: String) -> crate::Result<Uri> {
+        encode_uri(&endpoint, "health", &[])
+    }
+
+    fn query_uri(self: &Self, endpoint: String) -> Option<crate::Result<Uri>> {
+        // Queries can't be made without the org.
+        let org = self.org.clone()?;
+        Some(encode_uri(&endpoint, "api/v2/query", &[("org", Some(org))]))
+    }
+
+    fn read_back_request(
+        self: &Self,
+        endpoint: String,
+        measurement: &str,
+        run: &str,
+    ) -> Option<crate::Result<hyper13::Request<hyper13::Body>>> {
+        let uri = self.query_uri(endpoint)?;
+        let from = match (&self.bucket, &self.bucket_id) {
+            (Some(bucket), _) => format!("bucket: {}", double_quoted(bucket)),
+            (None, Some(bucket_id)) => format!("bucketID: {}", double_quoted(bucket_id)),
+            (None, None) => return None,
+        };
+        let query = format!(
+            "from({}) |> range(start: -1h) |> filter(fn: (r) => r._measurement == {} and r.run == {})",
+            from,
+            double_quoted(measurement),
+            double_quoted(run)
+        );
+        Some(uri.map(|uri| {
+            hyper13::Request::post(uri)
+                .header("Content-Type", "application/vnd.flux")
+                .header("Accept", "application/csv")
+                .body(hyper13::Body::from(query))
+                .unwrap()
+        }))
+    }
+
+    fn delete_uri(self: &Self, endpoint: String) -> Option<crate::Result<Uri>> {
+        // Deletes can't be made without the org either.
+        let org = self.org.clone()?;
+        Some(encode_uri(
+            &endpoint,
+            "api/v2/delete",
+            &[
+                ("org", Some(org)),
+                ("bucket", self.bucket.clone()),
+                ("bucketID", self.bucket_id.clone()),
+            ],
+        ))
+    }
+
+    fn token(self: &Self) -> String {
+        self.token.clone().unwrap_or_default()
+    }
+
+    fn token_reload(self: &Self) -> Option<(PathBuf, Duration)> {
+        let secs = self.token_reload_secs?;
+        Some((self.token_path()?, Duration::from_secs(secs)))
+    }
+
+    fn route(self: &Self, tags: Option<&BTreeMap<String, String>>) -> Option<String> {
+        let bucket_tag = self.bucket_tag.as_ref()?;
+        tags?.get(bucket_tag).cloned()
+    }
+
+    fn routed_write_uri(
+        self: &Self,
+        endpoint: String,
+        precision: Precision,
+        bucket: &str,
+    ) -> crate::Result<Uri> {
+        InfluxDB2Settings {
+            bucket: Some(bucket.to_owned()),
+            bucket_id: None,
+            ..self.clone()
+        }
+        .write_uri(endpoint, precision)
+    }
+}
+
+// V3: https://docs.influxdata.com/influxdb3/core/write-data/http-api/v3-write-lp/
+impl InfluxDBSettings for InfluxDB3Settings {
+    fn write_uri(self: &Self, endpoint: String, precision: Precision) -> crate::Result<Uri> {
+        encode_uri(
+            &endpoint,
+            "api/v3/write_lp",
+            &[
+                ("db", Some(self.database.clone())),
+                ("precision", Some(precision.as_v3_str().to_owned())),
+            ],
+        )
+    }
+
+    fn healthcheck_uri(self: &Self, endpoint: String) -> crate::Result<Uri> {
+        encode_uri(&endpoint, "health", &[])
+    }
+
+    // https://docs.influxdata.com/influxdb3/core/api/v3/#operation/GetExecuteQuerySQL
+    fn read_back_request(
+        self: &Self,
+        endpoint: String,
+        measurement: &str,
+        run: &str,
+    ) -> Option<crate::Result<hyper13::Request<hyper13::Body>>> {
+        let query = format!(
+            "SELECT * FROM \"{}\" WHERE \"run\" = '{}'",
+            measurement.replace('"', "\"\""),
+            run.replace('\'', "''")
+        );
+        let uri = encode_uri(
+            &endpoint,
+            "api/v3/query_sql",
+            &[
+                ("db", Some(self.database.clone())),
+                ("q", Some(query)),
+                ("format", Some("json".to_owned())),
+            ],
+        );
+        Some(uri.map(|uri| {
+            hyper13::Request::get(uri)
+                .body(hyper13::Body::empty())
+                .unwrap()
+        }))
+    }
+
+    fn token(self: &Self) -> String {
+        self.token.clone()
+    }
+}
+
+/// `value` in double quotes, escaped with backslashes, for Flux string
+/// literals and InfluxQL identifiers.
+fn double_quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn influxdb_settings(
+    api_version: Option<ApiVersion>,
+    influxdb1_settings: Option<InfluxDB1Settings>,
+    influxdb2_settings: Option<InfluxDB2Settings>,
+    influxdb3_settings: Option<InfluxDB3Settings>,
+) -> Result<Box<dyn InfluxDBSettings>, crate::Error> {
+    // Serde hands every key to a single flattened struct, so the `database` and
+    // `token` of a v3 config arrive in the v1 and v2 settings. All their fields
+    // are optional, and the version meant is worked out from `api_version` or
+    // the ones set. This also lets a partially configured version report the
+    // field it's missing.
+    let influxdb1_settings =
+        influxdb1_settings.filter(|settings| settings.database.is_some() || settings.has_options());
+    let influxdb2_settings =
+        influxdb2_settings.filter(|settings| settings.token.is_some() || settings.has_options());
+
+    let mismatch = |api_version: ApiVersion, other: &'static str| ConfigError::VersionMismatch {
+        api_version: api_version.as_str(),
+        other,
+    };
+    let (influxdb1_settings, influxdb2_settings, influxdb3_settings) = match api_version {
+        // A `token` next to a `database` could be a v1 JWT or a v3 token, and
+        // isn't guessed.
+        None => match (&influxdb1_settings, &influxdb2_settings) {
+            (Some(_), Some(v2)) if !v2.has_options() => {
+                return Err(ConfigError::AmbiguousVersion.into())
+            }
+            _ => (influxdb1_settings, influxdb2_settings, influxdb3_settings),
+        },
+        Some(ApiVersion::V1) => {
+            if influxdb3_settings.is_some() {
+                return Err(mismatch(ApiVersion::V1, "v3").into());
+            }
+            let token = match influxdb2_settings {
+                Some(v2) if v2.has_options() => return Err(mismatch(ApiVersion::V1, "v2").into()),
+                Some(v2) => v2.token,
+                None => None,
+            };
+            let v1 = influxdb1_settings.unwrap_or_default();
+            let v1 = InfluxDB1Settings {
+                token: v1.token.or(token),
+                ..v1
+            };
+            (Some(v1), None, None)
+        }
+        Some(ApiVersion::V2) => {
+            if influxdb1_settings.is_some() {
+                return Err(mismatch(ApiVersion::V2, "v1").into());
+            }
+            if influxdb3_settings.is_some() {
+                return Err(mismatch(ApiVersion::V2, "v3").into());
+            }
+            (None, Some(influxdb2_settings.unwrap_or_default()), None)
+        }
+        Some(ApiVersion::V3) => {
+            if influxdb1_settings
+                .as_ref()
+                .map_or(false, |v1| v1.has_options())
+            {
+                return Err(mismatch(ApiVersion::V3, "v1").into());
+            }
+            if influxdb2_settings
+                .as_ref()
+                .map_or(false, |v2| v2.has_options())
+            {
+                return Err(mismatch(ApiVersion::V3, "v2").into());
+            }
+            let v3 = match influxdb3_settings {
+                Some(v3) => v3,
+                None => {
+                    let database = influxdb1_settings.and_then(|v1| v1.database);
+                    let token = influxdb2_settings.and_then(|v2| v2.token);
+                    required("v3", "database", &database)?;
+                    required("v3", "token", &token)?;
+                    InfluxDB3Settings {
+                        database: database.unwrap(),
+                        token: token.unwrap(),
+                    }
+                }
+            };
+            (None, None, Some(v3))
+        }
+    };
+
+    if influxdb1_settings.is_some() & influxdb2_settings.is_some() {
+        return Err(ConfigError::BothConfiguration {
+            v1_settings: influxdb1_settings.unwrap(),
+            v2_settings: influxdb2_settings.unwrap(),
+        }
+        .into());
+    }
+
+    if let Some(settings) = influxdb1_settings {
+        settings.validate()?;
+        Ok(Box::new(settings.split_database()?))
+    } else if let Some(settings) = influxdb2_settings {
+        settings.validate()?;
+        Ok(Box::new(settings.resolve_token()?))
+    } else if let Some(settings) = influxdb3_settings {
+        settings.validate()?;
+        Ok(Box::new(settings))
+    } else {
+        Err(ConfigError::MissingConfiguration.into())
+    }
+}
+
+/// The `instance_tag` of the sinks, naming the Vector instance that wrote a
+/// point so that points can be told apart in a fleet writing to one bucket.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceTagConfig {
+    pub key: Option<String>,
+    /// Takes precedence over `id_from`.
+    pub id: Option<String>,
+    #[serde(default)]
+    pub id_from: InstanceIdFrom,
+}
+
+/// Where the id of an instance comes from when it isn't configured.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceIdFrom {
+    Hostname,
+    /// A random UUID, generated at startup.
+    Uuid,
+}
+
+impl Default for InstanceIdFrom {
+    fn default() -> Self {
+        InstanceIdFrom::Hostname
+    }
+}
+
+impl InstanceTagConfig {
+    /// Resolves the id once at startup.
+    fn build(&self) -> Result<InstanceTag, ConfigError> {
+        let key = self
+            .key
+            .clone()
+            .unwrap_or_else(|| DEFAULT_INSTANCE_TAG.to_owned());
+        non_empty("instance_tag.key", &key)?;
+        let value = match (&self.id, self.id_from) {
+            (Some(id), _) => {
+                non_empty("instance_tag.id", id)?;
+                id.clone()
+            }
+            // Without a hostname the instance still gets an id of its own.
+            (None, InstanceIdFrom::Hostname) => hostname::get_hostname()
+                .filter(|hostname| !hostname.is_empty())
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            (None, InstanceIdFrom::Uuid) => uuid::Uuid::new_v4().to_string(),
+        };
+        Ok(InstanceTag { key, value })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct InstanceTag {
+    key: String,
+    value: String,
+}
+
+impl InstanceTag {
+    /// Sets the tag, replacing a tag of the event with the same key.
+    fn apply(&self, tags: &mut BTreeMap<String, String>) {
+        tags.insert(self.key.clone(), self.value.clone());
+    }
+}
+
+/// Resolves the `default_tags` once at startup, expanding `${HOSTNAME}` in
+/// their values to the name of this host.
+fn resolve_default_tags(tags: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let mut cached_hostname = None;
+    tags.iter()
+        .map(|(key, value)| {
+            let value = if value.contains("${HOSTNAME}") {
+                let hostname = cached_hostname
+                    .get_or_insert_with(|| hostname::get_hostname().unwrap_or_default());
+                value.replace("${HOSTNAME}", hostname)
+            } else {
+                value.clone()
+            };
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+/// Adds the `default_tags` to `tags`, keeping the value of `tags` for keys
+/// present in both.
+fn merge_default_tags(
+    default_tags: &BTreeMap<String, String>,
+    tags: Option<BTreeMap<String, String>>,
+) -> Option<BTreeMap<String, String>> {
+    if default_tags.is_empty() {
+        return tags;
+    }
+    let mut merged = default_tags.clone();
+    merged.extend(tags.into_iter().flatten());
+    Some(merged)
+}
+
+thread_local! {
+    static OUTPUT_BUFFER: RefCell<String> = RefCell::new(String::new());
+}
+
+/// Buffers above this capacity are released instead of kept for reuse.
+const MAX_RETAINED_BUFFER_BYTES: usize = 1024 * 1024;
+
+/// Runs `f` with an empty buffer that keeps its capacity between calls, so
+/// frequent flushes don't have to grow a fresh `String` every time. The
+/// buffer is cleared before use, so output left by a failed encode never
+/// reaches the next one.
+fn with_output_buffer<T>(f: impl FnOnce(&mut String) -> T) -> T {
+    OUTPUT_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+        Ok(mut buffer) => {
+            buffer.clear();
+            let result = f(&mut buffer);
+            if buffer.capacity() > MAX_RETAINED_BUFFER_BYTES {
+                *buffer = String::new();
+            }
+            result
+        }
+        // Already in use further up the stack.
+        Err(_) => f(&mut String::new()),
+    })
+}
+
+fn cached_resolver(resolver: Resolver, ttl_secs: Option<u64>) -> Resolver {
+    resolver.with_cache(Duration::from_secs(
+        ttl_secs.unwrap_or(DEFAULT_DNS_CACHE_TTL_SECS),
+    ))
+}
+
+/// The client shared by the healthcheck and every write, so that they reuse
+/// the same pool of keep-alive connections.
+fn http_client(
+    resolver: Resolver,
+    keepalive_idle_timeout_secs: Option<u64>,
+    max_idle_connections_per_host: Option<usize>,
+) -> crate::Result<HttpClient> {
+    let mut builder = hyper13::Client::builder();
+    builder.pool_idle_timeout(Duration::from_secs(
+        keepalive_idle_timeout_secs.unwrap_or(DEFAULT_KEEPALIVE_IDLE_TIMEOUT_SECS),
+    ));
+    if let Some(max_idle) = max_idle_connections_per_host {
+        builder.pool_max_idle_per_host(max_idle);
+    }
+    HttpClient::new_with_builder(resolver, None, &builder)
+}
+
+/// The `User-Agent` sent instead of the `Vector/<version>` one `HttpClient`
+/// adds to requests without it.
+fn user_agent(user_agent: Option<&str>) -> crate::Result<Option<HeaderValue>> {
+    Ok(user_agent
+        .map(HeaderValue::from_str)
+        .transpose()
+        .context(InvalidUserAgent)?)
+}
+
+/// The header that carries the hash of each request body, so a server or
+/// proxy can drop a batch it has already written when it's retried.
+fn idempotency_header(name: Option<&str>) -> crate::Result<Option<HeaderName>> {
+    Ok(name
+        .map(HeaderName::from_bytes)
+        .transpose()
+        .context(InvalidIdempotencyHeader)?)
+}
+
+/// A stable hash of a request body, sent in the idempotency header.
+fn body_hash(body: &[u8]) -> HeaderValue {
+    HeaderValue::from_str(&format!("{:016x}", seahash::hash(body)))
+        .expect("hex digits are a valid header value")
+}
+
+/// The `compression` of the sinks. zstd compresses better than gzip for the
+/// same CPU, but isn't accepted by every InfluxDB, and needs the
+/// `sinks-influxdb-zstd` feature.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxDBCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Default for InfluxDBCompression {
+    fn default() -> Self {
+        InfluxDBCompression::None
+    }
+}
+
+impl InfluxDBCompression {
+    fn as_str(self) -> &'static str {
+        match self {
+            InfluxDBCompression::None => "none",
+            InfluxDBCompression::Gzip => "gzip",
+            InfluxDBCompression::Zstd => "zstd",
+        }
+    }
+}
+
+/// Compression of write request bodies. Bodies smaller than `min_bytes`
+/// aren't worth the CPU and latency of compressing, so they're sent as they
+/// are.
+#[derive(Clone, Copy, Debug, Default)]
+struct BodyCompression {
+    compression: InfluxDBCompression,
+    // The level of the algorithm, resolved from `compression_level`.
+    level: i32,
+    min_bytes: usize,
+}
+
+impl BodyCompression {
+    fn new(
+        compression: InfluxDBCompression,
+        level: Option<i32>,
+        min_bytes: Option<usize>,
+    ) -> Result<Self, ConfigError> {
+        let (default, min, max) = match compression {
+            InfluxDBCompression::None => (0, 0, 0),
+            InfluxDBCompression::Gzip => (flate2::Compression::default().level() as i32, 0, 9),
+            #[cfg(feature = "sinks-influxdb-zstd")]
+            InfluxDBCompression::Zstd => (DEFAULT_ZSTD_LEVEL, 1, 22),
+            #[cfg(not(feature = "sinks-influxdb-zstd"))]
+            InfluxDBCompression::Zstd => return Err(ConfigError::ZstdUnsupported),
+        };
+        let level = match level {
+            // Without compression there's no level to check.
+            Some(level) if compression != InfluxDBCompression::None => {
+                if level < min || level > max {
+                    return Err(ConfigError::InvalidCompressionLevel {
+                        compression: compression.as_str(),
+                        level,
+                        min,
+                        max,
+                    });
+                }
+                level
+            }
+            _ => default,
+        };
+        Ok(BodyCompression {
+            compression,
+            level,
+            min_bytes: min_bytes.unwrap_or(0),
+        })
+    }
+
+    fn is_enabled(self) -> bool {
+        self.compression != InfluxDBCompression::None
+    }
+
+    /// Returns the body to send and its `Content-Encoding`, which is only
+    /// set when the body was actually compressed.
+    fn apply(self, body: Vec<u8>) -> (Vec<u8>, Option<&'static str>) {
+        if body.len() < self.min_bytes {
+            return (body, None);
+        }
+        match self.compression {
+            InfluxDBCompression::None => (body, None),
+            InfluxDBCompression::Gzip => {
+                let level = flate2::Compression::new(self.level as u32);
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                std::io::Write::write_all(&mut encoder, &body)
+                    .expect("writing to a Vec can't fail");
+                let body = encoder.finish().expect("writing to a Vec can't fail");
+                (body, Some("gzip"))
+            }
+            #[cfg(feature = "sinks-influxdb-zstd")]
+            InfluxDBCompression::Zstd => {
+                let body = zstd::stream::encode_all(&body[..], self.level)
+                    .expect("writing to a Vec can't fail");
+                (body, Some("zstd"))
+            }
+            #[cfg(not(feature = "sinks-influxdb-zstd"))]
+            InfluxDBCompression::Zstd => unreachable!("rejected by `BodyCompression::new`"),
+        }
+    }
+
+    /// Like `apply`, but sets the `Content-Encoding` of the request in
+    /// `headers`. Both sinks compress their bodies through this, so that
+    /// they can't disagree on the header.
+    fn compress(self, body: Vec<u8>, headers: &mut HeaderMap) -> Vec<u8> {
+        let (body, content_encoding) = self.apply(body);
+        if let Some(content_encoding) = content_encoding {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(content_encoding));
+        }
+        body
+    }
+}
+
+/// The server version from the `X-Influxdb-Version` header of the
+/// healthcheck response, shared between the healthcheck and the sink so that
+/// version-specific behavior can depend on it once the healthcheck has run.
+#[derive(Clone, Debug, Default)]
+pub struct ServerVersion(Arc<Mutex<Option<String>>>);
+
+impl ServerVersion {
+    /// `None` until the healthcheck has run, or when the server didn't send
+    /// its version.
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, version: String) {
+        *self.0.lock().unwrap() = Some(version);
+    }
+}
+
+const VERSION_HEADER: &str = "X-Influxdb-Version";
+// The smallest query that goes through the query engine and the token's
+// permissions.
+const FLUX_PROBE: &str = "buckets() |> limit(n: 1)";
+// `ENT` for InfluxDB Enterprise, `OSS` for the open source edition.
+const BUILD_HEADER: &str = "X-Influxdb-Build";
+
+/// Whether the server is an InfluxDB Enterprise cluster, the only edition the
+/// v1 `consistency` parameter means anything to.
+fn supports_consistency(headers: &HeaderMap) -> bool {
+    headers
+        .get(BUILD_HEADER)
+        .map_or(false, |build| build.as_bytes().eq_ignore_ascii_case(b"ENT"))
+}
+
+// V1: https://docs.influxdata.com/influxdb/v1.7/tools/api/#ping-http-endpoint
+// V2: https://v2.docs.influxdata.com/v2.0/api/#operation/GetHealth
+// V3: https://docs.influxdata.com/influxdb3/core/api/v3/#operation/GetHealth
+//
+// With `verify_write` a point is also written to the configured destination,
+// as a healthy server doesn't mean the credentials are allowed to write.
+fn healthcheck(
+    endpoint: String,
+    api_version: Option<ApiVersion>,
+    influxdb1_settings: Option<InfluxDB1Settings>,
+    influxdb2_settings: Option<InfluxDB2Settings>,
+    influxdb3_settings: Option<InfluxDB3Settings>,
+    verify_write: bool,
+    flux_probe: bool,
+    user_agent: Option<HeaderValue>,
+    max_body_bytes: usize,
+    server_version: ServerVersion,
+    mut client: HttpClient,
+) -> crate::Result<super::Healthcheck> {
+    let settings = influxdb_settings(
+        api_version,
+        influxdb1_settings.clone(),
+        influxdb2_settings.clone(),
+        influxdb3_settings.clone(),
+    )?;
+
+    let uri = settings.healthcheck_uri(endpoint.clone())?;
+    let consistency = influxdb1_settings.and_then(|settings| settings.consistency);
+
+    let mut builder = hyper13::Request::get(uri);
+    if let Some(user_agent) = &user_agent {
+        builder = builder.header(USER_AGENT, user_agent.clone());
+    }
+    let request = builder.body(hyper13::Body::empty()).unwrap();
+
+    let write_request = if verify_write {
+        let mut body = String::new();
+        influx_line_protocol(
+            "vector_healthcheck".to_owned(),
+            "healthcheck",
+            None,
+            Some(
+                vec![("write".to_owned(), Field::Bool(true))]
+                    .into_iter()
+                    .collect(),
+            ),
+            encode_timestamp(None),
+            BoolFormat::default(),
+            &mut body,
+        )?;
+        let mut builder = hyper13::Request::post(settings.write_uri(endpoint, Precision::Ns)?)
+            .header("Content-Type", "text/plain");
+        if let Some(authorization) = settings.authorization() {
+            builder = builder.header("Authorization", authorization);
+        }
+        if let Some(user_agent) = user_agent {
+            builder = builder.header(USER_AGENT, user_agent);
+        }
+        Some(builder.body(hyper13::Body::from(body)).unwrap())
+    } else {
+        None
+    };
+
+    let query_request = match (flux_probe, settings.query_uri(endpoint.clone())) {
+        (false, _) => None,
+        (true, None) => {
+            warn!("`healthcheck.flux_probe` is only supported by InfluxDB 2 with an `org`; skipping it.");
+            None
+        }
+        (true, Some(uri)) => {
+            let mut builder = hyper13::Request::post(uri?)
+                .header("Content-Type", "application/vnd.flux")
+                .header("Accept", "application/csv");
+            if let Some(authorization) = settings.authorization() {
+                builder = builder.header("Authorization", authorization);
+            }
+            if let Some(user_agent) = &user_agent {
+                builder = builder.header(USER_AGENT, user_agent.clone());
+            }
+            Some(builder.body(hyper13::Body::from(FLUX_PROBE)).unwrap())
+        }
+    };
+
+    let healthcheck = async move {
+        let (parts, body) = client.send(request).await?.into_parts();
+        if let Some(version) = parts
+            .headers
+            .get(VERSION_HEADER)
+            .and_then(|version| version.to_str().ok())
+        {
+            info!(message = "InfluxDB server version.", version = %version);
+            server_version.set(version.to_owned());
+        }
+        if let Some(consistency) = &consistency {
+            if !supports_consistency(&parts.headers) {
+                warn!(
+                    message = "`consistency` is only supported by InfluxDB Enterprise clusters; the server may ignore or reject it.",
+                    consistency = %consistency,
+                );
+            }
+        }
+        let body = ResponseBody::read(body, max_body_bytes).await?;
+        match parts.status {
+            StatusCode::OK | StatusCode::NO_CONTENT => {}
+            status => {
+                return Err(HealthError::UnexpectedResponse {
+                    status,
+                    body: body.to_string(),
+                }
+                .into())
+            }
+        }
+        // Anything this large isn't a health document.
+        if !body.truncated {
+            check_health_body(&parts.headers, &body.bytes, max_body_bytes)?;
+        }
+
+        if let Some(request) = query_request {
+            let (parts, body) = client.send(request).await?.into_parts();
+            match parts.status {
+                StatusCode::OK => {}
+                status @ StatusCode::UNAUTHORIZED | status @ StatusCode::FORBIDDEN => {
+                    return Err(HealthError::QueryNotAllowed { status }.into())
+                }
+                status => {
+                    let body = ResponseBody::read(body, max_body_bytes).await?;
+                    return Err(HealthError::UnexpectedResponse {
+                        status,
+                        body: body.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        if let Some(request) = write_request {
+            let (parts, body) = client.send(request).await?.into_parts();
+            match parts.status {
+                status if status.is_success() => {}
+                status @ StatusCode::UNAUTHORIZED | status @ StatusCode::FORBIDDEN => {
+                    return Err(HealthError::WriteNotAllowed { status }.into())
+                }
+                status => {
+                    let body = ResponseBody::read(body, max_body_bytes).await?;
+                    return Err(HealthError::UnexpectedResponse {
+                        status,
+                        body: body.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        Ok::<(), crate::Error>(())
+    };
+
+    Ok(Box::new(healthcheck.boxed().compat()))
+}
+
+/// How much of a health or setup response is read by default. Proxies in front
+/// of InfluxDB can answer with arbitrarily large error pages.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// The start of a response body, read up to a limit.
+#[derive(Debug, PartialEq)]
+struct ResponseBody {
+    bytes: Vec<u8>,
+    /// Whether the body was longer than the limit.
+    truncated: bool,
+}
+
+impl ResponseBody {
+    /// Reads at most `max_bytes` of `body`, leaving the rest unread.
+    async fn read(mut body: hyper13::Body, max_bytes: usize) -> Result<Self, hyper13::Error> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            let remaining = max_bytes - bytes.len();
+            if chunk.len() > remaining {
+                bytes.extend_from_slice(&chunk[..remaining]);
+                return Ok(ResponseBody {
+                    bytes,
+                    truncated: true,
+                });
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(ResponseBody {
+            bytes,
+            truncated: false,
+        })
+    }
+}
+
+impl fmt::Display for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.bytes))?;
+        if self.truncated {
+            write!(f, "... (truncated)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthStatus {
+    status: String,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+// Some proxies compress the health response even though it was never asked
+// for, so the body is decoded based on `Content-Encoding` alone. Bodies that
+// aren't a health document (v1 `/ping` returns none) are accepted as-is.
+fn check_health_body(headers: &HeaderMap, body: &[u8], max_bytes: usize) -> crate::Result<()> {
+    let gzip = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.trim().eq_ignore_ascii_case("gzip"));
+
+    let body = if gzip {
+        let mut decoded = Vec::new();
+        GzDecoder::new(body)
+            .take(max_bytes as u64)
+            .read_to_end(&mut decoded)
+            .context(DecodeHealthBody)?;
+        decoded
+    } else {
+        body.to_vec()
+    };
+
+    match serde_json::from_slice::<HealthStatus>(&body) {
+        Ok(health) if health.status != "pass" => Err(HealthError::NotPassing {
+            status: health.status,
+            message: health.message.unwrap_or_default(),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Snafu)]
+enum HealthError {
+    #[snafu(display(
+        "Healthcheck write was rejected with {}; check that the credentials are allowed to write to the configured bucket or database.",
+        status
+    ))]
+    WriteNotAllowed { status: StatusCode },
+    #[snafu(display(
+        "Healthcheck query was rejected with {}; check that the token is allowed to read the organization's buckets.",
+        status
+    ))]
+    QueryNotAllowed { status: StatusCode },
+    #[snafu(display("Unexpected status: {}: {}", status, body))]
+    UnexpectedResponse { status: StatusCode, body: String },
+    #[snafu(display("InfluxDB reported status {:?}: {}", status, message))]
+    NotPassing { status: String, message: String },
+    #[snafu(display("Could not decode gzip-encoded health response: {}", source))]
+    DecodeHealthBody { source: std::io::Error },
+}
+
+#[derive(Debug, Snafu)]
+enum CleanupError {
+    #[snafu(display("InfluxDB delete failed with {}: {}", status, body))]
+    DeleteFailed { status: StatusCode, body: String },
+}
+
+#[derive(Debug, Snafu)]
+enum SetupError {
+    #[snafu(display("InfluxDB setup failed with {}: {}", status, body))]
+    SetupFailed { status: StatusCode, body: String },
+    #[snafu(display("Could not parse InfluxDB setup response: {}", source))]
+    ParseSetupResponse { source: serde_json::Error },
+}
+
+#[derive(Debug, Deserialize)]
+struct SetupResponse {
+    auth: SetupAuthorization,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetupAuthorization {
+    token: String,
+}
+
+/// Performs the initial setup of an InfluxDB v2 instance, creating the user,
+/// organization and bucket.
+///
+/// Returns the operator token created by the setup, or `None` when the
+/// instance has already been set up. The token of an existing setup can't be
+/// retrieved this way.
+// https://v2.docs.influxdata.com/v2.0/api/#operation/PostSetup
+pub async fn setup_v2(
+    resolver: Resolver,
+    endpoint: &str,
+    username: &str,
+    password: &str,
+    org: &str,
+    bucket: &str,
+) -> crate::Result<Option<String>> {
+    let body = serde_json::json!({
+        "username": username,
+        "password": password,
+        "org": org,
+        "bucket": bucket,
+    });
+    let request = hyper13::Request::post(encode_uri(endpoint, "api/v2/setup", &[])?)
+        .header("Content-Type", "application/json")
+        .body(hyper13::Body::from(body.to_string()))
+        .unwrap();
+
+    let mut client = HttpClient::new(resolver, None)?;
+    let response = client.send(request).await?;
+    let status = response.status();
+    let body = ResponseBody::read(response.into_body(), DEFAULT_MAX_RESPONSE_BYTES).await?;
+    parse_setup_response(status, &body)
+}
+
+fn parse_setup_response(status: StatusCode, body: &ResponseBody) -> crate::Result<Option<String>> {
+    match status {
+        StatusCode::CREATED => {
+            let response: SetupResponse =
+                serde_json::from_slice(&body.bytes).context(ParseSetupResponse)?;
+            Ok(Some(response.auth.token))
+        }
+        // Returned once an instance has been set up.
+        StatusCode::UNPROCESSABLE_ENTITY => Ok(None),
+        status => Err(SetupError::SetupFailed {
+            status,
+            body: body.to_string(),
+        }
+        .into()),
+    }
+}
+
+/// A write refused because of the token.
+#[derive(Debug, PartialEq, Snafu)]
+pub enum AuthError {
+    #[snafu(display(
+        "Invalid token: InfluxDB didn't accept the token ({}); check that it's correct and hasn't been revoked.",
+        status
+    ))]
+    InvalidToken { status: StatusCode },
+    #[snafu(display(
+        "Insufficient permission: the token isn't allowed to write ({}); check that it has write access to the bucket or database.",
+        status
+    ))]
+    InsufficientPermission { status: StatusCode },
+}
+
+impl AuthError {
+    fn from_status(status: StatusCode) -> Option<Self> {
+        match status {
+            StatusCode::UNAUTHORIZED => Some(AuthError::InvalidToken { status }),
+            StatusCode::FORBIDDEN => Some(AuthError::InsufficientPermission { status }),
+            _ => None,
+        }
+    }
+}
+
+/// Classifies write responses so that requests rejected by InfluxDB itself
+/// (schema conflicts, auth failures, ...) are dropped instead of being retried
+/// forever, while transient failures still go through the backoff.
+///
+/// With a retry budget, a retry that finds the budget empty is dropped like
+/// a non-retryable failure.
+#[derive(Clone, Default)]
+struct InfluxDBRetryLogic {
+    budget: Option<RetryBudget>,
+}
+
+impl InfluxDBRetryLogic {
+    fn new(budget: Option<&RetryBudgetConfig>, component_type: &'static str) -> Self {
+        InfluxDBRetryLogic {
+            budget: budget.map(|budget| budget.build(component_type)),
+        }
+    }
+
+    fn within_budget(&self) -> bool {
+        self.budget
+            .as_ref()
+            .map_or(true, |budget| budget.withdraw(Instant::now()))
+    }
+
+    fn classify_response(&self, response: &hyper13::Response<Bytes>) -> RetryAction {
+        let status = response.status();
+
+        // Retrying won't fix the token, so this fails fast and loudly.
+        if let Some(error) = AuthError::from_status(status) {
+            let reason = format!("{} {}", error, String::from_utf8_lossy(response.body()));
+            emit!(InfluxDBAuthFailed { error });
+            return RetryAction::DontRetry(reason);
+        }
+
+        match status {
+            StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => RetryAction::Retry(format!(
+                "{}: {}",
+                status,
+                String::from_utf8_lossy(response.body())
+            )),
+            StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::PAYLOAD_TOO_LARGE => {
+                RetryAction::DontRetry(format!(
+                    "{}: {}",
+                    status,
+                    String::from_utf8_lossy(response.body())
+                ))
+            }
+            _ => HttpRetryLogic.should_retry_response(response),
+        }
+    }
+}
+
+impl RetryLogic for InfluxDBRetryLogic {
+    type Error = hyper13::Error;
+    type Response = hyper13::Response<Bytes>;
+
+    fn is_retriable_error(&self, error: &Self::Error) -> bool {
+        (error.is_connect() || error.is_closed()) && self.within_budget()
+    }
+
+    fn should_retry_response(&self, response: &Self::Response) -> RetryAction {
+        match self.classify_response(response) {
+            RetryAction::Retry(reason) if !self.within_budget() => {
+                RetryAction::DontRetry(format!("retry budget exhausted after {}", reason))
+            }
+            action => action,
+        }
+    }
+}
+
+// https://v2.docs.influxdata.com/v2.0/reference/syntax/line-protocol/
+//
+// Public, along with `encode_tags` and `encode_fields`, for the benchmarks.
+#[doc(hidden)]
+pub fn influx_line_protocol(
+    measurement: String,
+    metric_type: &str,
+    tags: Option<BTreeMap<String, String>>,
+    fields: Option<HashMap<String, Field>>,
+    timestamp: i64,
+    bool_format: BoolFormat,
+    line_protocol: &mut String,
+) -> Result<(), LineProtocolError> {
+    // Fields, without the NaN and infinite floats InfluxDB can't store
+    let mut unwrapped_fields = fields.unwrap_or_else(|| HashMap::new());
+    unwrapped_fields.retain(|_, value| match value {
+        Field::Float(value) => value.is_finite(),
+        _ => true,
+    });
+    // LineProtocol should have a field
+    if unwrapped_fields.is_empty() {
+        return Err(LineProtocolError::NoFields);
+    }
+
+    // InfluxDB rejects lines that start with the tag set
+    if measurement.is_empty() {
+        return Err(LineProtocolError::EmptyMeasurement);
+    }
+    // Nor can a measurement be escaped across lines, as it can be in a
+    // namespace or a metric name taken from a log.
+    if measurement.contains(|c| c == '\n' || c == '\r') {
+        return Err(LineProtocolError::MeasurementLineBreak { measurement });
+    }
+
+    encode_measurement(&measurement, line_protocol);
+
+    // Tags, after a comma only if there are any, as `measurement fields` is a
+    // valid line without tags and `measurement, fields` isn't.
+    let mut unwrapped_tags = tags.unwrap_or_else(|| BTreeMap::new());
+    if let Some(value) = insert_metric_type(&mut unwrapped_tags, metric_type) {
+        warn!(
+            message = "tag `metric_type` is reserved; overwriting the event's value.",
+            %value,
+            %metric_type,
+            rate_limit_secs = 30
+        );
+    }
+    line_protocol.push(',');
+    let tags_start = line_protocol.len();
+    encode_tags(unwrapped_tags, line_protocol);
+    if line_protocol.len() == tags_start {
+        line_protocol.pop();
+    }
+    line_protocol.push(' ');
+
+    // Fields
+    encode_fields(unwrapped_fields, bool_format, line_protocol);
+    line_protocol.push(' ');
+
+    // Timestamp
+    line_protocol.push_str(&timestamp.to_string());
+    line_protocol.push('\n');
+
+    Ok(())
+}
+
+impl LineEncoder {
+    /// Reports an event dropped because it couldn't be encoded.
+    fn dropped(&self, component_type: &'static str, error: LineProtocolError) {
+        emit!(InfluxDBEventDropped {
+            component_type,
+            error,
+            on_encode_error: self.on_encode_error,
+        });
+    }
+
+    /// Like `influx_line_protocol`, but writes the nanosecond `timestamp` at
+    /// the configured precision and enforces the maximum line length, not
+    /// counting the trailing newline.
+    fn encode(
+        &self,
+        measurement: String,
+        metric_type: &str,
+        tags: Option<BTreeMap<String, String>>,
+        fields: Option<HashMap<String, Field>>,
+        timestamp: i64,
+        line_protocol: &mut String,
+    ) -> Result<(), LineProtocolError> {
+        let timestamp = self.precision.scale(timestamp);
+        let tags = tags.map(|tags| self.underscore_keys.sanitize(tags));
+        let mut fields = fields.map(|fields| self.underscore_keys.sanitize(fields));
+        let max = match self.max_bytes {
+            Some(max) => max,
+            None => {
+                return influx_line_protocol(
+                    measurement,
+                    metric_type,
+                    tags,
+                    fields,
+                    timestamp,
+                    self.bool_format,
+                    line_protocol,
+                )
+            }
+        };
+
+        // Each attempt is written straight to `line_protocol` and cut off
+        // again if it doesn't fit, so nothing is left behind on error.
+        let start = line_protocol.len();
+        loop {
+            let encoded = influx_line_protocol(
+                measurement.clone(),
+                metric_type,
+                tags.clone(),
+                fields.clone(),
+                timestamp,
+                self.bool_format,
+                line_protocol,
+            );
+            let length = line_protocol[start..].trim_end_matches('\n').len();
+            if encoded.is_ok() && length <= max {
+                return Ok(());
+            }
+            line_protocol.truncate(start);
+            encoded?;
+
+            let truncated = self.on_oversize == OnOversize::Truncate
+                && fields.as_mut().map_or(false, |fields| {
+                    truncate_longest_string(fields, length - max)
+                });
+            if !truncated {
+                return Err(LineProtocolError::LineTooLong { length, max });
+            }
+        }
+    }
+}
+
+/// Shortens the longest string field so that its encoded form is at least
+/// `excess` bytes shorter, or empties it if that's not possible. Returns
+/// `false` when there is nothing left to truncate.
+///
+/// The value is cut between characters before escaping, so the result is
+/// always valid UTF-8 and never ends in a dangling escape.
+fn truncate_longest_string(fields: &mut HashMap<String, Field>, excess: usize) -> bool {
+    let longest = fields
+        .values_mut()
+        .filter_map(|field| match field {
+            Field::String(value) if !value.is_empty() => Some(value),
+            _ => None,
+        })
+        .max_by_key(|value| value.len());
+
+    let value = match longest {
+        Some(value) => value,
+        None => return false,
+    };
+
+    let encoded: usize = value.chars().map(escaped_len).sum();
+    truncate_on_char_boundary(value, encoded.saturating_sub(excess), escaped_len);
+    true
+}
+
+/// Sets the internal `metric_type` tag, which always takes precedence over
+/// a user tag with the same key. Returns the user value it replaced, if any.
+fn insert_metric_type(tags: &mut BTreeMap<String, String>, metric_type: &str) -> Option<String> {
+    tags.insert("metric_type".to_owned(), metric_type.to_owned())
+        .filter(|value| value != metric_type)
+}
+
+#[doc(hidden)]
+pub fn encode_tags(tags: BTreeMap<String, String>, output: &mut String) {
+    let start = output.len();
+    let sorted = tags
+        // sort by key
+        .iter()
+        .collect::<BTreeMap<_, _>>();
+
+    for (key, value) in sorted {
+        if key.is_empty() || value.is_empty() {
+            continue;
+        }
+        encode_string(key, output);
+        output.push('=');
+        encode_string(value, output);
+        output.push(',');
+    }
+
+    // remove last ',', if any tag was written
+    if output.len() > start {
+        output.pop();
+    }
+}
+
+#[doc(hidden)]
+pub fn encode_fields(fields: HashMap<String, Field>, bool_format: BoolFormat, output: &mut String) {
+    let sorted = fields
+        // sort by key, so encoding the same point twice gives the same bytes
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+    for (key, value) in sorted {
+        encode_string(&key, output);
+        output.push('=');
+        match bool_format {
+            BoolFormat::Long => write!(output, "{}", value),
+            BoolFormat::Short => write!(output, "{:#}", value),
+        }
+        .expect("writing to a String can't fail");
+        output.push(',');
+    }
+
+    // remove last ','
+    output.pop();
+}
+
+// Characters escaped in each position of a line, see
+// https://v2.docs.influxdata.com/v2.0/reference/syntax/line-protocol/#special-characters
+//
+//   measurement          comma, space
+//   tag key              comma, equals sign, space
+//   tag value            comma, equals sign, space
+//   field key            comma, equals sign, space
+//   field string value   double quote, backslash
+//
+// Keys and tag values also escape backslashes, so that a trailing backslash
+// can't escape the delimiter following it. Line protocol has no way to escape
+// newlines, carriage returns and tabs in keys and tag values so those are
+// stripped, while string field values get them escaped as `\n`, `\r`, `\t`.
+
+/// Length of a character once escaped by `encode_field_string`.
+fn escaped_len(c: char) -> usize {
+    match c {
+        '\n' | '\r' | '\t' | '\\' | '"' => 2,
+        _ => c.len_utf8(),
+    }
+}
+
+fn encode_field_string(value: &str, output: &mut String) {
+    write_field_string(value, output).expect("writing to a String can't fail");
+}
+
+fn write_field_string<W: Write>(value: &str, output: &mut W) -> fmt::Result {
+    output.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '\n' => output.write_str("\\n")?,
+            '\r' => output.write_str("\\r")?,
+            '\t' => output.write_str("\\t")?,
+            '\\' | '"' => {
+                output.write_char('\\')?;
+                output.write_char(c)?;
+            }
+            _ => output.write_char(c)?,
+        }
+    }
+    output.write_char('"')
+}
+
+fn encode_measurement(measurement: &str, output: &mut String) {
+    for c in measurement.chars() {
+        if ", ".contains(c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+}
+
+/// Escapes a tag key, a tag value or a field key, which are all escaped alike.
+/// Measurements are escaped by `encode_measurement` and string field values
+/// by `encode_field_string`.
+fn encode_string(key: &str, output: &mut String) {
+    for c in key.chars() {
+        if "\n\r\t".contains(c) {
+            continue;
+        }
+        if "\\, =".contains(c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+}
+
+/// Nanoseconds since the epoch, `LineEncoder` scales them to the write precision.
+fn encode_timestamp(timestamp: Option<DateTime<Utc>>) -> i64 {
+    encode_timestamp_with(timestamp, Utc::now)
+}
+
+/// Like `encode_timestamp`, reading the time from `now` when there's none.
+fn encode_timestamp_with(
+    timestamp: Option<DateTime<Utc>>,
+    now: impl FnOnce() -> DateTime<Utc>,
+) -> i64 {
+    timestamp.unwrap_or_else(now).timestamp_nanos()
+}
+
+/// Converts a nanosecond timestamp to the given write precision.
+///
+/// Timestamps before 1970 are negative, so this rounds toward negative
+/// infinity to keep every point inside the interval it was recorded in.
+fn scale_timestamp(nanos: i64, precision: Precision) -> i64 {
+    let divisor = match precision {
+        Precision::S => 1_000_000_000,
+        Precision::Ms => 1_000_000,
+        Precision::Us => 1_000,
+        Precision::Ns => 1,
+    };
+    nanos.div_euclid(divisor)
+}
+
+fn encode_namespace(namespace: &str, name: &str) -> String {
+    if !namespace.is_empty() {
+        format!("{}.{}", namespace, name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn encode_uri(endpoint: &str, path: &str, pairs: &[(&str, Option<String>)]) -> crate::Result<Uri> {
+    validate_endpoint(endpoint)?;
+
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+
+    for pair in pairs {
+        if let Some(v) = &pair.1 {
+            serializer.append_pair(pair.0, v);
+        }
+    }
+
+    // Form encoding writes spaces as `+`, which not every server decodes in a
+    // query string. A literal `+` is already written as `%2B`.
+    let query = serializer.finish().replace('+', "%20");
+
+    let mut url = if endpoint.ends_with('/') {
+        format!("{}{}?{}", endpoint, path, query)
+    } else {
+        format!("{}/{}?{}", endpoint, path, query)
+    };
+
+    if url.ends_with("?") {
+        url.pop();
+    }
+
+    Ok(url.parse::<Uri>().context(InvalidEndpoint { endpoint })?)
+}
+
+/// Query parameters of the write URI that hold credentials.
+const SENSITIVE_QUERY_PARAMS: &[&str] = &["p"];
+
+/// The URI with the values of credential query parameters, such as the v1
+/// password, replaced, so that it can be logged.
+fn redacted_uri(uri: &impl fmt::Display) -> String {
+    let uri = uri.to_string();
+    let (base, query) = match uri.find('?') {
+        Some(index) => uri.split_at(index + 1),
+        None => return uri,
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.find('=') {
+            Some(index) if SENSITIVE_QUERY_PARAMS.contains(&&pair[..index]) => {
+                format!("{}=redacted", &pair[..index])
+            }
+            _ => pair.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}{}", base, query)
+}
+
+/// Logs where a sink writes to, so that a wrong endpoint or a typo shows up
+/// at startup rather than on the first failed write.
+fn log_write_uri(component_type: &'static str, uri: &impl fmt::Display) {
+    info!(
+        message = "Writing to InfluxDB.",
+        %component_type,
+        uri = %redacted_uri(uri),
+    );
+}
+
+/// The endpoint with `path_prefix` appended, for proxies that serve InfluxDB
+/// under a path of their own. The InfluxDB paths such as `/api/v2/write` and
+/// `/health` follow it. Slashes around the prefix don't matter, `influx` and
+/// `/influx/` are the same.
+fn with_path_prefix(endpoint: &str, path_prefix: Option<&str>) -> String {
+    match path_prefix.map(|path_prefix| path_prefix.trim_matches('/')) {
+        Some(path_prefix) if !path_prefix.is_empty() => {
+            format!("{}/{}", endpoint.trim_end_matches('/'), path_prefix)
+        }
+        _ => endpoint.to_owned(),
+    }
+}
+
+fn validate_path_prefix(path_prefix: Option<&str>) -> Result<(), EndpointError> {
+    match path_prefix {
+        Some(path_prefix) if path_prefix.contains(|c| c == '?' || c == '#') => {
+            Err(EndpointError::InvalidPathPrefix {
+                path_prefix: path_prefix.to_owned(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_endpoint(endpoint: &str) -> Result<(), EndpointError> {
+    if !endpoint.contains("://") {
+        return Err(EndpointError::MissingScheme {
+            endpoint: endpoint.to_owned(),
+        });
+    }
+
+    match url::Url::parse(endpoint) {
+        Ok(url) if url.host().is_none() => Err(EndpointError::InvalidHost {
+            endpoint: endpoint.to_owned(),
+            reason: "missing host".to_owned(),
+        }),
+        Err(error @ url::ParseError::EmptyHost)
+        | Err(error @ url::ParseError::IdnaError)
+        | Err(error @ url::ParseError::InvalidPort)
+        | Err(error @ url::ParseError::InvalidIpv4Address)
+        | Err(error @ url::ParseError::InvalidIpv6Address)
+        | Err(error @ url::ParseError::InvalidDomainCharacter) => Err(EndpointError::InvalidHost {
+            endpoint: endpoint.to_owned(),
+            reason: error.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+pub mod test_util {
+    use super::*;
+    use chrono::offset::TimeZone;
+
+    pub(crate) const ORG: &str = "my-org";
+    pub(crate) const BUCKET: &str = "my-bucket";
+    pub(crate) const TOKEN: &str = "my-token";
+    pub(crate) const DATABASE: &str = "my-database";
+
+    pub(crate) fn ts() -> DateTime<Utc> {
+        Utc.ymd(2018, 11, 14).and_hms_nano(8, 9, 10, 11)
+    }
+
+    pub(crate) fn tags() -> BTreeMap<String, String> {
+        vec![
+            ("normal_tag".to_owned(), "value".to_owned()),
+            ("true_tag".to_owned(), "true".to_owned()),
+            ("empty_tag".to_owned(), "".to_owned()),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    pub(crate) fn assert_fields(value: String, fields: Vec<&str>) {
+        let encoded_fields: Vec<&str> = value.split(',').collect();
+
+        assert_eq!(fields.len(), encoded_fields.len());
+
+        for field in fields.into_iter() {
+            assert!(
+                encoded_fields.contains(&field),
+                format!("Fields: {} has to have: {}", value, field)
+            )
+        }
+    }
+
+    // ns.requests,metric_type=distribution,normal_tag=value,true_tag=true avg=1.875,count=8.0,max=3.0,median=2.0,min=1.0,quantile_0.95=3.0,sum=15.0 1542182950000000011
+    //
+    // =>
+    //
+    // ns.requests
+    // metric_type=distribution,normal_tag=value,true_tag=true
+    // avg=1.875,count=8.0,max=3.0,median=2.0,min=1.0,quantile_0.95=3.0,sum=15.0
+    // 1542182950000000011
+    //
+    pub(crate) fn split_line_protocol(line_protocol: &str) -> (&str, &str, String, &str) {
+        let mut split = line_protocol.splitn(2, ',').collect::<Vec<&str>>();
+        let measurement = split[0];
+
+        split = split[1].splitn(3, ' ').collect::<Vec<&str>>();
+
+        return (measurement, split[0], split[1].to_string(), split[2]);
+    }
+
+    pub(crate) fn onboarding_v2() {
+        let mut body = std::collections::HashMap::new();
+        body.insert("username", "my-user");
+        body.insert("password", "my-password");
+        body.insert("org", ORG);
+        body.insert("bucket", BUCKET);
+        body.insert("token", TOKEN);
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let res = client
+            .post("http://localhost:9999/api/v2/setup")
+            .json(&body)
+            .header("accept", "application/json")
+            .send()
+            .unwrap();
+
+        let status = res.status();
+
+        assert!(
+            status == http::StatusCode::CREATED || status == http::StatusCode::UNPROCESSABLE_ENTITY,
+            format!("UnexpectedStatus: {}", status)
+        );
+    }
+
+    /// Creates a bucket in `ORG`, unless one with that name already exists.
+    pub(crate) fn create_bucket_v2(name: &str) {
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let mut res = client
+            .get(&format!("http://localhost:9999/api/v2/orgs?org={}", ORG))
+            .header("Authorization", format!("Token {}", TOKEN))
+            .send()
+            .unwrap();
+        let orgs: serde_json::Value = res.json().unwrap();
+        let org_id = orgs["orgs"][0]["id"].as_str().unwrap();
+
+        let res = client
+            .post("http://localhost:9999/api/v2/buckets")
+            .json(&serde_json::json!({
+                "orgID": org_id,
+                "name": name,
+                "retentionRules": [],
+            }))
+            .header("Authorization", format!("Token {}", TOKEN))
+            .send()
+            .unwrap();
+
+        let status = res.status();
+
+        assert!(
+            status == http::StatusCode::CREATED
+                || status == http::StatusCode::CONFLICT
+                || status == http::StatusCode::UNPROCESSABLE_ENTITY,
+            format!("UnexpectedStatus: {}", status)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffers::Acker;
+    use crate::sinks::influxdb::test_util::{assert_fields, split_line_protocol, tags, ts};
+    use crate::sinks::util::BatchSink;
+    use crate::test_util::runtime;
+    use chrono::offset::TimeZone;
+    use futures01::{Future, Sink};
+    use std::sync::Mutex;
+    use tokio01_test::clock::MockClock;
+
+    #[derive(Deserialize, Serialize, Debug, Clone, Default)]
+    #[serde(deny_unknown_fields)]
+    pub struct InfluxDBTestConfig {
+        pub api_version: Option<ApiVersion>,
+        #[serde(flatten)]
+        pub influxdb1_settings: Option<InfluxDB1Settings>,
+        #[serde(flatten)]
+        pub influxdb2_settings: Option<InfluxDB2Settings>,
+        // Assembled from the v1 `database` and v2 `token` by `influxdb_settings`
+        // with `api_version = "v3"`.
+        #[serde(skip)]
+        pub influxdb3_settings: Option<InfluxDB3Settings>,
+    }
+
+    #[test]
+    fn test_influxdb_settings_both() {
+        let config = r#"
+        bucket = "my-bucket"
+        org = "my-org"
+        token = "my-token"
+        database = "my-database"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let settings = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        );
+        match settings {
+            Ok(_) => assert!(false, "Expected error"),
+            Err(e) => assert_eq!(format!("{}",e), "Unclear settings. Both version configured v1: InfluxDB1Settings { database: Some(\"my-database\"), consistency: None, retention_policy_name: None, rp_tag: None, username: None, password: None, token: None }, v2: InfluxDB2Settings { org: Some(\"my-org\"), bucket: Some(\"my-bucket\"), bucket_id: None, token: Some(\"my-token\"), token_file: None, token_dir: None, token_key: None, token_reload_secs: None, bucket_tag: None }.".to_owned())
+        }
+    }
+
+    #[test]
+    fn test_influxdb_settings_missing() {
+        let config = r#"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let settings = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        );
+        match settings {
+            Ok(_) => assert!(false, "Expected error"),
+            Err(e) => assert_eq!(
+                format!("{}", e),
+                "InfluxDB v1, v2 or v3 should be configured as endpoint.".to_owned()
+            ),
+        }
+    }
+
+    #[test]
+    fn test_parse_setup_response() {
+        let body = |bytes: &[u8]| ResponseBody {
+            bytes: bytes.to_vec(),
+            truncated: false,
+        };
+
+        let created =
+            body(br#"{"user":{"name":"my-user"},"auth":{"id":"0a1b","token":"new-token"}}"#);
+        assert_eq!(
+            parse_setup_response(StatusCode::CREATED, &created).unwrap(),
+            Some("new-token".to_owned())
+        );
+
+        let done =
+            body(br#"{"code":"conflict","message":"onboarding has already been completed"}"#);
+        assert_eq!(
+            parse_setup_response(StatusCode::UNPROCESSABLE_ENTITY, &done).unwrap(),
+            None
+        );
+
+        let error = parse_setup_response(StatusCode::BAD_REQUEST, &body(b"bad")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "InfluxDB setup failed with 400 Bad Request: bad"
+        );
+        assert!(parse_setup_response(StatusCode::CREATED, &body(b"{}")).is_err());
+    }
+
+    #[test]
+    fn test_influxdb_settings_partial() {
+        let cases = vec![
+            (
+                r#"
+                org = "my-org"
+                bucket = "my-bucket"
+                "#,
+                "InfluxDB v2 settings are missing `token`.",
+            ),
+            (
+                r#"
+                username = "writer"
+                password = "secret"
+                "#,
+                "InfluxDB v1 settings are missing `database`.",
+            ),
+            (
+                r#"
+                bucket = "my-bucket"
+                token = "my-token"
+                "#,
+                "InfluxDB v2 settings are missing `org`.",
+            ),
+            (
+                r#"
+                bucket = "my-bucket"
+                bucket_id = "0123456789abcdef"
+                token = "my-token"
+                "#,
+                "Only one of `bucket` and `bucket_id` can be set.",
+            ),
+            (
+                r#"
+                bucket_id = "0123456789abcdef"
+                bucket_tag = "bucket"
+                token = "my-token"
+                "#,
+                "InfluxDB v2 settings are missing `org`.",
+            ),
+        ];
+
+        for (config, message) in cases {
+            let config: InfluxDBTestConfig = toml::from_str(config).unwrap();
+            let error = influxdb_settings(
+                config.api_version,
+                config.influxdb1_settings,
+                config.influxdb2_settings,
+                config.influxdb3_settings,
+            )
+            .err()
+            .unwrap();
+            assert_eq!(format!("{}", error), message);
+        }
+    }
+
+    #[test]
+    fn test_influxdb1_settings() {
+        let config = r#"
+        database = "my-database"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let _ = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_influxdb2_settings() {
+        let config = r#"
+        bucket = "my-bucket"
+        org = "my-org"
+        token = "my-token"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let _ = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_influxdb2_settings_bucket_id() {
+        let config = r#"
+        bucket_id = "0123456789abcdef"
+        token = "my-token"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let settings = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+        .unwrap();
+
+        let uri = settings
+            .write_uri("http://localhost:9999".to_owned(), Precision::Ns)
+            .unwrap();
+        assert_eq!(
+            "http://localhost:9999/api/v2/write?bucket=0123456789abcdef&precision=ns",
+            uri.to_string()
+        );
+        assert!(settings
+            .query_uri("http://localhost:9999".to_owned())
+            .is_none());
+    }
+
+    #[test]
+    fn test_influxdb_settings_empty_fields() {
+        let cases = vec![
+            (r#"database = """#, "database"),
+            (
+                r#"
+                org = ""
+                bucket = "my-bucket"
+                token = "my-token"
+                "#,
+                "org",
+            ),
+            (
+                r#"
+                org = "my-org"
+                bucket = ""
+                token = "my-token"
+                "#,
+                "bucket",
+            ),
+            (
+                r#"
+                org = "my-org"
+                bucket = "my-bucket"
+                token = ""
+                "#,
+                "token",
+            ),
+            (
+                r#"
+                api_version = "v3"
+                database = "my-database"
+                token = ""
+                "#,
+                "token",
+            ),
+        ];
+
+        for (config, field) in cases {
+            let config: InfluxDBTestConfig = toml::from_str(config).unwrap();
+            let settings = influxdb_settings(
+                config.api_version,
+                config.influxdb1_settings,
+                config.influxdb2_settings,
+                config.influxdb3_settings,
+            );
+            match settings {
+                Ok(_) => panic!("Expected error for empty `{}`", field),
+                Err(e) => assert_eq!(
+                    format!("{}", e),
+                    format!("InfluxDB setting `{}` must not be empty.", field)
+                ),
+            }
+        }
+    }
+
+    fn influxdb2_settings_with(extra: &str) -> crate::Result<Box<dyn InfluxDBSettings>> {
+        let config = format!(
+            r#"
+            org = "my-org"
+            bucket = "my-bucket"
+            {}
+            "#,
+            extra
+        );
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+    }
+
+    #[test]
+    fn test_influxdb2_token_file() {
+        let path = crate::test_util::temp_file();
+        std::fs::write(&path, "file-token\n").unwrap();
+
+        let settings =
+            influxdb2_settings_with(&format!("token_file = {:?}", path.to_str().unwrap())).unwrap();
+        assert_eq!(settings.token(), "file-token");
+    }
+
+    #[test]
+    fn test_influxdb2_token_file_missing() {
+        let path = crate::test_util::temp_file();
+
+        let error = influxdb2_settings_with(&format!("token_file = {:?}", path.to_str().unwrap()))
+            .err()
+            .unwrap();
+        assert!(format!("{}", error)
+            .starts_with(&format!("Could not read InfluxDB token from {:?}:", path)));
+    }
+
+    #[test]
+    fn test_influxdb2_token_and_token_file() {
+        let error = influxdb2_settings_with(
+            r#"
+            token = "my-token"
+            token_file = "/run/secrets/influxdb-token"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            format!("{}", error),
+            "Only one of `token` and `token_file` can be set."
+        );
+    }
+
+    #[test]
+    fn test_influxdb2_token_dir() {
+        let dir = crate::test_util::temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("influxdb-token"), "dir-token\n").unwrap();
+
+        let settings = influxdb2_settings_with(&format!(
+            "token_dir = {:?}\ntoken_key = \"influxdb-token\"",
+            dir.to_str().unwrap()
+        ))
+        .unwrap();
+        assert_eq!(settings.token(), "dir-token");
+        assert!(settings.token_reload().is_none());
+
+        let error = influxdb2_settings_with(&format!(
+            "token_dir = {:?}\ntoken_key = \"missing\"",
+            dir.to_str().unwrap()
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(
+            format!("{}", error),
+            format!(
+                "Could not read InfluxDB token from {:?}: the file doesn't exist, check that the secret is mounted there.",
+                dir.join("missing")
+            )
+        );
+
+        for (extra, message) in &[
+            (
+                "token_key = \"influxdb-token\"",
+                "`token_dir` and `token_key` must be set together.",
+            ),
+            (
+                "token = \"my-token\"\ntoken_dir = \"/run/secrets\"\ntoken_key = \"influxdb-token\"",
+                "`token_dir` can't be combined with `token` or `token_file`.",
+            ),
+            (
+                "token = \"my-token\"\ntoken_reload_secs = 60",
+                "`token_reload_secs` needs `token_file` or `token_dir` to read from.",
+            ),
+        ] {
+            let error = influxdb2_settings_with(extra).err().unwrap();
+            assert_eq!(format!("{}", error), *message);
+        }
+    }
+
+    #[test]
+    fn test_token_reload() {
+        let dir = crate::test_util::temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("influxdb-token");
+        std::fs::write(&path, "first-token\n").unwrap();
+
+        let settings = influxdb2_settings_with(&format!(
+            "token_dir = {:?}\ntoken_key = \"influxdb-token\"\ntoken_reload_secs = 60",
+            dir.to_str().unwrap()
+        ))
+        .unwrap();
+        assert_eq!(
+            settings.token_reload(),
+            Some((path.clone(), Duration::from_secs(60)))
+        );
+
+        let authorization = Authorization::new(&*settings, "influxdb_logs");
+        let start = Instant::now();
+        assert_eq!(
+            authorization.get_at(start).as_deref(),
+            Some("Token first-token")
+        );
+
+        // Not read again before the interval has passed.
+        std::fs::write(&path, "second-token\n").unwrap();
+        let later = start + Duration::from_secs(30);
+        assert_eq!(
+            authorization.get_at(later).as_deref(),
+            Some("Token first-token")
+        );
+        let later = start + Duration::from_secs(61);
+        assert_eq!(
+            authorization.get_at(later).as_deref(),
+            Some("Token second-token")
+        );
+
+        // A secret being rotated may briefly be missing or empty.
+        std::fs::remove_file(&path).unwrap();
+        let later = start + Duration::from_secs(122);
+        assert_eq!(
+            authorization.get_at(later).as_deref(),
+            Some("Token second-token")
+        );
+        std::fs::write(&path, "").unwrap();
+        let later = start + Duration::from_secs(183);
+        assert_eq!(
+            authorization.get_at(later).as_deref(),
+            Some("Token second-token")
+        );
+    }
+
+    #[test]
+    fn test_influxdb3_settings() {
+        let config = r#"
+        api_version = "v3"
+        database = "my-database"
+        token = "my-token"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let settings = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+        .unwrap();
+        assert_eq!(
+            "http://localhost:8181/api/v3/write_lp?db=my-database&precision=nanosecond",
+            settings
+                .write_uri("http://localhost:8181".to_owned(), Precision::Ns)
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_influxdb_settings_api_version() {
+        let error = |config: &str| {
+            let config: InfluxDBTestConfig = toml::from_str(config).unwrap();
+            influxdb_settings(
+                config.api_version,
+                config.influxdb1_settings,
+                config.influxdb2_settings,
+                config.influxdb3_settings,
+            )
+            .err()
+            .map(|error| error.to_string())
+        };
+
+        assert_eq!(
+            error(
+                r#"
+                database = "my-database"
+                token = "my-token"
+                "#
+            ),
+            Some(
+                "`database` and `token` are used by both InfluxDB v1 and v3, set `api_version` to \"v1\" or \"v3\"."
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            error(
+                r#"
+                api_version = "v3"
+                database = "my-database"
+                token = "my-token"
+                retention_policy_name = "autogen"
+                "#
+            ),
+            Some("`api_version = \"v3\"` can't be combined with InfluxDB v1 settings.".to_owned())
+        );
+        assert_eq!(
+            error(
+                r#"
+                api_version = "v1"
+                database = "my-database"
+                org = "my-org"
+                "#
+            ),
+            Some("`api_version = \"v1\"` can't be combined with InfluxDB v2 settings.".to_owned())
+        );
+        assert_eq!(
+            error(
+                r#"
+                api_version = "v2"
+                database = "my-database"
+                org = "my-org"
+                bucket = "my-bucket"
+                token = "my-token"
+                "#
+            ),
+            Some("`api_version = \"v2\"` can't be combined with InfluxDB v1 settings.".to_owned())
+        );
+        assert_eq!(
+            error(
+                r#"
+                api_version = "v3"
+                token = "my-token"
+                "#
+            ),
+            Some("InfluxDB v3 settings are missing `database`.".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_influxdb1_settings_token() {
+        let config = r#"
+        api_version = "v1"
+        database = "my-database"
+        token = "my-jwt"
+        retention_policy_name = "autogen"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let settings = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+        .unwrap();
+        assert_eq!(settings.authorization(), Some("Bearer my-jwt".to_owned()));
+        assert_eq!(
+            "http://localhost:8086/write?db=my-database&rp=autogen&precision=ns",
+            settings
+                .write_uri("http://localhost:8086".to_owned(), Precision::Ns)
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_influxdb1_settings_token_database() {
+        let config = r#"
+        api_version = "v1"
+        database = "my-database"
+        token = "my-jwt"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let settings = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+        .unwrap();
+        assert_eq!(settings.authorization(), Some("Bearer my-jwt".to_owned()));
+        assert_eq!(
+            "http://localhost:8086/write?db=my-database&precision=ns",
+            settings
+                .write_uri("http://localhost:8086".to_owned(), Precision::Ns)
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_influxdb1_settings_token_and_credentials() {
+        let config = r#"
+        api_version = "v1"
+        database = "my-database"
+        token = "my-jwt"
+        username = "writer"
+        password = "secret"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let error = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            format!("{}", error),
+            "InfluxDB v1 `token` can't be combined with `username` or `password`."
+        );
+    }
+
+    #[test]
+    fn test_influxdb1_settings_database_rp() {
+        let write_uri = |config: &str| {
+            let config: InfluxDBTestConfig = toml::from_str(config).unwrap();
+            influxdb_settings(
+                config.api_version,
+                config.influxdb1_settings,
+                config.influxdb2_settings,
+                config.influxdb3_settings,
+            )
+            .and_then(|settings| {
+                settings.write_uri("http://localhost:8086".to_owned(), Precision::Ns)
+            })
+            .map(|uri| uri.to_string())
+            .map_err(|error| error.to_string())
+        };
+
+        assert_eq!(
+            write_uri(r#"database = "mydb/myrp""#),
+            Ok("http://localhost:8086/write?db=mydb&rp=myrp&precision=ns".to_owned())
+        );
+        assert_eq!(
+            write_uri(
+                r#"
+                database = "mydb"
+                retention_policy_name = "myrp"
+                "#
+            ),
+            Ok("http://localhost:8086/write?db=mydb&rp=myrp&precision=ns".to_owned())
+        );
+        assert_eq!(
+            write_uri(
+                r#"
+                database = "mydb/myrp"
+                retention_policy_name = "autogen"
+                "#
+            ),
+            Err("InfluxDB v1 `database` \"mydb/myrp\" names a retention policy, which can't be combined with `retention_policy_name`.".to_owned())
+        );
+        for database in &["mydb/", "/myrp", "mydb/myrp/extra"] {
+            assert_eq!(
+                write_uri(&format!("database = {:?}", database)),
+                Err(format!(
+                    "InfluxDB v1 `database` {:?} should be `database` or `database/retention_policy`.",
+                    database
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_influxdb1_test_write_uri() {
+        let settings = InfluxDB1Settings::builder()
+            .consistency("quorum")
+            .database("vector_db")
+            .retention_policy_name("autogen")
+            .username("writer")
+            .password("secret")
+            .build();
+
+        let uri = settings
+            .write_uri("http://localhost:8086".to_owned(), Precision::Ns)
+            .unwrap();
+        assert_eq!("http://localhost:8086/write?consistency=quorum&db=vector_db&rp=autogen&p=secret&u=writer&precision=ns", uri.to_string())
+    }
+
+    #[test]
+    fn test_influxdb1_minimal_write_uri() {
+        // The options left unset are skipped without leaving an empty
+        // parameter or a stray separator behind.
+        let config = r#"
+        database = "vector_db"
+    "#;
+        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
+        let settings = influxdb_settings(
+            config.api_version,
+            config.influxdb1_settings,
+            config.influxdb2_settings,
+            config.influxdb3_settings,
+        )
+        .unwrap();
+        for endpoint in &["http://localhost:8086", "http://localhost:8086/"] {
+            assert_eq!(
+                settings
+                    .write_uri((*endpoint).to_owned(), Precision::Ns)
+                    .unwrap()
+                    .to_string(),
+                "http://localhost:8086/write?db=vector_db&precision=ns"
+            );
+        }
+
+        // Only the last parameter set.
+        let uri = encode_uri(
+            "http://localhost:8086",
+            "write",
+            &[
+                ("consistency", None),
+                ("db", None),
+                ("precision", Some("s".to_owned())),
+            ],
+        )
+        .unwrap();
+        assert_eq!(uri, "http://localhost:8086/write?precision=s");
+
+        // Only the first.
+        let uri = encode_uri(
+            "http://localhost:8086",
+            "write",
+            &[
+                ("db", Some("vector_db".to_owned())),
+                ("rp", None),
+                ("u", None),
+            ],
+        )
+        .unwrap();
+        assert_eq!(uri, "http://localhost:8086/write?db=vector_db");
+
+        // None at all.
+        let uri = encode_uri("http://localhost:8086", "ping", &[("db", None)]).unwrap();
+        assert_eq!(uri, "http://localhost:8086/ping");
+    }
+
+    #[test]
+    fn test_redacted_uri() {
+        let settings = InfluxDB1Settings::builder()
+            .database("vector_db")
+            .username("writer")
+            .password("s3cr=t&p")
+            .build();
+        let uri = settings
+            .write_uri("http://localhost:8086".to_owned(), Precision::Ns)
+            .unwrap();
+        assert_eq!(
+            redacted_uri(&uri),
+            "http://localhost:8086/write?db=vector_db&p=redacted&u=writer&precision=ns"
+        );
+
+        let settings = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .build();
+        let uri = settings
+            .write_uri("http://localhost:9999".to_owned(), Precision::Ns)
+            .unwrap();
+        assert_eq!(
+            redacted_uri(&uri),
+            "http://localhost:9999/api/v2/write?org=my-org&bucket=my-bucket&precision=ns"
+        );
+
+        assert_eq!(
+            redacted_uri(&"http://localhost:8086/ping"),
+            "http://localhost:8086/ping"
+        );
+    }
+
+    #[test]
+    fn test_cleanup_config() {
+        let build = |config: &CleanupConfig, settings: &dyn InfluxDBSettings| {
+            config
+                .build(
+                    settings,
+                    "http://localhost:9999".to_owned(),
+                    None,
+                    "influxdb_logs",
+                )
+                .map_err(|error| error.to_string())
+        };
+        let config = CleanupConfig {
+            measurement: "ns.vector".to_owned(),
+            older_than_secs: 7 * 24 * 60 * 60,
+            interval_secs: DEFAULT_CLEANUP_INTERVAL_SECS,
+        };
+        let settings = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .build();
+
+        let cleanup = build(&config, &settings).unwrap();
+        assert_eq!(
+            cleanup.uri.to_string(),
+            "http://localhost:9999/api/v2/delete?org=my-org&bucket=my-bucket"
+        );
+        assert_eq!(
+            cleanup.authorization.get().as_deref(),
+            Some("Token my-token")
+        );
+
+        let cleanup = build(
+            &config,
+            &InfluxDB2Settings::builder()
+                .org("my-org")
+                .bucket_id("0123456789abcdef")
+                .token("my-token")
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(
+            cleanup.uri.to_string(),
+            "http://localhost:9999/api/v2/delete?org=my-org&bucketID=0123456789abcdef"
+        );
+
+        let unsupported = "`cleanup` is only supported by InfluxDB 2 with an `org`.";
+        assert_eq!(
+            build(
+                &config,
+                &InfluxDB1Settings::builder().database("vector_db").build()
+            )
+            .unwrap_err(),
+            unsupported
+        );
+        assert_eq!(
+            build(
+                &config,
+                &InfluxDB2Settings::builder()
+                    .bucket_id("0123456789abcdef")
+                    .token("my-token")
+                    .build()
+            )
+            .unwrap_err(),
+            unsupported
+        );
+
+        assert_eq!(
+            build(
+                &CleanupConfig {
+                    older_than_secs: 60,
+                    ..config.clone()
+                },
+                &settings
+            )
+            .unwrap_err(),
+            "`cleanup.older_than_secs` must be at least 3600."
+        );
+        assert_eq!(
+            build(
+                &CleanupConfig {
+                    interval_secs: 1,
+                    ..config.clone()
+                },
+                &settings
+            )
+            .unwrap_err(),
+            "`cleanup.interval_secs` must be at least 60."
+        );
+        assert_eq!(
+            build(
+                &CleanupConfig {
+                    measurement: "".to_owned(),
+                    ..config
+                },
+                &settings
+            )
+            .unwrap_err(),
+            "InfluxDB setting `cleanup.measurement` must not be empty."
+        );
+    }
+
+    #[test]
+    fn test_cleanup_request() {
+        let settings = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .build();
+        let cleanup = CleanupConfig {
+            measurement: r#"ns."quoted"\vector"#.to_owned(),
+            older_than_secs: 2 * 24 * 60 * 60,
+            interval_secs: 60,
+        }
+        .build(
+            &settings,
+            "http://localhost:9999".to_owned(),
+            Some(HeaderValue::from_static("vector-test")),
+            "influxdb_logs",
+        )
+        .unwrap();
+
+        let request = cleanup.request(Utc.ymd(2018, 11, 14).and_hms_nano(8, 9, 10, 11));
+        assert_eq!(request.method(), "POST");
+        assert_eq!(
+            request.uri().to_string(),
+            "http://localhost:9999/api/v2/delete?org=my-org&bucket=my-bucket"
+        );
+        assert_eq!(request.headers()["Authorization"], "Token my-token");
+        assert_eq!(request.headers()["Content-Type"], "application/json");
+        assert_eq!(request.headers()[USER_AGENT], "vector-test");
+
+        let body = runtime()
+            .block_on_std(hyper13::body::to_bytes(request.into_body()))
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "start": "1970-01-01T00:00:00Z",
+                "stop": "2018-11-14T08:09:10Z",
+                "predicate": r#"_measurement="ns.\"quoted\"\\vector""#,
+            })
+        );
+    }
+
+    #[test]
+    fn test_influxdb2_test_write_uri() {
+        let settings = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .build();
+
+        let uri = settings
+            .write_uri("http://localhost:9999".to_owned(), Precision::Ns)
+            .unwrap();
+        assert_eq!(
+            "http://localhost:9999/api/v2/write?org=my-org&bucket=my-bucket&precision=ns",
+            uri.to_string()
+        );
+
+        let settings = InfluxDB2Settings {
+            org: None,
+            bucket: None,
+            bucket_id: Some("0123456789abcdef".to_owned()),
+            ..settings
+        };
+        let uri = settings
+            .write_uri("http://localhost:9999".to_owned(), Precision::Ns)
+            .unwrap();
+        assert_eq!(
+            "http://localhost:9999/api/v2/write?bucket=0123456789abcdef&precision=ns",
+            uri.to_string()
+        );
+    }
+
+    #[test]
+    fn test_influxdb3_test_write_uri() {
+        let settings = InfluxDB3Settings {
+            database: "vector_db".to_owned(),
+            token: "my-token".to_owned(),
+        };
+
+        let uri = settings
+            .write_uri("http://localhost:8181".to_owned(), Precision::Ns)
+            .unwrap();
+        assert_eq!(
+            "http://localhost:8181/api/v3/write_lp?db=vector_db&precision=nanosecond",
+            uri.to_string()
+        )
+    }
+
+    #[test]
+    fn test_write_uri_precision() {
+        let settings = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .build();
+        let uri = settings
+            .write_uri("http://localhost:9999".to_owned(), Precision::Ms)
+            .unwrap();
+        assert_eq!(
+            "http://localhost:9999/api/v2/write?org=my-org&bucket=my-bucket&precision=ms",
+            uri.to_string()
+        );
+
+        let settings = InfluxDB3Settings {
+            database: "vector_db".to_owned(),
+            token: "my-token".to_owned(),
+        };
+        let uri = settings
+            .write_uri("http://localhost:8181".to_owned(), Precision::Ms)
+            .unwrap();
+        assert_eq!(
+            "http://localhost:8181/api/v3/write_lp?db=vector_db&precision=millisecond",
+            uri.to_string()
+        );
+    }
+
+    #[test]
+    fn test_influxdb2_route_by_bucket_tag() {
+        let settings = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .bucket_tag("tenant")
+            .build();
+
+        let tags = vec![("tenant".to_owned(), "tenant-a".to_owned())]
+            .into_iter()
+            .collect();
+        assert_eq!(Some("tenant-a".to_owned()), settings.route(Some(&tags)));
+        assert_eq!(None, settings.route(Some(&BTreeMap::new())));
+        assert_eq!(None, settings.route(None));
+
+        let uri = settings
+            .routed_write_uri(
+                "http://localhost:9999".to_owned(),
+                Precision::Ns,
+                "tenant-a",
+            )
+            .unwrap();
+        assert_eq!(
+            "http://localhost:9999/api/v2/write?org=my-org&bucket=tenant-a&precision=ns",
+            uri.to_string()
+        )
+    }
+
+    #[test]
+    fn test_influxdb1_route_by_rp_tag() {
+        let settings = InfluxDB1Settings::builder()
+            .database("vector_db")
+            .retention_policy_name("autogen")
+            .rp_tag("tier")
+            .build();
+
+        let tags = vec![("tier".to_owned(), "short".to_owned())]
+            .into_iter()
+            .collect();
+        assert_eq!(Some("short".to_owned()), settings.route(Some(&tags)));
+        assert_eq!(None, settings.route(Some(&BTreeMap::new())));
+        assert_eq!(None, settings.route(None));
+
+        let uri = settings
+            .routed_write_uri("http://localhost:8086".to_owned(), Precision::Ns, "short")
+            .unwrap();
+        assert_eq!(
+            "http://localhost:8086/write?db=vector_db&rp=short&precision=ns",
+            uri.to_string()
+        )
+    }
+
+    #[test]
+    fn test_influxdb1_test_healthcheck_uri() {
+        let settings = InfluxDB1Settings::builder()
+            .consistency("quorum")
+            .database("vector_db")
+            .retention_policy_name("autogen")
+            .username("writer")
+            .password("secret")
+            .build();
+
+        let uri = settings
+            .healthcheck_uri("http://localhost:8086".to_owned())
+            .unwrap();
+        assert_eq!("http://localhost:8086/ping", uri.to_string())
+    }
+
+    #[test]
+    fn test_influxdb2_test_healthcheck_uri() {
+        let settings = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .build();
+
+        let uri = settings
+            .healthcheck_uri("http://localhost:9999".to_owned())
+            .unwrap();
+        assert_eq!("http://localhost:9999/health", uri.to_string())
+    }
+
+    #[test]
+    fn test_influxdb3_test_healthcheck_uri() {
+        let settings = InfluxDB3Settings {
+            database: "vector_db".to_owned(),
+            token: "my-token".to_owned(),
+        };
+
+        let uri = settings
+            .healthcheck_uri("http://localhost:8181".to_owned())
+            .unwrap();
+        assert_eq!("http://localhost:8181/health", uri.to_string())
+    }
+
+    #[test]
+    fn test_retry_logic_retryable_statuses() {
+        let logic = InfluxDBRetryLogic::default();
+
+        for status in &[408, 429, 500, 502, 503, 504] {
+            let response = hyper13::Response::builder()
+                .status(*status)
+                .body(Bytes::new())
+                .unwrap();
+            assert!(
+                logic.should_retry_response(&response).is_retryable(),
+                format!("Status {} should be retryable", status)
+            );
+        }
+    }
+
+    #[test]
+    fn test_retry_logic_non_retryable_statuses() {
+        let logic = InfluxDBRetryLogic::default();
+
+        for status in &[400, 401, 403, 404, 413] {
+            let response = hyper13::Response::builder()
+                .status(*status)
+                .body(Bytes::from("{\"code\":\"invalid\"}"))
+                .unwrap();
+            assert!(
+                logic.should_retry_response(&response).is_not_retryable(),
+                format!("Status {} should not be retryable", status)
+            );
+        }
+    }
+
+    #[test]
+    fn test_retry_logic_auth_errors() {
+        let logic = InfluxDBRetryLogic::default();
+        let reason = |status: u16| {
+            let response = hyper13::Response::builder()
+                .status(status)
+                .body(Bytes::from("{\"code\":\"unauthorized\"}"))
+                .unwrap();
+            match logic.should_retry_response(&response) {
+                RetryAction::DontRetry(reason) => reason,
+                _ => panic!("Status {} should not be retryable", status),
+            }
+        };
+
+        let unauthorized = reason(401);
+        assert!(
+            unauthorized.starts_with("Invalid token: "),
+            "{}",
+            unauthorized
+        );
+        assert!(unauthorized.contains("401 Unauthorized"));
+        assert!(unauthorized.ends_with("{\"code\":\"unauthorized\"}"));
+
+        let forbidden = reason(403);
+        assert!(
+            forbidden.starts_with("Insufficient permission: "),
+            "{}",
+            forbidden
+        );
+        assert!(forbidden.contains("403 Forbidden"));
+
+        assert_eq!(AuthError::from_status(StatusCode::BAD_REQUEST), None);
+    }
+
+    #[test]
+    fn test_retry_logic_successful_statuses() {
+        let logic = InfluxDBRetryLogic::default();
+
+        for status in &[200, 204] {
+            let response = hyper13::Response::builder()
+                .status(*status)
+                .body(Bytes::new())
+                .unwrap();
+            assert!(logic.should_retry_response(&response).is_successful());
+        }
+    }
+
+    #[test]
+    fn test_encode_tags() {
+        let mut value = String::new();
+        encode_tags(tags(), &mut value);
+
+        assert_eq!(value, "normal_tag=value,true_tag=true");
+
+        let tags_to_escape = vec![
+            ("tag".to_owned(), "val=ue".to_owned()),
+            ("name escape".to_owned(), "true".to_owned()),
+            ("value_escape".to_owned(), "value escape".to_owned()),
+            ("a_first_place".to_owned(), "10".to_owned()),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut value = String::new();
+        encode_tags(tags_to_escape, &mut value);
+        assert_eq!(
+            value,
+            "a_first_place=10,name\\ escape=true,tag=val\\=ue,value_escape=value\\ escape"
+        );
+
+        // Without any tag to write, what came before is left alone.
+        let mut value = "measurement,".to_owned();
+        encode_tags(
+            vec![("empty".to_owned(), "".to_owned())]
+                .into_iter()
+                .collect(),
+            &mut value,
+        );
+        assert_eq!(value, "measurement,");
+    }
+
+    #[test]
+    fn test_line_protocol_without_tags() {
+        let fields = || {
+            Some(
+                vec![("value".to_owned(), Field::Float(1.5))]
+                    .into_iter()
+                    .collect(),
+            )
+        };
+        let empty_tags = vec![
+            ("empty_value".to_owned(), "".to_owned()),
+            ("".to_owned(), "empty_key".to_owned()),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        // An empty `metric_type` is skipped like any other empty tag.
+        for tags in vec![None, Some(BTreeMap::new()), Some(empty_tags)] {
+            let mut line = String::new();
+            influx_line_protocol(
+                "cpu".to_owned(),
+                "",
+                tags,
+                fields(),
+                1,
+                BoolFormat::default(),
+                &mut line,
+            )
+            .unwrap();
+            assert_eq!(line, "cpu value=1.5 1\n");
+        }
+    }
+
+    #[test]
+    fn test_instance_tag_config() {
+        let build = |config: &str| toml::from_str::<InstanceTagConfig>(config).unwrap().build();
+
+        assert_eq!(
+            build(r#"id = "agent-7""#).unwrap(),
+            InstanceTag {
+                key: "vector_instance".to_owned(),
+                value: "agent-7".to_owned(),
+            }
+        );
+        assert_eq!(
+            build("key = \"agent\"\nid = \"agent-7\"\nid_from = \"uuid\"").unwrap(),
+            InstanceTag {
+                key: "agent".to_owned(),
+                value: "agent-7".to_owned(),
+            }
+        );
+
+        let hostname = build("").unwrap();
+        assert!(!hostname.value.is_empty());
+        if let Some(expected) = hostname::get_hostname() {
+            assert_eq!(hostname.value, expected);
+        }
+
+        // Generated once per build, so each instance gets its own.
+        let first = build(r#"id_from = "uuid""#).unwrap();
+        let second = build(r#"id_from = "uuid""#).unwrap();
+        assert!(uuid::Uuid::parse_str(&first.value).is_ok());
+        assert_ne!(first.value, second.value);
+
+        assert_eq!(
+            format!("{}", build(r#"id = """#).err().unwrap()),
+            "InfluxDB setting `instance_tag.id` must not be empty."
+        );
+    }
+
+    #[test]
+    fn test_tag_limits() {
+        assert!(TagLimits::new("influxdb_logs", None, None).is_none());
+        let limits = TagLimits::new("influxdb_logs", Some(16), Some(10)).unwrap();
+
+        let mut tags = vec![
+            ("host", "web-01"),
+            (
+                "trace",
+                "at Foo.bar(Foo.java:10)\n\tat Baz.qux(Baz.java:20)",
+            ),
+            // 10 bytes escaped, exactly the limit.
+            ("path", "a b,c d"),
+            ("city", "Zürich, Schweiz"),
+            ("a_key_that_is_far_too_long", "value"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect::<BTreeMap<_, _>>();
+        limits.apply(&mut tags);
+
+        let mut line = String::new();
+        encode_tags(tags.clone(), &mut line);
+        assert_eq!(
+            line,
+            "city=Zürich\\,,host=web-01,path=a\\ b\\,c\\ d,trace=at\\ Foo.ba"
+        );
+        assert!(tags.keys().all(|key| tag_len(key) <= 16));
+        assert!(tags.values().all(|value| tag_len(value) <= 10));
+
+        // Each truncated key is remembered once.
+        let mut truncated = limits
+            .truncated
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        truncated.sort();
+        assert_eq!(truncated, vec!["city", "trace"]);
+
+        // A value is never cut inside a multibyte character or an escape.
+        let mut value = "ab,ü".to_owned();
+        truncate_tag_value(&mut value, 3);
+        assert_eq!(value, "ab");
+        let mut value = "abcü".to_owned();
+        truncate_tag_value(&mut value, 4);
+        assert_eq!(value, "abc");
+        let mut value = "abcü".to_owned();
+        truncate_tag_value(&mut value, 5);
+        assert_eq!(value, "abcü");
+    }
+
+    #[test]
+    fn test_truncate_on_char_boundary() {
+        let originals = vec![
+            "ab😀cd",
+            // A family emoji, four emoji joined by zero width joiners
+            "a👨\u{200d}👩\u{200d}👧\u{200d}👦b",
+            // An e followed by a combining acute accent
+            "cafe\u{301} au lait",
+            "日本語のテキスト",
+            "x\u{1f1eb}\u{1f1f7}\"y\\",
+        ];
+        for original in originals {
+            for max in 0..=original.len() + 1 {
+                let mut value = original.to_owned();
+                let cut = truncate_on_char_boundary(&mut value, max, escaped_len);
+                assert!(original.starts_with(&value), "{:?} {}", original, max);
+                assert!(original.is_char_boundary(value.len()));
+                assert!(std::str::from_utf8(value.as_bytes()).is_ok());
+                assert!(value.chars().map(escaped_len).sum::<usize>() <= max);
+                assert_eq!(cut, value.len() < original.len());
+                // Cut as late as possible: the next character doesn't fit.
+                if let Some(next) = original[value.len()..].chars().next() {
+                    assert!(
+                        value.chars().map(escaped_len).sum::<usize>() + escaped_len(next) > max
+                    );
+                }
+            }
+        }
+
+        let mut value = "ab😀cd".to_owned();
+        assert!(truncate_on_char_boundary(&mut value, 5, char::len_utf8));
+        assert_eq!(value, "ab");
+        let mut value = "ab😀cd".to_owned();
+        assert!(truncate_on_char_boundary(&mut value, 6, char::len_utf8));
+        assert_eq!(value, "ab😀");
+        let mut value = "ab😀cd".to_owned();
+        assert!(!truncate_on_char_boundary(&mut value, 8, char::len_utf8));
+        assert_eq!(value, "ab😀cd");
+
+        // The accent is a character of its own and can be cut off its letter,
+        // which is still valid UTF-8.
+        let mut value = "cafe\u{301}".to_owned();
+        assert!(truncate_on_char_boundary(&mut value, 5, char::len_utf8));
+        assert_eq!(value, "cafe");
+
+        let mut fields = vec![("message".to_owned(), Field::String("ok 😀😀".to_owned()))]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        assert!(truncate_longest_string(&mut fields, 2));
+        assert_eq!(fields["message"], Field::String("ok 😀".to_owned()));
+    }
+
+    #[test]
+    fn test_encode_fields() {
+        let fields = vec![
+            (
+                "field_string".to_owned(),
+                Field::String("string value".to_owned()),
+            ),
+            (
+                "field_string_escape".to_owned(),
+                Field::String("string\\val\"ue".to_owned()),
+            ),
+            ("field_float".to_owned(), Field::Float(123.45)),
+            ("field_unsigned_int".to_owned(), Field::UnsignedInt(657)),
+            ("field_int".to_owned(), Field::Int(657646)),
+            ("field_bool_true".to_owned(), Field::Bool(true)),
+            ("field_bool_false".to_owned(), Field::Bool(false)),
+            ("escape key".to_owned(), Field::Float(10.0)),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut value = String::new();
+        encode_fields(fields, BoolFormat::Long, &mut value);
+        assert_fields(
+            value,
+            [
+                "escape\\ key=10.0",
+                "field_float=123.45",
+                "field_string=\"string value\"",
+                "field_string_escape=\"string\\\\val\\\"ue\"",
+                "field_unsigned_int=657u",
+                "field_int=657646i",
+                "field_bool_true=true",
+                "field_bool_false=false",
+            ]
+            .to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_encode_field_key_special_chars() {
+        let encoded = |key: &str| {
+            let mut value = String::new();
+            encode_string(key, &mut value);
+            value
+        };
+        assert_eq!(encoded("a,b=c"), "a\\,b\\=c");
+        assert_eq!(encoded("a=b"), "a\\=b");
+        assert_eq!(encoded("a, b"), "a\\,\\ b");
+        assert_eq!(encoded("==,,"), "\\=\\=\\,\\,");
+        // A trailing backslash can't escape the `=` that follows the key
+        assert_eq!(encoded("a\\"), "a\\\\");
+
+        let fields = vec![
+            ("a,b=c".to_owned(), Field::Int(1)),
+            (
+                "status code=".to_owned(),
+                Field::String("a=b, c".to_owned()),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let mut value = String::new();
+        encode_fields(fields, BoolFormat::Long, &mut value);
+        assert_eq!(value, "a\\,b\\=c=1i,status\\ code\\==\"a=b, c\"");
+    }
+
+    #[test]
+    fn test_encode_string() {
+        let mut value = String::new();
+        encode_string("measurement_name", &mut value);
+        assert_eq!(value, "measurement_name");
+
+        let mut value = String::new();
+        encode_string("measurement name", &mut value);
+        assert_eq!(value, "measurement\\ name");
+
+        let mut value = String::new();
+        encode_string("measurement=name", &mut value);
+        assert_eq!(value, "measurement\\=name");
+
+        let mut value = String::new();
+        encode_string("measurement,name", &mut value);
+        assert_eq!(value, "measurement\\,name");
+    }
+
+    #[test]
+    fn test_encode_measurement() {
+        let mut value = String::new();
+        encode_measurement("measurement name", &mut value);
+        assert_eq!(value, "measurement\\ name");
+
+        let mut value = String::new();
+        encode_measurement("measurement,name", &mut value);
+        assert_eq!(value, "measurement\\,name");
+
+        let mut value = String::new();
+        encode_measurement("a=b", &mut value);
+        assert_eq!(value, "a=b");
+
+        let mut value = String::new();
+        encode_tags(
+            vec![("a=b".to_owned(), "value".to_owned())]
+                .into_iter()
+                .collect(),
+            &mut value,
+        );
+        assert_eq!(value, "a\\=b=value");
+    }
+
+    fn escaped(encode: fn(&str, &mut String), value: &str) -> String {
+        let mut output = String::new();
+        encode(value, &mut output);
+        output
+    }
+
+    #[test]
+    fn test_escaping_per_position() {
+        assert_eq!(escaped(encode_measurement, "a,b"), "a\\,b");
+        assert_eq!(escaped(encode_measurement, "a b"), "a\\ b");
+        assert_eq!(escaped(encode_measurement, "a=b"), "a=b");
+        assert_eq!(escaped(encode_measurement, "a\"b"), "a\"b");
+        assert_eq!(escaped(encode_measurement, "a\\b"), "a\\b");
+
+        assert_eq!(escaped(encode_string, "a,b"), "a\\,b");
+        assert_eq!(escaped(encode_string, "a b"), "a\\ b");
+        assert_eq!(escaped(encode_string, "a=b"), "a\\=b");
+        assert_eq!(escaped(encode_string, "a\"b"), "a\"b");
+        assert_eq!(escaped(encode_string, "a\\b"), "a\\\\b");
+
+        assert_eq!(escaped(encode_field_string, "a,b"), "\"a,b\"");
+        assert_eq!(escaped(encode_field_string, "a b"), "\"a b\"");
+        assert_eq!(escaped(encode_field_string, "a=b"), "\"a=b\"");
+        assert_eq!(escaped(encode_field_string, "a\"b"), "\"a\\\"b\"");
+        assert_eq!(escaped(encode_field_string, "a\\b"), "\"a\\\\b\"");
+    }
+
+    #[test]
+    fn test_escape_measurement_and_tag_value() {
+        let mut value = String::new();
+        influx_line_protocol(
+            "a=b".to_owned(),
+            "logs",
+            Some(
+                vec![("quote".to_owned(), "say \"hi\"".to_owned())]
+                    .into_iter()
+                    .collect(),
+            ),
+            Some(
+                vec![("message".to_owned(), Field::String("say \"hi\"".to_owned()))]
+                    .into_iter()
+                    .collect(),
+            ),
+            1,
+            BoolFormat::Long,
+            &mut value,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            "a=b,metric_type=logs,quote=say\\ \"hi\" message=\"say \\\"hi\\\"\" 1\n"
+        );
+    }
+
+    #[test]
+    fn test_escape_line_terminators() {
+        assert_eq!(
+            escaped(encode_field_string, "first line\nsecond line\r\n\tindented"),
+            "\"first line\\nsecond line\\r\\n\\tindented\""
+        );
+        assert_eq!(escaped(encode_string, "tab\tvalue"), "tabvalue");
+        assert_eq!(escaped(encode_string, "new\nline"), "newline");
+
+        let mut value = String::new();
+        influx_line_protocol(
+            "measurement".to_owned(),
+            "logs",
+            Some(
+                vec![("tag".to_owned(), "tab\tvalue".to_owned())]
+                    .into_iter()
+                    .collect(),
+            ),
+            Some(
+                vec![(
+                    "message".to_owned(),
+                    Field::String("first line\nsecond line".to_owned()),
+                )]
+                .into_iter()
+                .collect(),
+            ),
+            1,
+            BoolFormat::Long,
+            &mut value,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            "measurement,metric_type=logs,tag=tabvalue message=\"first line\\nsecond line\" 1\n"
+        );
+    }
+
+    fn oversized_fields() -> HashMap<String, Field> {
+        vec![
+            ("message".to_owned(), Field::String("é\"x".repeat(20))),
+            ("level".to_owned(), Field::String("info".to_owned())),
+            ("count".to_owned(), Field::Int(1)),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn test_line_limit_drop() {
+        let limit = LineEncoder {
+            max_bytes: Some(64),
+            on_oversize: OnOversize::Drop,
+            ..Default::default()
+        };
+
+        let mut output = "previous 1\n".to_owned();
+        let result = limit.encode(
+            "vector".to_owned(),
+            "logs",
+            None,
+            Some(oversized_fields()),
+            1,
+            &mut output,
+        );
+        assert!(matches!(
+            result,
+            Err(LineProtocolError::LineTooLong { max: 64, .. })
+        ));
+        assert_eq!(output, "previous 1\n");
+    }
+
+    #[test]
+    fn test_output_buffer_reuse() {
+        let capacity = with_output_buffer(|buffer| {
+            // Left behind by an encode that failed half way.
+            buffer.push_str("partial");
+            buffer.capacity()
+        });
+
+        with_output_buffer(|buffer| {
+            assert_eq!(buffer, "");
+            assert_eq!(buffer.capacity(), capacity);
+
+            with_output_buffer(|nested| {
+                nested.push_str("nested");
+            });
+            assert_eq!(buffer, "");
+        });
+    }
+
+    const BATCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // Sends `items` to a `BatchSink` and lets `elapsed` pass before polling
+    // it, returning the batches that were flushed.
+    fn send_limited_batches(
+        limits: BatchLimits,
+        items: &[&str],
+        elapsed: Duration,
+    ) -> Vec<Vec<String>> {
+        let rt = runtime();
+        let mut clock = MockClock::new();
+
+        let (acker, _) = Acker::new_for_testing();
+        let sent_requests = Arc::new(Mutex::new(Vec::new()));
+
+        let svc = tower::service_fn(|req| {
+            let sent_requests = sent_requests.clone();
+
+            sent_requests.lock().unwrap().push(req);
+
+            futures01::future::ok::<_, std::io::Error>(())
+        });
+        let settings = BatchSettings {
+            size: limits.max_events,
+            timeout: BATCH_TIMEOUT,
+        };
+        // Counts the newline every line ends with.
+        let batch = LimitedBatch::new(Vec::new(), limits, |line: &String| line.len() + 1);
+        let mut buffered = BatchSink::with_executor(svc, batch, settings, acker, rt.executor());
+
+        clock.enter(|handle| {
+            for item in items {
+                assert!(buffered.start_send(item.to_string()).unwrap().is_ready());
+            }
+            handle.advance(elapsed);
+
+            futures01::future::poll_fn(|| buffered.poll_complete())
+                .wait()
+                .unwrap();
+        });
+
+        let output = sent_requests.lock().unwrap();
+        output.clone()
+    }
+
+    #[test]
+    fn test_batch_flush_on_max_events() {
+        let limits = BatchLimits {
+            max_events: 2,
+            max_bytes: Some(1000),
+        };
+        let sent = send_limited_batches(limits, &["a", "b", "c"], Duration::from_secs(0));
+        assert_eq!(sent, vec![vec!["a".to_owned(), "b".to_owned()]]);
+    }
+
+    #[test]
+    fn test_batch_flush_on_max_bytes() {
+        let config: InfluxDBBatchConfig = toml::from_str("max_bytes = 8").unwrap();
+        let (limits, _) = config.unwrap_or(None, None, 1);
+        let sent = send_limited_batches(limits, &["abc", "defg", "h"], Duration::from_secs(0));
+        assert_eq!(sent, vec![vec!["abc".to_owned(), "defg".to_owned()]]);
+
+        // The name used by the logs sink before `max_bytes`.
+        let config: InfluxDBBatchConfig = toml::from_str("max_size = 8").unwrap();
+        assert_eq!(config.max_bytes, Some(8));
+    }
+
+    #[test]
+    fn test_batch_flush_on_timeout() {
+        let limits = BatchLimits {
+            max_events: 10,
+            max_bytes: Some(1000),
+        };
+        let sent = send_limited_batches(limits, &["a", "b"], Duration::from_secs(0));
+        assert!(sent.is_empty());
+
+        let elapsed = BATCH_TIMEOUT + Duration::from_secs(1);
+        let sent = send_limited_batches(limits, &["a", "b"], elapsed);
+        assert_eq!(sent, vec![vec!["a".to_owned(), "b".to_owned()]]);
+    }
+
+    fn fields(fields: Vec<(&str, Field)>) -> HashMap<String, Field> {
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value))
+            .collect()
+    }
+
+    #[test]
+    fn test_tag_filter_allow() {
+        let filter = TagFilterConfig {
+            allow: Some(vec!["host".to_owned(), "region".to_owned()]),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+
+        let mut tags = tags_from(&[("host", "a"), ("region", "eu"), ("request_id", "1f3b")]);
+        let mut fields = fields(vec![("value", Field::Float(1.0))]);
+        filter.apply(&mut tags, &mut fields);
+        assert_eq!(tags, tags_from(&[("host", "a"), ("region", "eu")]));
+        assert_eq!(fields, self::fields(vec![("value", Field::Float(1.0))]));
+    }
+
+    #[test]
+    fn test_tag_filter_deny() {
+        let filter = TagFilterConfig {
+            deny: vec!["request_id".to_owned()],
+            demote_to_fields: true,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+
+        let mut tags = tags_from(&[("host", "a"), ("request_id", "1f3b")]);
+        let mut fields = fields(vec![("value", Field::Float(1.0))]);
+        filter.apply(&mut tags, &mut fields);
+        assert_eq!(tags, tags_from(&[("host", "a")]));
+        // Denied tags are dropped even when demoting
+        assert_eq!(fields, self::fields(vec![("value", Field::Float(1.0))]));
+
+        assert!(TagFilterConfig::default().build().is_none());
+    }
+
+    #[test]
+    fn test_tag_filter_demote_to_fields() {
+        let filter = TagFilterConfig {
+            allow: Some(vec!["host".to_owned()]),
+            deny: vec!["secret".to_owned()],
+            demote_to_fields: true,
+        }
+        .build()
+        .unwrap();
+
+        let mut tags = tags_from(&[
+            ("host", "a"),
+            ("request_id", "1f3b"),
+            ("secret", "s3cr3t"),
+            ("value", "tag"),
+        ]);
+        let mut fields = fields(vec![("value", Field::Float(1.0))]);
+        filter.apply(&mut tags, &mut fields);
+        assert_eq!(tags, tags_from(&[("host", "a")]));
+        assert_eq!(
+            fields,
+            self::fields(vec![
+                ("request_id", Field::String("1f3b".to_owned())),
+                ("value", Field::Float(1.0)),
+            ])
+        );
+    }
+
+    fn breaker(failure_threshold: usize) -> CircuitBreaker {
+        CircuitBreakerConfig {
+            failure_threshold,
+            cooldown_secs: 30,
+        }
+        .build("influxdb_metrics")
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens_after_threshold() {
+        let breaker = breaker(3);
+        let start = Instant::now();
+
+        breaker.record(false, start);
+        breaker.record(false, start);
+        assert_eq!(breaker.circuit_state(), CircuitState::Closed);
+        assert!(breaker.allow(start).is_some());
+
+        // A success resets the count
+        breaker.record(true, start);
+        breaker.record(false, start);
+        breaker.record(false, start);
+        assert_eq!(breaker.circuit_state(), CircuitState::Closed);
+
+        breaker.record(false, start);
+        assert_eq!(breaker.circuit_state(), CircuitState::Open);
+        assert!(breaker.allow(start + Duration::from_secs(29)).is_none());
+    }
+
+    #[test]
+    fn test_circuit_breaker_closes_after_probe() {
+        let breaker = breaker(1);
+        let start = Instant::now();
+
+        breaker.record(false, start);
+        assert_eq!(breaker.circuit_state(), CircuitState::Open);
+
+        // Only one probe at a time once the cooldown is over
+        let later = start + Duration::from_secs(30);
+        let probe = breaker.allow(later).unwrap();
+        assert_eq!(breaker.circuit_state(), CircuitState::HalfOpen);
+        assert!(breaker.allow(later).is_none());
+
+        // A failed probe starts another cooldown
+        probe.record(false, later);
+        assert_eq!(breaker.circuit_state(), CircuitState::Open);
+        assert!(breaker.allow(later + Duration::from_secs(1)).is_none());
+
+        let later = later + Duration::from_secs(30);
+        breaker.allow(later).unwrap().record(true, later);
+        assert_eq!(breaker.circuit_state(), CircuitState::Closed);
+        assert!(breaker.allow(later).is_some());
+    }
+
+    #[test]
+    fn test_circuit_breaker_probe_without_outcome() {
+        let breaker = breaker(1);
+        let start = Instant::now();
+        breaker.record(false, start);
+
+        // A probe that sent no request lets the next write probe instead.
+        let later = start + Duration::from_secs(30);
+        breaker.allow(later).unwrap().release();
+        assert_eq!(breaker.circuit_state(), CircuitState::Open);
+        let probe = breaker.allow(later).unwrap();
+        assert_eq!(breaker.circuit_state(), CircuitState::HalfOpen);
+
+        // A probe dropped before its outcome, as on a timeout, failed.
+        drop(probe);
+        assert_eq!(breaker.circuit_state(), CircuitState::Open);
+        assert!(breaker.allow(Instant::now()).is_none());
+
+        let later = Instant::now() + Duration::from_secs(30);
+        assert!(breaker.allow(later).is_some());
+        assert_eq!(breaker.circuit_state(), CircuitState::HalfOpen);
+    }
+
+    #[test]
+    fn test_retry_budget_limits_retries() {
+        let budget = RetryBudgetConfig {
+            retries_per_sec: 2.0,
+            burst: Some(5),
+        }
+        .build("influxdb_metrics");
+        let start = Instant::now();
+
+        // Many failing batches at once only get the burst
+        let granted = (0..100).filter(|_| budget.withdraw(start)).count();
+        assert_eq!(granted, 5);
+
+        // Then retries come back at the configured rate
+        let mut granted = 0;
+        for tick in 1..=100 {
+            let now = start + Duration::from_millis(100 * tick);
+            granted += (0..10).filter(|_| budget.withdraw(now)).count();
+        }
+        assert!(granted <= 20, "{} retries in 10 seconds", granted);
+        assert!(granted >= 19, "{} retries in 10 seconds", granted);
+    }
+
+    #[test]
+    fn test_retry_logic_budget_exhausted() {
+        let budget = RetryBudgetConfig {
+            retries_per_sec: 0.001,
+            burst: Some(3),
+        };
+        let logic = InfluxDBRetryLogic::new(Some(&budget), "influxdb_metrics");
+        let response = hyper13::Response::builder()
+            .status(503)
+            .body(Bytes::new())
+            .unwrap();
+
+        let actions = (0..10)
+            .map(|_| logic.clone().should_retry_response(&response))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions
+                .iter()
+                .filter(|action| action.is_retryable())
+                .count(),
+            3
+        );
+        match &actions[3] {
+            RetryAction::DontRetry(reason) => assert!(
+                reason.starts_with("retry budget exhausted after "),
+                "{}",
+                reason
+            ),
+            _ => panic!("Retries should stop once the budget is exhausted"),
+        }
+
+        // Responses that aren't retried don't use the budget
+        let response = hyper13::Response::builder()
+            .status(400)
+            .body(Bytes::new())
+            .unwrap();
+        assert!(logic.should_retry_response(&response).is_not_retryable());
+    }
+
+    #[test]
+    fn test_field_type_conflicts() {
+        let field_types = FieldTypes::shared(Some(OnFieldTypeConflict::Warn), None).unwrap();
+        let mut field_types = field_types.lock().unwrap();
+
+        let first = fields(vec![("count", Field::Int(1)), ("ok", Field::Bool(true))]);
+        assert!(field_types.conflicts("vector", &first).is_empty());
+        let other_measurement = fields(vec![("count", Field::Float(1.5))]);
+        assert!(field_types
+            .conflicts("other", &other_measurement)
+            .is_empty());
+
+        let switched = fields(vec![("count", Field::Float(1.5)), ("new", Field::Int(1))]);
+        assert_eq!(
+            field_types.conflicts("vector", &switched),
+            vec![FieldTypeConflict {
+                field: "count".to_owned(),
+                expected: "integer",
+                actual: "float",
+            }]
+        );
+        // Not remembered, InfluxDB rejected the point
+        let new = fields(vec![("new", Field::String("x".to_owned()))]);
+        assert!(field_types.conflicts("vector", &new).is_empty());
+
+        // Warned about, but still sent
+        assert!(field_types.check("influxdb_logs", "vector", &switched));
+        assert!(field_types.check("influxdb_logs", "vector", &first));
+    }
+
+    #[test]
+    fn test_field_type_conflicts_drop() {
+        let field_types = FieldTypes::shared(Some(OnFieldTypeConflict::Drop), Some(1)).unwrap();
+        let mut field_types = field_types.lock().unwrap();
+
+        assert!(field_types.check(
+            "influxdb_logs",
+            "vector",
+            &fields(vec![("count", Field::Int(1))])
+        ));
+        assert!(!field_types.check(
+            "influxdb_logs",
+            "vector",
+            &fields(vec![("count", Field::Float(1.5))])
+        ));
+
+        // Evicted by a more recently used field
+        assert!(field_types.check(
+            "influxdb_logs",
+            "vector",
+            &fields(vec![("other", Field::Int(1))])
+        ));
+        assert!(field_types.check(
+            "influxdb_logs",
+            "vector",
+            &fields(vec![("count", Field::Float(1.5))])
+        ));
+
+        assert!(FieldTypes::shared(None, Some(1)).is_none());
+    }
+
+    fn sample_schema() -> Schema {
+        let config: SchemaConfig = toml::from_str(
+            r#"
+            measurements = ["ns.cpu", "ns.mem"]
+            fields = ["usage_[a-z]+", "value"]
+        "#,
+        )
+        .unwrap();
+        config.build().unwrap()
+    }
 
-        for field in fields.into_iter() {
-            assert!(
-                encoded_fields.contains(&field),
-                format!("Fields: {} has to have: {}", value, field)
-            )
-        }
+    #[test]
+    fn test_schema_conforming() {
+        let schema = sample_schema();
+        let conforming = fields(vec![
+            ("usage_user", Field::Float(0.5)),
+            ("value", Field::Int(1)),
+        ]);
+        assert_eq!(schema.violation("ns.cpu", &conforming), None);
+        assert!(schema.check("influxdb_metrics", "ns.mem", &conforming));
+
+        let any = SchemaConfig::default().build().unwrap();
+        assert_eq!(
+            any.violation("anything", &fields(vec![("x", Field::Int(1))])),
+            None
+        );
     }
 
-    // ns.requests,metric_type=distribution,normal_tag=value,true_tag=true avg=1.875,count=8,max=3,median=2,min=1,quantile_0.95=3,sum=15 1542182950000000011
-    //
-    // =>
-    //
-    // ns.requests
-    // metric_type=distribution,normal_tag=value,true_tag=true
-    // avg=1.875,count=8,max=3,median=2,min=1,quantile_0.95=3,sum=15
-    // 1542182950000000011
-    //
-    pub(crate) fn split_line_protocol(line_protocol: &str) -> (&str, &str, String, &str) {
-        let mut split = line_protocol.splitn(2, ',').collect::<Vec<&str>>();
-        let measurement = split[0];
+    #[test]
+    fn test_schema_violations() {
+        let schema = sample_schema();
+        let value = fields(vec![("value", Field::Int(1))]);
+        assert_eq!(
+            schema.violation("ns.disk", &value),
+            Some(SchemaViolation::UnknownMeasurement {
+                measurement: "ns.disk".to_owned()
+            })
+        );
 
-        split = split[1].splitn(3, ' ').collect::<Vec<&str>>();
+        // Patterns match whole names only
+        let unknown = fields(vec![
+            ("value", Field::Int(1)),
+            ("usage_user2", Field::Int(1)),
+        ]);
+        assert_eq!(
+            schema.violation("ns.cpu", &unknown),
+            Some(SchemaViolation::UnknownField {
+                field: "usage_user2".to_owned()
+            })
+        );
+        assert!(!schema.check("influxdb_metrics", "ns.cpu", &unknown));
 
-        return (measurement, split[0], split[1].to_string(), split[2]);
+        let config = SchemaConfig {
+            measurements: vec![],
+            fields: vec!["(".to_owned()],
+        };
+        assert!(config.build().is_err());
     }
 
-    pub(crate) fn onboarding_v2() {
-        let mut body = std::collections::HashMap::new();
-        body.insert("username", "my-user");
-        body.insert("password", "my-password");
-        body.insert("org", ORG);
-        body.insert("bucket", BUCKET);
-        body.insert("token", TOKEN);
+    #[test]
+    fn test_line_limit_truncate() {
+        let limit = LineEncoder {
+            max_bytes: Some(64),
+            on_oversize: OnOversize::Truncate,
+            ..Default::default()
+        };
 
-        let client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
-            .build()
+        let mut output = String::new();
+        limit
+            .encode(
+                "vector".to_owned(),
+                "logs",
+                None,
+                Some(oversized_fields()),
+                1,
+                &mut output,
+            )
             .unwrap();
 
-        let res = client
-            .post("http://localhost:9999/api/v2/setup")
-            .json(&body)
-            .header("accept", "application/json")
-            .send()
+        let line = output.trim_end_matches('\n');
+        assert!(line.len() <= 64, "{}", line);
+        let (measurement, tags, fields, timestamp) = split_line_protocol(line);
+        assert_eq!(measurement, "vector");
+        assert_eq!(tags, "metric_type=logs");
+        assert_eq!(timestamp, "1");
+
+        let message = fields
+            .split(',')
+            .find(|field| field.starts_with("message="))
             .unwrap();
+        // Cut on a character boundary, without a dangling escape
+        assert_eq!(message, r#"message="é\"x""#);
+        assert!(fields.contains("level=\"info\""));
+        assert!(fields.contains("count=1i"));
+    }
 
-        let status = res.status();
+    #[test]
+    fn test_line_limit_truncate_not_enough() {
+        let limit = LineEncoder {
+            max_bytes: Some(10),
+            on_oversize: OnOversize::Truncate,
+            ..Default::default()
+        };
 
-        assert!(
-            status == http::StatusCode::CREATED || status == http::StatusCode::UNPROCESSABLE_ENTITY,
-            format!("UnexpectedStatus: {}", status)
+        let mut output = String::new();
+        let result = limit.encode(
+            "vector".to_owned(),
+            "logs",
+            None,
+            Some(oversized_fields()),
+            1,
+            &mut output,
         );
+        assert!(matches!(
+            result,
+            Err(LineProtocolError::LineTooLong { max: 10, .. })
+        ));
+        assert_eq!(output, "");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::sinks::influxdb::test_util::{assert_fields, tags, ts};
+    #[test]
+    fn test_metric_type_tag_collision() {
+        let mut tags = BTreeMap::new();
+        assert_eq!(insert_metric_type(&mut tags, "gauge"), None);
+        assert_eq!(insert_metric_type(&mut tags, "gauge"), None);
 
-    #[derive(Deserialize, Serialize, Debug, Clone, Default)]
-    #[serde(deny_unknown_fields)]
-    pub struct InfluxDBTestConfig {
-        #[serde(flatten)]
-        pub influxdb1_settings: Option<InfluxDB1Settings>,
-        #[serde(flatten)]
-        pub influxdb2_settings: Option<InfluxDB2Settings>,
+        tags.insert("metric_type".to_owned(), "user".to_owned());
+        assert_eq!(
+            insert_metric_type(&mut tags, "gauge"),
+            Some("user".to_owned())
+        );
+        assert_eq!(tags["metric_type"], "gauge");
+
+        let mut value = String::new();
+        influx_line_protocol(
+            "measurement".to_owned(),
+            "gauge",
+            Some(
+                vec![
+                    ("metric_type".to_owned(), "user".to_owned()),
+                    ("host".to_owned(), "localhost".to_owned()),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            Some(
+                vec![("value".to_owned(), Field::Float(1.0))]
+                    .into_iter()
+                    .collect(),
+            ),
+            1,
+            BoolFormat::Long,
+            &mut value,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            "measurement,host=localhost,metric_type=gauge value=1.0 1\n"
+        );
     }
 
     #[test]
-    fn test_influxdb_settings_both() {
-        let config = r#"
-        bucket = "my-bucket"
-        org = "my-org"
-        token = "my-token"
-        database = "my-database"
-    "#;
-        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
-        let settings = influxdb_settings(config.influxdb1_settings, config.influxdb2_settings);
-        match settings {
-            Ok(_) => assert!(false, "Expected error"),
-            Err(e) => assert_eq!(format!("{}",e), "Unclear settings. Both version configured v1: InfluxDB1Settings { database: \"my-database\", consistency: None, retention_policy_name: None, username: None, password: None }, v2: InfluxDB2Settings { org: \"my-org\", bucket: \"my-bucket\", token: \"my-token\" }.".to_owned())
-        }
+    fn test_empty_measurement() {
+        let mut value = String::new();
+        let fields = vec![("value".to_owned(), Field::Float(1.0))]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            influx_line_protocol(
+                "".to_owned(),
+                "gauge",
+                None,
+                Some(fields),
+                1,
+                BoolFormat::Long,
+                &mut value
+            ),
+            Err(LineProtocolError::EmptyMeasurement)
+        );
+        assert_eq!(value, "");
     }
 
     #[test]
-    fn test_influxdb_settings_missing() {
-        let config = r#"
-    "#;
-        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
-        let settings = influxdb_settings(config.influxdb1_settings, config.influxdb2_settings);
-        match settings {
-            Ok(_) => assert!(false, "Expected error"),
-            Err(e) => assert_eq!(
-                format!("{}", e),
-                "InfluxDB v1 or v2 should be configured as endpoint.".to_owned()
+    fn test_measurement_line_break() {
+        let fields = || {
+            Some(
+                vec![("value".to_owned(), Field::Float(1.0))]
+                    .into_iter()
+                    .collect(),
+            )
+        };
+
+        let mut value = String::new();
+        assert_eq!(
+            influx_line_protocol(
+                encode_namespace("ns\n", "cpu"),
+                "gauge",
+                None,
+                fields(),
+                1,
+                BoolFormat::Long,
+                &mut value
             ),
-        }
+            Err(LineProtocolError::MeasurementLineBreak {
+                measurement: "ns\n.cpu".to_owned()
+            })
+        );
+        assert_eq!(value, "");
+
+        let encoder = LineEncoder {
+            max_bytes: Some(1024),
+            ..LineEncoder::default()
+        };
+        assert_eq!(
+            encoder.encode(
+                encode_namespace("ns", "cpu\r"),
+                "gauge",
+                None,
+                fields(),
+                1,
+                &mut value
+            ),
+            Err(LineProtocolError::MeasurementLineBreak {
+                measurement: "ns.cpu\r".to_owned()
+            })
+        );
+        assert_eq!(value, "");
     }
 
     #[test]
-    fn test_influxdb1_settings() {
-        let config = r#"
-        database = "my-database"
-    "#;
-        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
-        let _ = influxdb_settings(config.influxdb1_settings, config.influxdb2_settings).unwrap();
+    fn test_no_fields() {
+        let mut value = String::new();
+        let fields = vec![
+            ("nan".to_owned(), Field::Float(f64::NAN)),
+            ("inf".to_owned(), Field::Float(f64::INFINITY)),
+        ]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+        let encode = |fields, value: &mut String| {
+            influx_line_protocol(
+                "measurement".to_owned(),
+                "gauge",
+                None,
+                fields,
+                1,
+                BoolFormat::Long,
+                value,
+            )
+        };
+
+        assert_eq!(encode(None, &mut value), Err(LineProtocolError::NoFields));
+        assert_eq!(
+            encode(Some(fields.clone()), &mut value),
+            Err(LineProtocolError::NoFields)
+        );
+        assert_eq!(value, "");
+
+        // Only the finite fields are written
+        let mut mixed = fields;
+        mixed.insert("value".to_owned(), Field::Float(1.5));
+        encode(Some(mixed), &mut value).unwrap();
+        assert_eq!(value, "measurement,metric_type=gauge value=1.5 1\n");
     }
 
     #[test]
-    fn test_influxdb2_settings() {
-        let config = r#"
-        bucket = "my-bucket"
-        org = "my-org"
-        token = "my-token"
-    "#;
-        let config: InfluxDBTestConfig = toml::from_str(&config).unwrap();
-        let _ = influxdb_settings(config.influxdb1_settings, config.influxdb2_settings).unwrap();
+    fn test_encode_timestamp() {
+        let start = Utc::now().timestamp_nanos();
+        assert_eq!(encode_timestamp(Some(ts())), 1542182950000000011);
+        assert!(encode_timestamp(None) >= start)
     }
 
     #[test]
-    fn test_influxdb1_test_write_uri() {
-        let settings = InfluxDB1Settings {
-            consistency: Some("quorum".to_owned()),
-            database: "vector_db".to_owned(),
-            retention_policy_name: Some("autogen".to_owned()),
-            username: Some("writer".to_owned()),
-            password: Some("secret".to_owned()),
-        };
-
-        let uri = settings
-            .write_uri("http://localhost:8086".to_owned())
-            .unwrap();
-        assert_eq!("http://localhost:8086/write?consistency=quorum&db=vector_db&rp=autogen&p=secret&u=writer&precision=ns", uri.to_string())
+    fn test_encode_timestamp_with_clock() {
+        let now = || Utc.ymd(2020, 6, 1).and_hms_nano(12, 0, 0, 42);
+        assert_eq!(encode_timestamp_with(None, now), 1_591_012_800_000_000_042);
+        assert_eq!(
+            encode_timestamp_with(Some(ts()), now),
+            1_542_182_950_000_000_011
+        );
     }
 
     #[test]
-    fn test_influxdb2_test_write_uri() {
-        let settings = InfluxDB2Settings {
-            org: "my-org".to_owned(),
-            bucket: "my-bucket".to_owned(),
-            token: "my-token".to_owned(),
-        };
+    fn test_encode_timestamp_before_epoch() {
+        let ts = Utc.ymd(1969, 12, 31).and_hms_nano(23, 59, 58, 500_000_000);
+        assert_eq!(encode_timestamp(Some(ts)), -1_500_000_000);
 
-        let uri = settings
-            .write_uri("http://localhost:9999".to_owned())
-            .unwrap();
+        assert_eq!(scale_timestamp(-1_500_000_000, Precision::S), -2);
+        assert_eq!(scale_timestamp(-1_500_000_000, Precision::Ms), -1_500);
+        assert_eq!(scale_timestamp(-1, Precision::Us), -1);
+        assert_eq!(scale_timestamp(-1_000_000_000, Precision::S), -1);
+        assert_eq!(scale_timestamp(1_500_000_000, Precision::S), 1);
         assert_eq!(
-            "http://localhost:9999/api/v2/write?org=my-org&bucket=my-bucket&precision=ns",
-            uri.to_string()
-        )
+            scale_timestamp(-1_500_000_000, Precision::Ns),
+            -1_500_000_000
+        );
     }
 
     #[test]
-    fn test_influxdb1_test_healthcheck_uri() {
-        let settings = InfluxDB1Settings {
-            consistency: Some("quorum".to_owned()),
-            database: "vector_db".to_owned(),
-            retention_policy_name: Some("autogen".to_owned()),
-            username: Some("writer".to_owned()),
-            password: Some("secret".to_owned()),
+    fn test_line_encoder_precision() {
+        let encoder = LineEncoder {
+            precision: Precision::Ms,
+            ..Default::default()
         };
 
-        let uri = settings
-            .healthcheck_uri("http://localhost:8086".to_owned())
+        let mut output = String::new();
+        encoder
+            .encode(
+                "vector".to_owned(),
+                "counter",
+                None,
+                Some(
+                    vec![("value".to_owned(), Field::Float(1.0))]
+                        .into_iter()
+                        .collect(),
+                ),
+                encode_timestamp(Some(ts())),
+                &mut output,
+            )
             .unwrap();
-        assert_eq!("http://localhost:8086/ping", uri.to_string())
+        assert_eq!(
+            output,
+            "vector,metric_type=counter value=1.0 1542182950000\n"
+        );
+        assert_eq!(Precision::Ms.scale(-1_500_000_000), -1_500);
     }
 
     #[test]
-    fn test_influxdb2_test_healthcheck_uri() {
-        let settings = InfluxDB2Settings {
-            org: "my-org".to_owned(),
-            bucket: "my-bucket".to_owned(),
-            token: "my-token".to_owned(),
+    fn test_underscore_keys() {
+        let encode = |underscore_keys, fields: Vec<(&str, Field)>| {
+            let encoder = LineEncoder {
+                underscore_keys,
+                ..Default::default()
+            };
+            let tags = vec![("_measurement".to_owned(), "cpu".to_owned())]
+                .into_iter()
+                .collect();
+            let fields = fields
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect();
+            let mut output = String::new();
+            encoder
+                .encode(
+                    "vector".to_owned(),
+                    "gauge",
+                    Some(tags),
+                    Some(fields),
+                    1,
+                    &mut output,
+                )
+                .map(|_| output)
         };
+        let fields = || vec![("_time", Field::Int(42)), ("value", Field::Float(1.5))];
 
-        let uri = settings
-            .healthcheck_uri("http://localhost:9999".to_owned())
-            .unwrap();
-        assert_eq!("http://localhost:9999/health", uri.to_string())
+        assert_eq!(
+            encode(UnderscoreKeys::Keep, fields()).unwrap(),
+            "vector,_measurement=cpu,metric_type=gauge _time=42i,value=1.5 1\n"
+        );
+        assert_eq!(
+            encode(UnderscoreKeys::Rename, fields()).unwrap(),
+            "vector,measurement=cpu,metric_type=gauge time=42i,value=1.5 1\n"
+        );
+        assert_eq!(
+            encode(UnderscoreKeys::Drop, fields()).unwrap(),
+            "vector,metric_type=gauge value=1.5 1\n"
+        );
+
+        // A renamed key doesn't overwrite an existing one
+        let colliding = vec![("_time", Field::Int(42)), ("time", Field::Int(7))];
+        assert_eq!(
+            encode(UnderscoreKeys::Rename, colliding).unwrap(),
+            "vector,measurement=cpu,metric_type=gauge time=7i 1\n"
+        );
+        assert_eq!(
+            encode(UnderscoreKeys::Drop, vec![("_time", Field::Int(42))]),
+            Err(LineProtocolError::NoFields)
+        );
     }
 
     #[test]
-    fn test_encode_tags() {
-        let mut value = String::new();
-        encode_tags(tags(), &mut value);
-
-        assert_eq!(value, "normal_tag=value,true_tag=true");
+    fn test_missing_timestamp() {
+        for policy in &[
+            MissingTimestamp::Now,
+            MissingTimestamp::Drop,
+            MissingTimestamp::Error,
+        ] {
+            assert_eq!(policy.apply("influxdb_metrics", Some(ts())), Some(ts()));
+        }
 
-        let tags_to_escape = vec![
-            ("tag".to_owned(), "val=ue".to_owned()),
-            ("name escape".to_owned(), "true".to_owned()),
-            ("value_escape".to_owned(), "value escape".to_owned()),
-            ("a_first_place".to_owned(), "10".to_owned()),
-        ]
-        .into_iter()
-        .collect();
+        let before = Utc::now();
+        let now = MissingTimestamp::Now
+            .apply("influxdb_metrics", None)
+            .unwrap();
+        assert!(now >= before && now <= Utc::now());
 
-        let mut value = String::new();
-        encode_tags(tags_to_escape, &mut value);
+        assert_eq!(MissingTimestamp::Drop.apply("influxdb_metrics", None), None);
         assert_eq!(
-            value,
-            "a_first_place=10,name\\ escape=true,tag=val\\=ue,value_escape=value\\ escape"
+            MissingTimestamp::Error.apply("influxdb_metrics", None),
+            None
         );
     }
 
     #[test]
-    fn test_encode_fields() {
-        let fields = vec![
-            (
-                "field_string".to_owned(),
-                Field::String("string value".to_owned()),
-            ),
-            (
-                "field_string_escape".to_owned(),
-                Field::String("string\\val\"ue".to_owned()),
-            ),
-            ("field_float".to_owned(), Field::Float(123.45)),
-            ("field_unsigned_int".to_owned(), Field::UnsignedInt(657)),
-            ("field_int".to_owned(), Field::Int(657646)),
-            ("field_bool_true".to_owned(), Field::Bool(true)),
-            ("field_bool_false".to_owned(), Field::Bool(false)),
-            ("escape key".to_owned(), Field::Float(10.0)),
-        ]
-        .into_iter()
-        .collect();
+    fn test_field_from() {
+        assert_eq!(Field::from(1.5), Field::Float(1.5));
+        assert_eq!(Field::from(1.5f32), Field::Float(1.5));
+        assert_eq!(Field::from(-5i64), Field::Int(-5));
+        assert_eq!(Field::from(-5i32), Field::Int(-5));
+        assert_eq!(Field::from(-5i16), Field::Int(-5));
+        assert_eq!(Field::from(-5i8), Field::Int(-5));
+        assert_eq!(Field::from(u64::MAX), Field::UnsignedInt(u64::MAX));
+        assert_eq!(Field::from(5u32), Field::UnsignedInt(5));
+        assert_eq!(Field::from(5u16), Field::UnsignedInt(5));
+        assert_eq!(Field::from(5u8), Field::UnsignedInt(5));
+        assert_eq!(Field::from(true), Field::Bool(true));
+        assert_eq!(
+            Field::from("text".to_owned()),
+            Field::String("text".to_owned())
+        );
+        assert_eq!(Field::from("text"), Field::String("text".to_owned()));
+    }
 
-        let mut value = String::new();
-        encode_fields(fields, &mut value);
-        assert_fields(
-            value,
-            [
-                "escape\\ key=10",
-                "field_float=123.45",
-                "field_string=\"string value\"",
-                "field_string_escape=\"string\\\\val\\\"ue\"",
-                "field_unsigned_int=657u",
-                "field_int=657646i",
-                "field_bool_true=true",
-                "field_bool_false=false",
-            ]
-            .to_vec(),
-        )
+    #[test]
+    fn test_field_display() {
+        assert_eq!(Field::Float(1.5).to_string(), "1.5");
+        assert_eq!(Field::Float(-2.0).to_string(), "-2.0");
+        assert_eq!(Field::Float(42.0).to_string(), "42.0");
+        assert_eq!(Field::Float(0.0).to_string(), "0.0");
+        assert_eq!(Field::Float(1e21).to_string(), "1000000000000000000000.0");
+        assert_eq!(
+            Field::UnsignedInt(u64::MAX).to_string(),
+            "18446744073709551615u"
+        );
+        assert_eq!(Field::Int(-5).to_string(), "-5i");
+        assert_eq!(Field::Bool(true).to_string(), "true");
+        assert_eq!(Field::Bool(false).to_string(), "false");
+        assert_eq!(format!("{:#}", Field::Bool(true)), "t");
+        assert_eq!(format!("{:#}", Field::Bool(false)), "f");
+        assert_eq!(format!("{:#}", Field::Int(1)), "1i");
+        assert_eq!(Field::from("text").to_string(), r#""text""#);
+        assert_eq!(
+            Field::from("say \"hi\"\\\n").to_string(),
+            r#""say \"hi\"\\\n""#
+        );
     }
 
     #[test]
-    fn test_encode_string() {
-        let mut value = String::new();
-        encode_string("measurement_name".to_string(), &mut value);
-        assert_eq!(value, "measurement_name");
+    fn test_field_display_without_exponent() {
+        assert_eq!(Field::Float(0.0000001).to_string(), "0.0000001");
+        assert_eq!(Field::Float(1e20).to_string(), "100000000000000000000.0");
+        assert_eq!(
+            Field::Float(1234567890.12345).to_string(),
+            "1234567890.12345"
+        );
+        assert_eq!(Field::Float(-1.5e-10).to_string(), "-0.00000000015");
 
-        let mut value = String::new();
-        encode_string("measurement name".to_string(), &mut value);
-        assert_eq!(value, "measurement\\ name");
+        // Even the extremes are written out in full
+        for value in &[f64::MAX, f64::MIN, f64::MIN_POSITIVE, f64::EPSILON, 5e-324] {
+            let formatted = Field::Float(*value).to_string();
+            assert!(
+                !formatted.contains(|c| c == 'e' || c == 'E'),
+                "{}",
+                formatted
+            );
+            assert_eq!(formatted.parse::<f64>().unwrap(), *value);
+        }
+    }
 
-        let mut value = String::new();
-        encode_string("measurement=name".to_string(), &mut value);
-        assert_eq!(value, "measurement\\=name");
+    #[test]
+    fn test_field_coerce() {
+        use FieldType::*;
 
-        let mut value = String::new();
-        encode_string("measurement,name".to_string(), &mut value);
-        assert_eq!(value, "measurement\\,name");
+        let coerced = vec![
+            (Field::Int(42), String, Field::String("42".to_owned())),
+            (Field::Float(1.5), String, Field::String("1.5".to_owned())),
+            (Field::Bool(true), String, Field::String("true".to_owned())),
+            (Field::Int(-3), Float, Field::Float(-3.0)),
+            (Field::UnsignedInt(3), Float, Field::Float(3.0)),
+            (Field::String(" 2.5 ".to_owned()), Float, Field::Float(2.5)),
+            (Field::UnsignedInt(7), Int, Field::Int(7)),
+            (Field::Float(-4.0), Int, Field::Int(-4)),
+            (Field::String("-12".to_owned()), Int, Field::Int(-12)),
+            (Field::Int(7), Uint, Field::UnsignedInt(7)),
+            (Field::Float(4.0), Uint, Field::UnsignedInt(4)),
+            (Field::String("12".to_owned()), Uint, Field::UnsignedInt(12)),
+            (Field::String("false".to_owned()), Bool, Field::Bool(false)),
+            (Field::String("T".to_owned()), Bool, Field::Bool(true)),
+            (Field::Bool(true), Bool, Field::Bool(true)),
+        ];
+        for (field, field_type, expected) in coerced {
+            assert_eq!(
+                field.clone().coerce(field_type),
+                Ok(expected),
+                "{:?} as {:?}",
+                field,
+                field_type
+            );
+        }
+
+        let failed = vec![
+            (Field::String("yes".to_owned()), Bool),
+            (Field::Int(1), Bool),
+            (Field::Bool(true), Float),
+            (Field::String("fast".to_owned()), Float),
+            (Field::String("NaN".to_owned()), Float),
+            (Field::Float(1.5), Int),
+            (Field::Float(1e19), Int),
+            (Field::UnsignedInt(u64::MAX), Int),
+            (Field::String("1.0".to_owned()), Int),
+            (Field::Int(-1), Uint),
+            (Field::Float(-1.0), Uint),
+            (Field::Float(f64::NAN), Uint),
+        ];
+        for (field, field_type) in failed {
+            assert_eq!(
+                field.clone().coerce(field_type),
+                Err(field.clone()),
+                "{:?} as {:?}",
+                field,
+                field_type
+            );
+        }
     }
 
     #[test]
-    fn test_encode_timestamp() {
-        let start = Utc::now().timestamp_nanos();
-        assert_eq!(encode_timestamp(Some(ts())), 1542182950000000011);
-        assert!(encode_timestamp(None) >= start)
+    fn test_encode_fields_bool_format() {
+        let fields = || {
+            vec![
+                ("up".to_owned(), Field::Bool(true)),
+                ("down".to_owned(), Field::Bool(false)),
+            ]
+            .into_iter()
+            .collect::<HashMap<_, _>>()
+        };
+
+        let mut long = String::new();
+        encode_fields(fields(), BoolFormat::Long, &mut long);
+        let mut long = long.split(',').collect::<Vec<_>>();
+        long.sort();
+        assert_eq!(long, vec!["down=false", "up=true"]);
+
+        let mut short = String::new();
+        encode_fields(fields(), BoolFormat::Short, &mut short);
+        let mut short = short.split(',').collect::<Vec<_>>();
+        short.sort();
+        assert_eq!(short, vec!["down=f", "up=t"]);
     }
 
     #[test]
@@ -661,13 +6423,78 @@ mod tests {
         .unwrap();
         assert_eq!(
             uri,
-            "http://localhost:9999/api/v2/write?org=Orgazniation+name&bucket=Bucket%3Dname"
+            "http://localhost:9999/api/v2/write?org=Orgazniation%20name&bucket=Bucket%3Dname"
         );
     }
 
+    #[test]
+    fn test_encode_uri_special_chars() {
+        let uri = encode_uri(
+            "http://localhost:9999",
+            "api/v2/write",
+            &[
+                ("org", Some("my org+co".to_owned())),
+                ("bucket", Some("logs/prod é".to_owned())),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            uri,
+            "http://localhost:9999/api/v2/write?org=my%20org%2Bco&bucket=logs%2Fprod%20%C3%A9"
+        );
+    }
+
+    #[test]
+    fn test_encode_uri_plus_and_space_round_trip() {
+        // Servers differ in whether they decode `+` in a query string as a
+        // space, so neither is ever written as `+` and both decodings give
+        // back the original name.
+        let names = vec!["a+b", "a b", "a+ b", "a%20b", "a%2Bb", "+", " "];
+        let queries = names
+            .iter()
+            .map(|name| {
+                let settings = InfluxDB2Settings::builder()
+                    .org(*name)
+                    .bucket(*name)
+                    .token("my-token")
+                    .build();
+                let uri = settings
+                    .write_uri("http://localhost:9999".to_owned(), Precision::Ns)
+                    .unwrap();
+                let query = uri.query().unwrap().to_owned();
+                assert!(!query.contains('+'), "{}", query);
+
+                let form_decoded = url::form_urlencoded::parse(query.as_bytes())
+                    .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                    .collect::<Vec<_>>();
+                let percent_decoded = query
+                    .split('&')
+                    .map(|pair| {
+                        let mut pair = pair.splitn(2, '=').map(|part| {
+                            url::percent_encoding::percent_decode(part.as_bytes())
+                                .decode_utf8()
+                                .unwrap()
+                                .into_owned()
+                        });
+                        (pair.next().unwrap(), pair.next().unwrap())
+                    })
+                    .collect::<Vec<_>>();
+                let expected = vec![
+                    ("org".to_owned(), (*name).to_owned()),
+                    ("bucket".to_owned(), (*name).to_owned()),
+                    ("precision".to_owned(), "ns".to_owned()),
+                ];
+                assert_eq!(form_decoded, expected);
+                assert_eq!(percent_decoded, expected);
+                query
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(queries.len(), names.len());
+    }
+
     #[test]
     fn test_encode_uri_invalid() {
-        encode_uri(
+        let error = encode_uri(
             "localhost:9999",
             "api/v2/write",
             &[
@@ -676,6 +6503,494 @@ mod tests {
             ],
         )
         .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<EndpointError>(),
+            Some(EndpointError::MissingScheme { .. })
+        ));
+        assert_eq!(
+            error.to_string(),
+            "InfluxDB endpoint \"localhost:9999\" has no scheme, try \"http://localhost:9999\"."
+        );
+    }
+
+    #[test]
+    fn test_encode_uri_invalid_host() {
+        for endpoint in &["http://:9999", "http://local host:9999"] {
+            let error = encode_uri(endpoint, "health", &[]).unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<EndpointError>(),
+                    Some(EndpointError::InvalidHost { .. })
+                ),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_endpoint() {
+        assert!(validate_endpoint("http://localhost:8086").is_ok());
+        assert!(validate_endpoint("https://influxdb.example.com/prefix").is_ok());
+        assert!(matches!(
+            validate_endpoint("localhost:8086"),
+            Err(EndpointError::MissingScheme { .. })
+        ));
+        assert!(matches!(
+            validate_endpoint("file:///var/run/influxdb.sock"),
+            Err(EndpointError::InvalidHost { .. })
+        ));
+    }
+
+    #[test]
+    fn test_path_prefix() {
+        for path_prefix in &["influx", "/influx", "influx/", "/influx/"] {
+            assert_eq!(
+                with_path_prefix("http://localhost:8086", Some(path_prefix)),
+                "http://localhost:8086/influx"
+            );
+            assert_eq!(
+                with_path_prefix("http://localhost:8086/", Some(path_prefix)),
+                "http://localhost:8086/influx"
+            );
+        }
+        assert_eq!(
+            with_path_prefix("http://localhost:8086/gateway", Some("/influx/v2/")),
+            "http://localhost:8086/gateway/influx/v2"
+        );
+        assert_eq!(
+            with_path_prefix("http://localhost:8086/", Some("/")),
+            "http://localhost:8086/"
+        );
+        assert_eq!(
+            with_path_prefix("http://localhost:8086", None),
+            "http://localhost:8086"
+        );
+
+        let endpoint = with_path_prefix("http://localhost:8086/", Some("/influx/"));
+        let v1 = InfluxDB1Settings::builder().database("my-database").build();
+        assert_eq!(
+            v1.write_uri(endpoint.clone(), Precision::Ns)
+                .unwrap()
+                .to_string(),
+            "http://localhost:8086/influx/write?db=my-database&precision=ns"
+        );
+        assert_eq!(
+            v1.healthcheck_uri(endpoint.clone()).unwrap().to_string(),
+            "http://localhost:8086/influx/ping"
+        );
+        let v2 = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .build();
+        assert_eq!(
+            v2.write_uri(endpoint.clone(), Precision::Ns)
+                .unwrap()
+                .to_string(),
+            "http://localhost:8086/influx/api/v2/write?org=my-org&bucket=my-bucket&precision=ns"
+        );
+        assert_eq!(
+            v2.healthcheck_uri(endpoint).unwrap().to_string(),
+            "http://localhost:8086/influx/health"
+        );
+
+        assert!(validate_path_prefix(Some("/influx")).is_ok());
+        assert!(matches!(
+            validate_path_prefix(Some("/influx?db=x")),
+            Err(EndpointError::InvalidPathPrefix { .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_back_request() {
+        let v1 = InfluxDB1Settings::builder()
+            .database("my-database")
+            .retention_policy_name("autogen")
+            .build();
+        let request = v1
+            .read_back_request("http://localhost:8086".to_owned(), "vector_validate", "r'1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method(), "GET");
+        assert_eq!(
+            request.uri().to_string(),
+            "http://localhost:8086/query?db=my-database&rp=autogen&q=SELECT%20*%20FROM%20%22vector_validate%22%20WHERE%20%22run%22%20%3D%20%27r%5C%271%27"
+        );
+
+        let v2 = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my \"bucket\"")
+            .token("my-token")
+            .build();
+        let request = v2
+            .read_back_request("http://localhost:9999".to_owned(), "vector_validate", "1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(
+            request.uri().to_string(),
+            "http://localhost:9999/api/v2/query?org=my-org"
+        );
+        let body = runtime()
+            .block_on_std(hyper13::body::to_bytes(request.into_body()))
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            r#"from(bucket: "my \"bucket\"") |> range(start: -1h) |> filter(fn: (r) => r._measurement == "vector_validate" and r.run == "1")"#
+        );
+
+        // Flux queries need the org.
+        let v2 = InfluxDB2Settings::builder()
+            .bucket_id("0123456789abcdef")
+            .token("my-token")
+            .build();
+        assert!(v2
+            .read_back_request("http://localhost:9999".to_owned(), "vector_validate", "1")
+            .is_none());
+    }
+
+    #[test]
+    fn test_encode_uri_unparseable() {
+        let error = encode_uri("http://localhost:9999/my path", "health", &[]).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<EndpointError>(),
+            Some(EndpointError::InvalidEndpoint { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resolve_default_tags() {
+        let hostname = hostname::get_hostname().unwrap_or_default();
+        let resolved = resolve_default_tags(&tags_from(&[
+            ("host", "${HOSTNAME}"),
+            ("instance", "vector-${HOSTNAME}"),
+            ("env", "production"),
+        ]));
+
+        assert_eq!(
+            resolved,
+            tags_from(&[
+                ("host", &hostname),
+                ("instance", &format!("vector-{}", hostname)),
+                ("env", "production"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_merge_default_tags() {
+        let defaults = tags_from(&[("env", "production"), ("host", "default-host")]);
+
+        assert_eq!(merge_default_tags(&BTreeMap::new(), None), None);
+        assert_eq!(merge_default_tags(&defaults, None), Some(defaults.clone()));
+        assert_eq!(
+            merge_default_tags(
+                &defaults,
+                Some(tags_from(&[("host", "metric-host"), ("region", "eu")]))
+            ),
+            Some(tags_from(&[
+                ("env", "production"),
+                ("host", "metric-host"),
+                ("region", "eu"),
+            ]))
+        );
+    }
+
+    fn tags_from(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn gzip(body: &str) -> Vec<u8> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_body_size_warning() {
+        let size = BodySize {
+            component_type: "influxdb_metrics",
+            warn_bytes: Some(1024),
+        };
+        assert!(!size.record(1024));
+        assert!(size.record(1025));
+
+        let unlimited = BodySize {
+            component_type: "influxdb_metrics",
+            warn_bytes: None,
+        };
+        assert!(!unlimited.record(usize::MAX));
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let path = crate::test_util::temp_file();
+        let dead_letter = DeadLetterConfig { path: path.clone() }.build("influxdb_metrics");
+
+        assert!(DeadLetter::is_rejection(StatusCode::BAD_REQUEST));
+        assert!(!DeadLetter::is_rejection(StatusCode::SERVICE_UNAVAILABLE));
+
+        let body = b"cpu,metric_type=gauge value=1.0 1\ncpu,metric_type=gauge value=\"x\" 2\n";
+        let error = br#"{"code":"invalid","message":"field type conflict"}"#;
+        dead_letter.write(body, StatusCode::BAD_REQUEST, error);
+
+        let written = std::fs::read_to_string(path).unwrap();
+        let records = written
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["line"], "cpu,metric_type=gauge value=1.0 1");
+        assert_eq!(records[1]["line"], "cpu,metric_type=gauge value=\"x\" 2");
+        assert_eq!(records[1]["status"], 400);
+        assert_eq!(records[1]["error"], String::from_utf8_lossy(error).as_ref());
+    }
+
+    #[test]
+    fn test_body_compression() {
+        let body = b"vector,metric_type=logs message=\"hello\" 1\n".to_vec();
+
+        let none = BodyCompression::new(InfluxDBCompression::None, None, None).unwrap();
+        assert_eq!(none.apply(body.clone()), (body.clone(), None));
+
+        let below =
+            BodyCompression::new(InfluxDBCompression::Gzip, None, Some(body.len() + 1)).unwrap();
+        assert_eq!(below.apply(body.clone()), (body.clone(), None));
+
+        let above =
+            BodyCompression::new(InfluxDBCompression::Gzip, None, Some(body.len())).unwrap();
+        let (compressed, encoding) = above.apply(body.clone());
+        assert_eq!(encoding, Some("gzip"));
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(below.compress(body.clone(), &mut headers), body);
+        assert_eq!(headers.get(CONTENT_ENCODING), None);
+        assert_eq!(above.compress(body.clone(), &mut headers), compressed);
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+    }
+
+    #[test]
+    fn test_body_compression_level() {
+        let body = b"vector,metric_type=logs message=\"hello\" 1\n".repeat(16);
+
+        let fast = BodyCompression::new(InfluxDBCompression::Gzip, Some(1), None).unwrap();
+        let (compressed, _) = fast.apply(body.clone());
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+
+        assert_eq!(
+            format!(
+                "{}",
+                BodyCompression::new(InfluxDBCompression::Gzip, Some(10), None)
+                    .err()
+                    .unwrap()
+            ),
+            "`compression_level` 10 is out of range for gzip, which takes 0 to 9."
+        );
+        // Ignored without compression.
+        assert!(BodyCompression::new(InfluxDBCompression::None, Some(10), None).is_ok());
+    }
+
+    #[cfg(feature = "sinks-influxdb-zstd")]
+    #[test]
+    fn test_body_compression_zstd() {
+        let body = b"vector,metric_type=logs message=\"hello\" 1\n".repeat(16);
+
+        for level in &[None, Some(1), Some(19)] {
+            let zstd = BodyCompression::new(InfluxDBCompression::Zstd, *level, None).unwrap();
+            let mut headers = HeaderMap::new();
+            let compressed = zstd.compress(body.clone(), &mut headers);
+            assert_eq!(headers[CONTENT_ENCODING], "zstd");
+            assert!(compressed.len() < body.len());
+            assert_eq!(zstd::stream::decode_all(&compressed[..]).unwrap(), body);
+        }
+
+        assert_eq!(
+            format!(
+                "{}",
+                BodyCompression::new(InfluxDBCompression::Zstd, Some(0), None)
+                    .err()
+                    .unwrap()
+            ),
+            "`compression_level` 0 is out of range for zstd, which takes 1 to 22."
+        );
+    }
+
+    #[test]
+    fn test_health_body_gzip() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+
+        let pass =
+            gzip(r#"{"name":"influxdb","message":"ready for queries and writes","status":"pass"}"#);
+        check_health_body(&headers, &pass, DEFAULT_MAX_RESPONSE_BYTES).unwrap();
+
+        let fail = gzip(r#"{"name":"influxdb","message":"not ready","status":"fail"}"#);
+        let error = check_health_body(&headers, &fail, DEFAULT_MAX_RESPONSE_BYTES).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<HealthError>(),
+            Some(HealthError::NotPassing { .. })
+        ));
+    }
+
+    #[test]
+    fn test_health_body_plain() {
+        let headers = HeaderMap::new();
+
+        check_health_body(&headers, b"", DEFAULT_MAX_RESPONSE_BYTES).unwrap();
+        check_health_body(
+            &headers,
+            br#"{"status":"pass"}"#,
+            DEFAULT_MAX_RESPONSE_BYTES,
+        )
+        .unwrap();
+        assert!(check_health_body(
+            &headers,
+            br#"{"status":"fail"}"#,
+            DEFAULT_MAX_RESPONSE_BYTES
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_healthcheck_reads_bounded_body() {
+        use crate::sinks::util::test::build_test_responder;
+        use crate::test_util::{next_addr, runtime};
+        use crate::topology::SinkContext;
+        use hyper13::{Body, Response};
+
+        let mut rt = runtime();
+        let addr = next_addr();
+
+        // A proxy error page that never ends
+        let (_, server) = build_test_responder(addr, &mut rt, |_| async {
+            let page = futures::stream::repeat(())
+                .map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![b'x'; 1024])));
+            let mut response = Response::new(Body::wrap_stream(page));
+            *response.status_mut() = StatusCode::BAD_GATEWAY;
+            Ok::<_, crate::Error>(response)
+        });
+        rt.spawn(server);
+
+        let settings = InfluxDB2Settings::builder()
+            .org("my-org")
+            .bucket("my-bucket")
+            .token("my-token")
+            .build();
+        let cx = SinkContext::new_test(rt.executor());
+        let healthcheck = healthcheck(
+            format!("http://{}", addr),
+            None,
+            None,
+            Some(settings),
+            None,
+            false,
+            false,
+            None,
+            4096,
+            ServerVersion::default(),
+            HttpClient::new(cx.resolver(), None).unwrap(),
+        )
+        .unwrap();
+
+        let error = rt.block_on(healthcheck).unwrap_err().to_string();
+        let expected = format!(
+            "Unexpected status: 502 Bad Gateway: {}... (truncated)",
+            "x".repeat(4096)
+        );
+        assert_eq!(error, expected);
+    }
+
+    #[test]
+    fn test_healthcheck_captures_server_version() {
+        use crate::sinks::util::test::build_test_responder;
+        use crate::test_util::{next_addr, runtime};
+        use crate::topology::SinkContext;
+        use hyper13::{Body, Request, Response};
+
+        let mut rt = runtime();
+        let addr = next_addr();
+
+        let (_, server) =
+            build_test_responder(addr, &mut rt, |request: Request<Body>| async move {
+                assert_eq!(request.uri().path(), "/ping");
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::NO_CONTENT;
+                response
+                    .headers_mut()
+                    .insert(VERSION_HEADER, HeaderValue::from_static("1.8.10"));
+                Ok::<_, crate::Error>(response)
+            });
+        rt.spawn(server);
+
+        let settings = InfluxDB1Settings::builder().database("vector_db").build();
+        let cx = SinkContext::new_test(rt.executor());
+        let server_version = ServerVersion::default();
+        let healthcheck = healthcheck(
+            format!("http://{}", addr),
+            None,
+            Some(settings),
+            None,
+            None,
+            false,
+            false,
+            None,
+            DEFAULT_MAX_RESPONSE_BYTES,
+            server_version.clone(),
+            HttpClient::new(cx.resolver(), None).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(server_version.get(), None);
+        rt.block_on(healthcheck).unwrap();
+        assert_eq!(server_version.get(), Some("1.8.10".to_owned()));
+    }
+
+    #[test]
+    fn test_supports_consistency() {
+        let headers = |build: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(build) = build {
+                headers.insert(BUILD_HEADER, HeaderValue::from_static(build));
+            }
+            headers
+        };
+
+        assert!(supports_consistency(&headers(Some("ENT"))));
+        assert!(!supports_consistency(&headers(Some("OSS"))));
+        assert!(!supports_consistency(&headers(None)));
+    }
+
+    #[test]
+    fn test_health_body_bad_gzip() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+
+        let error = check_health_body(
+            &headers,
+            br#"{"status":"pass"}"#,
+            DEFAULT_MAX_RESPONSE_BYTES,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<HealthError>(),
+            Some(HealthError::DecodeHealthBody { .. })
+        ));
     }
 }
 
@@ -683,7 +6998,11 @@ mod tests {
 #[cfg(test)]
 mod integration_tests {
     use crate::sinks::influxdb::test_util::{onboarding_v2, BUCKET, DATABASE, ORG, TOKEN};
-    use crate::sinks::influxdb::{healthcheck, InfluxDB1Settings, InfluxDB2Settings};
+    use crate::sinks::influxdb::{
+        healthcheck, setup_v2, validate::validate, InfluxDB1Settings, InfluxDB2Settings,
+        ServerVersion, DEFAULT_MAX_RESPONSE_BYTES,
+    };
+    use crate::sinks::util::http2::HttpClient;
     use crate::test_util::runtime;
     use crate::topology::SinkContext;
 
@@ -695,22 +7014,202 @@ mod integration_tests {
         let cx = SinkContext::new_test(rt.executor());
         let endpoint = "http://localhost:9999".to_string();
         let influxdb1_settings = None;
-        let influxdb2_settings = Some(InfluxDB2Settings {
-            org: ORG.to_string(),
-            bucket: BUCKET.to_string(),
-            token: TOKEN.to_string(),
-        });
+        let influxdb2_settings = Some(
+            InfluxDB2Settings::builder()
+                .org(ORG)
+                .bucket(BUCKET)
+                .token(TOKEN)
+                .build(),
+        );
 
         let healthcheck = healthcheck(
             endpoint,
+            None,
             influxdb1_settings,
             influxdb2_settings,
-            cx.resolver(),
+            None,
+            false,
+            false,
+            None,
+            DEFAULT_MAX_RESPONSE_BYTES,
+            ServerVersion::default(),
+            HttpClient::new(cx.resolver(), None).unwrap(),
         )
         .unwrap();
         rt.block_on(healthcheck).unwrap();
     }
 
+    #[test]
+    fn influxdb2_validate() {
+        onboarding_v2();
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let settings = InfluxDB2Settings::builder()
+            .org(ORG)
+            .bucket(BUCKET)
+            .token(TOKEN)
+            .build();
+
+        let report = rt
+            .block_on_std(validate(
+                cx.resolver(),
+                "http://localhost:9999",
+                None,
+                None,
+                Some(settings),
+                None,
+            ))
+            .unwrap();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.steps.len(), 3);
+    }
+
+    #[test]
+    fn influxdb2_setup_already_done() {
+        onboarding_v2();
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let setup = setup_v2(
+            cx.resolver(),
+            "http://localhost:9999",
+            "other-user",
+            "other-password",
+            ORG,
+            BUCKET,
+        );
+        let token = rt.block_on_std(setup).unwrap();
+        assert_eq!(token, None);
+    }
+
+    fn read_only_token() -> String {
+        let client = reqwest::Client::new();
+
+        let orgs: serde_json::Value = client
+            .get("http://localhost:9999/api/v2/orgs")
+            .query(&[("org", ORG)])
+            .header("Authorization", format!("Token {}", TOKEN))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        let org_id = orgs["orgs"][0]["id"].as_str().unwrap().to_owned();
+
+        let authorization: serde_json::Value = client
+            .post("http://localhost:9999/api/v2/authorizations")
+            .header("Authorization", format!("Token {}", TOKEN))
+            .json(&serde_json::json!({
+                "orgID": org_id,
+                "permissions": [{
+                    "action": "read",
+                    "resource": { "type": "buckets", "orgID": org_id }
+                }]
+            }))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        authorization["token"].as_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn influxdb2_healthchecks_verify_write() {
+        onboarding_v2();
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let settings = |token: String| {
+            Some(
+                InfluxDB2Settings::builder()
+                    .org(ORG)
+                    .bucket(BUCKET)
+                    .token(token)
+                    .build(),
+            )
+        };
+
+        let writable = healthcheck(
+            "http://localhost:9999".to_string(),
+            None,
+            None,
+            settings(TOKEN.to_string()),
+            None,
+            true,
+            false,
+            None,
+            DEFAULT_MAX_RESPONSE_BYTES,
+            ServerVersion::default(),
+            HttpClient::new(cx.resolver(), None).unwrap(),
+        )
+        .unwrap();
+        rt.block_on(writable).unwrap();
+
+        let read_only = healthcheck(
+            "http://localhost:9999".to_string(),
+            None,
+            None,
+            settings(read_only_token()),
+            None,
+            true,
+            false,
+            None,
+            DEFAULT_MAX_RESPONSE_BYTES,
+            ServerVersion::default(),
+            HttpClient::new(cx.resolver(), None).unwrap(),
+        )
+        .unwrap();
+        let error = rt.block_on(read_only).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Healthcheck write was rejected"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn influxdb2_healthchecks_flux_probe() {
+        onboarding_v2();
+
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let probe = |token: &str| {
+            healthcheck(
+                "http://localhost:9999".to_string(),
+                None,
+                None,
+                Some(
+                    InfluxDB2Settings::builder()
+                        .org(ORG)
+                        .bucket(BUCKET)
+                        .token(token)
+                        .build(),
+                ),
+                None,
+                false,
+                true,
+                None,
+                DEFAULT_MAX_RESPONSE_BYTES,
+                ServerVersion::default(),
+                HttpClient::new(cx.resolver(), None).unwrap(),
+            )
+            .unwrap()
+        };
+
+        rt.block_on(probe(TOKEN)).unwrap();
+
+        let error = rt.block_on(probe("not-a-token")).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Healthcheck query was rejected with 401"),
+            "{}",
+            error
+        );
+    }
+
     #[test]
     fn influxdb2_healthchecks_fail() {
         onboarding_v2();
@@ -719,16 +7218,25 @@ mod integration_tests {
         let cx = SinkContext::new_test(rt.executor());
         let endpoint = "http://not_exist:9999".to_string();
         let influxdb1_settings = None;
-        let influxdb2_settings = Some(InfluxDB2Settings {
-            org: ORG.to_string(),
-            bucket: BUCKET.to_string(),
-            token: TOKEN.to_string(),
-        });
+        let influxdb2_settings = Some(
+            InfluxDB2Settings::builder()
+                .org(ORG)
+                .bucket(BUCKET)
+                .token(TOKEN)
+                .build(),
+        );
         let healthcheck = healthcheck(
             endpoint,
+            None,
             influxdb1_settings,
             influxdb2_settings,
-            cx.resolver(),
+            None,
+            false,
+            false,
+            None,
+            DEFAULT_MAX_RESPONSE_BYTES,
+            ServerVersion::default(),
+            HttpClient::new(cx.resolver(), None).unwrap(),
         )
         .unwrap();
         rt.block_on(healthcheck).unwrap_err();
@@ -739,20 +7247,21 @@ mod integration_tests {
         let mut rt = runtime();
         let cx = SinkContext::new_test(rt.executor());
         let endpoint = "http://localhost:8086".to_string();
-        let influxdb1_settings = Some(InfluxDB1Settings {
-            database: DATABASE.to_string(),
-            consistency: None,
-            retention_policy_name: None,
-            username: None,
-            password: None,
-        });
+        let influxdb1_settings = Some(InfluxDB1Settings::builder().database(DATABASE).build());
         let influxdb2_settings = None;
 
         let healthcheck = healthcheck(
             endpoint,
+            None,
             influxdb1_settings,
             influxdb2_settings,
-            cx.resolver(),
+            None,
+            false,
+            false,
+            None,
+            DEFAULT_MAX_RESPONSE_BYTES,
+            ServerVersion::default(),
+            HttpClient::new(cx.resolver(), None).unwrap(),
         )
         .unwrap();
         rt.block_on(healthcheck).unwrap();
@@ -763,20 +7272,21 @@ mod integration_tests {
         let mut rt = runtime();
         let cx = SinkContext::new_test(rt.executor());
         let endpoint = "http://not_exist:8086".to_string();
-        let influxdb1_settings = Some(InfluxDB1Settings {
-            database: DATABASE.to_string(),
-            consistency: None,
-            retention_policy_name: None,
-            username: None,
-            password: None,
-        });
+        let influxdb1_settings = Some(InfluxDB1Settings::builder().database(DATABASE).build());
         let influxdb2_settings = None;
 
         let healthcheck = healthcheck(
             endpoint,
+            None,
             influxdb1_settings,
             influxdb2_settings,
-            cx.resolver(),
+            None,
+            false,
+            false,
+            None,
+            DEFAULT_MAX_RESPONSE_BYTES,
+            ServerVersion::default(),
+            HttpClient::new(cx.resolver(), None).unwrap(),
         )
         .unwrap();
         rt.block_on(healthcheck).unwrap_err();
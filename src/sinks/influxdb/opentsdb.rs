@@ -0,0 +1,155 @@
+//! Writes points as OpenTSDB telnet `put` lines, for stores that speak the
+//! OpenTSDB protocol rather than line protocol:
+//!
+//!   put <metric> <timestamp> <value> <tagk=tagv ...>
+//!
+//! A point becomes one line per numeric field. The `value` field is written
+//! under the measurement itself and any other field under
+//! `<measurement>.<field>`, so a histogram writes `requests.bucket_1`,
+//! `requests.count` and so on.
+
+use crate::sinks::influxdb::{Field, Precision};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// Appends the `put` lines of a point to `output`, each ending in a newline.
+///
+/// OpenTSDB only takes seconds or milliseconds, so the nanosecond `timestamp`
+/// is written in seconds at the `s` precision and in milliseconds otherwise.
+/// String fields and values that aren't finite can't be stored and are
+/// skipped.
+pub fn encode_put_lines(
+    measurement: &str,
+    tags: &BTreeMap<String, String>,
+    fields: &HashMap<String, Field>,
+    timestamp: i64,
+    precision: Precision,
+    output: &mut String,
+) {
+    let timestamp = match precision {
+        Precision::S => timestamp / 1_000_000_000,
+        _ => timestamp / 1_000_000,
+    };
+
+    let sorted = fields.iter().collect::<BTreeMap<_, _>>();
+    for (key, value) in sorted {
+        let value = match value {
+            Field::Float(value) if value.is_finite() => value.to_string(),
+            Field::UnsignedInt(value) => value.to_string(),
+            Field::Int(value) => value.to_string(),
+            Field::Bool(value) => (*value as u8).to_string(),
+            Field::Float(_) | Field::String(_) => continue,
+        };
+
+        output.push_str("put ");
+        encode_name(measurement, output);
+        if key != "value" {
+            output.push('.');
+            encode_name(key, output);
+        }
+        write!(output, " {} {}", timestamp, value).expect("writing to a String can't fail");
+        for (key, value) in tags {
+            if key.is_empty() || value.is_empty() {
+                continue;
+            }
+            output.push(' ');
+            encode_name(key, output);
+            output.push('=');
+            encode_name(value, output);
+        }
+        output.push('\n');
+    }
+}
+
+/// Metric names, tag keys and tag values may only hold letters, digits and
+/// `-_./`, anything else is replaced with `_`.
+fn encode_name(name: &str, output: &mut String) {
+    for c in name.chars() {
+        if c.is_alphanumeric() || "-_./".contains(c) {
+            output.push(c);
+        } else {
+            output.push('_');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const TIMESTAMP: i64 = 1_542_182_950_000_000_011;
+
+    fn tags() -> BTreeMap<String, String> {
+        vec![("metric_type", "gauge"), ("host", "web 01"), ("empty", "")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_value() {
+        let fields = vec![("value".to_owned(), Field::Float(1.5))]
+            .into_iter()
+            .collect();
+
+        let mut output = String::new();
+        encode_put_lines(
+            "ns.cpu",
+            &tags(),
+            &fields,
+            TIMESTAMP,
+            Precision::Ns,
+            &mut output,
+        );
+        assert_eq!(
+            output,
+            "put ns.cpu 1542182950000 1.5 host=web_01 metric_type=gauge\n"
+        );
+
+        let mut output = String::new();
+        encode_put_lines(
+            "ns.cpu",
+            &tags(),
+            &fields,
+            TIMESTAMP,
+            Precision::S,
+            &mut output,
+        );
+        assert_eq!(
+            output,
+            "put ns.cpu 1542182950 1.5 host=web_01 metric_type=gauge\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_fields() {
+        let fields = vec![
+            ("count".to_owned(), Field::UnsignedInt(6)),
+            ("sum".to_owned(), Field::Float(12.0)),
+            ("up".to_owned(), Field::Bool(true)),
+            ("delta".to_owned(), Field::Int(-3)),
+            ("nan".to_owned(), Field::Float(f64::NAN)),
+            ("text".to_owned(), Field::String("skipped".to_owned())),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut output = String::new();
+        encode_put_lines(
+            "requests",
+            &tags(),
+            &fields,
+            TIMESTAMP,
+            Precision::Ms,
+            &mut output,
+        );
+        assert_eq!(
+            output,
+            "put requests.count 1542182950000 6 host=web_01 metric_type=gauge\n\
+             put requests.delta 1542182950000 -3 host=web_01 metric_type=gauge\n\
+             put requests.sum 1542182950000 12 host=web_01 metric_type=gauge\n\
+             put requests.up 1542182950000 1 host=web_01 metric_type=gauge\n"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Writes Prometheus remote-write time series as line protocol, with the tag
+//! and field conventions of the `influxdb_metrics` sink: the metric name,
+//! prefixed by the namespace, is the measurement, labels are tags and each
+//! sample is a point with a `value` field.
+
+use crate::sinks::influxdb::{
+    encode_namespace, influx_line_protocol, BoolFormat, Field, LineProtocolError,
+};
+use snafu::{ResultExt, Snafu};
+use std::collections::{BTreeMap, HashMap};
+
+/// The label holding the metric name.
+const NAME_LABEL: &str = "__name__";
+
+/// A series of samples sharing a name and labels, as in a remote-write request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeSeries {
+    pub labels: Vec<Label>,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub value: f64,
+    /// Milliseconds since the epoch.
+    pub timestamp: i64,
+}
+
+/// The type from the remote-write metadata of a series, written as the
+/// `metric_type` tag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    Untyped,
+}
+
+impl MetricType {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+            MetricType::Summary => "summary",
+            MetricType::Untyped => "untyped",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Snafu)]
+pub enum ConversionError {
+    #[snafu(display("time series has no {} label", NAME_LABEL))]
+    MissingName,
+    #[snafu(display("sample timestamp {}ms can't be written in nanoseconds", timestamp))]
+    TimestampOutOfRange { timestamp: i64 },
+    #[snafu(display("invalid line protocol: {}", source))]
+    LineProtocol { source: LineProtocolError },
+}
+
+/// Appends one line per sample of `series` to `line_protocol`, or nothing if
+/// any of them can't be encoded.
+///
+/// Samples that aren't finite, such as the NaN staleness markers Prometheus
+/// sends when a series disappears, are skipped as InfluxDB can't store them.
+pub fn encode_time_series(
+    series: &TimeSeries,
+    metric_type: MetricType,
+    namespace: &str,
+    line_protocol: &mut String,
+) -> Result<(), ConversionError> {
+    let mut name = None;
+    let mut tags = BTreeMap::new();
+    for label in &series.labels {
+        if label.name == NAME_LABEL {
+            name = Some(&label.value);
+        } else {
+            tags.insert(label.name.clone(), label.value.clone());
+        }
+    }
+    let measurement = encode_namespace(namespace, name.ok_or(ConversionError::MissingName)?);
+
+    let mut lines = String::new();
+    for sample in series
+        .samples
+        .iter()
+        .filter(|sample| sample.value.is_finite())
+    {
+        let timestamp = sample.timestamp.checked_mul(1_000_000).ok_or(
+            ConversionError::TimestampOutOfRange {
+                timestamp: sample.timestamp,
+            },
+        )?;
+        let fields = vec![("value".to_owned(), Field::Float(sample.value))]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        influx_line_protocol(
+            measurement.clone(),
+            metric_type.as_str(),
+            Some(tags.clone()),
+            Some(fields),
+            timestamp,
+            BoolFormat::default(),
+            &mut lines,
+        )
+        .context(LineProtocol)?;
+    }
+
+    line_protocol.push_str(&lines);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn series(labels: &[(&str, &str)], samples: &[(f64, i64)]) -> TimeSeries {
+        TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(name, value)| Label {
+                    name: (*name).to_owned(),
+                    value: (*value).to_owned(),
+                })
+                .collect(),
+            samples: samples
+                .iter()
+                .map(|&(value, timestamp)| Sample { value, timestamp })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_encode_counter() {
+        let series = series(
+            &[
+                ("__name__", "http_requests_total"),
+                ("method", "GET"),
+                ("code", "200"),
+            ],
+            &[(1027.0, 1_542_182_950_000), (1031.0, 1_542_182_960_000)],
+        );
+
+        let mut output = String::new();
+        encode_time_series(&series, MetricType::Counter, "ns", &mut output).unwrap();
+        assert_eq!(
+            output,
+            "ns.http_requests_total,code=200,method=GET,metric_type=counter value=1027.0 1542182950000000000\n\
+             ns.http_requests_total,code=200,method=GET,metric_type=counter value=1031.0 1542182960000000000\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_gauge() {
+        let series = series(
+            &[("instance", "web 01"), ("__name__", "memory_usage_bytes")],
+            &[(2.5e9, 1_542_182_950_123), (f64::NAN, 1_542_182_960_000)],
+        );
+
+        let mut output = String::new();
+        encode_time_series(&series, MetricType::Gauge, "", &mut output).unwrap();
+        assert_eq!(
+            output,
+            "memory_usage_bytes,instance=web\\ 01,metric_type=gauge value=2500000000.0 1542182950123000000\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_missing_name() {
+        let series = series(&[("job", "node")], &[(1.0, 1_542_182_950_000)]);
+
+        let mut output = String::new();
+        assert_eq!(
+            encode_time_series(&series, MetricType::Gauge, "ns", &mut output),
+            Err(ConversionError::MissingName)
+        );
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_encode_timestamp_out_of_range() {
+        let series = series(&[("__name__", "up")], &[(1.0, i64::max_value() / 1_000)]);
+
+        let mut output = String::new();
+        assert_eq!(
+            encode_time_series(&series, MetricType::Gauge, "ns", &mut output),
+            Err(ConversionError::TimestampOutOfRange {
+                timestamp: i64::max_value() / 1_000
+            })
+        );
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_encode_timestamp_out_of_range_after_valid_sample() {
+        let series = series(
+            &[("__name__", "up")],
+            &[(1.0, 1_000), (1.0, i64::max_value() / 1_000)],
+        );
+
+        let mut output = "existing\n".to_owned();
+        assert!(encode_time_series(&series, MetricType::Gauge, "ns", &mut output).is_err());
+        assert_eq!(output, "existing\n");
+    }
+}
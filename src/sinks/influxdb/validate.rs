@@ -0,0 +1,380 @@
+//! Checks that a connection config works against a live server before it's
+//! deployed: the healthcheck passes, a test point can be written and the
+//! point can be read back.
+//!
+//! The test point is written to the `vector_validate` measurement of the
+//! configured destination, with a `run` tag unique to each validation.
+
+use crate::{
+    dns::Resolver,
+    sinks::influxdb::{
+        encode_timestamp, healthcheck, influx_line_protocol, influxdb_settings, ApiVersion,
+        BoolFormat, Field, InfluxDB1Settings, InfluxDB2Settings, InfluxDB3Settings, Precision,
+        ResponseBody, ServerVersion, DEFAULT_MAX_RESPONSE_BYTES,
+    },
+    sinks::util::http2::HttpClient,
+};
+use futures::compat::Future01CompatExt;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const MEASUREMENT: &str = "vector_validate";
+const RUN_TAG: &str = "run";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Healthcheck,
+    Write,
+    Read,
+}
+
+impl Step {
+    fn as_str(self) -> &'static str {
+        match self {
+            Step::Healthcheck => "healthcheck",
+            Step::Write => "write",
+            Step::Read => "read",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed {
+        error: String,
+    },
+    /// Not run, as an earlier step failed or the server has no query API for
+    /// the settings.
+    Skipped,
+}
+
+#[derive(Clone, Debug)]
+pub struct StepReport {
+    pub step: Step,
+    pub outcome: Outcome,
+    pub elapsed: Duration,
+}
+
+/// The outcome and timing of each step of `validate`, in order.
+#[derive(Clone, Debug)]
+pub struct ValidationReport {
+    pub steps: Vec<StepReport>,
+}
+
+impl ValidationReport {
+    /// Whether every step passed.
+    pub fn passed(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| step.outcome == Outcome::Passed)
+    }
+
+    pub fn step(&self, step: Step) -> Option<&StepReport> {
+        self.steps.iter().find(|report| report.step == step)
+    }
+
+    fn record(&mut self, step: Step, start: Instant, result: crate::Result<()>) -> bool {
+        let outcome = match result {
+            Ok(()) => Outcome::Passed,
+            Err(error) => Outcome::Failed {
+                error: error.to_string(),
+            },
+        };
+        let passed = outcome == Outcome::Passed;
+        self.steps.push(StepReport {
+            step,
+            outcome,
+            elapsed: start.elapsed(),
+        });
+        passed
+    }
+
+    fn skip(&mut self, step: Step) {
+        self.steps.push(StepReport {
+            step,
+            outcome: Outcome::Skipped,
+            elapsed: Duration::from_secs(0),
+        });
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for report in &self.steps {
+            match &report.outcome {
+                Outcome::Passed => writeln!(
+                    f,
+                    "{}: passed in {}ms",
+                    report.step.as_str(),
+                    report.elapsed.as_millis()
+                )?,
+                Outcome::Failed { error } => writeln!(
+                    f,
+                    "{}: failed in {}ms: {}",
+                    report.step.as_str(),
+                    report.elapsed.as_millis(),
+                    error
+                )?,
+                Outcome::Skipped => writeln!(f, "{}: skipped", report.step.as_str())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the healthcheck, writes a test point and reads it back with the v1
+/// InfluxQL, v2 Flux or v3 SQL query API, reporting each step.
+///
+/// Invalid settings are returned as an error, while the failure of a step is
+/// recorded in the report and skips the steps after it. Reading back needs
+/// the `org` with InfluxDB 2, and is skipped without it.
+pub async fn validate(
+    resolver: Resolver,
+    endpoint: &str,
+    api_version: Option<ApiVersion>,
+    influxdb1_settings: Option<InfluxDB1Settings>,
+    influxdb2_settings: Option<InfluxDB2Settings>,
+    influxdb3_settings: Option<InfluxDB3Settings>,
+) -> crate::Result<ValidationReport> {
+    let client = HttpClient::new(resolver, None)?;
+    let healthcheck = healthcheck(
+        endpoint.to_owned(),
+        api_version,
+        influxdb1_settings.clone(),
+        influxdb2_settings.clone(),
+        influxdb3_settings.clone(),
+        false,
+        false,
+        None,
+        DEFAULT_MAX_RESPONSE_BYTES,
+        ServerVersion::default(),
+        client.clone(),
+    )?;
+    let settings = influxdb_settings(
+        api_version,
+        influxdb1_settings,
+        influxdb2_settings,
+        influxdb3_settings,
+    )?;
+
+    let run = uuid::Uuid::new_v4().to_string();
+    let mut body = String::new();
+    influx_line_protocol(
+        MEASUREMENT.to_owned(),
+        "validate",
+        Some(
+            vec![(RUN_TAG.to_owned(), run.clone())]
+                .into_iter()
+                .collect(),
+        ),
+        Some(
+            vec![("write".to_owned(), Field::Bool(true))]
+                .into_iter()
+                .collect(),
+        ),
+        encode_timestamp(None),
+        BoolFormat::default(),
+        &mut body,
+    )?;
+    let mut builder =
+        hyper13::Request::post(settings.write_uri(endpoint.to_owned(), Precision::Ns)?)
+            .header("Content-Type", "text/plain");
+    if let Some(authorization) = settings.authorization() {
+        builder = builder.header("Authorization", authorization);
+    }
+    let write_request = builder.body(hyper13::Body::from(body)).unwrap();
+
+    let read_request = match settings.read_back_request(endpoint.to_owned(), MEASUREMENT, &run) {
+        Some(request) => {
+            let mut request = request?;
+            if let Some(authorization) = settings.authorization() {
+                request
+                    .headers_mut()
+                    .insert("Authorization", authorization.parse()?);
+            }
+            Some(request)
+        }
+        None => None,
+    };
+
+    let mut report = ValidationReport { steps: Vec::new() };
+
+    let start = Instant::now();
+    if !report.record(Step::Healthcheck, start, healthcheck.compat().await) {
+        report.skip(Step::Write);
+        report.skip(Step::Read);
+        return Ok(report);
+    }
+
+    let start = Instant::now();
+    let written = send(client.clone(), write_request).await.map(|_| ());
+    if !report.record(Step::Write, start, written) {
+        report.skip(Step::Read);
+        return Ok(report);
+    }
+
+    match read_request {
+        Some(request) => {
+            let start = Instant::now();
+            let read = send(client, request).await.and_then(|body| {
+                if String::from_utf8_lossy(&body.bytes).contains(&run) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "the test point with {}={} wasn't found in the query response: {}",
+                        RUN_TAG, run, body
+                    )
+                    .into())
+                }
+            });
+            report.record(Step::Read, start, read);
+        }
+        None => report.skip(Step::Read),
+    }
+
+    Ok(report)
+}
+
+async fn send(
+    mut client: HttpClient,
+    request: hyper13::Request<hyper13::Body>,
+) -> crate::Result<ResponseBody> {
+    let (parts, body) = client.send(request).await?.into_parts();
+    let body = ResponseBody::read(body, DEFAULT_MAX_RESPONSE_BYTES).await?;
+    if parts.status.is_success() {
+        Ok(body)
+    } else {
+        Err(format!("Unexpected status: {}: {}", parts.status, body).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::util::test::build_test_responder;
+    use crate::test_util::{next_addr, runtime};
+    use crate::topology::SinkContext;
+    use hyper13::{Body, Request, Response, StatusCode};
+    use std::sync::{Arc, Mutex};
+
+    /// Answers like InfluxDB 2: the query returns the points written so far
+    /// and writes are rejected with `write_status` if it isn't a success.
+    fn spawn_server(rt: &mut crate::runtime::Runtime, write_status: StatusCode) -> String {
+        let addr = next_addr();
+        let written = Arc::new(Mutex::new(String::new()));
+        let (_, server) = build_test_responder(addr, rt, move |request: Request<Body>| {
+            let written = Arc::clone(&written);
+            async move {
+                let path = request.uri().path().to_owned();
+                let body = hyper13::body::to_bytes(request.into_body()).await?;
+                let response = match path.as_str() {
+                    "/health" => Response::new(Body::from(r#"{"status":"pass"}"#)),
+                    "/api/v2/write" if write_status.is_success() => {
+                        written
+                            .lock()
+                            .unwrap()
+                            .push_str(&String::from_utf8_lossy(&body));
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::NO_CONTENT;
+                        response
+                    }
+                    "/api/v2/write" => {
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = write_status;
+                        response
+                    }
+                    "/api/v2/query" => Response::new(Body::from(written.lock().unwrap().clone())),
+                    _ => {
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        response
+                    }
+                };
+                Ok::<_, crate::Error>(response)
+            }
+        });
+        rt.spawn(server);
+        format!("http://{}", addr)
+    }
+
+    fn settings() -> Option<InfluxDB2Settings> {
+        Some(
+            InfluxDB2Settings::builder()
+                .org("my-org")
+                .bucket("my-bucket")
+                .token("my-token")
+                .build(),
+        )
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let endpoint = spawn_server(&mut rt, StatusCode::NO_CONTENT);
+
+        let report = rt
+            .block_on_std(validate(
+                cx.resolver(),
+                &endpoint,
+                None,
+                None,
+                settings(),
+                None,
+            ))
+            .unwrap();
+        assert!(report.passed(), "{}", report);
+        let steps = report
+            .steps
+            .iter()
+            .map(|report| report.step)
+            .collect::<Vec<_>>();
+        assert_eq!(steps, vec![Step::Healthcheck, Step::Write, Step::Read]);
+    }
+
+    #[test]
+    fn test_validate_write_rejected() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+        let endpoint = spawn_server(&mut rt, StatusCode::FORBIDDEN);
+
+        let report = rt
+            .block_on_std(validate(
+                cx.resolver(),
+                &endpoint,
+                None,
+                None,
+                settings(),
+                None,
+            ))
+            .unwrap();
+        assert!(!report.passed());
+        assert_eq!(
+            report.step(Step::Healthcheck).unwrap().outcome,
+            Outcome::Passed
+        );
+        assert!(matches!(
+            &report.step(Step::Write).unwrap().outcome,
+            Outcome::Failed { error } if error.contains("403")
+        ));
+        assert_eq!(report.step(Step::Read).unwrap().outcome, Outcome::Skipped);
+    }
+
+    #[test]
+    fn test_validate_invalid_settings() {
+        let mut rt = runtime();
+        let cx = SinkContext::new_test(rt.executor());
+
+        let settings = InfluxDB2Settings::builder().org("my-org").build();
+        let result = rt.block_on_std(validate(
+            cx.resolver(),
+            "http://localhost:9999",
+            None,
+            None,
+            Some(settings),
+            None,
+        ));
+        assert!(result.is_err());
+    }
+}
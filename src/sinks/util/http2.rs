@@ -15,7 +15,7 @@ use futures01::{Async, AsyncSink, Poll as Poll01, Sink, StartSend};
 use http02::header::HeaderValue;
 use http02::{Request, StatusCode};
 use hyper13::body::{self, Body, HttpBody};
-use hyper13::client::HttpConnector;
+use hyper13::client::{self, HttpConnector};
 use hyper13::Client;
 use hyper_openssl08::HttpsConnector;
 use serde::{Deserialize, Serialize};
@@ -108,11 +108,34 @@ where
         batch_settings: BatchSettings,
         tls_settings: impl Into<MaybeTlsSettings>,
         cx: &SinkContext,
+    ) -> Self {
+        let client =
+            HttpClient::new(cx.resolver(), tls_settings).expect("Unable to initialize http client");
+        Self::with_client(
+            sink,
+            batch,
+            logic,
+            client,
+            request_settings,
+            batch_settings,
+            cx,
+        )
+    }
+
+    /// Sends the requests with `client`, so that its connections can be
+    /// shared with other requests, such as the healthcheck.
+    pub fn with_client(
+        sink: T,
+        batch: B,
+        logic: L,
+        client: HttpClient,
+        request_settings: TowerRequestSettings,
+        batch_settings: BatchSettings,
+        cx: &SinkContext,
     ) -> Self {
         let sink = Arc::new(sink);
         let sink1 = sink.clone();
-        let svc =
-            HttpBatchService::new(cx.resolver(), tls_settings, move |b| sink1.build_request(b));
+        let svc = HttpBatchService::with_client(client, move |b| sink1.build_request(b));
 
         let inner = request_settings.batch_sink(logic, svc, batch, batch_settings, cx.acker());
 
@@ -177,6 +200,15 @@ where
     pub fn new(
         resolver: Resolver,
         tls_settings: impl Into<MaybeTlsSettings>,
+    ) -> crate::Result<HttpClient<B>> {
+        Self::new_with_builder(resolver, tls_settings, &Client::builder())
+    }
+
+    /// Like `new`, with the connection pool and protocol options of `builder`.
+    pub fn new_with_builder(
+        resolver: Resolver,
+        tls_settings: impl Into<MaybeTlsSettings>,
+        builder: &client::Builder,
     ) -> crate::Result<HttpClient<B>> {
         let mut http = HttpConnector::new_with_resolver(resolver.clone());
         http.enforce_http(false);
@@ -194,7 +226,7 @@ where
             Ok(())
         });
 
-        let client = Client::builder().build(https);
+        let client = builder.build(https);
 
         let version = crate::get_version();
         let user_agent = HeaderValue::from_str(&format!("Vector/{}", version))
@@ -290,6 +322,13 @@ impl<B> HttpBatchService<B> {
         let inner =
             HttpClient::new(resolver, tls_settings).expect("Unable to initialize http client");
 
+        HttpBatchService::with_client(inner, request_builder)
+    }
+
+    pub fn with_client(
+        inner: HttpClient<Body>,
+        request_builder: impl Fn(B) -> hyper13::Request<Vec<u8>> + Sync + Send + 'static,
+    ) -> HttpBatchService<B> {
         HttpBatchService {
             inner,
             request_builder: Arc::new(Box::new(request_builder)),
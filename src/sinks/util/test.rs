@@ -8,9 +8,13 @@ use futures::{compat::Future01CompatExt, FutureExt, TryFutureExt, TryStreamExt};
 use futures01::{sync::mpsc, Future, Sink, Stream};
 use hyper13::{
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, Request, Response, Server, StatusCode,
 };
 use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 
 pub fn load_sink<T>(config: &str) -> crate::Result<(T, SinkContext, Runtime)>
 where
@@ -30,14 +34,31 @@ pub fn build_test_server(
     mpsc::Receiver<(http02::request::Parts, Vec<u8>)>,
     stream_cancel::Trigger,
     impl Future<Item = (), Error = ()>,
+) {
+    build_test_server_failing(addr, rt, 0)
+}
+
+/// Like `build_test_server`, but answers the first `failures` requests with
+/// a 500 so the sink under test retries them.
+pub fn build_test_server_failing(
+    addr: std::net::SocketAddr,
+    rt: &mut Runtime,
+    failures: usize,
+) -> (
+    mpsc::Receiver<(http02::request::Parts, Vec<u8>)>,
+    stream_cancel::Trigger,
+    impl Future<Item = (), Error = ()>,
 ) {
     let (tx, rx) = mpsc::channel(100);
+    let received = Arc::new(AtomicUsize::new(0));
     let service = make_service_fn(move |_| {
         let tx = tx.clone();
+        let received = Arc::clone(&received);
         async {
             Ok::<_, Error>(service_fn(move |req: Request<Body>| {
                 let tx = tx.clone();
-                async {
+                let fail = received.fetch_add(1, Ordering::SeqCst) < failures;
+                async move {
                     let (parts, body) = req.into_parts();
 
                     tokio01::spawn(
@@ -50,7 +71,11 @@ pub fn build_test_server(
                             .map_err(|e| panic!(e)),
                     );
 
-                    Ok::<_, Error>(Response::new(Body::empty()))
+                    let mut response = Response::new(Body::empty());
+                    if fail {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                    Ok::<_, Error>(response)
                 }
             }))
         }
@@ -67,3 +92,60 @@ pub fn build_test_server(
 
     (rx, trigger, server)
 }
+
+/// How many connections and requests a server from `build_test_responder`
+/// has accepted.
+#[derive(Clone, Debug, Default)]
+pub struct TestServerCounts {
+    connections: Arc<AtomicUsize>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl TestServerCounts {
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+/// Like `build_test_server`, but answers each request with whatever `respond`
+/// resolves to rather than collecting it. The listener is bound before this
+/// returns, so there's no need to wait for the port, which would count as a
+/// connection.
+pub fn build_test_responder<F, R>(
+    addr: std::net::SocketAddr,
+    rt: &mut Runtime,
+    respond: F,
+) -> (TestServerCounts, impl Future<Item = (), Error = ()>)
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + 'static,
+    R: std::future::Future<Output = Result<Response<Body>, Error>> + Send + 'static,
+{
+    let counts = TestServerCounts::default();
+    let service = {
+        let counts = counts.clone();
+        make_service_fn(move |_| {
+            counts.connections.fetch_add(1, Ordering::SeqCst);
+            let requests = Arc::clone(&counts.requests);
+            let respond = respond.clone();
+            async move {
+                Ok::<_, Error>(service_fn(move |req: Request<Body>| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    respond(req)
+                }))
+            }
+        })
+    };
+
+    let server = rt.block_on_std(async move {
+        Server::bind(&addr)
+            .serve(service)
+            .compat()
+            .map_err(|e| panic!("server error: {}", e))
+    });
+
+    (counts, server)
+}
@@ -213,7 +213,7 @@ pub fn build_pieces(
         .filter(|(name, _)| diff.sinks.contains_new(&name))
     {
         let sink_inputs = &sink.inputs;
-        let enable_healthcheck = sink.healthcheck;
+        let enable_healthcheck = sink.healthcheck.enabled;
 
         let typetag = sink.inner.sink_type();
         let input_type = sink.inner.input_type();
@@ -231,6 +231,7 @@ pub fn build_pieces(
             resolver: resolver.clone(),
             acker,
             exec: exec.clone(),
+            healthcheck: sink.healthcheck,
         };
 
         let (sink, healthcheck) = match sink.inner.build(cx) {
@@ -10,7 +10,7 @@ use crate::{
 use component::ComponentDescription;
 use futures01::sync::mpsc;
 use indexmap::IndexMap; // IndexMap preserves insertion order, allowing us to output errors in the same order they are present in the file
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use snafu::{ResultExt, Snafu};
 use std::fs::DirBuilder;
 use std::{collections::HashMap, path::PathBuf};
@@ -148,13 +148,70 @@ inventory::collect!(SourceDescription);
 pub struct SinkOuter {
     #[serde(default)]
     pub buffer: crate::buffers::BufferConfig,
-    #[serde(default = "healthcheck_default")]
-    pub healthcheck: bool,
+    #[serde(default)]
+    pub healthcheck: SinkHealthcheckOptions,
     pub inputs: Vec<String>,
     #[serde(flatten)]
     pub inner: Box<dyn SinkConfig>,
 }
 
+/// Whether the healthcheck of a sink runs. Set with either
+/// `healthcheck = false` or `healthcheck.enabled = false`, the table also
+/// holding the options of sinks with deeper checks.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SinkHealthcheckOptions {
+    pub enabled: bool,
+    /// Also write to the sink's destination to check the credentials may.
+    pub verify_write: bool,
+    /// Also run a query to check the query engine and the credentials.
+    pub flux_probe: bool,
+}
+
+impl Default for SinkHealthcheckOptions {
+    fn default() -> Self {
+        Self {
+            enabled: healthcheck_default(),
+            verify_write: false,
+            flux_probe: false,
+        }
+    }
+}
+
+/// The `healthcheck` table form of `SinkHealthcheckOptions`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthcheckTable {
+    #[serde(default = "healthcheck_default")]
+    enabled: bool,
+    #[serde(default)]
+    verify_write: bool,
+    #[serde(default)]
+    flux_probe: bool,
+}
+
+impl<'de> Deserialize<'de> for SinkHealthcheckOptions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Options {
+            Enabled(bool),
+            Table(HealthcheckTable),
+        }
+
+        Ok(match Options::deserialize(deserializer)? {
+            Options::Enabled(enabled) => Self {
+                enabled,
+                ..Self::default()
+            },
+            Options::Table(table) => Self {
+                enabled: table.enabled,
+                verify_write: table.verify_write,
+                flux_probe: table.flux_probe,
+            },
+        })
+    }
+}
+
 #[typetag::serde(tag = "type")]
 pub trait SinkConfig: core::fmt::Debug {
     fn build(&self, cx: SinkContext) -> crate::Result<(sinks::RouterSink, sinks::Healthcheck)>;
@@ -169,6 +226,7 @@ pub struct SinkContext {
     pub(super) acker: Acker,
     pub(super) resolver: Resolver,
     pub(super) exec: TaskExecutor,
+    pub(super) healthcheck: SinkHealthcheckOptions,
 }
 
 impl SinkContext {
@@ -178,9 +236,14 @@ impl SinkContext {
             acker: Acker::Null,
             resolver: Resolver::new(Vec::new(), exec.clone()).unwrap(),
             exec,
+            healthcheck: SinkHealthcheckOptions::default(),
         }
     }
 
+    pub fn healthcheck(&self) -> SinkHealthcheckOptions {
+        self.healthcheck
+    }
+
     pub fn acker(&self) -> Acker {
         self.acker.clone()
     }
@@ -193,6 +256,13 @@ impl SinkContext {
         self.resolver.clone()
     }
 
+    pub fn with_resolver(&self, resolver: Resolver) -> Self {
+        Self {
+            resolver,
+            ..self.clone()
+        }
+    }
+
     pub fn executor(&self) -> &TaskExecutor {
         &self.exec
     }
@@ -330,7 +400,7 @@ impl Config {
         let inputs = inputs.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
         let sink = SinkOuter {
             buffer: Default::default(),
-            healthcheck: true,
+            healthcheck: SinkHealthcheckOptions::default(),
             inner: Box::new(sink),
             inputs,
         };
@@ -600,6 +670,46 @@ mod test {
         assert_eq!("then", config.global.log_schema.timestamp_key().to_string());
     }
 
+    #[test]
+    fn healthcheck_forms() {
+        let healthcheck = |options: &str| {
+            toml::from_str::<Config>(&format!(
+                r#"
+      [sources.in]
+      type = "file"
+      include = ["/var/log/messages"]
+
+      [sinks.out]
+      type = "console"
+      inputs = ["in"]
+      encoding = "json"
+      {}
+      "#,
+                options
+            ))
+            .map(|config| config.sinks["out"].healthcheck)
+        };
+
+        assert!(healthcheck("").unwrap().enabled);
+        assert!(!healthcheck("healthcheck = false").unwrap().enabled);
+        assert!(
+            !healthcheck("[sinks.out.healthcheck]\n      enabled = false")
+                .unwrap()
+                .enabled
+        );
+        assert!(
+            healthcheck("[sinks.out.healthcheck]\n      verify_write = true")
+                .unwrap()
+                .verify_write
+        );
+        assert!(
+            healthcheck("[sinks.out.healthcheck]\n      flux_probe = true")
+                .unwrap()
+                .flux_probe
+        );
+        assert!(healthcheck("[sinks.out.healthcheck]\n      timeout = 5").is_err());
+    }
+
     #[test]
     fn config_append() {
         let mut config: Config = toml::from_str(
@@ -494,6 +494,52 @@ fn disabled_healthcheck() {
     .unwrap();
 }
 
+#[cfg(all(feature = "sources-socket", feature = "sinks-socket"))]
+#[test]
+fn disabled_healthcheck_table() {
+    load(
+        r#"
+      [sources.in]
+      type = "socket"
+      mode = "tcp"
+      address = "127.0.0.1:1234"
+
+      [sinks.out]
+      type = "socket"
+      mode = "tcp"
+      inputs = ["in"]
+      address = "0.0.0.0:0"
+      encoding = "text"
+      healthcheck.enabled = false
+      "#,
+    )
+    .unwrap();
+}
+
+#[cfg(all(feature = "sources-socket", feature = "sinks-socket"))]
+#[test]
+fn disabled_healthcheck_section() {
+    load(
+        r#"
+      [sources.in]
+      type = "socket"
+      mode = "tcp"
+      address = "127.0.0.1:1234"
+
+      [sinks.out]
+      type = "socket"
+      mode = "tcp"
+      inputs = ["in"]
+      address = "0.0.0.0:0"
+      encoding = "text"
+
+      [sinks.out.healthcheck]
+      enabled = false
+      "#,
+    )
+    .unwrap();
+}
+
 #[cfg(all(feature = "sources-stdin", feature = "sinks-http"))]
 #[test]
 fn parses_sink_no_request() {